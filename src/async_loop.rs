@@ -0,0 +1,262 @@
+//! Tokio based alternative to [`crate::main_loop`], enabled with the `async`
+//! feature. Stdin is read, timers fire and replies are written on their own
+//! tasks, so a handler awaiting a slow call (Redis, a reply from a peer) no
+//! longer holds up reading the next messages.
+
+use crate::{
+    scheduler::{TimerHandle, TimerId},
+    writters::MessageWritter,
+    Body, ErrorPayload, Init, InitPayload, Message,
+};
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc::{unbounded_channel, UnboundedSender},
+};
+
+/// Async counterpart of [`crate::Node`].
+pub trait AsyncNode<Payload>: Send {
+    /// Called once before any message is handled.
+    fn init(
+        &mut self,
+        scheduler: AsyncScheduler<Message<Payload>>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        writter: &mut (dyn MessageWritter<Message<Payload>> + Send),
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Called once stdin is closed and every pending message was handled.
+    fn on_shutdown(
+        &mut self,
+        _writter: &mut (dyn MessageWritter<Message<Payload>> + Send),
+    ) -> impl Future<Output = anyhow::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Same contract as [`crate::scheduler::Scheduler`], with every timer running
+/// as a tokio task.
+pub struct AsyncScheduler<T> {
+    tx: UnboundedSender<T>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl<T> Clone for AsyncScheduler<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> AsyncScheduler<T> {
+    pub fn new(tx: UnboundedSender<T>) -> Self {
+        Self {
+            tx,
+            next_id: Arc::default(),
+        }
+    }
+
+    fn handle(&self) -> TimerHandle {
+        TimerHandle {
+            id: TimerId(self.next_id.fetch_add(1, Ordering::Relaxed)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Delivers `item` once after `delay`.
+    pub fn schedule_once(&self, delay: Duration, item: T) -> TimerHandle {
+        let handle = self.handle();
+        let cancelled = handle.cancelled.clone();
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+
+            if !cancelled.load(Ordering::Relaxed) {
+                let _ = tx.send(item);
+            }
+        });
+
+        handle
+    }
+
+    /// Delivers `item` every `interval`, starting one interval from now.
+    pub fn schedule_periodic(&self, interval: Duration, item: T) -> TimerHandle {
+        let handle = self.handle();
+        let cancelled = handle.cancelled.clone();
+
+        let tx = self.tx.clone();
+        tokio::spawn(async move {
+            let mut ticks =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+            loop {
+                ticks.tick().await;
+
+                if cancelled.load(Ordering::Relaxed) || tx.send(item.clone()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        handle
+    }
+}
+
+/// Same as [`crate::main_loop`]: builds the node from the `init` message,
+/// replies `init_ok` and runs the node until stdin is closed. Must be called
+/// from within a tokio runtime, e.g. from a `#[tokio::main]` function.
+pub async fn async_main_loop<N, P, F>(new_node: F) -> anyhow::Result<()>
+where
+    N: AsyncNode<P>,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+{
+    let mut writter = StdoutWritter;
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    let line = lines
+        .next_line()
+        .await
+        .context("Failed to read init message")?
+        .context("Stdin closed before init")?;
+    let init = serde_json::from_str::<Message<InitPayload>>(&line)
+        .context("Failed to parse init message")?;
+    let InitPayload::Init(payload) = &init.body.payload else {
+        bail!("Expected init as the first message");
+    };
+
+    let mut node = new_node(payload.clone())?;
+    writter.send_message(&init.reply(InitPayload::InitOk))?;
+
+    let (tx, mut rx) = unbounded_channel();
+    let (timer_tx, mut timer_rx) = unbounded_channel();
+
+    node.init(AsyncScheduler::new(timer_tx)).await?;
+
+    let reciver_task = tokio::spawn(async move {
+        while let Some(line) = lines.next_line().await.context("Failed to read stdin")? {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: Message<P> =
+                serde_json::from_str(&line).context("Failed to parse stdin input message")?;
+
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+
+        anyhow::Ok(())
+    });
+
+    // Timers are kept on their own channel so that closing stdin, and not
+    // the timers, decides when the loop is over
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(message) => {
+                    // Requests that fail are answered with an error, as
+                    // main_loop does
+                    let request = (message.msg_id().is_some() && message.in_reply_to().is_none())
+                        .then(|| {
+                            Message::new(
+                                message.src().to_owned(),
+                                message.dest().to_owned(),
+                                Body::new(message.msg_id(), None, ()),
+                            )
+                        });
+
+                    match (node.handle_message(message, &mut writter).await, request) {
+                        (Err(error), Some(request)) => {
+                            let reply = request.reply(ErrorPayload::from_error(&error));
+                            writter.send_message(&reply).map_err(|_| error)?
+                        }
+                        (result, _) => result?,
+                    }
+                }
+                None => break,
+            },
+            Some(message) = timer_rx.recv() => node.handle_message(message, &mut writter).await?,
+        }
+    }
+
+    // Dropping the receiver stops the timer tasks on their next tick
+    drop(timer_rx);
+    node.on_shutdown(&mut writter).await?;
+
+    reciver_task
+        .await
+        .context("Failed to join reciver task")?
+        .context("Failed to read input messages")?;
+
+    Ok(())
+}
+
+/// Writes to stdout without holding its lock, which can't be sent across
+/// tasks.
+struct StdoutWritter;
+
+impl<T: Serialize> MessageWritter<T> for StdoutWritter {
+    fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(message).context("Error serializing response")?;
+        line.push(b'\n');
+
+        std::io::stdout()
+            .write_all(&line)
+            .context("Error writing response to stdout")
+    }
+
+    fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AsyncScheduler;
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn test_timers() {
+        let (tx, mut rx) = unbounded_channel();
+        let scheduler = AsyncScheduler::new(tx);
+
+        let once = scheduler.schedule_once(Duration::from_millis(5), "once");
+        let cancelled = scheduler.schedule_once(Duration::from_millis(5), "cancelled");
+        let periodic = scheduler.schedule_periodic(Duration::from_millis(5), "tick");
+        cancelled.cancel();
+
+        let mut received = Vec::new();
+        while received.iter().filter(|item| **item == "tick").count() < 3 {
+            received.push(rx.recv().await.unwrap());
+        }
+        periodic.cancel();
+
+        assert!(!once.is_cancelled());
+        assert!(received.contains(&"once"));
+        assert!(!received.contains(&"cancelled"));
+    }
+}
@@ -0,0 +1,36 @@
+//! Runs the node of any challenge, named by the first argument:
+//!
+//! ```text
+//! challenges broadcast --gossip-interval=100
+//! ```
+//!
+//! Flags after the name are the same as for the challenge's own binary.
+//! Maelstrom runs binaries without arguments, so without a name the binary
+//! goes by the one it was invoked as, e.g. through a `broadcast` symlink to
+//! it.
+
+use anyhow::bail;
+use distributed_system_challenges::{challenges, config::Config};
+use std::path::Path;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().peekable();
+    let program = args.next().unwrap_or_default();
+
+    let name = match args.next_if(|arg| !arg.starts_with("--")) {
+        Some(name) => name,
+        None => Path::new(&program)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    if !challenges::NAMES.contains(&name.as_str()) {
+        bail!(
+            "Usage: challenges <{}> [flags]",
+            challenges::NAMES.join("|")
+        );
+    }
+
+    let config = Config::parse(|name| std::env::var(name).ok(), args)?;
+    challenges::run(&name, config)
+}
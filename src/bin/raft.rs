@@ -0,0 +1,5 @@
+use distributed_system_challenges::{challenges::raft, config::Config};
+
+fn main() -> anyhow::Result<()> {
+    raft::run(Config::from_env()?)
+}
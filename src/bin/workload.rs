@@ -0,0 +1,518 @@
+//! Exercises a node binary without a Maelstrom install: spawns
+//! `--node-count` copies of it, routes the messages they exchange, sends them
+//! a mix of client requests at a fixed rate and reports throughput, latency
+//! and errors per request type.
+//!
+//! ```shell
+//! workload target/debug/kafka_style_log --mix send=3,poll=1 --rate 500 --time-limit 10 --node-count 3
+//! ```
+
+use anyhow::{bail, Context};
+use distributed_system_challenges::{Body, Message};
+use rand::{rngs::ThreadRng, Rng};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    str::FromStr,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, Instant},
+};
+
+/// Client every request comes from.
+const CLIENT: &str = "c1";
+/// How long to wait for the nodes to answer `init` and `topology`.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for the replies still missing once the time limit is up.
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+/// Keys `send` and `poll` requests are spread over.
+const KEYS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Echo,
+    Broadcast,
+    Send,
+    Poll,
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> anyhow::Result<Self> {
+        match kind {
+            "echo" => Ok(Kind::Echo),
+            "broadcast" => Ok(Kind::Broadcast),
+            "send" => Ok(Kind::Send),
+            "poll" => Ok(Kind::Poll),
+            _ => bail!("Unknown request type {kind}, expected echo, broadcast, send or poll"),
+        }
+    }
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Echo => "echo",
+            Kind::Broadcast => "broadcast",
+            Kind::Send => "send",
+            Kind::Poll => "poll",
+        }
+    }
+
+    /// Payload of the `n`th request.
+    fn request(self, n: usize, rng: &mut ThreadRng) -> Value {
+        let key = format!("k{}", rng.gen_range(0..KEYS));
+
+        match self {
+            Kind::Echo => json!({ "type": "echo", "echo": format!("Please echo {n}") }),
+            Kind::Broadcast => json!({ "type": "broadcast", "message": n }),
+            Kind::Send => json!({ "type": "send", "key": key, "msg": n }),
+            Kind::Poll => json!({ "type": "poll", "offsets": { key: 0 } }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    binary: String,
+    /// Request types with their weights.
+    mix: Vec<(Kind, u32)>,
+    /// Requests per second.
+    rate: u32,
+    time_limit: Duration,
+    node_count: usize,
+}
+
+impl Options {
+    /// Parses `<binary>` followed by flags given as `--name value` or
+    /// `--name=value`.
+    fn parse<A: IntoIterator<Item = String>>(args: A) -> anyhow::Result<Self> {
+        let mut args = args.into_iter();
+        let binary = args
+            .next()
+            .context("Usage: workload <node binary> [flags]")?;
+        let mut options = Options {
+            binary,
+            mix: vec![(Kind::Echo, 1)],
+            rate: 100,
+            time_limit: Duration::from_secs(10),
+            node_count: 1,
+        };
+
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                bail!("Unexpected argument {arg}");
+            };
+            let (name, value) = match flag.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => (
+                    flag.to_owned(),
+                    args.next()
+                        .with_context(|| format!("Missing value for --{flag}"))?,
+                ),
+            };
+
+            match name.as_str() {
+                "mix" => options.mix = mix(&value)?,
+                "rate" => options.rate = value.parse().context("Invalid --rate")?,
+                "time-limit" => {
+                    options.time_limit =
+                        Duration::from_secs(value.parse().context("Invalid --time-limit")?)
+                }
+                "node-count" => {
+                    options.node_count = value.parse().context("Invalid --node-count")?
+                }
+                _ => bail!("Unknown flag --{name}"),
+            }
+        }
+
+        if options.rate == 0 || options.node_count == 0 {
+            bail!("--rate and --node-count must be positive");
+        }
+
+        Ok(options)
+    }
+}
+
+/// Parses `echo=1,send=3`, a weight of 1 when left out.
+fn mix(value: &str) -> anyhow::Result<Vec<(Kind, u32)>> {
+    let mix = value
+        .split(',')
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((kind, weight)) => Ok((
+                kind.parse()?,
+                weight
+                    .parse()
+                    .with_context(|| format!("Invalid weight in {entry}"))?,
+            )),
+            None => Ok((entry.parse()?, 1)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if mix.iter().all(|(_, weight)| *weight == 0) {
+        bail!("Invalid --mix {value}: no request type has a weight");
+    }
+
+    Ok(mix)
+}
+
+/// Picks a request type with probability proportional to its weight.
+fn pick(mix: &[(Kind, u32)], rng: &mut ThreadRng) -> Kind {
+    let total = mix.iter().map(|(_, weight)| weight).sum::<u32>();
+    let mut target = rng.gen_range(0..total);
+
+    for (kind, weight) in mix {
+        if target < *weight {
+            return *kind;
+        }
+        target -= weight;
+    }
+
+    unreachable!("target is below the total weight")
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    sent: usize,
+    ok: usize,
+    errors: usize,
+    latencies: Vec<Duration>,
+}
+
+impl Stats {
+    /// Latency below which `quantile` of the replies arrived.
+    fn percentile(&mut self, quantile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+
+        self.latencies.sort_unstable();
+        let index = ((self.latencies.len() - 1) as f64 * quantile).round() as usize;
+        self.latencies[index]
+    }
+}
+
+struct Nodes {
+    children: Vec<Child>,
+    stdins: HashMap<String, ChildStdin>,
+}
+
+impl Nodes {
+    /// Spawns the nodes, forwarding every message they print to `tx`.
+    fn spawn(options: &Options, tx: &Sender<Message<Value>>) -> anyhow::Result<Self> {
+        let mut nodes = Nodes {
+            children: Vec::new(),
+            stdins: HashMap::new(),
+        };
+
+        for i in 1..=options.node_count {
+            let mut child = Command::new(&options.binary)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .with_context(|| format!("Failed to spawn {}", options.binary))?;
+
+            let node_id = format!("n{i}");
+            let stdout = child.stdout.take().context("Node without stdout")?;
+            let tx = tx.clone();
+            std::thread::spawn(move || forward_output(stdout, &tx));
+
+            let stdin = child.stdin.take().context("Node without stdin")?;
+            nodes.stdins.insert(node_id, stdin);
+            nodes.children.push(child);
+        }
+
+        Ok(nodes)
+    }
+
+    fn node_ids(&self) -> Vec<String> {
+        let mut node_ids = self.stdins.keys().cloned().collect::<Vec<_>>();
+        node_ids.sort();
+        node_ids
+    }
+
+    /// Writes `message` to its destination, returning `false` if no node
+    /// has that id.
+    fn deliver(&mut self, message: &Message<Value>) -> anyhow::Result<bool> {
+        let Some(stdin) = self.stdins.get_mut(message.dest()) else {
+            return Ok(false);
+        };
+
+        let line = serde_json::to_string(message)?;
+        writeln!(stdin, "{line}")
+            .and_then(|_| stdin.flush())
+            .with_context(|| format!("Failed to write to {}", message.dest()))?;
+
+        Ok(true)
+    }
+}
+
+impl Drop for Nodes {
+    fn drop(&mut self) {
+        // Closing stdin lets the nodes shut down on their own
+        self.stdins.clear();
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn forward_output<R: std::io::Read>(stdout: R, tx: &Sender<Message<Value>>) {
+    for line in BufReader::new(stdout).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+
+        match serde_json::from_str(&line) {
+            Ok(message) => {
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+            Err(e) => eprintln!("Unparsable node output {line}: {e}"),
+        }
+    }
+}
+
+/// Routes the nodes' messages among themselves, handing replies to the client
+/// to `on_reply`, until `deadline`.
+fn route_until(
+    nodes: &mut Nodes,
+    rx: &Receiver<Message<Value>>,
+    deadline: Instant,
+    dropped: &mut usize,
+    mut on_reply: impl FnMut(Message<Value>),
+) -> anyhow::Result<()> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let message = match rx.recv_timeout(timeout) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => return Ok(()),
+            Err(RecvTimeoutError::Disconnected) => bail!("Every node exited"),
+        };
+
+        if message.dest().starts_with('c') {
+            on_reply(message);
+        } else if !nodes.deliver(&message)? {
+            *dropped += 1;
+        }
+    }
+}
+
+/// A little while from now, to check on what's still pending, but no later
+/// than `deadline`.
+fn soon(deadline: Instant) -> Instant {
+    (Instant::now() + Duration::from_millis(10)).min(deadline)
+}
+
+/// Sends `payload` to every node and waits for all of them to reply.
+fn setup(
+    nodes: &mut Nodes,
+    rx: &Receiver<Message<Value>>,
+    next_msg_id: &mut usize,
+    dropped: &mut usize,
+    payload: impl Fn(&str) -> Value,
+) -> anyhow::Result<()> {
+    let mut waiting = HashMap::new();
+    for node_id in nodes.node_ids() {
+        let msg_id = *next_msg_id;
+        *next_msg_id += 1;
+
+        let body = Body::new(Some(msg_id), None, payload(&node_id));
+        nodes.deliver(&Message::new(CLIENT.to_owned(), node_id.clone(), body))?;
+        waiting.insert(msg_id, node_id);
+    }
+
+    let deadline = Instant::now() + SETUP_TIMEOUT;
+    while !waiting.is_empty() && Instant::now() < deadline {
+        route_until(nodes, rx, soon(deadline), dropped, |reply| {
+            if let Some(msg_id) = reply.in_reply_to() {
+                waiting.remove(&msg_id);
+            }
+        })?;
+    }
+
+    if !waiting.is_empty() {
+        bail!(
+            "No reply from {:?}",
+            waiting.into_values().collect::<Vec<_>>()
+        );
+    }
+
+    Ok(())
+}
+
+fn run(options: &Options) -> anyhow::Result<()> {
+    let (tx, rx) = channel();
+    let mut nodes = Nodes::spawn(options, &tx)?;
+    drop(tx);
+
+    let node_ids = nodes.node_ids();
+    let mut next_msg_id = 0;
+    let mut dropped = 0;
+
+    setup(
+        &mut nodes,
+        &rx,
+        &mut next_msg_id,
+        &mut dropped,
+        |node_id| json!({ "type": "init", "node_id": node_id, "node_ids": node_ids }),
+    )?;
+    if options.mix.iter().any(|(kind, _)| *kind == Kind::Broadcast) {
+        // Everyone is a neighbour of everyone else
+        setup(&mut nodes, &rx, &mut next_msg_id, &mut dropped, |_| {
+            let topology = node_ids
+                .iter()
+                .map(|node_id| {
+                    let others = node_ids.iter().filter(|other| *other != node_id);
+                    (node_id.clone(), others.cloned().collect::<Vec<_>>())
+                })
+                .collect::<HashMap<_, _>>();
+            json!({ "type": "topology", "topology": topology })
+        })?;
+    }
+
+    let mut rng = rand::thread_rng();
+    let mut stats = BTreeMap::<Kind, Stats>::new();
+    let mut pending = Pending::new();
+
+    let interval = Duration::from_secs(1) / options.rate;
+    let started = Instant::now();
+    let end = started + options.time_limit;
+    let mut next_request = started;
+    let mut requests = 0;
+
+    while next_request < end {
+        route_until(&mut nodes, &rx, next_request, &mut dropped, |reply| {
+            on_reply(&mut stats, &mut pending, &reply)
+        })?;
+
+        let kind = pick(&options.mix, &mut rng);
+        let dest = &node_ids[rng.gen_range(0..node_ids.len())];
+        let body = Body::new(Some(next_msg_id), None, kind.request(requests, &mut rng));
+        nodes.deliver(&Message::new(CLIENT.to_owned(), dest.clone(), body))?;
+
+        pending.insert(next_msg_id, (kind, Instant::now()));
+        stats.entry(kind).or_default().sent += 1;
+        next_msg_id += 1;
+        requests += 1;
+        next_request += interval;
+    }
+    let elapsed = started.elapsed();
+
+    let grace = Instant::now() + GRACE_PERIOD;
+    while !pending.is_empty() && Instant::now() < grace {
+        route_until(&mut nodes, &rx, soon(grace), &mut dropped, |reply| {
+            on_reply(&mut stats, &mut pending, &reply)
+        })?;
+    }
+
+    report(&mut stats, &pending, elapsed, dropped);
+
+    Ok(())
+}
+
+/// Requests waiting for a reply by `msg_id`, with their type and when they
+/// were sent.
+type Pending = HashMap<usize, (Kind, Instant)>;
+
+fn on_reply(stats: &mut BTreeMap<Kind, Stats>, pending: &mut Pending, reply: &Message<Value>) {
+    let Some((kind, sent_at)) = reply.in_reply_to().and_then(|id| pending.remove(&id)) else {
+        return;
+    };
+
+    let stats = stats.entry(kind).or_default();
+    if reply.body().payload["type"] == "error" {
+        stats.errors += 1;
+    } else {
+        stats.ok += 1;
+        stats.latencies.push(sent_at.elapsed());
+    }
+}
+
+fn report(stats: &mut BTreeMap<Kind, Stats>, pending: &Pending, elapsed: Duration, dropped: usize) {
+    let millis = |latency: Duration| latency.as_secs_f64() * 1000.0;
+
+    for (kind, stats) in stats.iter_mut() {
+        let timed_out = pending.values().filter(|(k, _)| k == kind).count();
+        let (p50, p99, max) = (
+            stats.percentile(0.5),
+            stats.percentile(0.99),
+            stats.percentile(1.0),
+        );
+        println!(
+            "{:<10} {:>7} sent {:>7} ok {:>5} errors {:>5} timed out {:>9.1} ok/s   \
+             p50 {:>7.2}ms p99 {:>7.2}ms max {:>7.2}ms",
+            kind.name(),
+            stats.sent,
+            stats.ok,
+            stats.errors,
+            timed_out,
+            stats.ok as f64 / elapsed.as_secs_f64(),
+            millis(p50),
+            millis(p99),
+            millis(max),
+        );
+    }
+
+    if dropped > 0 {
+        println!("{dropped} messages to unknown destinations dropped");
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    run(&options)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{mix, Kind, Options, Stats};
+    use std::time::Duration;
+
+    #[test]
+    fn test_options() {
+        let args = [
+            "./broadcast",
+            "--mix=broadcast=3,echo",
+            "--rate",
+            "50",
+            "--node-count=5",
+        ];
+        let options = Options::parse(args.map(str::to_owned)).unwrap();
+        assert_eq!(
+            options,
+            Options {
+                binary: "./broadcast".to_owned(),
+                mix: vec![(Kind::Broadcast, 3), (Kind::Echo, 1)],
+                rate: 50,
+                time_limit: Duration::from_secs(10),
+                node_count: 5,
+            }
+        );
+
+        let invalid = |args: &[&str]| Options::parse(args.iter().map(|a| a.to_string())).is_err();
+        assert!(invalid(&[]));
+        assert!(invalid(&["./echo", "--rate", "0"]));
+        assert!(invalid(&["./echo", "--nodes", "3"]));
+        assert!(mix("echo=0").is_err());
+        assert!(mix("txn").is_err());
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut stats = Stats {
+            latencies: (1..=100).rev().map(Duration::from_millis).collect(),
+            ..Stats::default()
+        };
+
+        assert_eq!(stats.percentile(0.5), Duration::from_millis(51));
+        assert_eq!(stats.percentile(0.99), Duration::from_millis(99));
+        assert_eq!(stats.percentile(1.0), Duration::from_millis(100));
+        assert_eq!(Stats::default().percentile(0.5), Duration::ZERO);
+    }
+}
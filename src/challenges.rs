@@ -0,0 +1,42 @@
+//! The nodes solving each challenge. Every one has a binary of its own and
+//! all of them run from the `challenges` binary too, e.g.
+//! `challenges broadcast --gossip-interval=100`.
+
+use crate::config::Config;
+use anyhow::bail;
+
+pub mod broadcast;
+pub mod echo;
+pub mod grow_only_counter;
+pub mod kafka_style_log;
+pub mod raft;
+pub mod totally_available_transactions;
+pub mod unique_id;
+
+/// Names [`run`] knows, in the order of the challenges.
+pub const NAMES: [&str; 7] = [
+    "echo",
+    "unique-ids",
+    "broadcast",
+    "counter",
+    "kafka",
+    "txn",
+    "lin-kv",
+];
+
+/// Runs the node of the challenge `name`, one of [`NAMES`].
+pub fn run(name: &str, config: Config) -> anyhow::Result<()> {
+    match name {
+        "echo" => echo::run(config),
+        "unique-ids" => unique_id::run(config),
+        "broadcast" => broadcast::run(config),
+        "counter" => grow_only_counter::run(config),
+        "kafka" => kafka_style_log::run(config),
+        "txn" => totally_available_transactions::run(config),
+        "lin-kv" => raft::run(config),
+        _ => bail!(
+            "Unknown challenge {name}, expected one of {}",
+            NAMES.join(", ")
+        ),
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    config::Config,
+    flow_control::{Ack, GOSSIP_ACK_TIMEOUT, RECEIVE_WINDOW},
+    gossip::GossipEngine,
+    main_loop_with_config,
+    scheduler::{Scheduler, TimerId},
+    state_transfer::{sync_chunks, StateTransfer, SYNC_THRESHOLD, SYNC_TIMEOUT},
+    Event, Init, Message, MessageSender, Node, NodeBase, NodeContext,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+    BroadcastMulti {
+        messages: Vec<usize>,
+    },
+    BroadcastMultiOk,
+    Read,
+    ReadOk {
+        messages: HashSet<usize>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+    Gossip {
+        seen: HashSet<usize>,
+        size: usize,
+    },
+    GossipOk(Ack),
+    SyncRequest,
+    SyncChunk {
+        messages: HashSet<usize>,
+        last: bool,
+    },
+}
+
+struct BroadcastNode {
+    base: NodeBase,
+    gossip: GossipEngine<usize>,
+    /// Ticks every gossip round, set at init.
+    gossip_timer: Option<TimerId>,
+    state_transfer: StateTransfer,
+}
+
+impl BroadcastNode {
+    fn new(init: Init, config: &Config) -> Self {
+        Self {
+            gossip: GossipEngine::new(
+                &init.node_id,
+                config.gossip_interval,
+                RECEIVE_WINDOW,
+                GOSSIP_ACK_TIMEOUT,
+            )
+            .with_jitter(config.gossip_jitter),
+            gossip_timer: None,
+            base: NodeBase::new(&init),
+            state_transfer: StateTransfer::new(SYNC_TIMEOUT),
+        }
+    }
+
+    fn messages(&self) -> &HashSet<usize> {
+        self.gossip.items()
+    }
+
+    fn handle_broadcast(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        value: usize,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::BroadcastOk);
+
+        self.gossip.insert(value);
+        sender.send(reply)
+    }
+
+    fn handle_broadcast_multi(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        values: &[usize],
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::BroadcastMultiOk);
+
+        for value in values {
+            self.gossip.insert(*value);
+        }
+        sender.send(reply)
+    }
+
+    fn handle_read(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::ReadOk {
+            messages: self.messages().clone(),
+        });
+
+        sender.send(reply)
+    }
+
+    fn handle_topology(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        topology: &HashMap<String, Vec<String>>,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::TopologyOk);
+
+        self.gossip.set_peers(
+            topology
+                .get(self.base.node_id())
+                .map_or_else(Vec::new, |v| v.clone()),
+        );
+
+        sender.send(reply)
+    }
+
+    fn handle_gossip(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        seen: HashSet<usize>,
+        size: usize,
+    ) -> anyhow::Result<()> {
+        self.gossip.receive(message.src(), seen, |_| {});
+
+        if size > self.messages().len() + SYNC_THRESHOLD && self.state_transfer.begin() {
+            let sync_request = self.base.message(message.src(), Payload::SyncRequest);
+
+            sender.send_with_retry(sync_request)?;
+        }
+
+        let ack = self.gossip.ack(self.state_transfer.is_running());
+
+        sender.send(message.reply(Payload::GossipOk(ack)))
+    }
+
+    fn handle_gossip_ok(&mut self, reply: &Message<Payload>, window: usize) {
+        self.gossip.on_ack(reply, window);
+    }
+
+    fn handle_sync_request(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let sync_chunks = sync_chunks(message, self.messages().iter().copied(), |chunk| {
+            Payload::SyncChunk {
+                messages: chunk.items.into_iter().collect(),
+                last: chunk.last,
+            }
+        });
+
+        sender.send_all(sync_chunks)
+    }
+
+    fn handle_sync_chunk(&mut self, src: &str, messages: &HashSet<usize>, last: bool) {
+        self.gossip.receive(src, messages.iter().copied(), |_| {});
+
+        if last {
+            self.state_transfer.finish();
+        }
+    }
+
+    fn handle_gossip_tick(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let size = self.messages().len();
+
+        self.gossip.round(sender, |seen| Payload::Gossip {
+            seen: seen.into_iter().collect(),
+            size,
+        })
+    }
+}
+
+impl Node<Payload> for BroadcastNode {
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        self.gossip_timer = Some(self.gossip.start(&scheduler).id());
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.gossip_timer => self.handle_gossip_tick(ctx),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match &message.body().payload {
+            Payload::Broadcast { message: value } => {
+                self.handle_broadcast(ctx, &message, *value)?
+            }
+
+            Payload::BroadcastOk => {}
+            Payload::BroadcastMulti { messages } => {
+                self.handle_broadcast_multi(ctx, &message, messages)?
+            }
+            Payload::BroadcastMultiOk => {}
+            Payload::Read => self.handle_read(ctx, &message)?,
+            Payload::ReadOk { .. } => {}
+            Payload::Topology { topology } => self.handle_topology(ctx, &message, topology)?,
+            Payload::TopologyOk => {}
+            Payload::Gossip { seen, size } => {
+                self.handle_gossip(ctx, &message, seen.clone(), *size)?
+            }
+            Payload::GossipOk(ack) => self.handle_gossip_ok(&message, ack.window),
+            Payload::SyncRequest => self.handle_sync_request(ctx, &message)?,
+            Payload::SyncChunk { messages, last } => {
+                self.handle_sync_chunk(message.src(), messages, *last)
+            }
+        };
+
+        Ok(())
+    }
+    fn stats(&self) -> serde_json::Value {
+        serde_json::json!({
+            "messages": self.gossip.items().len(),
+            "peers": self.gossip.peers(),
+        })
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    main_loop_with_config(config.clone(), |init| Ok(BroadcastNode::new(init, &config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BroadcastNode, Payload};
+    use crate::{
+        config::Config,
+        conformance::{assert_reply_to, assert_round_trip, assert_serializes, init},
+        simulator::Simulator,
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+    use std::collections::{HashMap, HashSet};
+
+    const TOPOLOGY: &str = r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{"n1":["n2","n3"],"n2":["n1"],"n3":["n1"]}}}"#;
+    const BROADCAST: &str =
+        r#"{"src":"c2","dest":"n1","body":{"type":"broadcast","msg_id":3,"message":1000}}"#;
+    const BROADCAST_MULTI: &str =
+        r#"{"src":"c3","dest":"n1","body":{"type":"broadcast_multi","msg_id":4,"messages":[1,2]}}"#;
+    const READ: &str = r#"{"src":"c4","dest":"n1","body":{"type":"read","msg_id":5}}"#;
+    const GOSSIP: &str =
+        r#"{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":7,"seen":[3],"size":4}}"#;
+    const GOSSIP_OK: &str =
+        r#"{"src":"n1","dest":"n2","body":{"type":"gossip_ok","in_reply_to":7,"window":4}}"#;
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[
+            TOPOLOGY,
+            BROADCAST,
+            BROADCAST_MULTI,
+            READ,
+            GOSSIP,
+            GOSSIP_OK,
+        ]);
+        // Gossip rounds are timer ticks, clients can't trigger them
+        let trigger = r#"{"src":"c1","dest":"n1","body":{"type":"trigger_gossip"}}"#;
+        assert!(serde_json::from_str::<Message<Payload>>(trigger).is_err());
+
+        assert_serializes(&[
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c2".to_owned(),
+                    Body::new(Some(0), Some(3), Payload::BroadcastOk),
+                ),
+                r#"{"src":"n1","dest":"c2","body":{"type":"broadcast_ok","msg_id":0,"in_reply_to":3}}"#,
+            ),
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c4".to_owned(),
+                    Body::new(
+                        Some(1),
+                        Some(5),
+                        Payload::ReadOk {
+                            messages: HashSet::from([1000]),
+                        },
+                    ),
+                ),
+                r#"{"src":"n1","dest":"c4","body":{"type":"read_ok","msg_id":1,"in_reply_to":5,"messages":[1000]}}"#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = BroadcastNode::new(init(), &Config::default());
+
+        let requests = [TOPOLOGY, BROADCAST, BROADCAST_MULTI, READ]
+            .map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), requests.len());
+        for (request, reply) in requests.iter().zip(sent.iter()) {
+            assert_reply_to(request, reply);
+        }
+    }
+
+    #[test]
+    fn test_broadcast_converges() {
+        let config = Config::default();
+        let mut simulator =
+            Simulator::new(3, |init| Ok(BroadcastNode::new(init, &config))).unwrap();
+        let request = |dest: &str, msg_id, payload| {
+            Message::new(
+                "c1".to_owned(),
+                dest.to_owned(),
+                Body::new(Some(msg_id), None, payload),
+            )
+        };
+
+        // A line, so n3 only learns about n1's messages through n2
+        let topology = HashMap::from([
+            ("n1".to_owned(), vec!["n2".to_owned()]),
+            ("n2".to_owned(), vec!["n1".to_owned(), "n3".to_owned()]),
+            ("n3".to_owned(), vec!["n2".to_owned()]),
+        ]);
+        let node_ids = simulator.node_ids().map(str::to_owned).collect::<Vec<_>>();
+        for node_id in &node_ids {
+            let topology = topology.clone();
+            simulator.send(request(node_id, 1, Payload::Topology { topology }));
+        }
+        simulator.send(request("n1", 2, Payload::Broadcast { message: 7 }));
+        simulator.send(request("n3", 3, Payload::Broadcast { message: 8 }));
+        let messages = vec![9, 10];
+        simulator.send(request("n2", 4, Payload::BroadcastMulti { messages }));
+
+        simulator.run_for(config.gossip_interval * 4).unwrap();
+
+        for node_id in &node_ids {
+            assert_eq!(
+                *simulator.node(node_id).messages(),
+                HashSet::from([7, 8, 9, 10])
+            );
+        }
+    }
+}
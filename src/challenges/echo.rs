@@ -0,0 +1,108 @@
+use crate::{
+    config::Config, main_loop_with_config, scheduler::Scheduler, Message, MessageSender, Node,
+    NodeContext,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+struct EchoNode;
+
+impl EchoNode {
+    fn handle_echo(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        echo: &str,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::EchoOk {
+            echo: echo.to_owned(),
+        });
+
+        sender.send(reply)
+    }
+}
+
+impl Node<Payload> for EchoNode {
+    fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match &message.body().payload {
+            Payload::Echo { echo } => self.handle_echo(ctx, &message, echo)?,
+
+            Payload::EchoOk { .. } => {}
+        };
+
+        Ok(())
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    main_loop_with_config(config, |_| Ok(EchoNode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EchoNode, Payload};
+    use crate::{
+        conformance::{assert_reply_to, assert_round_trip, assert_serializes},
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+
+    const ECHO: &str =
+        r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":2,"echo":"Please echo 35"}}"#;
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[ECHO]);
+
+        assert_serializes(&[(
+            Message::new(
+                "n1".to_owned(),
+                "c1".to_owned(),
+                Body::new(
+                    Some(0),
+                    Some(2),
+                    Payload::EchoOk {
+                        echo: "Please echo 35".to_owned(),
+                    },
+                ),
+            ),
+            r#"{"src":"n1","dest":"c1","body":{"type":"echo_ok","msg_id":0,"in_reply_to":2,"echo":"Please echo 35"}}"#,
+        )]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = EchoNode;
+
+        let requests = [ECHO].map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), requests.len());
+        for (request, reply) in requests.iter().zip(sent.iter()) {
+            assert_reply_to(request, reply);
+        }
+    }
+}
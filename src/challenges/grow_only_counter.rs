@@ -0,0 +1,536 @@
+use crate::{
+    config::Config,
+    flow_control::{Ack, FlowControl, GOSSIP_ACK_TIMEOUT, RECEIVE_WINDOW},
+    main_loop_with_config,
+    rpc::Rpc,
+    scheduler::{Scheduler, TimerId},
+    stability::{StabilityTracker, Watermarks},
+    state_transfer::{sync_chunks, StateTransfer, SYNC_THRESHOLD, SYNC_TIMEOUT},
+    Body, Event, Init, Message, MessageSender, Node, NodeBase, NodeContext,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Add {
+        delta: usize,
+    },
+    AddOk,
+    AddMulti {
+        deltas: Vec<usize>,
+    },
+    AddMultiOk,
+    Read,
+    ReadOk {
+        value: usize,
+    },
+    Gossip {
+        seen: WireEntries,
+        delivered: Watermarks,
+    },
+    GossipOk(Ack),
+    SyncRequest,
+    SyncChunk {
+        entries: WireEntries,
+        collected: Watermarks,
+        folded: HashMap<NodeId, usize>,
+        last: bool,
+    },
+}
+
+type NodeId = String;
+type Entries = HashMap<NodeId, BTreeMap<u64, usize>>;
+// Integer map keys can't be deserialized inside internally tagged enums, so
+// entries travel as (sequence, delta) pairs
+type WireEntries = HashMap<NodeId, Vec<(u64, usize)>>;
+
+struct GrowOnlyCounterNode {
+    base: NodeBase,
+    gossip_interval: Duration,
+    gossip_jitter: Duration,
+    /// Ticks every gossip round, set at init.
+    gossip_timer: Option<TimerId>,
+    sequence: u64,
+    entries: Entries,
+    delivered: Watermarks,
+    collected: Watermarks,
+    folded: HashMap<NodeId, usize>,
+    value: usize,
+    stability: StabilityTracker,
+    flow_control: FlowControl,
+    state_transfer: StateTransfer,
+    rpc: Rpc<Self, Payload>,
+}
+
+impl GrowOnlyCounterNode {
+    fn new(init: Init, config: &Config) -> Self {
+        Self {
+            base: NodeBase::new(&init),
+            stability: StabilityTracker::new(init.node_ids),
+            gossip_interval: config.gossip_interval,
+            gossip_jitter: config.gossip_jitter,
+            gossip_timer: None,
+            sequence: 0,
+            entries: HashMap::new(),
+            delivered: HashMap::new(),
+            collected: HashMap::new(),
+            folded: HashMap::new(),
+            value: 0,
+            flow_control: FlowControl::new(RECEIVE_WINDOW, GOSSIP_ACK_TIMEOUT),
+            state_transfer: StateTransfer::new(SYNC_TIMEOUT),
+            rpc: Rpc::new(),
+        }
+    }
+
+    fn handle_add(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        delta: usize,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::AddOk);
+
+        self.sequence += 1;
+        let node_id = self.base.node_id().to_owned();
+        self.insert_entry(&node_id, self.sequence, delta);
+
+        sender.send(reply)
+    }
+
+    /// Folds the whole batch into a single entry so it is applied and
+    /// replicated atomically.
+    fn handle_add_multi(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        deltas: &[usize],
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::AddMultiOk);
+
+        self.sequence += 1;
+        let node_id = self.base.node_id().to_owned();
+        self.insert_entry(&node_id, self.sequence, deltas.iter().sum());
+
+        sender.send(reply)
+    }
+
+    fn handle_read(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let reply = message.reply(Payload::ReadOk { value: self.value });
+
+        sender.send(reply)
+    }
+
+    fn handle_gossip(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        seen: &WireEntries,
+        delivered: &Watermarks,
+    ) -> anyhow::Result<()> {
+        for (origin, entries) in seen {
+            for (sequence, delta) in entries {
+                self.insert_entry(origin, *sequence, *delta);
+            }
+        }
+
+        self.stability.update(message.src(), delivered);
+        self.collect_stable();
+
+        let behind = delivered
+            .iter()
+            .map(|(origin, watermark)| {
+                let own = self.delivered.get(origin).copied().unwrap_or_default();
+                watermark.saturating_sub(own)
+            })
+            .sum::<u64>();
+
+        if behind > SYNC_THRESHOLD as u64 && self.state_transfer.begin() {
+            let sync_request = self.base.message(message.src(), Payload::SyncRequest);
+
+            sender.send(sync_request)?;
+        }
+
+        let ack = self.flow_control.ack(self.state_transfer.is_running());
+
+        sender.send(message.reply(Payload::GossipOk(ack)))
+    }
+
+    fn handle_gossip_ok(&mut self, reply: Message<Payload>) -> anyhow::Result<()> {
+        if let Payload::GossipOk(ack) = &reply.body().payload
+            && let Some(msg_id) = reply.in_reply_to()
+        {
+            self.flow_control.on_ack(reply.src(), msg_id, ack.window);
+        }
+
+        Ok(())
+    }
+
+    fn handle_sync_request(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let entries = self.entries.iter().flat_map(|(origin, entries)| {
+            entries
+                .iter()
+                .map(|(sequence, delta)| (origin.to_owned(), *sequence, *delta))
+        });
+        let sync_chunks = sync_chunks(message, entries, |chunk| {
+            let mut entries = WireEntries::new();
+            for (origin, sequence, delta) in chunk.items {
+                entries.entry(origin).or_default().push((sequence, delta));
+            }
+
+            // Collected components only need to travel once
+            let (collected, folded) = if chunk.first {
+                (self.collected.clone(), self.folded.clone())
+            } else {
+                Default::default()
+            };
+
+            Payload::SyncChunk {
+                entries,
+                collected,
+                folded,
+                last: chunk.last,
+            }
+        });
+
+        sender.send_all(sync_chunks)
+    }
+
+    fn handle_sync_chunk(
+        &mut self,
+        entries: &WireEntries,
+        collected: &Watermarks,
+        folded: &HashMap<NodeId, usize>,
+        last: bool,
+    ) -> anyhow::Result<()> {
+        for (origin, watermark) in collected {
+            let sum = folded.get(origin).copied().unwrap_or_default();
+            self.adopt_folded(origin, *watermark, sum);
+        }
+
+        for (origin, entries) in entries {
+            for (sequence, delta) in entries {
+                self.insert_entry(origin, *sequence, *delta);
+            }
+        }
+
+        if last {
+            self.state_transfer.finish();
+        }
+
+        Ok(())
+    }
+
+    fn insert_entry(&mut self, origin: &str, sequence: u64, delta: usize) {
+        let collected = self.collected.get(origin).copied().unwrap_or_default();
+        if sequence <= collected {
+            return;
+        }
+
+        let entries = self.entries.entry(origin.to_owned()).or_default();
+        if entries.insert(sequence, delta).is_none() {
+            self.value += delta;
+        }
+
+        self.advance_delivered(origin);
+    }
+
+    /// Replaces everything we hold from `origin` up to `watermark` with the
+    /// folded sum a peer already collected for that range.
+    fn adopt_folded(&mut self, origin: &str, watermark: u64, sum: usize) {
+        let collected = self.collected.get(origin).copied().unwrap_or_default();
+        if watermark <= collected {
+            return;
+        }
+
+        let entries = self.entries.entry(origin.to_owned()).or_default();
+        let kept = entries.split_off(&(watermark + 1));
+        let dropped = std::mem::replace(entries, kept).values().sum::<usize>();
+        let own_folded = self.folded.get(origin).copied().unwrap_or_default();
+
+        self.value = self.value - own_folded - dropped + sum;
+        self.folded.insert(origin.to_owned(), sum);
+        self.collected.insert(origin.to_owned(), watermark);
+
+        self.advance_delivered(origin);
+    }
+
+    fn advance_delivered(&mut self, origin: &str) {
+        let collected = self.collected.get(origin).copied().unwrap_or_default();
+        let entries = self.entries.entry(origin.to_owned()).or_default();
+
+        let delivered = self.delivered.entry(origin.to_owned()).or_default();
+        *delivered = (*delivered).max(collected);
+        while entries.contains_key(&(*delivered + 1)) {
+            *delivered += 1;
+        }
+    }
+
+    /// Drops the entries every node has already delivered. Their deltas are
+    /// already part of `value`, so only the bookkeeping is released.
+    fn collect_stable(&mut self) {
+        self.stability.update(self.base.node_id(), &self.delivered);
+
+        for (origin, entries) in self.entries.iter_mut() {
+            let stable = self.stability.stable(origin);
+            if self.collected.get(origin).is_some_and(|c| *c >= stable) {
+                continue;
+            }
+
+            let kept = entries.split_off(&(stable + 1));
+            let collected = std::mem::replace(entries, kept);
+
+            *self.folded.entry(origin.to_owned()).or_default() += collected.values().sum::<usize>();
+            self.collected.insert(origin.to_owned(), stable);
+        }
+    }
+
+    fn handle_gossip_tick(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let neighbors = self.base.peers();
+        if neighbors.is_empty() {
+            return Ok(());
+        }
+
+        let messages = neighbors
+            .iter()
+            .filter_map(|n| {
+                let msg_id = sender.next_msg_id();
+                if !self.flow_control.try_acquire(n, msg_id) {
+                    return None;
+                }
+
+                let known = self.stability.watermarks(n).expect("Unknown node");
+
+                let n_not_seen = self
+                    .entries
+                    .iter()
+                    .map(|(origin, entries)| {
+                        let watermark = known.get(origin).copied().unwrap_or_default();
+                        let not_seen = entries
+                            .range(watermark + 1..)
+                            .map(|(sequence, delta)| (*sequence, *delta))
+                            .collect::<Vec<_>>();
+
+                        (origin.to_owned(), not_seen)
+                    })
+                    .filter(|(_, not_seen)| !not_seen.is_empty())
+                    .collect();
+
+                Some(Message::new(
+                    self.base.node_id().to_owned(),
+                    n.to_owned(),
+                    Body::new(
+                        Some(msg_id),
+                        None,
+                        Payload::Gossip {
+                            seen: n_not_seen,
+                            delivered: self.delivered.clone(),
+                        },
+                    ),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        self.rpc.prune(GOSSIP_ACK_TIMEOUT);
+        for message in &messages {
+            let msg_id = message.msg_id().expect("Gossip without msg_id");
+            self.rpc.register(msg_id, |node: &mut Self, reply| {
+                node.handle_gossip_ok(reply)
+            });
+        }
+
+        sender.send_all(messages)
+    }
+}
+
+impl Node<Payload> for GrowOnlyCounterNode {
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        let timer = scheduler.tick_jittered(self.gossip_interval, self.gossip_jitter);
+        self.gossip_timer = Some(timer.id());
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.gossip_timer => self.handle_gossip_tick(ctx),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        if let Some(callback) = self.rpc.take_callback(&message) {
+            return callback(self, message);
+        }
+
+        match &message.body().payload {
+            Payload::Add { delta } => self.handle_add(ctx, &message, *delta),
+            Payload::AddOk => Ok(()),
+            Payload::AddMulti { deltas } => self.handle_add_multi(ctx, &message, deltas),
+            Payload::AddMultiOk => Ok(()),
+            Payload::Read => self.handle_read(ctx, &message),
+            Payload::ReadOk { .. } => Ok(()),
+            Payload::Gossip { seen, delivered } => {
+                self.handle_gossip(ctx, &message, seen, delivered)
+            }
+            Payload::GossipOk { .. } => Ok(()),
+            Payload::SyncRequest => self.handle_sync_request(ctx, &message),
+            Payload::SyncChunk {
+                entries,
+                collected,
+                folded,
+                last,
+            } => self.handle_sync_chunk(entries, collected, folded, *last),
+        }
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    main_loop_with_config(config.clone(), |init| {
+        Ok(GrowOnlyCounterNode::new(init, &config))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GrowOnlyCounterNode, Payload};
+    use crate::{
+        config::Config,
+        conformance::{assert_reply_to, assert_round_trip, assert_serializes, init},
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+
+    const ADD: &str = r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":2,"delta":3}}"#;
+    const ADD_MULTI: &str =
+        r#"{"src":"c2","dest":"n1","body":{"type":"add_multi","msg_id":3,"deltas":[1,2]}}"#;
+    const READ: &str = r#"{"src":"c3","dest":"n1","body":{"type":"read","msg_id":4}}"#;
+    const GOSSIP: &str = r#"{"src":"n2","dest":"n1","body":{"type":"gossip","msg_id":7,"seen":{"n2":[[1,5]]},"delivered":{"n2":1}}}"#;
+    const GOSSIP_OK: &str =
+        r#"{"src":"n1","dest":"n2","body":{"type":"gossip_ok","in_reply_to":7,"window":4}}"#;
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[ADD, ADD_MULTI, READ, GOSSIP, GOSSIP_OK]);
+
+        assert_serializes(&[
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c1".to_owned(),
+                    Body::new(Some(0), Some(2), Payload::AddOk),
+                ),
+                r#"{"src":"n1","dest":"c1","body":{"type":"add_ok","msg_id":0,"in_reply_to":2}}"#,
+            ),
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c3".to_owned(),
+                    Body::new(Some(1), Some(4), Payload::ReadOk { value: 6 }),
+                ),
+                r#"{"src":"n1","dest":"c3","body":{"type":"read_ok","msg_id":1,"in_reply_to":4,"value":6}}"#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = GrowOnlyCounterNode::new(init(), &Config::default());
+
+        let requests =
+            [ADD, ADD_MULTI, READ].map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), requests.len());
+        for (request, reply) in requests.iter().zip(sent.iter()) {
+            assert_reply_to(request, reply);
+        }
+    }
+
+    #[test]
+    fn test_add_multi() {
+        let writter = MemoryWritter::new();
+        let mut sender = MessageSender::new(writter);
+        let mut node = GrowOnlyCounterNode::new(init(), &Config::default());
+
+        for request in [ADD, ADD_MULTI] {
+            let request = serde_json::from_str::<Message<Payload>>(request).unwrap();
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        // The batch is a single entry, gossiped and collected as one
+        assert_eq!(node.value, 6);
+        assert_eq!(node.sequence, 2);
+        assert_eq!(node.entries["n1"].get(&2), Some(&3));
+    }
+
+    #[test]
+    fn test_collect_stable() {
+        let writter = MemoryWritter::new();
+        let mut sender = MessageSender::new(writter);
+        let mut node = GrowOnlyCounterNode::new(init(), &Config::default());
+        let gossip = |src: &str, seen: &str, delivered: u64| {
+            let gossip = format!(
+                r#"{{"src":"{src}","dest":"n1","body":{{"type":"gossip","msg_id":1,"seen":{seen},"delivered":{{"n2":{delivered}}}}}}}"#
+            );
+            serde_json::from_str::<Message<Payload>>(&gossip).unwrap()
+        };
+
+        node.handle_message(
+            gossip("n2", r#"{"n2":[[1,5],[2,3]]}"#, 2),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.value, 8);
+        assert_eq!(node.entries["n2"].len(), 2);
+
+        // Once n3 delivered them too, only their sum is kept
+        node.handle_message(gossip("n3", "{}", 1), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(node.collected["n2"], 1);
+        assert_eq!(node.folded["n2"], 5);
+        assert_eq!(node.entries["n2"].len(), 1);
+        assert_eq!(node.value, 8);
+
+        // Redelivered entries don't count twice
+        node.handle_message(
+            gossip("n3", r#"{"n2":[[1,5],[2,3]]}"#, 2),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.collected["n2"], 2);
+        assert!(node.entries["n2"].is_empty());
+        assert_eq!(node.value, 8);
+    }
+}
@@ -0,0 +1,877 @@
+use crate::{
+    concurrent::concurrent_main_loop,
+    config::Config,
+    membership::Membership,
+    middleware::{Dedup, Middleware},
+    ring::Ring,
+    routing::{Route, Router},
+    scheduler::{Scheduler, TimerId},
+    state_transfer::{sync_chunks, StateTransfer, SYNC_THRESHOLD, SYNC_TIMEOUT},
+    Event, Init, Message, MessageSender, Node, NodeBase, NodeContext,
+};
+use anyhow::Context;
+use redis::{Commands, Connection};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Send {
+        key: KeyId,
+        msg: usize,
+    },
+    SendOk {
+        offset: Offset,
+    },
+    Poll {
+        offsets: HashMap<KeyId, Offset>,
+    },
+    PollOk {
+        #[serde(
+            serialize_with = "serialize_as_pairs",
+            deserialize_with = "deserialize_pairs"
+        )]
+        msgs: HashMap<KeyId, HashMap<Offset, usize>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<KeyId, Offset>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: HashSet<KeyId>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<KeyId, Offset>,
+    },
+    InternalSend {
+        log_entry: LogEntry,
+    },
+    InternalSendOk,
+    InternalCommitOffsets {
+        offsets: HashMap<KeyId, Offset>,
+    },
+    InternalCommitOffsetsOk,
+    SyncRequest,
+    SyncChunk {
+        log_entries: Vec<LogEntry>,
+        offsets: HashMap<KeyId, Offset>,
+        last: bool,
+    },
+}
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(300);
+const FORWARD_RETRY_TIMEOUT: Duration = Duration::from_millis(500);
+const VIRTUAL_NODES: usize = 64;
+const DEDUP_CAPACITY: usize = 10_000;
+const WORKERS: usize = 4;
+
+type NodeId = String;
+type KeyId = String;
+type Offset = usize;
+type Logs = HashMap<KeyId, HashSet<LogEntry>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LogEntry {
+    msg_id: usize,
+    key: KeyId,
+    offset: Offset,
+    msg: usize,
+    seen_by: HashSet<NodeId>,
+}
+
+impl PartialEq for LogEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.offset == other.offset
+    }
+}
+
+impl Eq for LogEntry {}
+
+impl Hash for LogEntry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+        self.offset.hash(state);
+    }
+}
+
+struct LogStore {
+    src: String,
+    logs: Logs,
+    offsets: HashMap<KeyId, usize>,
+}
+
+impl LogStore {
+    fn new(src: &str) -> Self {
+        Self {
+            src: src.to_owned(),
+            logs: Default::default(),
+            offsets: Default::default(),
+        }
+    }
+
+    fn insert(&mut self, log_entry: LogEntry) -> anyhow::Result<()> {
+        self.logs
+            .entry(log_entry.key.to_owned())
+            .and_modify(|entries| {
+                entries.insert(log_entry.clone());
+            })
+            .or_insert(HashSet::from([log_entry]));
+
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        key: &str,
+        msg_id: usize,
+        offset: usize,
+        msg: usize,
+    ) -> anyhow::Result<LogEntry> {
+        let seen_by = HashSet::from([self.src.to_owned()]);
+
+        let log_entry = LogEntry {
+            msg_id,
+            key: key.to_owned(),
+            offset,
+            msg,
+            seen_by,
+        };
+
+        self.logs
+            .entry(key.to_owned())
+            .or_default()
+            .insert(log_entry.clone());
+
+        Ok(log_entry)
+    }
+
+    /// Appends `msg` at the offset after the highest one of `key`, picking
+    /// the offset and inserting the entry under the same borrow so two sends
+    /// to the same key can't get the same offset.
+    fn append_next(&mut self, key: &str, msg_id: usize, msg: usize) -> anyhow::Result<LogEntry> {
+        let offset = self.highest_offset(key) + 1;
+
+        self.append(key, msg_id, offset, msg)
+    }
+
+    fn highest_offset(&self, key: &str) -> Offset {
+        self.logs
+            .get(key)
+            .and_then(|entries| entries.iter().map(|entry| entry.offset).max())
+            .unwrap_or_default()
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &LogEntry> {
+        self.logs.values().flatten()
+    }
+
+    fn offsets(&self) -> &HashMap<KeyId, usize> {
+        &self.offsets
+    }
+
+    fn commit(&mut self, offsets: &HashMap<String, usize>) -> anyhow::Result<()> {
+        // Commits only move forward, whichever order they arrive in
+        for (key, offset) in offsets {
+            let committed = self.offsets.entry(key.to_owned()).or_default();
+            *committed = (*committed).max(*offset);
+        }
+
+        Ok(())
+    }
+
+    fn list_logs(&self, keys: &HashMap<KeyId, usize>) -> anyhow::Result<Logs> {
+        let mut committed_logs = HashMap::new();
+
+        for (key, offset) in keys {
+            let Some(entries) = self.logs.get(key) else {
+                continue;
+            };
+
+            let logs = entries
+                .iter()
+                .filter(|entry| entry.offset >= *offset)
+                .cloned()
+                .collect::<HashSet<_>>();
+
+            committed_logs.insert(key.clone(), logs);
+        }
+
+        Ok(committed_logs)
+    }
+
+    fn list_committed_offsets(
+        &self,
+        keys: &HashSet<KeyId>,
+    ) -> anyhow::Result<HashMap<String, usize>> {
+        let mut committed_offsets = HashMap::default();
+
+        for key in keys {
+            let Some(offset) = self.offsets.get(key) else {
+                continue;
+            };
+
+            committed_offsets.insert(key.clone(), *offset);
+        }
+
+        Ok(committed_offsets)
+    }
+}
+
+/// Redis connections shared by the workers of the main loop. A worker takes
+/// an idle connection for the duration of a command, or opens a new one, so
+/// workers don't wait on each other's round trips.
+#[derive(Clone)]
+struct RedisPool {
+    client: redis::Client,
+    idle: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl RedisPool {
+    fn new(client: redis::Client) -> anyhow::Result<Self> {
+        // Fails early if Redis can't be reached
+        let connection = client.get_connection()?;
+
+        Ok(Self {
+            client,
+            idle: Arc::new(Mutex::new(vec![connection])),
+        })
+    }
+
+    /// Allocates the next offset of `key`.
+    fn next_offset(&self, key: &str) -> anyhow::Result<Offset> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(connection) => connection,
+            None => self.client.get_connection()?,
+        };
+
+        let offset = connection.incr(format!("{key}::offset"), 1)?;
+        self.idle.lock().unwrap().push(connection);
+
+        Ok(offset)
+    }
+}
+
+/// Cloned for every worker of the main loop, so everything that changes
+/// after init is shared.
+#[derive(Clone)]
+struct KafkaStyleLogNode {
+    base: NodeBase,
+    redis: Option<RedisPool>,
+    router: Option<Arc<Mutex<Router<Payload>>>>,
+    log_store: Arc<Mutex<LogStore>>,
+    state_transfer: Arc<Mutex<StateTransfer>>,
+    /// Ticks the retries of forwarded sends, in per-key-leader mode.
+    retry_timer: Option<TimerId>,
+}
+
+impl KafkaStyleLogNode {
+    /// Offsets are allocated through Redis when a pool is given. Otherwise
+    /// the node runs in per-key-leader mode: every key is owned by one node
+    /// which allocates its offsets locally, and sends for keys owned
+    /// elsewhere are forwarded to the owner.
+    fn new(init: Init, redis: Option<RedisPool>) -> Self {
+        let router = redis.is_none().then(|| {
+            let ring = Ring::with_members(VIRTUAL_NODES, 1, init.node_ids.iter().cloned());
+            Arc::new(Mutex::new(Router::new(
+                &init.node_id,
+                move |key| ring.owner(key).expect("Empty ring"),
+                FORWARD_RETRY_TIMEOUT,
+            )))
+        });
+
+        Self {
+            base: NodeBase::new(&init),
+            redis,
+            router,
+            log_store: Arc::new(Mutex::new(LogStore::new(&init.node_id))),
+            state_transfer: Arc::new(Mutex::new(StateTransfer::new(SYNC_TIMEOUT))),
+            retry_timer: None,
+        }
+    }
+
+    fn handle_send(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        key: &str,
+        msg: usize,
+    ) -> anyhow::Result<()> {
+        if let Some(router) = &self.router
+            && let Route::Forward(forward) =
+                router
+                    .lock()
+                    .unwrap()
+                    .route(key, message, sender.next_msg_id())
+        {
+            return sender.send(forward);
+        }
+
+        let log_entry = match &self.redis {
+            Some(redis) => {
+                let offset = redis.next_offset(key)?;
+
+                self.log_store
+                    .lock()
+                    .unwrap()
+                    .append(key, sender.next_msg_id(), offset, msg)?
+            }
+            None => self
+                .log_store
+                .lock()
+                .unwrap()
+                .append_next(key, sender.next_msg_id(), msg)?,
+        };
+
+        self.broadcast_send(sender, &log_entry)?;
+
+        let reply = message.reply(Payload::SendOk {
+            offset: log_entry.offset,
+        });
+
+        sender.send(reply)
+    }
+
+    fn handle_send_ok(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let Some(reply) = self
+            .router
+            .as_ref()
+            .and_then(|router| router.lock().unwrap().relay(message))
+        else {
+            return Ok(());
+        };
+
+        sender.send(reply)
+    }
+
+    fn handle_retry_tick(&mut self, ctx: &mut NodeContext<Payload>) -> anyhow::Result<()> {
+        let Some(router) = &self.router else {
+            return Ok(());
+        };
+
+        // Local routes are handled below, with the router unlocked
+        let routes = router.lock().unwrap().retry_expired(|| ctx.next_msg_id());
+
+        for route in routes {
+            match route {
+                Route::Forward(forward) => ctx.send(forward)?,
+                Route::Local(request) => self.handle_message(request, ctx)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_poll(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        offsets: HashMap<String, usize>,
+    ) -> anyhow::Result<()> {
+        let committed_logs = self.log_store.lock().unwrap().list_logs(&offsets)?;
+
+        let msgs = committed_logs
+            .iter()
+            .map(|(key, entries)| {
+                let offsets = entries
+                    .iter()
+                    .map(|entry| (entry.offset, entry.msg))
+                    .collect::<HashMap<_, _>>();
+
+                (key.to_owned(), offsets)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let reply = message.reply(Payload::PollOk { msgs });
+
+        sender.send(reply)
+    }
+
+    fn handle_commit_offsets(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        offsets: HashMap<String, usize>,
+    ) -> anyhow::Result<()> {
+        self.log_store.lock().unwrap().commit(&offsets)?;
+
+        self.broadcast_commit_offsets(sender, &offsets)?;
+
+        let reply = message.reply(Payload::CommitOffsetsOk);
+
+        sender.send(reply)
+    }
+
+    fn handle_list_committed_offsets(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        keys: &HashSet<KeyId>,
+    ) -> anyhow::Result<()> {
+        let offsets = self
+            .log_store
+            .lock()
+            .unwrap()
+            .list_committed_offsets(keys)?;
+
+        let reply = message.reply(Payload::ListCommittedOffsetsOk { offsets });
+
+        sender.send(reply)
+    }
+
+    fn handle_internal_send(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        log_entry: &LogEntry,
+    ) -> anyhow::Result<()> {
+        let highest_offset = self
+            .log_store
+            .lock()
+            .unwrap()
+            .highest_offset(&log_entry.key);
+
+        self.log_store.lock().unwrap().insert(log_entry.clone())?;
+        sender.send(message.reply(Payload::InternalSendOk))?;
+
+        if log_entry.offset > highest_offset + SYNC_THRESHOLD
+            && self.state_transfer.lock().unwrap().begin()
+        {
+            let sync_request = self.base.message(message.src(), Payload::SyncRequest);
+
+            sender.send_with_retry(sync_request)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_internal_commit_offsets(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        offsets: &HashMap<String, usize>,
+    ) -> anyhow::Result<()> {
+        self.log_store.lock().unwrap().commit(offsets)?;
+
+        sender.send(message.reply(Payload::InternalCommitOffsetsOk))
+    }
+
+    fn handle_sync_request(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let sync_chunks = {
+            let log_store = self.log_store.lock().unwrap();
+
+            sync_chunks(message, log_store.entries().cloned(), |chunk| {
+                Payload::SyncChunk {
+                    log_entries: chunk.items,
+                    offsets: if chunk.first {
+                        log_store.offsets().clone()
+                    } else {
+                        HashMap::new()
+                    },
+                    last: chunk.last,
+                }
+            })
+        };
+
+        sender.send_all(sync_chunks)
+    }
+
+    fn handle_sync_chunk(
+        &mut self,
+        log_entries: &[LogEntry],
+        offsets: &HashMap<KeyId, Offset>,
+        last: bool,
+    ) -> anyhow::Result<()> {
+        let mut log_store = self.log_store.lock().unwrap();
+
+        for log_entry in log_entries {
+            log_store.insert(log_entry.clone())?;
+        }
+        log_store.commit(offsets)?;
+
+        if last {
+            self.state_transfer.lock().unwrap().finish();
+        }
+
+        Ok(())
+    }
+
+    fn broadcast_send(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        log_entry: &LogEntry,
+    ) -> anyhow::Result<()> {
+        let seen_by = self
+            .base
+            .membership()
+            .members()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        for n in self.base.peers() {
+            let internal_send = self.base.message(
+                &n,
+                Payload::InternalSend {
+                    log_entry: LogEntry {
+                        seen_by: seen_by.clone(),
+                        ..log_entry.clone()
+                    },
+                },
+            );
+            sender.send_with_retry(internal_send)?;
+        }
+
+        Ok(())
+    }
+
+    fn broadcast_commit_offsets(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        offsets: &HashMap<String, usize>,
+    ) -> anyhow::Result<()> {
+        for n in self.base.peers() {
+            let internal_commit_offsets = self.base.message(
+                &n,
+                Payload::InternalCommitOffsets {
+                    offsets: offsets.clone(),
+                },
+            );
+            sender.send_with_retry(internal_commit_offsets)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Node<Payload> for KafkaStyleLogNode {
+    fn middleware(&mut self) -> Vec<Box<dyn Middleware<Payload>>> {
+        vec![Box::new(Dedup::new(DEDUP_CAPACITY))]
+    }
+
+    // Replication follows membership changes, in key-leader mode keys keep
+    // the owners they had at init
+    fn membership(&mut self) -> Option<&mut Membership> {
+        Some(self.base.membership_mut())
+    }
+
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        if self.router.is_none() {
+            return Ok(());
+        }
+
+        self.retry_timer = Some(scheduler.tick_periodic(RETRY_INTERVAL).id());
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.retry_timer => self.handle_retry_tick(ctx),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match &message.body().payload {
+            Payload::Send { key, msg } => self.handle_send(ctx, &message, key, *msg)?,
+            Payload::SendOk { .. } => self.handle_send_ok(ctx, &message)?,
+            Payload::Poll { offsets } => self.handle_poll(ctx, &message, offsets.clone())?,
+            Payload::PollOk { .. } => {}
+            Payload::CommitOffsets { offsets } => {
+                self.handle_commit_offsets(ctx, &message, offsets.clone())?
+            }
+            Payload::CommitOffsetsOk => {}
+            Payload::ListCommittedOffsets { keys } => {
+                self.handle_list_committed_offsets(ctx, &message, keys)?
+            }
+            Payload::ListCommittedOffsetsOk { .. } => {}
+            Payload::InternalSend { log_entry } => {
+                self.handle_internal_send(ctx, &message, log_entry)?
+            }
+            Payload::InternalSendOk => {}
+            Payload::InternalCommitOffsets { offsets } => {
+                self.handle_internal_commit_offsets(ctx, &message, offsets)?
+            }
+            Payload::InternalCommitOffsetsOk => {}
+            Payload::SyncRequest => self.handle_sync_request(ctx, &message)?,
+            Payload::SyncChunk {
+                log_entries,
+                offsets,
+                last,
+            } => self.handle_sync_chunk(log_entries, offsets, *last)?,
+        };
+
+        Ok(())
+    }
+}
+
+/// Runs the node as [`concurrent_main_loop`] does, on Redis unless
+/// `KAFKA_MODE=key-leader`.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    let redis = match std::env::var("KAFKA_MODE").as_deref() {
+        Ok("key-leader") => None,
+        _ => {
+            let redis_client = redis::Client::open(config.redis_url.as_str())
+                .context("Error connecting to Redis server")?;
+
+            Some(RedisPool::new(redis_client)?)
+        }
+    };
+
+    // Sends wait on Redis, or on the owner of their key, without holding up
+    // the other clients
+    concurrent_main_loop(WORKERS, config, |init| {
+        Ok(KafkaStyleLogNode::new(init, redis))
+    })
+}
+
+fn serialize_as_pairs<S>(
+    msgs: &HashMap<String, HashMap<Offset, usize>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    let result = msgs
+        .iter()
+        .map(|(key, entries)| {
+            let mut pairs = entries
+                .iter()
+                .map(|(offset, message)| (*offset, *message))
+                .collect::<Vec<_>>();
+
+            pairs.sort_by_key(|(k, _)| *k);
+
+            let pairs = pairs.iter().map(|(k, v)| vec![*k, *v]).collect();
+
+            (key.to_owned(), pairs)
+        })
+        .collect::<HashMap<String, Vec<Vec<_>>>>();
+
+    let json = serde_json::value::to_value(&result).expect("Error deserializing HashMap");
+
+    json.serialize(serializer)
+}
+
+/// Reads back what [`serialize_as_pairs`] writes.
+fn deserialize_pairs<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, HashMap<Offset, usize>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let msgs = HashMap::<String, Vec<(Offset, usize)>>::deserialize(deserializer)?;
+
+    Ok(msgs
+        .into_iter()
+        .map(|(key, pairs)| (key, pairs.into_iter().collect()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KafkaStyleLogNode, LogStore, Payload, VIRTUAL_NODES};
+    use crate::{
+        conformance::{
+            assert_message_round_trip, assert_reply_to, assert_round_trip, assert_serializes, init,
+            strategies,
+        },
+        ring::Ring,
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+    use proptest::{collection::hash_map, prelude::*};
+    use std::collections::{HashMap, HashSet};
+
+    const SEND: &str =
+        r#"{"src":"c1","dest":"n1","body":{"type":"send","msg_id":2,"key":"k1","msg":123}}"#;
+    const POLL: &str =
+        r#"{"src":"c2","dest":"n1","body":{"type":"poll","msg_id":3,"offsets":{"k1":1}}}"#;
+    const COMMIT_OFFSETS: &str = r#"{"src":"c3","dest":"n1","body":{"type":"commit_offsets","msg_id":4,"offsets":{"k1":1}}}"#;
+    const LIST_COMMITTED_OFFSETS: &str = r#"{"src":"c4","dest":"n1","body":{"type":"list_committed_offsets","msg_id":5,"keys":["k1"]}}"#;
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[SEND, POLL, COMMIT_OFFSETS, LIST_COMMITTED_OFFSETS]);
+
+        assert_serializes(&[
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c1".to_owned(),
+                    Body::new(Some(0), Some(2), Payload::SendOk { offset: 1 }),
+                ),
+                r#"{"src":"n1","dest":"c1","body":{"type":"send_ok","msg_id":0,"in_reply_to":2,"offset":1}}"#,
+            ),
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c2".to_owned(),
+                    Body::new(
+                        Some(1),
+                        Some(3),
+                        Payload::PollOk {
+                            msgs: HashMap::from([(
+                                "k1".to_owned(),
+                                HashMap::from([(2, 9), (1, 123)]),
+                            )]),
+                        },
+                    ),
+                ),
+                r#"{"src":"n1","dest":"c2","body":{"type":"poll_ok","msg_id":1,"in_reply_to":3,"msgs":{"k1":[[1,123],[2,9]]}}}"#,
+            ),
+            (
+                Message::new(
+                    "n1".to_owned(),
+                    "c4".to_owned(),
+                    Body::new(
+                        Some(2),
+                        Some(5),
+                        Payload::ListCommittedOffsetsOk {
+                            offsets: HashMap::from([("k1".to_owned(), 1)]),
+                        },
+                    ),
+                ),
+                r#"{"src":"n1","dest":"c4","body":{"type":"list_committed_offsets_ok","msg_id":2,"in_reply_to":5,"offsets":{"k1":1}}}"#,
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = KafkaStyleLogNode::new(init(), None);
+
+        let requests = [POLL, COMMIT_OFFSETS, LIST_COMMITTED_OFFSETS]
+            .map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        // Replication traffic to other nodes is interleaved with the replies
+        let sent = sent.lock().unwrap();
+        let replies = sent
+            .iter()
+            .filter(|m| m.dest().starts_with('c'))
+            .collect::<Vec<_>>();
+
+        assert_eq!(replies.len(), requests.len());
+        for (request, reply) in requests.iter().zip(replies) {
+            assert_reply_to(request, reply);
+        }
+    }
+
+    #[test]
+    fn test_commit() {
+        let mut log_store = LogStore::new("n1");
+        let offsets = |entries: &[(&str, usize)]| {
+            entries
+                .iter()
+                .map(|(key, offset)| (key.to_string(), *offset))
+                .collect::<HashMap<_, _>>()
+        };
+
+        log_store.commit(&offsets(&[("k1", 3)])).unwrap();
+        assert_eq!(log_store.offsets(), &offsets(&[("k1", 3)]));
+
+        // A sync chunk from a peer behind doesn't move commits back
+        log_store.commit(&offsets(&[("k1", 2), ("k2", 1)])).unwrap();
+        assert_eq!(log_store.offsets(), &offsets(&[("k1", 3), ("k2", 1)]));
+    }
+
+    #[test]
+    fn test_concurrent_sends() {
+        const SENDS: usize = 200;
+
+        let init = init();
+        let ring = Ring::with_members(VIRTUAL_NODES, 1, init.node_ids.iter().cloned());
+        let key = (0..)
+            .map(|i| format!("k{i}"))
+            .find(|key| ring.owner(key).as_deref() == Some("n1"))
+            .unwrap();
+        let node = KafkaStyleLogNode::new(init, None);
+
+        // Two clients sending to the same key, handled by different workers
+        let offsets = std::thread::scope(|scope| {
+            let workers = ["c1", "c2"].map(|client| {
+                let mut node = node.clone();
+                let key = key.clone();
+
+                scope.spawn(move || {
+                    let writter = MemoryWritter::new();
+                    let sent = writter.messages();
+                    let mut sender = MessageSender::new(writter);
+
+                    for msg in 0..SENDS {
+                        let request = Message::new(
+                            client.to_owned(),
+                            "n1".to_owned(),
+                            Body::new(
+                                Some(msg),
+                                None,
+                                Payload::Send {
+                                    key: key.clone(),
+                                    msg,
+                                },
+                            ),
+                        );
+                        node.handle_message(request, &mut NodeContext::new(&mut sender))
+                            .unwrap();
+                    }
+
+                    sent.lock()
+                        .unwrap()
+                        .iter()
+                        .filter_map(|m| match m.body().payload {
+                            Payload::SendOk { offset } => Some(offset),
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>()
+                })
+            });
+
+            workers.map(|worker| worker.join().unwrap())
+        });
+
+        let offsets = offsets.iter().flatten().copied().collect::<HashSet<_>>();
+        assert_eq!(offsets.len(), 2 * SENDS);
+        assert_eq!(offsets, (1..=2 * SENDS).collect());
+        assert_eq!(node.log_store.lock().unwrap().entries().count(), 2 * SENDS);
+    }
+
+    proptest! {
+        #[test]
+        fn test_poll_ok_round_trip(message in strategies::message(
+            hash_map("k[0-9]", hash_map(any::<usize>(), any::<usize>(), 0..4), 0..4)
+                .prop_map(|msgs| Payload::PollOk { msgs }),
+        )) {
+            assert_message_round_trip(&message);
+        }
+    }
+}
@@ -0,0 +1,3721 @@
+//! Key-value store for Maelstrom's `lin-kv` workload, on Raft.
+//!
+//! Nodes elect a leader among themselves, see [`RaftNode::start_election`],
+//! once a majority said they'd vote for them without moving to a new term,
+//! see [`RaftNode::start_pre_vote`]. Leaders send every follower what it
+//! misses, if anything, every [`Config::heartbeat_interval`], which also
+//! keeps them from standing for election, see [`Config::election_timeout`].
+//! Leaders that don't hear back from a majority for as long step down, see
+//! [`RaftNode::check_quorum`].
+//!
+//! The leader appends the writes of clients to its log and replicates it to
+//! the others with `append_entries`, see [`RaftNode::replicate`]. Entries are
+//! applied to the store, and clients answered, once a majority of the
+//! cluster has them. Nodes remember the last request of each client they
+//! applied, so one retried after a change of leader isn't applied twice, see
+//! [`RaftNode::apply_entry`]. With [`Config::write_batch_interval`] leaders
+//! gather writes to append them as one entry, see [`RaftNode::flush_writes`].
+//! Followers forward the requests of clients to the leader and relay its
+//! replies back, see [`RaftNode::forward`].
+//!
+//! Leaders serve reads once a heartbeat round confirms they still lead, at
+//! the commit index they had when the read arrived, see
+//! [`RaftNode::serve_reads`]. With [`Config::lease_reads`] they serve them
+//! right away instead while they hold a lease, see [`RaftNode::lease`]. With
+//! [`Config::quorum_reads`] any node serves them instead, once it applied as
+//! far as the longest log of a majority goes, see
+//! [`RaftNode::start_quorum_read`].
+//!
+//! Nodes replace the entries they applied with a snapshot of their store
+//! every [`Config::snapshot_threshold`] entries, and leaders send followers
+//! that miss entries no longer in their log their snapshot instead. With
+//! [`RAFT_FILE`] set, nodes save their term, vote and log before answering
+//! anyone and pick up from there when restarted.
+//!
+//! Nodes join and leave through the log as well, the leader moving the
+//! cluster to its new members through a configuration both old and new
+//! members must agree in, see [`RaftNode::handle_membership_change`].
+//! Learners are sent the log without voting, until promoted to members once
+//! caught up, see [`RaftNode::handle_learner_change`]. Witnesses, see
+//! [`Config::witnesses`], vote and count towards quorums but are sent only
+//! the terms of entries, which can leave the cluster stalled once the leader
+//! goes down.
+
+use crate::{
+    challenges::totally_available_transactions::Operation as TxnOperation,
+    config::Config,
+    main_loop_with_config,
+    membership::MembershipChange,
+    scheduler::{Scheduler, TimerId},
+    Body, ErrorCode, ErrorPayload, Event, Init, MaelstromError, Message, MessageSender, Node,
+    NodeBase, NodeContext,
+};
+use anyhow::Context;
+use configuration::Configuration;
+use log::{Entry, Log, Operation, Session, Snapshot};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    ops::Range,
+    time::{Duration, Instant},
+};
+use storage::{Recovered, Storage};
+
+mod configuration;
+mod log;
+mod storage;
+
+/// Env var with the path of the file nodes save their term, vote and log
+/// to. `{node_id}` is replaced with the node's id. Nothing is saved when
+/// unset.
+pub const RAFT_FILE: &str = "RAFT_FILE";
+
+type Key = usize;
+type Term = u64;
+/// What applying an entry ended in, the transaction completed with the
+/// values read for `txn`s.
+type Applied = Result<Option<Vec<TxnOperation>>, MaelstromError>;
+
+/// How often election timeouts are checked.
+const ELECTION_TICK: Duration = Duration::from_millis(10);
+/// How long a follower waits for the leader to answer a request it
+/// forwarded, after which the client will have given up.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(1);
+/// Share of the shortest election timeout a lease lasts. Leases are timed
+/// on the leader's clock from when it sent the heartbeat a majority
+/// acknowledged, while followers hold off elections on theirs from when they
+/// received it, later. Clocks may be set apart by any amount, only the rate
+/// they run at matters: the leader's may run up to 10% slower than its
+/// followers' before it could serve a read after another leader was
+/// elected.
+const LEASE_SHARE: f64 = 0.9;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Read {
+        key: Key,
+    },
+    ReadOk {
+        value: usize,
+    },
+    Write {
+        key: Key,
+        value: usize,
+    },
+    WriteOk,
+    Cas {
+        key: Key,
+        from: usize,
+        to: usize,
+    },
+    CasOk,
+    /// Steps applied all at once, after every write before and before
+    /// every write after.
+    Txn {
+        txn: Vec<TxnOperation>,
+    },
+    TxnOk {
+        txn: Vec<TxnOperation>,
+    },
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+    /// The `request` of `client` a follower forwarded to the leader, sent
+    /// by the client with `client_msg_id`, which sessions go by.
+    Forward {
+        client: String,
+        client_msg_id: Option<usize>,
+        request: Box<Payload>,
+    },
+    RequestVote {
+        term: Term,
+        last_log_index: usize,
+        last_log_term: Term,
+    },
+    RequestVoteOk {
+        term: Term,
+        vote_granted: bool,
+    },
+    /// Whether the recipient would vote for the sender in `term`, were it to
+    /// stand. Changes nothing on the recipient.
+    PreVote {
+        term: Term,
+        last_log_index: usize,
+        last_log_term: Term,
+    },
+    PreVoteOk {
+        term: Term,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: Term,
+        prev_log_index: usize,
+        prev_log_term: Term,
+        entries: Vec<Entry>,
+        leader_commit: usize,
+        /// Heartbeat round of the leader, echoed back.
+        round: u64,
+    },
+    /// Replaces the follower's log up to the snapshot. Answered with an
+    /// `append_entries_ok` as well.
+    InstallSnapshot {
+        term: Term,
+        snapshot: Snapshot,
+        round: u64,
+    },
+    /// `match_index` is the last entry the follower has in common with the
+    /// leader when it succeeded. When it didn't have the entry before the
+    /// ones sent, `conflict_index` is where the leader may send them from
+    /// instead: right past its log when shorter, else the first of its
+    /// entries of `conflict_term`, the term of the entry it has there.
+    AppendEntriesOk {
+        term: Term,
+        success: bool,
+        match_index: usize,
+        round: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflict_index: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        conflict_term: Option<Term>,
+    },
+    /// Asks a node how far its log goes, for the quorum read `read` of the
+    /// sender.
+    ReadReplica {
+        read: u64,
+    },
+    /// `index` of the last entry in the log of the sender.
+    ReadReplicaOk {
+        read: u64,
+        index: usize,
+    },
+    /// Asks a node where it stands, to debug elections from Maelstrom's
+    /// logs.
+    RaftState,
+    /// `match_index` is empty but on leaders.
+    RaftStateOk {
+        term: Term,
+        role: Role,
+        leader: Option<String>,
+        last_log_index: usize,
+        commit_index: usize,
+        match_index: BTreeMap<String, usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Role {
+    Follower,
+    /// Polling its peers before standing for election.
+    PreCandidate,
+    Candidate,
+    Leader,
+}
+
+/// Control messages adding a learner, which is sent the log but doesn't
+/// vote, and making one a member once it caught up, answered with
+/// `add_learner_ok` and `promote_learner_ok`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum LearnerChange {
+    AddLearner { node_id: String },
+    PromoteLearner { node_id: String },
+}
+
+impl LearnerChange {
+    /// Payload of the reply acknowledging the change.
+    fn reply(&self) -> Value {
+        let reply_type = match self {
+            LearnerChange::AddLearner { .. } => "add_learner_ok",
+            LearnerChange::PromoteLearner { .. } => "promote_learner_ok",
+        };
+
+        serde_json::json!({ "type": reply_type })
+    }
+}
+
+/// How elections went on this node, for the replies to `stats` requests,
+/// to tell how often leadership changes under partitions.
+struct ElectionStats {
+    pre_votes_started: u64,
+    elections_started: u64,
+    elections_won: u64,
+    /// Votes granted to candidates, each counted once per term.
+    votes_granted: u64,
+    /// Times the leader this node knows of changed to another node.
+    leader_changes: u64,
+    last_leader: Option<String>,
+    /// Time spent in each role, up to the last change of role.
+    time_in_role: HashMap<Role, Duration>,
+    role_since: Instant,
+}
+
+impl ElectionStats {
+    fn new() -> Self {
+        Self {
+            pre_votes_started: 0,
+            elections_started: 0,
+            elections_won: 0,
+            votes_granted: 0,
+            leader_changes: 0,
+            last_leader: None,
+            time_in_role: HashMap::new(),
+            role_since: Instant::now(),
+        }
+    }
+
+    /// Counts a change of leader if `leader` isn't the last one known.
+    fn observe_leader(&mut self, leader: &str) {
+        if self.last_leader.as_deref() != Some(leader) {
+            self.leader_changes += 1;
+            self.last_leader = Some(leader.to_owned());
+        }
+    }
+
+    /// Adds the time since the last change of role to `role`, the one left.
+    fn leave_role(&mut self, role: Role) {
+        *self.time_in_role.entry(role).or_default() += self.role_since.elapsed();
+        self.role_since = Instant::now();
+    }
+}
+
+/// A client request waiting for its entry to be applied.
+struct Pending {
+    /// Term the entry was appended in. Another entry may end up at its index
+    /// if the node loses the lead before it's committed.
+    term: Term,
+    request: Message<Payload>,
+    reply: Payload,
+}
+
+/// A read waiting for the leader to confirm it still leads.
+struct PendingRead {
+    /// Commit index the read is served at, or after.
+    read_index: usize,
+    /// Heartbeat round a majority must acknowledge.
+    round: u64,
+    request: Message<Payload>,
+    key: Key,
+}
+
+/// A read waiting for a majority of the cluster to say how far their logs
+/// go, then for the node to apply as far.
+struct QuorumRead {
+    request: Message<Payload>,
+    key: Key,
+    /// Index of the last entry in the log of each node that answered.
+    replies: HashMap<String, usize>,
+    /// The highest of the replies, once a quorum answered.
+    read_index: Option<usize>,
+    started: Instant,
+}
+
+/// A client request a follower forwarded to the leader.
+struct Forwarded {
+    request: Message<Payload>,
+    sent_at: Instant,
+}
+
+struct RaftNode {
+    base: NodeBase,
+    role: Role,
+    term: Term,
+    voted_for: Option<String>,
+    /// Votes granted to this node in its current term, or in its pre-vote
+    /// for the next, its own included.
+    votes: HashSet<String>,
+    /// The leader of the current term, once known.
+    leader: Option<String>,
+    /// When to stand for election unless a leader is heard from first.
+    election_deadline: Instant,
+    /// Picked from at random every time the election deadline is reset, so
+    /// nodes rarely stand at once.
+    election_timeout: Range<Duration>,
+    /// Checks the election deadline, set at init.
+    election_timer: Option<TimerId>,
+    heartbeat_interval: Duration,
+    /// Ticks every heartbeat, set at init.
+    heartbeat_timer: Option<TimerId>,
+    log: Log,
+    /// The configuration of the last configuration entry in the log, in
+    /// effect whether committed or not, and that entry's index.
+    config: Configuration,
+    config_index: usize,
+    /// The request of the membership or learner change being made and the
+    /// reply to it once committed, while leader.
+    pending_change: Option<(Message<Value>, Value)>,
+    /// The store as of the last entry dropped from the log.
+    snapshot: Snapshot,
+    snapshot_threshold: usize,
+    /// Index of the next entry to send each follower, while leader.
+    next_index: HashMap<String, usize>,
+    /// Index of the last entry each follower is known to have, while leader.
+    match_index: HashMap<String, usize>,
+    /// Index of the entry before the first and index of the last entry of
+    /// each `append_entries` sent each follower and not acknowledged yet,
+    /// oldest first, while leader.
+    in_flight: HashMap<String, VecDeque<(usize, usize)>>,
+    replication_window: usize,
+    /// When each follower catching up was last sent a batch or snapshot,
+    /// see [`Config::catch_up_interval`], while leader.
+    catch_up_sent: HashMap<String, Instant>,
+    catch_up_batch: usize,
+    catch_up_interval: Duration,
+    /// Index of the last entry known to be on a majority of the cluster.
+    commit_index: usize,
+    /// Index of the last entry applied to the store.
+    last_applied: usize,
+    /// Client requests by the index of their entry, several for batches,
+    /// while leader.
+    pending: HashMap<usize, Vec<Pending>>,
+    /// Writes waiting to be appended as one entry, see
+    /// [`Config::write_batch_interval`], while leader.
+    batched_writes: Vec<(Operation, Message<Payload>)>,
+    write_batch_interval: Duration,
+    /// Appends the batched writes, set at init if they're batched.
+    batch_timer: Option<TimerId>,
+    /// Index of the no-op entry the leader appended when elected. Reads
+    /// wait for it to commit, for the leader's commit index to be up to
+    /// date.
+    term_start: usize,
+    /// Last heartbeat round of the leader, one per `append_entries` sent to
+    /// every follower.
+    round: u64,
+    /// Last round each follower acknowledged, while leader.
+    acked_round: HashMap<String, u64>,
+    /// When each follower last answered an `append_entries`, while leader,
+    /// see [`RaftNode::check_quorum`].
+    heard_from: HashMap<String, Instant>,
+    reads: Vec<PendingRead>,
+    /// Whether reads are served on a lease, see [`RaftNode::lease`].
+    lease_reads: bool,
+    /// When each heartbeat round the leader might still take a lease from
+    /// started.
+    round_started: BTreeMap<u64, Instant>,
+    /// Until when the leader may serve reads on its own.
+    lease_expiry: Option<Instant>,
+    /// Whether clients are refused anything but `lin-kv`'s single-key
+    /// requests, see [`Config::strict_lin_kv`].
+    strict_lin_kv: bool,
+    /// Whether reads are served from a majority's stores, see
+    /// [`RaftNode::start_quorum_read`].
+    quorum_reads: bool,
+    /// Quorum reads waiting for replies, by id.
+    waiting_quorum_reads: HashMap<u64, QuorumRead>,
+    /// Id of the last quorum read started.
+    last_quorum_read: u64,
+    /// When the follower last heard from the leader of its term.
+    leader_contact: Option<Instant>,
+    /// Where the term, vote and log are saved, if anywhere.
+    storage: Option<Storage>,
+    /// Client requests forwarded to the leader, by the `msg_id` they were
+    /// forwarded with.
+    forwarded: HashMap<usize, Forwarded>,
+    store: HashMap<Key, usize>,
+    /// The last request of each client applied to the store, by client.
+    sessions: BTreeMap<String, Session>,
+    stats: ElectionStats,
+}
+
+impl RaftNode {
+    fn new(init: Init, config: &Config) -> Self {
+        let mut configuration = Configuration::new(init.node_ids.iter().cloned());
+        configuration.witnesses = config.witnesses.iter().cloned().collect();
+
+        Self {
+            base: NodeBase::new(&init),
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            votes: HashSet::new(),
+            leader: None,
+            election_deadline: Instant::now() + random_timeout(&config.election_timeout),
+            election_timeout: config.election_timeout.clone(),
+            election_timer: None,
+            heartbeat_interval: config.heartbeat_interval,
+            heartbeat_timer: None,
+            log: Log::default(),
+            config: configuration.clone(),
+            config_index: 0,
+            pending_change: None,
+            snapshot: Snapshot {
+                config: configuration,
+                ..Snapshot::default()
+            },
+            snapshot_threshold: config.snapshot_threshold.max(1),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            in_flight: HashMap::new(),
+            replication_window: config.replication_window.max(1),
+            catch_up_sent: HashMap::new(),
+            catch_up_batch: config.catch_up_batch.max(1),
+            catch_up_interval: config.catch_up_interval,
+            commit_index: 0,
+            last_applied: 0,
+            pending: HashMap::new(),
+            batched_writes: Vec::new(),
+            write_batch_interval: config.write_batch_interval,
+            batch_timer: None,
+            term_start: 0,
+            round: 0,
+            acked_round: HashMap::new(),
+            reads: Vec::new(),
+            lease_reads: config.lease_reads,
+            round_started: BTreeMap::new(),
+            heard_from: HashMap::new(),
+            lease_expiry: None,
+            strict_lin_kv: config.strict_lin_kv,
+            quorum_reads: config.quorum_reads,
+            waiting_quorum_reads: HashMap::new(),
+            last_quorum_read: 0,
+            leader_contact: None,
+            storage: None,
+            forwarded: HashMap::new(),
+            store: HashMap::new(),
+            sessions: BTreeMap::new(),
+            stats: ElectionStats::new(),
+        }
+    }
+
+    /// Picks up from what a previous run saved to `storage`, if any, and
+    /// saves there from now on.
+    fn with_storage(mut self, storage: Option<(Storage, Recovered)>) -> Self {
+        let Some((storage, recovered)) = storage else {
+            return self;
+        };
+
+        let mut snapshot = recovered.snapshot;
+        if snapshot.config.members.is_empty() {
+            snapshot.config = self.snapshot.config.clone();
+        }
+        self.term = recovered.term;
+        self.voted_for = recovered.voted_for;
+        self.log = recovered.log;
+        self.store = snapshot.store.iter().copied().collect();
+        self.sessions = snapshot.sessions.clone();
+        self.commit_index = snapshot.index;
+        self.last_applied = snapshot.index;
+        self.update_membership(&snapshot.config.members);
+        self.snapshot = snapshot;
+        self.reload_config();
+        self.storage = Some(storage);
+
+        self
+    }
+
+    /// Saves what changed while handling an event. What the handler sent
+    /// only goes out once it returns, see [`crate::atomically`], so after
+    /// this.
+    fn persist(&mut self) -> anyhow::Result<()> {
+        let Some(storage) = &mut self.storage else {
+            return Ok(());
+        };
+
+        storage.save(self.term, &self.voted_for, &self.snapshot, &mut self.log)
+    }
+
+    /// Whether the votes granted so far make a quorum of the configuration.
+    fn has_votes(&self) -> bool {
+        self.config.is_quorum(|node| self.votes.contains(node))
+    }
+
+    /// Every member of the configuration but this node.
+    fn peers(&self) -> Vec<String> {
+        self.config.peers(self.base.node_id())
+    }
+
+    /// The configuration in effect as of the entry at `index` and the index
+    /// of its entry, the snapshot's if no entry after it changed it.
+    fn config_at(&self, index: usize) -> (usize, Configuration) {
+        let snapshot_index = self.log.snapshot_index();
+
+        self.log
+            .entries_from(snapshot_index + 1)
+            .iter()
+            .take(index.saturating_sub(snapshot_index))
+            .enumerate()
+            .rev()
+            .find_map(|(offset, entry)| match &entry.operation {
+                Operation::Configuration(config) => {
+                    Some((snapshot_index + 1 + offset, config.clone()))
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| (snapshot_index, self.snapshot.config.clone()))
+    }
+
+    /// Picks up the configuration of the log again, after it was truncated
+    /// or replaced.
+    fn reload_config(&mut self) {
+        (self.config_index, self.config) = self.config_at(self.log.last_index());
+    }
+
+    /// Appends an entry moving to `config`, in effect right away.
+    fn append_config(&mut self, config: Configuration) {
+        self.config = config.clone();
+        self.config_index = self.log.append(Entry {
+            term: self.term,
+            operation: Operation::Configuration(config),
+            request: None,
+        });
+    }
+
+    /// Makes the node's membership `members`, once a configuration of them
+    /// is committed.
+    fn update_membership(&mut self, members: &BTreeSet<String>) {
+        let membership = self.base.membership_mut();
+        for node_id in membership.members() {
+            if !members.contains(&node_id) {
+                membership.apply(&MembershipChange::NodeRemoved { node_id });
+            }
+        }
+        for node_id in members {
+            membership.apply(&MembershipChange::NodeAdded {
+                node_id: node_id.clone(),
+            });
+        }
+    }
+
+    fn set_role(&mut self, role: Role) {
+        self.stats.leave_role(self.role);
+        self.role = role;
+    }
+
+    fn reset_election_deadline(&mut self) {
+        self.election_deadline = Instant::now() + random_timeout(&self.election_timeout);
+    }
+
+    /// Asks every peer whether it would vote for this node in the next term.
+    /// Peers that still hear from a leader say no, so a node coming back
+    /// from a partition doesn't depose a leader by moving to a new term.
+    fn start_pre_vote(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        self.set_role(Role::PreCandidate);
+        self.stats.pre_votes_started += 1;
+        self.votes = HashSet::from([self.base.node_id().to_owned()]);
+        self.reset_election_deadline();
+
+        if self.has_votes() {
+            return self.start_election(sender);
+        }
+
+        let pre_vote = Payload::PreVote {
+            term: self.term + 1,
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
+        let pre_votes = self
+            .peers()
+            .into_iter()
+            .map(|peer| self.base.message(&peer, pre_vote.clone()));
+
+        sender.send_all(pre_votes.collect::<Vec<_>>())
+    }
+
+    /// Starts a new term, standing for election in it: the node votes for
+    /// itself and asks every peer for its vote.
+    fn start_election(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        self.term += 1;
+        self.set_role(Role::Candidate);
+        self.stats.elections_started += 1;
+        self.leader = None;
+        self.voted_for = Some(self.base.node_id().to_owned());
+        self.votes = HashSet::from([self.base.node_id().to_owned()]);
+        self.reset_election_deadline();
+        tracing::info!(term = self.term, "Standing for election");
+
+        if self.has_votes() {
+            return self.become_leader(sender);
+        }
+
+        let request_vote = Payload::RequestVote {
+            term: self.term,
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
+        let request_votes = self
+            .peers()
+            .into_iter()
+            .map(|peer| self.base.message(&peer, request_vote.clone()));
+
+        sender.send_all(request_votes.collect::<Vec<_>>())
+    }
+
+    /// Takes the lead, appending a no-op entry to commit every entry of
+    /// earlier terms with, and sending every follower the entries it may
+    /// miss.
+    fn become_leader(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        tracing::info!(term = self.term, "Elected leader");
+        self.set_role(Role::Leader);
+        self.stats.elections_won += 1;
+        self.stats.observe_leader(self.base.node_id());
+        self.leader = Some(self.base.node_id().to_owned());
+
+        let next_index = self.log.last_index() + 1;
+        self.next_index = self
+            .peers()
+            .into_iter()
+            .map(|peer| (peer, next_index))
+            .collect();
+        self.match_index = self.peers().into_iter().map(|peer| (peer, 0)).collect();
+        self.in_flight.clear();
+        self.catch_up_sent.clear();
+        self.acked_round.clear();
+        // Given an election timeout to answer from now
+        self.heard_from = self
+            .peers()
+            .into_iter()
+            .map(|peer| (peer, Instant::now()))
+            .collect();
+        self.round_started.clear();
+        self.lease_expiry = None;
+        self.pending_change = None;
+        self.term_start = self.log.append(Entry {
+            term: self.term,
+            operation: Operation::Noop,
+            request: None,
+        });
+
+        self.replicate(sender)?;
+        self.advance_commit_index(sender)
+    }
+
+    /// Follows `leader`, the leader of the current term, which other
+    /// candidates of the term may learn only once it won.
+    fn follow(&mut self, leader: &str) {
+        self.set_role(Role::Follower);
+        self.stats.observe_leader(leader);
+        self.leader = Some(leader.to_owned());
+        self.leader_contact = Some(Instant::now());
+        self.reset_election_deadline();
+    }
+
+    /// Moves on to `term`, seen in a message of another node, as a follower.
+    /// Messages of older terms change nothing.
+    fn observe_term(&mut self, term: Term) {
+        if term > self.term {
+            self.term = term;
+            self.set_role(Role::Follower);
+            self.voted_for = None;
+            self.leader = None;
+        }
+    }
+
+    /// Sends every follower the entries from its next index on, starting
+    /// a heartbeat round.
+    fn replicate(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        self.round += 1;
+        if self.lease_reads {
+            self.round_started.insert(self.round, Instant::now());
+        }
+        let append_entries = self
+            .peers()
+            .into_iter()
+            .map(|peer| self.append_entries(&peer));
+
+        sender.send_all(append_entries.collect::<Vec<_>>())
+    }
+
+    /// What `peer` is sent next: the entries from its next index on, none
+    /// while [`Config::replication_window`] `append_entries` are in flight
+    /// to it already, or its snapshot when the entries it misses are no
+    /// longer in the log. Its next index moves past the entries sent, for
+    /// the next ones to carry only what was appended since. A peer missing
+    /// more than [`Config::catch_up_batch`] entries is sent one batch at a
+    /// time, at most every [`Config::catch_up_interval`], and nothing but
+    /// heartbeats in between.
+    fn append_entries(&mut self, peer: &str) -> Message<Payload> {
+        let next_index = self.next_index.get(peer).copied().unwrap_or(1);
+        // Misses more entries than a batch holds
+        let catching_up = self.log.last_index().saturating_sub(next_index) >= self.catch_up_batch;
+        let throttled = catching_up
+            && self
+                .catch_up_sent
+                .get(peer)
+                .is_some_and(|sent| sent.elapsed() < self.catch_up_interval);
+        if next_index <= self.log.snapshot_index() && !throttled {
+            if catching_up {
+                self.catch_up_sent.insert(peer.to_owned(), Instant::now());
+            }
+            let snapshot = if self.config.witnesses.contains(peer) {
+                self.snapshot.for_witness()
+            } else {
+                self.snapshot.clone()
+            };
+            return self.base.message(
+                peer,
+                Payload::InstallSnapshot {
+                    term: self.term,
+                    snapshot,
+                    round: self.round,
+                },
+            );
+        }
+        let prev_log_index = next_index - 1;
+
+        let in_flight = self.in_flight.entry(peer.to_owned()).or_default();
+        let window = if catching_up {
+            1
+        } else {
+            self.replication_window
+        };
+        let entries =
+            if in_flight.len() < window && !throttled && next_index > self.log.snapshot_index() {
+                let entries = self.log.entries_from(next_index);
+                entries[..entries.len().min(self.catch_up_batch)].to_vec()
+            } else {
+                Vec::new()
+            };
+        let entries = if self.config.witnesses.contains(peer) {
+            entries.iter().map(Entry::for_witness).collect()
+        } else {
+            entries
+        };
+        if !entries.is_empty() {
+            if catching_up {
+                self.catch_up_sent.insert(peer.to_owned(), Instant::now());
+            }
+            let last_index = prev_log_index + entries.len();
+            in_flight.push_back((prev_log_index, last_index));
+            self.next_index.insert(peer.to_owned(), last_index + 1);
+        }
+
+        self.base.message(
+            peer,
+            Payload::AppendEntries {
+                term: self.term,
+                prev_log_index,
+                prev_log_term: self.log.term(prev_log_index).unwrap_or_default(),
+                entries,
+                leader_commit: self.commit_index,
+                round: self.round,
+            },
+        )
+    }
+
+    /// Applies `operation` to the store, or fails leaving it as it was.
+    fn apply(&mut self, operation: &Operation) -> Applied {
+        match operation {
+            Operation::Write { key, value } => {
+                self.store.insert(*key, *value);
+            }
+            Operation::Cas { key, from, to } => match self.store.get_mut(key) {
+                None => return Err(key_does_not_exist(*key)),
+                Some(value) if *value != *from => return Err(precondition_failed(*from, *value)),
+                Some(value) => *value = *to,
+            },
+            Operation::Txn { txn } => return self.apply_txn(txn).map(Some),
+            Operation::Batch { entries } => {
+                // Failed requests answer their own clients alone
+                for entry in entries {
+                    let _ = self.apply_entry(entry);
+                }
+            }
+            Operation::Noop | Operation::Configuration(_) => {}
+        }
+
+        Ok(None)
+    }
+
+    /// Applies the steps of `txn` in order, each seeing the writes of the
+    /// ones before it, and the writes to the store only once every compare
+    /// held. Returns `txn` with the values read.
+    fn apply_txn(&mut self, txn: &[TxnOperation]) -> Result<Vec<TxnOperation>, MaelstromError> {
+        let mut writes = HashMap::new();
+        let mut completed = Vec::with_capacity(txn.len());
+        for operation in txn {
+            match operation {
+                TxnOperation::Read { key, .. } => {
+                    let value = writes.get(key).or_else(|| self.store.get(key)).copied();
+                    completed.push(TxnOperation::Read { key: *key, value });
+                    continue;
+                }
+                TxnOperation::Write { key, value } => {
+                    writes.insert(*key, *value);
+                }
+                TxnOperation::Cas { key, from, to } => {
+                    match writes.get(key).or_else(|| self.store.get(key)).copied() {
+                        None => return Err(key_does_not_exist(*key)),
+                        Some(value) if value != *from => {
+                            return Err(precondition_failed(*from, value));
+                        }
+                        Some(_) => writes.insert(*key, *to),
+                    };
+                }
+            }
+            completed.push(operation.clone());
+        }
+        self.store.extend(writes);
+
+        Ok(completed)
+    }
+
+    /// Applies `entry`, unless it was appended for a request of a client
+    /// that was applied already, which ends as it did then.
+    fn apply_entry(&mut self, entry: &Entry) -> Applied {
+        let Some((client, msg_id)) = &entry.request else {
+            return self.apply(&entry.operation);
+        };
+        if let Some(session) = self.sessions.get(client)
+            && session.msg_id == *msg_id
+        {
+            return session.result();
+        }
+
+        let applied = self.apply(&entry.operation);
+        self.sessions
+            .insert(client.clone(), Session::new(*msg_id, &applied));
+
+        applied
+    }
+
+    /// Applies the entries committed since last time, answering the
+    /// clients waiting for them.
+    fn apply_committed(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = self
+                .log
+                .get(index)
+                .cloned()
+                .with_context(|| format!("Committed entry {index} is missing from the log"))?;
+            let applied = self.apply_entry(&entry);
+            self.last_applied = index;
+            if let Operation::Configuration(config) = &entry.operation
+                && config.joint.is_none()
+            {
+                self.commit_config(sender, config)?;
+            }
+
+            for pending in self.pending.remove(&index).unwrap_or_default() {
+                if pending.term != entry.term {
+                    let error = MaelstromError::new(
+                        ErrorCode::TemporarilyUnavailable,
+                        "Lost the lead before the request was committed",
+                    );
+                    sender.send(pending.request.error_reply(&error.into())?)?;
+                    continue;
+                }
+                let applied = match &entry.operation {
+                    Operation::Batch { .. } => self.session_result(&pending.request),
+                    _ => applied.clone(),
+                };
+                sender.send(applied_reply(&pending.request, pending.reply, applied)?)?;
+            }
+        }
+
+        self.leave_joint_config(sender)?;
+        self.take_snapshot();
+        self.serve_reads(sender)?;
+        self.serve_quorum_reads(sender)
+    }
+
+    /// Makes the members of `config`, a configuration that was committed,
+    /// the node's membership. The leader answers the change that led to it,
+    /// and steps down if it's no longer a member.
+    fn commit_config(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        config: &Configuration,
+    ) -> anyhow::Result<()> {
+        self.update_membership(&config.members);
+        if self.role != Role::Leader {
+            return Ok(());
+        }
+
+        if let Some((request, reply)) = self.pending_change.take() {
+            sender.send_raw(request.reply(reply))?;
+        }
+        if !config.members.contains(self.base.node_id()) {
+            tracing::info!(term = self.term, "Stepping down, no longer a member");
+            self.set_role(Role::Follower);
+            self.leader = None;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the cluster on to the new members alone once the joint
+    /// configuration is committed. Leaders only.
+    fn leave_joint_config(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let Some(config) = self.config.leaving_joint() else {
+            return Ok(());
+        };
+        if self.role != Role::Leader || self.config_index > self.last_applied {
+            return Ok(());
+        }
+
+        self.append_config(config);
+        self.replicate(sender)
+    }
+
+    /// Replaces the applied entries with a snapshot of the store once there
+    /// are [`Config::snapshot_threshold`] of them.
+    fn take_snapshot(&mut self) {
+        if self.last_applied - self.log.snapshot_index() < self.snapshot_threshold {
+            return;
+        }
+
+        self.snapshot = Snapshot {
+            index: self.last_applied,
+            term: self.applied_term(),
+            store: self.store.iter().map(|(k, v)| (*k, *v)).collect(),
+            config: self.config_at(self.last_applied).1,
+            sessions: self.sessions.clone(),
+        };
+        self.log.compact(self.last_applied);
+        tracing::debug!(index = self.last_applied, "Took a snapshot");
+    }
+
+    /// Last heartbeat round a quorum of the configuration acknowledged,
+    /// this node included.
+    fn confirmed_round(&self) -> u64 {
+        let mut rounds = self
+            .acked_round
+            .values()
+            .copied()
+            .chain([self.round])
+            .collect::<Vec<_>>();
+        rounds.sort_unstable_by(|a, b| b.cmp(a));
+
+        rounds
+            .into_iter()
+            .find(|round| {
+                self.config.is_quorum(|node| {
+                    node == self.base.node_id()
+                        || self
+                            .acked_round
+                            .get(node)
+                            .is_some_and(|acked| acked >= round)
+                })
+            })
+            .unwrap_or_default()
+    }
+
+    /// How long a lease lasts, see [`LEASE_SHARE`].
+    fn lease(&self) -> Duration {
+        self.election_timeout.start.mul_f64(LEASE_SHARE)
+    }
+
+    /// Extends the lease to [`RaftNode::lease`] past the start of the last
+    /// round a majority acknowledged. No follower of that majority votes for another
+    /// leader before it runs out, see [`RaftNode::handle_request_vote`].
+    fn extend_lease(&mut self) {
+        let confirmed_round = self.confirmed_round();
+        let lease = self.lease();
+        if let Some(started) = self.round_started.get(&confirmed_round) {
+            let expiry = *started + lease;
+            self.lease_expiry = self.lease_expiry.max(Some(expiry));
+        }
+        self.round_started = self.round_started.split_off(&confirmed_round);
+        self.round_started
+            .retain(|_, started| started.elapsed() < lease);
+    }
+
+    /// Whether the leader may serve a read on its own: it holds a lease and
+    /// committed an entry of its term, so its store has every write
+    /// acknowledged so far.
+    fn holds_lease(&self) -> bool {
+        self.lease_reads
+            && self.role == Role::Leader
+            && self.last_applied >= self.term_start
+            && self
+                .lease_expiry
+                .is_some_and(|expiry| Instant::now() < expiry)
+    }
+
+    /// Answers the reads a majority confirmed the lead for once the store
+    /// caught up with their read index. Reads fail once the node no longer
+    /// leads, as it can't tell whether they'd be stale.
+    fn serve_reads(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        if self.role != Role::Leader {
+            for read in self.reads.drain(..) {
+                let error = MaelstromError::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    "Lost the lead before the read was confirmed",
+                );
+                sender.send(read.request.error_reply(&error.into())?)?;
+            }
+            return Ok(());
+        }
+
+        let confirmed_round = self.confirmed_round();
+        let (ready, waiting) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|read| {
+                read.round <= confirmed_round && self.last_applied >= read.read_index
+            });
+        self.reads = waiting;
+
+        for read in ready {
+            let reply = match self.store.get(&read.key) {
+                Some(value) => read.request.reply(Payload::ReadOk { value: *value }),
+                None => read
+                    .request
+                    .error_reply(&key_does_not_exist(read.key).into())?,
+            };
+            sender.send(reply)?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits up to the last entry of the current term a quorum of the
+    /// configuration has. Entries of earlier terms are committed along with it,
+    /// never by counting their replicas, see §5.4.2 of the Raft paper.
+    fn advance_commit_index(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let committed = (self.commit_index + 1..=self.log.last_index())
+            .rev()
+            .take_while(|index| self.log.term(*index) == Some(self.term))
+            .find(|index| {
+                self.config.is_quorum(|node| {
+                    node == self.base.node_id()
+                        || self
+                            .match_index
+                            .get(node)
+                            .is_some_and(|matched| matched >= index)
+                })
+            });
+
+        if let Some(committed) = committed {
+            self.commit_index = committed;
+        }
+
+        self.apply_committed(sender)
+    }
+
+    /// Appends `operation` to the log and replicates it, answering
+    /// `request` with `reply` once it's applied. A retry of a request that
+    /// was applied already is answered right away instead. Leaders only.
+    fn propose(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        operation: Operation,
+        request: &Message<Payload>,
+        reply: Payload,
+    ) -> anyhow::Result<()> {
+        self.propose_all(sender, vec![(operation, request.clone(), reply)])
+    }
+
+    /// Proposes every operation along with its request and reply as
+    /// [`RaftNode::propose`] does, all in one entry. Leaders only.
+    fn propose_all(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        proposals: Vec<(Operation, Message<Payload>, Payload)>,
+    ) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        let mut pending = Vec::new();
+        for (operation, request, reply) in proposals {
+            if let Some((client, msg_id)) = client_request(&request)
+                && let Some(session) = self.sessions.get(&client)
+                && session.msg_id == msg_id
+            {
+                sender.send(applied_reply(&request, reply, session.result())?)?;
+                continue;
+            }
+
+            entries.push(Entry {
+                term: self.term,
+                operation,
+                request: client_request(&request),
+            });
+            pending.push(Pending {
+                term: self.term,
+                request,
+                reply,
+            });
+        }
+        let entry = match entries.len() {
+            0 => return Ok(()),
+            1 => entries.remove(0),
+            _ => Entry {
+                term: self.term,
+                operation: Operation::Batch { entries },
+                request: None,
+            },
+        };
+
+        let index = self.log.append(entry);
+        self.pending.insert(index, pending);
+        self.replicate(sender)?;
+        self.advance_commit_index(sender)
+    }
+
+    /// What applying `request`, part of a batch, ended in, as its client's
+    /// session recorded.
+    fn session_result(&self, request: &Message<Payload>) -> Applied {
+        let Some((client, msg_id)) = client_request(request) else {
+            return Ok(None);
+        };
+        match self.sessions.get(&client) {
+            Some(session) if session.msg_id == msg_id => session.result(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Appends the writes gathered since last time as one entry. Leaders
+    /// only, the writes fail if the node lost the lead since.
+    fn flush_writes(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let writes = std::mem::take(&mut self.batched_writes);
+        if self.role != Role::Leader {
+            for (_, request) in writes {
+                let error = MaelstromError::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    "Lost the lead before the write was appended",
+                );
+                sender.send(request.error_reply(&error.into())?)?;
+            }
+            return Ok(());
+        }
+
+        let proposals = writes
+            .into_iter()
+            .map(|(operation, request)| (operation, request, Payload::WriteOk))
+            .collect();
+        self.propose_all(sender, proposals)
+    }
+
+    /// Forwards the request of a client to the leader, wrapped in a
+    /// [`Payload::Forward`] for the leader to know whose it is, for its reply
+    /// to be relayed back, see [`RaftNode::relay`]. Fails when there's no leader
+    /// known, and for requests other nodes forwarded, which would otherwise
+    /// bounce between nodes that disagree on who leads.
+    fn forward(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let leader = match &self.leader {
+            Some(leader) if !self.base.membership().contains(message.src()) => leader.clone(),
+            _ => {
+                return Err(MaelstromError::new(
+                    ErrorCode::TemporarilyUnavailable,
+                    format!(
+                        "Not the leader, {} is",
+                        self.leader.as_deref().unwrap_or("none")
+                    ),
+                )
+                .into());
+            }
+        };
+
+        let msg_id = sender.next_msg_id();
+        self.forwarded.insert(
+            msg_id,
+            Forwarded {
+                request: message.clone(),
+                sent_at: Instant::now(),
+            },
+        );
+
+        let forward = Payload::Forward {
+            client: message.src().to_owned(),
+            client_msg_id: message.msg_id(),
+            request: Box::new(message.body().payload.clone()),
+        };
+        sender.send(Message::new(
+            self.base.node_id().to_owned(),
+            leader,
+            Body::new(Some(msg_id), None, forward),
+        ))
+    }
+
+    /// Relays the leader's reply to a forwarded request to its client.
+    /// Returns whether `message` was such a reply.
+    fn relay(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<bool> {
+        let Some(forwarded) = message
+            .in_reply_to()
+            .and_then(|msg_id| self.forwarded.remove(&msg_id))
+        else {
+            return Ok(false);
+        };
+
+        sender.send(forwarded.request.reply(message.body().payload.clone()))?;
+        Ok(true)
+    }
+
+    /// Term of the last entry applied.
+    fn applied_term(&self) -> Term {
+        self.log.term(self.last_applied).unwrap_or_default()
+    }
+
+    /// Asks every peer how far its log goes, to answer the read from this
+    /// node's store once it applied up to the last entry of any log of a
+    /// quorum of the configuration, this node included. Every write
+    /// acknowledged so far was committed by a quorum, which shares a node
+    /// with this one, so it's at or before that entry. Witnesses, which keep
+    /// no store, forward reads to the leader.
+    fn start_quorum_read(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        key: Key,
+    ) -> anyhow::Result<()> {
+        if self.config.witnesses.contains(self.base.node_id()) {
+            return self.forward(sender, message);
+        }
+
+        self.last_quorum_read += 1;
+        let read = self.last_quorum_read;
+        self.waiting_quorum_reads.insert(
+            read,
+            QuorumRead {
+                request: message.clone(),
+                key,
+                replies: HashMap::from([(self.base.node_id().to_owned(), self.log.last_index())]),
+                read_index: None,
+                started: Instant::now(),
+            },
+        );
+
+        let requests = self
+            .peers()
+            .into_iter()
+            .map(|peer| self.base.message(&peer, Payload::ReadReplica { read }));
+        sender.send_all(requests.collect::<Vec<_>>())?;
+
+        self.finish_quorum_read(sender, read)
+    }
+
+    /// Sets the read index of the quorum read `read` once a quorum replied.
+    fn finish_quorum_read(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        read: u64,
+    ) -> anyhow::Result<()> {
+        let Some(quorum_read) = self.waiting_quorum_reads.get_mut(&read) else {
+            return Ok(());
+        };
+        let answered = self
+            .config
+            .is_quorum(|node| quorum_read.replies.contains_key(node));
+        if answered && quorum_read.read_index.is_none() {
+            quorum_read.read_index = quorum_read.replies.values().max().copied();
+        }
+
+        self.serve_quorum_reads(sender)
+    }
+
+    /// Answers the quorum reads whose read index was applied.
+    fn serve_quorum_reads(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let ready = self
+            .waiting_quorum_reads
+            .iter()
+            .filter(|(_, quorum_read)| {
+                quorum_read
+                    .read_index
+                    .is_some_and(|read_index| read_index <= self.last_applied)
+            })
+            .map(|(read, _)| *read)
+            .collect::<Vec<_>>();
+
+        for read in ready {
+            let Some(quorum_read) = self.waiting_quorum_reads.remove(&read) else {
+                continue;
+            };
+            let reply = match self.store.get(&quorum_read.key) {
+                Some(value) => quorum_read.request.reply(Payload::ReadOk { value: *value }),
+                None => quorum_read
+                    .request
+                    .error_reply(&key_does_not_exist(quorum_read.key).into())?,
+            };
+            sender.send(reply)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_read_replica(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        read: u64,
+    ) -> anyhow::Result<()> {
+        sender.send(message.reply(Payload::ReadReplicaOk {
+            read,
+            index: self.log.last_index(),
+        }))
+    }
+
+    fn handle_read_replica_ok(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let Payload::ReadReplicaOk { read, index } = message.body().payload else {
+            return Ok(());
+        };
+        if let Some(quorum_read) = self.waiting_quorum_reads.get_mut(&read) {
+            quorum_read.replies.insert(message.src().to_owned(), index);
+        }
+
+        self.finish_quorum_read(sender, read)
+    }
+
+    /// Fails the quorum reads that didn't hear from a quorum, or apply as
+    /// far, within [`FORWARD_TIMEOUT`].
+    fn expire_quorum_reads(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        let expired = self
+            .waiting_quorum_reads
+            .iter()
+            .filter(|(_, quorum_read)| quorum_read.started.elapsed() >= FORWARD_TIMEOUT)
+            .map(|(read, _)| *read)
+            .collect::<Vec<_>>();
+
+        for read in expired {
+            let Some(quorum_read) = self.waiting_quorum_reads.remove(&read) else {
+                continue;
+            };
+            let error = MaelstromError::new(
+                ErrorCode::TemporarilyUnavailable,
+                "Timed out waiting for a quorum to confirm the read",
+            );
+            sender.send(quorum_read.request.error_reply(&error.into())?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Stands for election once the deadline passes, unless leader, a
+    /// learner or a witness.
+    fn handle_tick(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        if self.role == Role::Leader || Instant::now() < self.election_deadline {
+            return Ok(());
+        }
+        if !self.config.can_lead(self.base.node_id()) {
+            return Ok(());
+        }
+
+        self.start_pre_vote(sender)
+    }
+
+    /// Sends followers an `append_entries`, empty for those that have every
+    /// entry. Leaders only.
+    fn handle_heartbeat(&mut self, sender: &mut MessageSender<Payload>) -> anyhow::Result<()> {
+        self.forwarded
+            .retain(|_, forwarded| forwarded.sent_at.elapsed() < FORWARD_TIMEOUT);
+        self.expire_quorum_reads(sender)?;
+        if self.role != Role::Leader || !self.check_quorum() {
+            return self.serve_reads(sender);
+        }
+
+        self.replicate(sender)
+    }
+
+    /// Steps down unless a majority answered within the shortest election
+    /// timeout, as by then they may have elected another leader, returning
+    /// whether the node still leads. Keeps a partitioned leader from
+    /// serving reads, on a lease or not, for good. Leaders only.
+    fn check_quorum(&mut self) -> bool {
+        let timeout = self.election_timeout.start;
+        let heard_from_quorum = self.config.is_quorum(|node| {
+            node == self.base.node_id()
+                || self
+                    .heard_from
+                    .get(node)
+                    .is_some_and(|heard| heard.elapsed() < timeout)
+        });
+        if heard_from_quorum {
+            return true;
+        }
+
+        tracing::info!(term = self.term, "Stepping down, no majority answered");
+        self.set_role(Role::Follower);
+        self.leader = None;
+        self.lease_expiry = None;
+        self.reset_election_deadline();
+
+        false
+    }
+
+    /// Grants the vote of the current term to the first candidate asking
+    /// whose log is at least as up to date as this node's.
+    fn handle_request_vote(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        term: Term,
+        last_log_index: usize,
+        last_log_term: Term,
+    ) -> anyhow::Result<()> {
+        // While leases are on, followers that heard from their leader less
+        // than an election timeout ago ignore candidates
+        let lease_held = self.lease_reads
+            && self.role == Role::Follower
+            && self
+                .leader_contact
+                .is_some_and(|contact| contact.elapsed() < self.election_timeout.start);
+        if lease_held {
+            return sender.send(message.reply(Payload::RequestVoteOk {
+                term: self.term,
+                vote_granted: false,
+            }));
+        }
+        self.observe_term(term);
+
+        let candidate = message.src();
+        let vote_granted = term == self.term
+            && self
+                .voted_for
+                .as_deref()
+                .is_none_or(|voted_for| voted_for == candidate)
+            && self.log.is_up_to_date(last_log_index, last_log_term);
+        if vote_granted {
+            if self.voted_for.is_none() {
+                self.stats.votes_granted += 1;
+            }
+            self.voted_for = Some(candidate.to_owned());
+            self.reset_election_deadline();
+        }
+
+        sender.send(message.reply(Payload::RequestVoteOk {
+            term: self.term,
+            vote_granted,
+        }))
+    }
+
+    /// Says whether this node would vote for the candidate in `term`: it
+    /// would if it didn't hear from a leader for an election timeout and the
+    /// candidate's log is at least as up to date as its own.
+    fn handle_pre_vote(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        term: Term,
+        last_log_index: usize,
+        last_log_term: Term,
+    ) -> anyhow::Result<()> {
+        let leader_heard = self.role == Role::Leader
+            || self
+                .leader_contact
+                .is_some_and(|contact| contact.elapsed() < self.election_timeout.start);
+        let vote_granted = term > self.term
+            && !leader_heard
+            && self.log.is_up_to_date(last_log_index, last_log_term);
+
+        sender.send(message.reply(Payload::PreVoteOk {
+            term: self.term,
+            vote_granted,
+        }))
+    }
+
+    fn handle_pre_vote_ok(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        term: Term,
+        vote_granted: bool,
+    ) -> anyhow::Result<()> {
+        self.observe_term(term);
+        if self.role != Role::PreCandidate || !vote_granted {
+            return Ok(());
+        }
+
+        self.votes.insert(message.src().to_owned());
+        if self.has_votes() {
+            return self.start_election(sender);
+        }
+
+        Ok(())
+    }
+
+    fn handle_request_vote_ok(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        term: Term,
+        vote_granted: bool,
+    ) -> anyhow::Result<()> {
+        self.observe_term(term);
+        if self.role != Role::Candidate || term != self.term || !vote_granted {
+            return Ok(());
+        }
+
+        self.votes.insert(message.src().to_owned());
+        if self.has_votes() {
+            return self.become_leader(sender);
+        }
+
+        Ok(())
+    }
+
+    /// Appends the leader's entries after `prev_log_index`, provided the
+    /// log has the entry the leader has there. Entries that conflict with
+    /// the leader's are dropped, along with every one after them. Entries
+    /// the leader committed are then applied.
+    fn handle_append_entries(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let Payload::AppendEntries {
+            term,
+            prev_log_index,
+            prev_log_term,
+            ref entries,
+            leader_commit,
+            round,
+        } = message.body().payload
+        else {
+            return Ok(());
+        };
+        self.observe_term(term);
+        if term == self.term {
+            self.follow(message.src());
+        }
+
+        // Entries up to the snapshot are committed, the leader has them too
+        let snapshot_index = self.log.snapshot_index();
+        if term == self.term && prev_log_index < snapshot_index {
+            return sender.send(message.reply(Payload::AppendEntriesOk {
+                term: self.term,
+                success: true,
+                match_index: snapshot_index,
+                round,
+                conflict_index: None,
+                conflict_term: None,
+            }));
+        }
+
+        let success = term == self.term && self.log.term(prev_log_index) == Some(prev_log_term);
+        let mut match_index = 0;
+        if success {
+            let mut config_changed = false;
+            for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                match self.log.term(index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => {
+                        self.log.truncate(index);
+                        config_changed |= index <= self.config_index;
+                    }
+                    None => {}
+                }
+                config_changed |= matches!(entry.operation, Operation::Configuration(_));
+                self.log.append(entry.clone());
+            }
+            if config_changed {
+                self.reload_config();
+            }
+            match_index = prev_log_index + entries.len();
+            self.commit_index = self.commit_index.max(leader_commit.min(match_index));
+        }
+
+        let (conflict_index, conflict_term) = match self.log.term(prev_log_index) {
+            _ if success || term != self.term => (None, None),
+            None => (Some(self.log.last_index() + 1), None),
+            Some(conflict_term) => (
+                Some(self.log.first_index_of_term(prev_log_index)),
+                Some(conflict_term),
+            ),
+        };
+        sender.send(message.reply(Payload::AppendEntriesOk {
+            term: self.term,
+            success,
+            match_index,
+            round,
+            conflict_index,
+            conflict_term,
+        }))?;
+
+        self.apply_committed(sender)
+    }
+
+    /// Replaces the log up to the leader's snapshot, and the store with it,
+    /// unless the log is past it already.
+    fn handle_install_snapshot(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let Payload::InstallSnapshot {
+            term,
+            ref snapshot,
+            round,
+        } = message.body().payload
+        else {
+            return Ok(());
+        };
+        self.observe_term(term);
+        if term != self.term {
+            return sender.send(message.reply(Payload::AppendEntriesOk {
+                term: self.term,
+                success: false,
+                match_index: 0,
+                round,
+                conflict_index: None,
+                conflict_term: None,
+            }));
+        }
+        self.follow(message.src());
+
+        if snapshot.index > self.commit_index {
+            if self.log.term(snapshot.index) == Some(snapshot.term) {
+                self.log.compact(snapshot.index);
+            } else {
+                self.log.reset(snapshot.index, snapshot.term);
+            }
+            self.store = snapshot.store.iter().copied().collect();
+            self.sessions = snapshot.sessions.clone();
+            self.commit_index = snapshot.index;
+            self.last_applied = snapshot.index;
+            self.snapshot = snapshot.clone();
+            self.reload_config();
+            self.update_membership(&snapshot.config.members);
+            tracing::debug!(index = snapshot.index, "Installed a snapshot");
+        }
+
+        sender.send(message.reply(Payload::AppendEntriesOk {
+            term: self.term,
+            success: true,
+            match_index: snapshot.index,
+            round,
+            conflict_index: None,
+            conflict_term: None,
+        }))?;
+        self.serve_quorum_reads(sender)
+    }
+
+    /// Moves the follower's indexes on. When it didn't have the entry before
+    /// the ones sent, they're sent again from where it says they conflict,
+    /// past the last entry of the conflicting term should this node have
+    /// it too, a term's worth of entries at once. Followers that don't say
+    /// are sent them along with the one before the oldest in flight, or
+    /// the one before them when none are.
+    fn handle_append_entries_ok(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let Payload::AppendEntriesOk {
+            term,
+            success,
+            match_index,
+            round,
+            conflict_index,
+            conflict_term,
+        } = message.body().payload
+        else {
+            return Ok(());
+        };
+        self.observe_term(term);
+        if self.role != Role::Leader || term != self.term {
+            return Ok(());
+        }
+
+        // Either way the follower still takes this node for its leader
+        let follower = message.src().to_owned();
+        self.heard_from.insert(follower.clone(), Instant::now());
+        let acked = self.acked_round.entry(follower.clone()).or_default();
+        *acked = (*acked).max(round);
+        if self.lease_reads {
+            self.extend_lease();
+        }
+        if success {
+            let matched = self.match_index.entry(follower.clone()).or_default();
+            *matched = (*matched).max(match_index);
+            let matched = *matched;
+            let in_flight = self.in_flight.entry(follower.clone()).or_default();
+            while in_flight.front().is_some_and(|(_, last)| *last <= matched) {
+                in_flight.pop_front();
+            }
+            let next_index = self.next_index.entry(follower).or_insert(1);
+            *next_index = (*next_index).max(matched + 1);
+            return self.advance_commit_index(sender);
+        }
+
+        let oldest = self
+            .in_flight
+            .remove(&follower)
+            .and_then(|in_flight| in_flight.front().map(|(prev, _)| *prev));
+        let conflict = conflict_index.map(|conflict_index| {
+            conflict_term
+                .and_then(|term| self.log.last_index_of_term(term))
+                .map_or(conflict_index, |last| last + 1)
+        });
+        let next_index = self.next_index.entry(follower.clone()).or_insert(1);
+        *next_index = conflict.or(oldest).unwrap_or(*next_index - 1).max(1);
+
+        sender.send(self.append_entries(&follower))?;
+        self.serve_reads(sender)
+    }
+
+    /// Handles the `request` of a client, sent along by `message` itself or
+    /// forwarded in it by a follower, which the reply goes back to.
+    fn handle_request(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        request: &Payload,
+    ) -> anyhow::Result<()> {
+        match request {
+            Payload::Read { key } => self.handle_read(sender, message, *key),
+            Payload::Write { key, value } => self.handle_write(sender, message, *key, *value),
+            Payload::Cas { key, from, to } => self.handle_cas(sender, message, *key, *from, *to),
+            Payload::Txn { txn } => self.handle_txn(sender, message, txn),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_read(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        key: Key,
+    ) -> anyhow::Result<()> {
+        if self.quorum_reads {
+            return self.start_quorum_read(sender, message, key);
+        }
+        if self.role != Role::Leader {
+            return self.forward(sender, message);
+        }
+
+        if self.holds_lease() {
+            let Some(value) = self.store.get(&key).copied() else {
+                return Err(key_does_not_exist(key).into());
+            };
+            return sender.send(message.reply(Payload::ReadOk { value }));
+        }
+
+        self.reads.push(PendingRead {
+            read_index: self.commit_index.max(self.term_start),
+            round: self.round + 1,
+            request: message.clone(),
+            key,
+        });
+        self.replicate(sender)?;
+
+        self.serve_reads(sender)
+    }
+
+    fn handle_write(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        key: Key,
+        value: usize,
+    ) -> anyhow::Result<()> {
+        if self.role != Role::Leader {
+            return self.forward(sender, message);
+        }
+        if !self.write_batch_interval.is_zero() {
+            self.batched_writes
+                .push((Operation::Write { key, value }, message.clone()));
+            return Ok(());
+        }
+
+        self.propose(
+            sender,
+            Operation::Write { key, value },
+            message,
+            Payload::WriteOk,
+        )
+    }
+
+    fn handle_txn(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        txn: &[TxnOperation],
+    ) -> anyhow::Result<()> {
+        if self.strict_lin_kv {
+            return Err(
+                MaelstromError::new(ErrorCode::NotSupported, "txn isn't part of lin-kv").into(),
+            );
+        }
+        if self.role != Role::Leader {
+            return self.forward(sender, message);
+        }
+
+        // Answered with the values read instead, see `applied_reply`
+        self.propose(
+            sender,
+            Operation::Txn { txn: txn.to_vec() },
+            message,
+            Payload::TxnOk { txn: Vec::new() },
+        )
+    }
+
+    fn handle_raft_state(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let match_index = match self.role {
+            Role::Leader => self.match_index.clone().into_iter().collect(),
+            _ => BTreeMap::new(),
+        };
+
+        sender.send(message.reply(Payload::RaftStateOk {
+            term: self.term,
+            role: self.role,
+            leader: self.leader.clone(),
+            last_log_index: self.log.last_index(),
+            commit_index: self.commit_index,
+            match_index,
+        }))
+    }
+
+    /// Appends the compare along with the swap, for the nodes to tell
+    /// whether it holds as they apply it, against every write before it.
+    fn handle_cas(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        key: Key,
+        from: usize,
+        to: usize,
+    ) -> anyhow::Result<()> {
+        if self.role != Role::Leader {
+            return self.forward(sender, message);
+        }
+
+        self.propose(
+            sender,
+            Operation::Cas { key, from, to },
+            message,
+            Payload::CasOk,
+        )
+    }
+
+    /// Starts moving the cluster to the members `change` leads to, through a
+    /// joint configuration of the old and new ones, see
+    /// [`RaftNode::leave_joint_config`]. Answered once the configuration of
+    /// the new members alone is committed. Leaders only, one change at a
+    /// time.
+    fn handle_membership_change(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: Message<Value>,
+        change: MembershipChange,
+    ) -> anyhow::Result<()> {
+        if let Some(text) = self.change_refusal() {
+            return refuse_change(sender, &message, ErrorCode::TemporarilyUnavailable, text);
+        }
+
+        let mut members = self.config.members.clone();
+        match &change {
+            // Learners leave right away, as they don't vote
+            MembershipChange::NodeRemoved { node_id } if self.config.learners.contains(node_id) => {
+                let mut config = self.config.clone();
+                config.learners.remove(node_id);
+                return self.change_config(sender, message, config, change.reply());
+            }
+            MembershipChange::NodeAdded { node_id } => members.insert(node_id.clone()),
+            MembershipChange::NodeRemoved { node_id } => members.remove(node_id),
+        };
+        if members == self.config.members {
+            return sender.send_raw(message.reply(change.reply()));
+        }
+
+        self.change_config(
+            sender,
+            message,
+            self.config.moving_to(members),
+            change.reply(),
+        )
+    }
+
+    /// Adds a learner, through a configuration of its own as who votes stays
+    /// the same, or promotes one to a member through a joint configuration
+    /// as [`RaftNode::handle_membership_change`] does, once it has every
+    /// committed entry. Answered once committed. Leaders only, one change at
+    /// a time.
+    fn handle_learner_change(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: Message<Value>,
+        change: LearnerChange,
+    ) -> anyhow::Result<()> {
+        if let Some(text) = self.change_refusal() {
+            return refuse_change(sender, &message, ErrorCode::TemporarilyUnavailable, text);
+        }
+
+        match &change {
+            LearnerChange::AddLearner { node_id } => {
+                if self.config.is_voter(node_id) || self.config.learners.contains(node_id) {
+                    return sender.send_raw(message.reply(change.reply()));
+                }
+
+                let mut config = self.config.clone();
+                config.learners.insert(node_id.clone());
+                self.change_config(sender, message, config, change.reply())
+            }
+            LearnerChange::PromoteLearner { node_id } => {
+                if self.config.is_voter(node_id) {
+                    return sender.send_raw(message.reply(change.reply()));
+                }
+                if !self.config.learners.contains(node_id) {
+                    let text = format!("{node_id} isn't a learner");
+                    return refuse_change(sender, &message, ErrorCode::PreconditionFailed, text);
+                }
+                let caught_up = self
+                    .match_index
+                    .get(node_id)
+                    .is_some_and(|matched| *matched >= self.commit_index);
+                if !caught_up {
+                    let text = format!("{node_id} is still catching up");
+                    return refuse_change(
+                        sender,
+                        &message,
+                        ErrorCode::TemporarilyUnavailable,
+                        text,
+                    );
+                }
+
+                let mut members = self.config.members.clone();
+                members.insert(node_id.clone());
+                self.change_config(
+                    sender,
+                    message,
+                    self.config.moving_to(members),
+                    change.reply(),
+                )
+            }
+        }
+    }
+
+    /// Why a membership or learner change can't be made now, if it can't.
+    fn change_refusal(&self) -> Option<String> {
+        if self.role != Role::Leader {
+            Some(format!(
+                "Not the leader, {} is",
+                self.leader.as_deref().unwrap_or("none")
+            ))
+        } else if self.config.joint.is_some() || self.config_index > self.last_applied {
+            Some("Another membership change is in progress".to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Moves to `config` for `request`, answered with `reply` once the
+    /// configuration it leads to is committed.
+    fn change_config(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        request: Message<Value>,
+        config: Configuration,
+        reply: Value,
+    ) -> anyhow::Result<()> {
+        self.append_config(config);
+        self.pending_change = Some((request, reply));
+        self.replicate(sender)?;
+        self.advance_commit_index(sender)
+    }
+}
+
+/// Answers `request`, a membership or learner change, with an error.
+fn refuse_change(
+    sender: &mut MessageSender<Payload>,
+    request: &Message<Value>,
+    code: ErrorCode,
+    text: String,
+) -> anyhow::Result<()> {
+    let error = ErrorPayload::Error { code, text };
+    let error = serde_json::to_value(error).context("Error serializing error")?;
+
+    sender.send_raw(request.reply(error))
+}
+
+fn random_timeout(range: &Range<Duration>) -> Duration {
+    rand::thread_rng().gen_range(range.clone())
+}
+
+/// The reply to `request` once applied: `reply`, or `txn_ok` with the
+/// values read for `txn`s, or the error applying it failed with.
+fn applied_reply(
+    request: &Message<Payload>,
+    reply: Payload,
+    applied: Applied,
+) -> anyhow::Result<Message<Payload>> {
+    match applied {
+        Ok(Some(txn)) => Ok(request.reply(Payload::TxnOk { txn })),
+        Ok(None) => Ok(request.reply(reply)),
+        Err(error) => request.error_reply(&error.into()),
+    }
+}
+
+/// The client that sent `request` and the `msg_id` it sent it with, which
+/// for forwarded requests are those of the original request.
+fn client_request(request: &Message<Payload>) -> Option<(String, usize)> {
+    match &request.body().payload {
+        Payload::Forward {
+            client,
+            client_msg_id,
+            ..
+        } => client_msg_id.map(|msg_id| (client.clone(), msg_id)),
+        _ => request
+            .msg_id()
+            .map(|msg_id| (request.src().to_owned(), msg_id)),
+    }
+}
+
+fn precondition_failed(from: usize, value: usize) -> MaelstromError {
+    MaelstromError::new(
+        ErrorCode::PreconditionFailed,
+        format!("Expected {from} but found {value}"),
+    )
+}
+
+fn key_does_not_exist(key: Key) -> MaelstromError {
+    MaelstromError::new(
+        ErrorCode::KeyDoesNotExist,
+        format!("Key {key} does not exist"),
+    )
+}
+
+impl Node<Payload> for RaftNode {
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        self.election_timer = Some(scheduler.tick_periodic(ELECTION_TICK).id());
+        self.heartbeat_timer = Some(scheduler.tick_periodic(self.heartbeat_interval).id());
+        if !self.write_batch_interval.is_zero() {
+            self.batch_timer = Some(scheduler.tick_periodic(self.write_batch_interval).id());
+        }
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        let result = match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.election_timer => self.handle_tick(ctx),
+            Event::Tick(id) if Some(id) == self.heartbeat_timer => self.handle_heartbeat(ctx),
+            Event::Tick(id) if Some(id) == self.batch_timer => self.flush_writes(ctx),
+            _ => Ok(()),
+        };
+        self.persist()?;
+
+        result
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        if self.relay(ctx, &message)? {
+            return Ok(());
+        }
+
+        match &message.body().payload {
+            Payload::Read { .. }
+            | Payload::Write { .. }
+            | Payload::Cas { .. }
+            | Payload::Txn { .. } => self.handle_request(ctx, &message, &message.body().payload),
+            Payload::Forward { request, .. } => self.handle_request(ctx, &message, request),
+            Payload::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => self.handle_request_vote(ctx, &message, *term, *last_log_index, *last_log_term),
+            Payload::RequestVoteOk { term, vote_granted } => {
+                self.handle_request_vote_ok(ctx, &message, *term, *vote_granted)
+            }
+            Payload::PreVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => self.handle_pre_vote(ctx, &message, *term, *last_log_index, *last_log_term),
+            Payload::PreVoteOk { term, vote_granted } => {
+                self.handle_pre_vote_ok(ctx, &message, *term, *vote_granted)
+            }
+            Payload::AppendEntries { .. } => self.handle_append_entries(ctx, &message),
+            Payload::InstallSnapshot { .. } => self.handle_install_snapshot(ctx, &message),
+            Payload::AppendEntriesOk { .. } => self.handle_append_entries_ok(ctx, &message),
+            Payload::ReadReplica { read } => self.handle_read_replica(ctx, &message, *read),
+            Payload::ReadReplicaOk { .. } => self.handle_read_replica_ok(ctx, &message),
+            Payload::RaftState => self.handle_raft_state(ctx, &message),
+            Payload::ReadOk { .. }
+            | Payload::WriteOk
+            | Payload::CasOk
+            | Payload::TxnOk { .. }
+            | Payload::Error { .. }
+            | Payload::RaftStateOk { .. } => Ok(()),
+        }
+    }
+
+    /// Membership changes go through the log rather than straight to the
+    /// node's membership, see [`RaftNode::handle_membership_change`], as do
+    /// learner changes, see [`RaftNode::handle_learner_change`].
+    fn handle_unknown(
+        &mut self,
+        message: Message<Value>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        let payload = message.body().payload.clone();
+        let result = if let Ok(change) = serde_json::from_value::<MembershipChange>(payload.clone())
+        {
+            self.handle_membership_change(ctx, message, change)
+        } else if let Ok(change) = serde_json::from_value::<LearnerChange>(payload) {
+            self.handle_learner_change(ctx, message, change)
+        } else {
+            eprintln!(
+                "Unknown message: {}",
+                serde_json::to_string(&message).context("Error serializing message")?
+            );
+            return Ok(());
+        };
+        self.persist()?;
+
+        result
+    }
+
+    /// Where the node stands and how its elections went, with the time
+    /// spent in each role in milliseconds.
+    fn stats(&self) -> Value {
+        let mut time_in_role = self.stats.time_in_role.clone();
+        *time_in_role.entry(self.role).or_default() += self.stats.role_since.elapsed();
+        let ms_in_role = time_in_role
+            .into_iter()
+            .map(|(role, time)| (role, time.as_millis()))
+            .collect::<HashMap<_, _>>();
+
+        serde_json::json!({
+            "term": self.term,
+            "role": self.role,
+            "leader": self.leader,
+            "pre_votes_started": self.stats.pre_votes_started,
+            "elections_started": self.stats.elections_started,
+            "elections_won": self.stats.elections_won,
+            "votes_granted": self.stats.votes_granted,
+            "leader_changes": self.stats.leader_changes,
+            "ms_in_role": ms_in_role,
+        })
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    main_loop_with_config(config.clone(), |init| {
+        let storage = Storage::from_env(&init.node_id)?;
+
+        Ok(RaftNode::new(init, &config).with_storage(storage))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        log::{Entry, Operation},
+        Payload, RaftNode, Role, FORWARD_TIMEOUT,
+    };
+    use crate::{
+        config::Config,
+        conformance::{assert_reply_to, assert_round_trip, init},
+        simulator::Simulator,
+        writters::MemoryWritter,
+        Body, ErrorCode, Message, MessageSender, Node, NodeContext,
+    };
+    use serde_json::json;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    const REQUEST_VOTE: &str = r#"{"src":"n2","dest":"n1","body":{"type":"request_vote","msg_id":1,"term":3,"last_log_index":2,"last_log_term":1}}"#;
+
+    type Sent = Arc<Mutex<Vec<Message<Payload>>>>;
+
+    fn message(src: &str, in_reply_to: Option<usize>, payload: Payload) -> Message<Payload> {
+        Message::new(
+            src.to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(1), in_reply_to, payload),
+        )
+    }
+
+    fn new_sender() -> (MessageSender<'static, Payload>, Sent) {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+
+        (MessageSender::new(writter), sent)
+    }
+
+    fn write(key: usize, value: usize, term: u64) -> Entry {
+        Entry {
+            term,
+            operation: Operation::Write { key, value },
+            request: None,
+        }
+    }
+
+    /// n1, elected leader of term 1 with n2's pre-vote and vote.
+    fn leader(sender: &mut MessageSender<Payload>) -> RaftNode {
+        let mut node = RaftNode::new(init(), &Config::default());
+        node.election_deadline = Instant::now();
+        node.handle_tick(sender).unwrap();
+
+        let pre_vote = Payload::PreVoteOk {
+            term: 0,
+            vote_granted: true,
+        };
+        node.handle_message(
+            message("n2", Some(0), pre_vote),
+            &mut NodeContext::new(sender),
+        )
+        .unwrap();
+        let vote = Payload::RequestVoteOk {
+            term: 1,
+            vote_granted: true,
+        };
+        node.handle_message(message("n2", Some(0), vote), &mut NodeContext::new(sender))
+            .unwrap();
+
+        node
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[
+            REQUEST_VOTE,
+            r#"{"src":"n1","dest":"n2","body":{"type":"request_vote_ok","in_reply_to":1,"term":3,"vote_granted":true}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"pre_vote","msg_id":1,"term":4,"last_log_index":2,"last_log_term":1}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"pre_vote_ok","in_reply_to":1,"term":3,"vote_granted":false}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":2,"key":1,"from":2,"to":3}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":4,"txn":[["r",1,null],["w",1,2],["cas",1,[2,3]]]}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"forward","msg_id":3,"client":"c1","client_msg_id":2,"request":{"type":"write","key":1,"value":2}}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"append_entries","msg_id":4,"term":2,"prev_log_index":1,"prev_log_term":1,"entries":[{"term":2,"operation":{"type":"write","key":1,"value":3}}],"leader_commit":1,"round":3}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"append_entries_ok","in_reply_to":4,"term":2,"success":true,"match_index":2,"round":3}}"#,
+            r#"{"src":"n3","dest":"n1","body":{"type":"append_entries_ok","in_reply_to":5,"term":2,"success":false,"match_index":0,"round":3,"conflict_index":4,"conflict_term":1}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"install_snapshot","msg_id":5,"term":2,"snapshot":{"index":7,"term":2,"store":[[1,3]],"config":{"members":["n1","n2"]}},"round":4}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"read_replica","msg_id":7,"read":2}}"#,
+            r#"{"src":"n2","dest":"n1","body":{"type":"read_replica_ok","in_reply_to":7,"read":2,"index":4}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"raft_state","msg_id":3}}"#,
+            r#"{"src":"n1","dest":"c1","body":{"type":"raft_state_ok","in_reply_to":3,"term":2,"role":"leader","leader":"n1","last_log_index":7,"commit_index":6,"match_index":{"n2":7,"n3":5}}}"#,
+            r#"{"src":"n1","dest":"n2","body":{"type":"append_entries","msg_id":6,"term":2,"prev_log_index":7,"prev_log_term":2,"entries":[{"term":2,"operation":{"type":"configuration","members":["n1","n2"],"joint":["n1","n2","n3"]}}],"leader_commit":7,"round":5}}"#,
+        ]);
+    }
+
+    #[test]
+    fn test_leader_election() {
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+
+        node.election_deadline = Instant::now();
+        node.handle_tick(&mut sender).unwrap();
+        assert_eq!(node.role, Role::PreCandidate);
+        assert_eq!(node.term, 0);
+
+        // Stands once a majority would vote for it
+        let pre_vote = Payload::PreVoteOk {
+            term: 0,
+            vote_granted: true,
+        };
+        node.handle_message(
+            message("n3", Some(0), pre_vote),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.role, Role::Candidate);
+        assert_eq!(node.term, 1);
+        let sent = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| (m.dest().to_owned(), m.body().payload.clone()))
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            &sent[..],
+            [
+                (_, Payload::PreVote { term: 1, .. }),
+                (_, Payload::PreVote { .. }),
+                (n2, Payload::RequestVote { term: 1, .. }),
+                (n3, Payload::RequestVote { .. }),
+            ] if n2 == "n2" && n3 == "n3"
+        ));
+
+        // n2's vote and its own make a majority of three
+        let vote = |term, vote_granted| {
+            message("n2", Some(0), Payload::RequestVoteOk { term, vote_granted })
+        };
+        node.handle_message(vote(1, true), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(node.role, Role::Leader);
+        assert_eq!(node.leader.as_deref(), Some("n1"));
+
+        // A newer term takes the node back to following
+        node.handle_message(vote(2, false), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(node.role, Role::Follower);
+        assert_eq!(node.term, 2);
+    }
+
+    #[test]
+    fn test_heartbeats_keep_the_leader() {
+        let config = Config::default();
+        let mut simulator = Simulator::new(3, |init| Ok(RaftNode::new(init, &config))).unwrap();
+        let leaders = |simulator: &Simulator<RaftNode, Payload>| {
+            simulator
+                .node_ids()
+                .map(|node_id| simulator.node(node_id))
+                .filter(|node| node.role == Role::Leader)
+                .map(|node| (node.base.node_id().to_owned(), node.term))
+                .collect::<Vec<_>>()
+        };
+
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        let elected = leaders(&simulator);
+        assert_eq!(elected.len(), 1);
+
+        // Long past every election timeout, no one stood again
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        assert_eq!(leaders(&simulator), elected);
+    }
+
+    #[test]
+    fn test_partitioned_followers_dont_depose_the_leader() {
+        let config = Config::default();
+        let mut simulator = Simulator::new(3, |init| Ok(RaftNode::new(init, &config))).unwrap();
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        let leader = |simulator: &Simulator<RaftNode, Payload>| {
+            simulator
+                .node_ids()
+                .map(|node_id| simulator.node(node_id))
+                .find(|node| node.role == Role::Leader)
+                .map(|node| (node.base.node_id().to_owned(), node.term))
+        };
+        let elected = leader(&simulator).unwrap();
+
+        let follower = ["n1", "n2", "n3"]
+            .into_iter()
+            .find(|node_id| *node_id != elected.0)
+            .unwrap();
+        let others = ["n1", "n2", "n3"]
+            .into_iter()
+            .filter(|node_id| *node_id != follower)
+            .collect::<Vec<_>>();
+        simulator.network().partition(&[&others, &[follower]]);
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        simulator.network().heal();
+        simulator.run_for(Duration::from_millis(500)).unwrap();
+
+        assert_eq!(simulator.node(follower).term, elected.1);
+        assert_eq!(leader(&simulator), Some(elected));
+    }
+
+    #[test]
+    fn test_witnesses() {
+        let config = Config {
+            witnesses: vec!["n3".to_owned()],
+            ..Config::default()
+        };
+        let mut simulator = Simulator::new(3, |init| Ok(RaftNode::new(init, &config))).unwrap();
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        let leader = ["n1", "n2"]
+            .into_iter()
+            .find(|node_id| simulator.node(node_id).role == Role::Leader)
+            .unwrap();
+        let other = if leader == "n1" { "n2" } else { "n1" };
+
+        // The leader and the witness make a majority on their own
+        simulator.network().partition(&[&[leader, "n3"], &[other]]);
+        let write = Payload::Write { key: 1, value: 2 };
+        simulator.send(Message::new(
+            "c1".to_owned(),
+            leader.to_owned(),
+            Body::new(Some(1), None, write),
+        ));
+        simulator.run_for(Duration::from_millis(500)).unwrap();
+
+        assert!(simulator
+            .client_messages()
+            .iter()
+            .any(|m| matches!(m.body().payload, Payload::WriteOk)));
+        let witness = simulator.node("n3");
+        assert_eq!(witness.commit_index, simulator.node(leader).commit_index);
+        assert!(witness.store.is_empty());
+        assert!(witness
+            .log
+            .entries_from(1)
+            .iter()
+            .all(|entry| !matches!(entry.operation, Operation::Write { .. })));
+
+        // Losing the leader then leaves no one to take over
+        simulator.network().partition(&[&[leader], &[other, "n3"]]);
+        simulator.run_for(Duration::from_secs(1)).unwrap();
+        assert_ne!(simulator.node(other).role, Role::Leader);
+    }
+
+    #[test]
+    fn test_pre_vote() {
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+        let pre_vote = || {
+            let pre_vote = Payload::PreVote {
+                term: 1,
+                last_log_index: 0,
+                last_log_term: 0,
+            };
+            message("n2", None, pre_vote)
+        };
+
+        node.handle_message(pre_vote(), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        // Not while it hears from a leader
+        node.leader_contact = Some(Instant::now());
+        node.handle_message(pre_vote(), &mut NodeContext::new(&mut sender))
+            .unwrap();
+
+        let granted = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| match m.body().payload {
+                Payload::PreVoteOk { vote_granted, .. } => vote_granted,
+                _ => panic!("Expected a pre-vote"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(granted, [true, false]);
+        assert_eq!((node.term, node.voted_for.as_deref()), (0, None));
+    }
+
+    #[test]
+    fn test_one_vote_per_term() {
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+
+        for candidate in ["n2", "n3", "n2"] {
+            let request =
+                serde_json::from_str::<Message<Payload>>(&REQUEST_VOTE.replace("n2", candidate))
+                    .unwrap();
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        // Not for a candidate whose log is behind
+        node.log.append(write(1, 1, 4));
+        let behind = Payload::RequestVote {
+            term: 5,
+            last_log_index: 3,
+            last_log_term: 3,
+        };
+        node.handle_message(
+            message("n3", None, behind),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+
+        let granted = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| match m.body().payload {
+                Payload::RequestVoteOk { vote_granted, .. } => vote_granted,
+                _ => panic!("Expected a vote"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(granted, [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_log_replication() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+
+        let request = message("c1", None, Payload::Write { key: 1, value: 2 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        // The no-op of its election, then the write alone
+        let append_entries = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.dest() == "n2")
+            .filter(|m| matches!(m.body().payload, Payload::AppendEntries { .. }))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(append_entries.len(), 2);
+        // Not applied nor answered before a majority has it
+        assert!(node.store.is_empty());
+        assert!(sent.lock().unwrap().iter().all(|m| m.dest() != "c1"));
+
+        // A follower that has every entry before them appends them
+        let (mut follower_sender, follower_sent) = new_sender();
+        let mut follower = RaftNode::new(init(), &Config::default());
+        for append_entries in append_entries {
+            follower
+                .handle_message(append_entries, &mut NodeContext::new(&mut follower_sender))
+                .unwrap();
+        }
+        assert_eq!(follower.log.last_index(), 2);
+        assert!(follower.store.is_empty());
+        assert_eq!(follower.leader.as_deref(), Some("n1"));
+        let reply = follower_sent.lock().unwrap().last().cloned().unwrap();
+        assert!(matches!(
+            reply.body().payload,
+            Payload::AppendEntriesOk {
+                success: true,
+                match_index: 2,
+                ..
+            }
+        ));
+        node.handle_message(reply, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(node.match_index["n2"], 2);
+        assert_eq!(node.commit_index, 2);
+        assert_eq!(node.store.get(&1), Some(&2));
+        let write_ok = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(write_ok.dest(), "c1");
+        assert!(matches!(write_ok.body().payload, Payload::WriteOk));
+
+        // One that misses the previous entry is sent every entry in flight
+        // to it again
+        sent.lock().unwrap().clear();
+        assert_eq!(node.next_index["n3"], 3);
+        let rejected = Payload::AppendEntriesOk {
+            term: 1,
+            success: false,
+            match_index: 0,
+            round: 0,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n3", Some(0), rejected),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let resent = sent.lock().unwrap()[0].clone();
+        assert!(matches!(
+            resent.body().payload,
+            Payload::AppendEntries {
+                prev_log_index: 0,
+                ref entries,
+                ..
+            } if entries.len() == 2
+        ));
+
+        // Then one entry further back each time, while none are in flight
+        let rejected = Payload::AppendEntriesOk {
+            term: 1,
+            success: false,
+            match_index: 0,
+            round: 0,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.in_flight.remove("n3");
+        node.next_index.insert("n3".to_owned(), 3);
+        sent.lock().unwrap().clear();
+        node.handle_message(
+            message("n3", Some(0), rejected),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert!(matches!(
+            sent.lock().unwrap()[0].body().payload,
+            Payload::AppendEntries {
+                prev_log_index: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_pipelined_replication() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.replication_window = 2;
+        let to_n2 = |sent: &Sent| {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .filter(|m| m.dest() == "n2")
+                .filter_map(|m| match &m.body().payload {
+                    Payload::AppendEntries {
+                        prev_log_index,
+                        entries,
+                        ..
+                    } => Some((*prev_log_index, entries.len())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for value in [1, 2] {
+            let client = format!("c{value}");
+            let request = message(&client, None, Payload::Write { key: 1, value });
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+        // The second write waits for room in the window
+        assert_eq!(to_n2(&sent), [(0, 1), (1, 1), (2, 0)]);
+        assert_eq!(node.next_index["n2"], 3);
+
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 2,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        sent.lock().unwrap().clear();
+        node.handle_heartbeat(&mut sender).unwrap();
+        assert_eq!(to_n2(&sent), [(2, 1)]);
+    }
+
+    #[test]
+    fn test_catch_up_throttling() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.catch_up_batch = 2;
+        node.catch_up_interval = Duration::from_secs(60);
+        for value in 1..=4 {
+            node.log.append(write(1, value, 1));
+        }
+        node.next_index.insert("n2".to_owned(), 5);
+        node.next_index.insert("n3".to_owned(), 1);
+        node.in_flight.clear();
+        let sent_to = |sent: &Sent, peer: &str| {
+            let sent = std::mem::take(&mut *sent.lock().unwrap());
+            sent.iter()
+                .filter(|m| m.dest() == peer)
+                .filter_map(|m| match &m.body().payload {
+                    Payload::AppendEntries {
+                        prev_log_index,
+                        entries,
+                        ..
+                    } => Some((*prev_log_index, entries.len())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        sent.lock().unwrap().clear();
+        node.replicate(&mut sender).unwrap();
+        // n3 misses 5 entries and gets a batch of 2 at a time
+        assert_eq!(sent_to(&sent, "n3"), [(0, 2)]);
+        node.replicate(&mut sender).unwrap();
+        assert_eq!(sent_to(&sent, "n3"), [(2, 0)]);
+
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 2,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n3", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        // Acknowledged, but the next batch waits for the interval
+        sent.lock().unwrap().clear();
+        node.replicate(&mut sender).unwrap();
+        assert_eq!(sent_to(&sent, "n3"), [(2, 0)]);
+
+        node.catch_up_sent.clear();
+        node.replicate(&mut sender).unwrap();
+        assert_eq!(sent_to(&sent, "n3"), [(2, 2)]);
+        // Only the last entry is left, sent without waiting
+        node.in_flight.clear();
+        node.replicate(&mut sender).unwrap();
+        assert_eq!(sent_to(&sent, "n3"), [(4, 1)]);
+    }
+
+    #[test]
+    fn test_reads_wait_for_a_heartbeat_round() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.store.insert(1, 5);
+        sent.lock().unwrap().clear();
+
+        let request = message("c1", None, Payload::Read { key: 1 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let heartbeats = sent.lock().unwrap().clone();
+        assert!(heartbeats.iter().all(|m| m.dest() != "c1"));
+
+        // n2 acknowledging an earlier round confirms nothing
+        let ack = |round| Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 1,
+            round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        let round = node.round;
+        node.handle_message(
+            message("n2", Some(0), ack(round - 1)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert!(sent.lock().unwrap().iter().all(|m| m.dest() != "c1"));
+
+        // Its ack of the round confirms the lead
+        node.handle_message(
+            message("n2", Some(0), ack(round)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let read_ok = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(read_ok.dest(), "c1");
+        assert!(matches!(
+            read_ok.body().payload,
+            Payload::ReadOk { value: 5 }
+        ));
+    }
+
+    #[test]
+    fn test_lease_reads() {
+        let config = Config {
+            lease_reads: true,
+            ..Config::default()
+        };
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.lease_reads = true;
+        node.store.insert(1, 5);
+
+        // Served right away once n2 acknowledged a round since the election
+        node.replicate(&mut sender).unwrap();
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 1,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert!(node.holds_lease());
+        sent.lock().unwrap().clear();
+        let request = message("c1", None, Payload::Read { key: 1 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let sent = sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(matches!(
+            sent[0].body().payload,
+            Payload::ReadOk { value: 5 }
+        ));
+
+        // Followers that just heard from their leader ignore candidates
+        let mut follower = RaftNode::new(init(), &config);
+        follower.leader_contact = Some(Instant::now());
+        let request = serde_json::from_str::<Message<Payload>>(REQUEST_VOTE).unwrap();
+        follower
+            .handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(follower.term, 0);
+        assert_eq!(follower.voted_for, None);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.snapshot_threshold = 2;
+
+        let request = message("c1", None, Payload::Write { key: 1, value: 2 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 2,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.log.snapshot_index(), 2);
+        assert_eq!(node.log.term(1), None);
+
+        // n3 misses entries no longer in the log
+        sent.lock().unwrap().clear();
+        let rejected = Payload::AppendEntriesOk {
+            term: 1,
+            success: false,
+            match_index: 0,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n3", Some(0), rejected),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let install_snapshot = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|m| m.dest() == "n3")
+            .cloned()
+            .unwrap();
+        assert!(matches!(
+            install_snapshot.body().payload,
+            Payload::InstallSnapshot { .. }
+        ));
+
+        let (mut follower_sender, follower_sent) = new_sender();
+        let mut follower = RaftNode::new(init(), &Config::default());
+        follower
+            .handle_message(
+                install_snapshot,
+                &mut NodeContext::new(&mut follower_sender),
+            )
+            .unwrap();
+        assert_eq!(follower.store.get(&1), Some(&2));
+        assert_eq!((follower.commit_index, follower.log.last_index()), (2, 2));
+        assert!(matches!(
+            follower_sent.lock().unwrap()[0].body().payload,
+            Payload::AppendEntriesOk {
+                success: true,
+                match_index: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_conflicting_entries_are_dropped() {
+        let (mut sender, _sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+        for term in [1, 1, 2] {
+            node.log.append(write(1, 1, term));
+        }
+
+        // The leader of term 3 has entries of its own from index 2 on
+        let append_entries = Payload::AppendEntries {
+            term: 3,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: vec![write(1, 7, 3)],
+            leader_commit: 2,
+            round: 1,
+        };
+        node.handle_message(
+            message("n2", None, append_entries),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.log.last_index(), 2);
+        assert_eq!(node.log.last_term(), 3);
+        assert_eq!(node.store.get(&1), Some(&7));
+    }
+
+    #[test]
+    fn test_followers_forward_requests() {
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+        let request = || message("c1", None, Payload::Write { key: 1, value: 2 });
+
+        // No leader to forward to yet
+        assert!(node
+            .handle_message(request(), &mut NodeContext::new(&mut sender))
+            .is_err());
+
+        node.leader = Some("n2".to_owned());
+        node.handle_message(request(), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(node.log.last_index(), 0);
+        let forwarded = sent.lock().unwrap()[0].clone();
+        assert_eq!(forwarded.dest(), "n2");
+        assert!(matches!(
+            &forwarded.body().payload,
+            Payload::Forward {
+                client,
+                client_msg_id: Some(1),
+                request,
+            } if client == "c1" && matches!(**request, Payload::Write { key: 1, value: 2 })
+        ));
+
+        // The leader's reply goes back to the client
+        let reply = message("n2", forwarded.msg_id(), Payload::WriteOk);
+        node.handle_message(reply, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let relayed = sent.lock().unwrap()[1].clone();
+        assert_eq!(relayed.dest(), "c1");
+        assert_eq!(relayed.in_reply_to(), Some(1));
+        assert!(matches!(relayed.body().payload, Payload::WriteOk));
+
+        // Requests other nodes forwarded aren't forwarded again
+        let request = message("n3", None, Payload::Read { key: 1 });
+        assert!(node
+            .handle_message(request, &mut NodeContext::new(&mut sender))
+            .is_err());
+    }
+
+    #[test]
+    fn test_membership_changes() {
+        let (mut sender, sent) = new_sender();
+        let raw = MemoryWritter::new();
+        let replies = raw.messages();
+        sender.set_raw_writter(raw);
+        let mut node = leader(&mut sender);
+        let add = |node_id: &str| {
+            let payload = json!({ "type": "node_added", "node_id": node_id });
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(1), None, payload),
+            )
+        };
+        let ack = |node: &RaftNode| Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: node.log.last_index(),
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+
+        node.handle_unknown(add("n4"), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert!(node.config.joint.is_some());
+        assert!(sent.lock().unwrap().iter().any(|m| m.dest() == "n4"));
+        assert!(!node.base.membership().contains("n4"));
+
+        // One change at a time
+        node.handle_unknown(add("n5"), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(replies.lock().unwrap()[0].body().payload["code"], 11);
+
+        // n2 makes a majority of the old members but not of the new ones
+        let joint_index = node.log.last_index();
+        node.handle_message(
+            message("n2", Some(0), ack(&node)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.commit_index, 0);
+        node.handle_message(
+            message("n4", Some(0), ack(&node)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.commit_index, joint_index);
+        // The leader moved on to the new members alone
+        assert_eq!(node.config.joint, None);
+        assert_eq!(node.config.members.len(), 4);
+        assert!(!node.base.membership().contains("n4"));
+
+        for follower in ["n2", "n4"] {
+            node.handle_message(
+                message(follower, Some(0), ack(&node)),
+                &mut NodeContext::new(&mut sender),
+            )
+            .unwrap();
+        }
+        assert_eq!(node.commit_index, joint_index + 1);
+        assert!(node.base.membership().contains("n4"));
+        let reply = replies.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.dest(), "c1");
+        assert_eq!(reply.body().payload["type"], "node_added_ok");
+    }
+
+    #[test]
+    fn test_learners() {
+        let (mut sender, sent) = new_sender();
+        let raw = MemoryWritter::new();
+        let replies = raw.messages();
+        sender.set_raw_writter(raw);
+        let mut node = leader(&mut sender);
+        let change = |change_type: &str| {
+            let payload = json!({ "type": change_type, "node_id": "n4" });
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(1), None, payload),
+            )
+        };
+        let ack = |node: &RaftNode| Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: node.log.last_index(),
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+
+        node.handle_unknown(change("add_learner"), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert!(sent.lock().unwrap().iter().any(|m| m.dest() == "n4"));
+        // Committed without the learner
+        node.handle_message(
+            message("n2", Some(0), ack(&node)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.commit_index, node.log.last_index());
+        assert_eq!(
+            replies.lock().unwrap()[0].body().payload["type"],
+            "add_learner_ok"
+        );
+        assert!(!node.config.is_voter("n4"));
+
+        node.handle_unknown(
+            change("promote_learner"),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(replies.lock().unwrap()[1].body().payload["code"], 11);
+
+        node.handle_message(
+            message("n4", Some(0), ack(&node)),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        node.handle_unknown(
+            change("promote_learner"),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert!(node.config.joint.is_some());
+        assert!(node.config.learners.is_empty());
+        for _ in 0..2 {
+            for follower in ["n2", "n4"] {
+                node.handle_message(
+                    message(follower, Some(0), ack(&node)),
+                    &mut NodeContext::new(&mut sender),
+                )
+                .unwrap();
+            }
+        }
+        assert_eq!(node.config.joint, None);
+        assert!(node.config.is_voter("n4"));
+        let reply = replies.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.body().payload["type"], "promote_learner_ok");
+
+        // Learners never stand for election
+        let (mut sender, sent) = new_sender();
+        let mut learner = RaftNode::new(init(), &Config::default());
+        learner.config.members.remove("n1");
+        learner.config.learners.insert("n1".to_owned());
+        learner.election_deadline = Instant::now();
+        learner.handle_tick(&mut sender).unwrap();
+        assert_eq!(learner.role, Role::Follower);
+        assert!(sent.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_cas_is_compared_when_applied() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        let requests = [
+            Payload::Write { key: 1, value: 1 },
+            // Before the write is committed
+            Payload::Cas {
+                key: 1,
+                from: 1,
+                to: 2,
+            },
+            Payload::Cas {
+                key: 1,
+                from: 1,
+                to: 3,
+            },
+        ];
+        for (msg_id, request) in (1..).zip(requests) {
+            let request = Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(msg_id), None, request),
+            );
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: node.log.last_index(),
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.store.get(&1), Some(&2));
+        let replies = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.dest() == "c1")
+            .map(|m| m.body().payload.clone())
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            &replies[..],
+            [
+                Payload::WriteOk,
+                Payload::CasOk,
+                Payload::Error {
+                    code: ErrorCode::PreconditionFailed,
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_retries_are_applied_once() {
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+        node.snapshot_threshold = 3;
+        let request = |client: &str, value| Entry {
+            term: 1,
+            operation: Operation::Write { key: 1, value },
+            request: Some((client.to_owned(), 1)),
+        };
+
+        // c1's retry, appended again after c2's write by the next leader
+        let append_entries = Payload::AppendEntries {
+            term: 1,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries: vec![request("c1", 1), request("c2", 2), request("c1", 1)],
+            leader_commit: 3,
+            round: 1,
+        };
+        node.handle_message(
+            message("n2", None, append_entries),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.store.get(&1), Some(&2));
+        // Kept in snapshots
+        assert_eq!(node.log.snapshot_index(), 3);
+        assert_eq!(node.snapshot.sessions.len(), 2);
+
+        // Leaders answer retries of requests applied already right away
+        let mut elected = leader(&mut sender);
+        elected.sessions = node.snapshot.sessions.clone();
+        let last_index = elected.log.last_index();
+        let retry = message("c1", None, Payload::Write { key: 1, value: 1 });
+        elected
+            .handle_message(retry, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(elected.log.last_index(), last_index);
+        let reply = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.dest(), "c1");
+        assert!(matches!(reply.body().payload, Payload::WriteOk));
+
+        // Also when forwarded, by whichever follower, with its own msg_id
+        let forward = |msg_id| {
+            let payload = Payload::Forward {
+                client: "c1".to_owned(),
+                client_msg_id: Some(1),
+                request: Box::new(Payload::Write { key: 1, value: 1 }),
+            };
+            Message::new(
+                "n3".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(msg_id), None, payload),
+            )
+        };
+        elected
+            .handle_message(forward(8), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(elected.log.last_index(), last_index);
+        let reply = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.dest(), "n3");
+        assert_eq!(reply.in_reply_to(), Some(8));
+        assert!(matches!(reply.body().payload, Payload::WriteOk));
+
+        // Clients behind the same follower keep sessions of their own
+        let payload = Payload::Forward {
+            client: "c3".to_owned(),
+            client_msg_id: Some(1),
+            request: Box::new(Payload::Write { key: 1, value: 3 }),
+        };
+        let other = Message::new(
+            "n3".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(9), None, payload),
+        );
+        elected
+            .handle_message(other, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(elected.log.last_index(), last_index + 1);
+        assert_eq!(
+            elected.log.get(last_index + 1).unwrap().request,
+            Some(("c3".to_owned(), 1))
+        );
+    }
+
+    #[test]
+    fn test_committed_entries_must_be_in_the_log() {
+        let (mut sender, _) = new_sender();
+        let mut node = RaftNode::new(init(), &Config::default());
+        node.log.append(write(1, 5, 1));
+        node.commit_index = 2;
+
+        assert!(node.apply_committed(&mut sender).is_err());
+        // What was there is applied, and nothing past it
+        assert_eq!(node.last_applied, 1);
+        assert_eq!(node.store.get(&1), Some(&5));
+    }
+
+    #[test]
+    fn test_raft_state() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+
+        node.handle_message(
+            message("c1", None, Payload::RaftState),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let reply = sent.lock().unwrap().last().cloned().unwrap();
+        assert!(matches!(
+            reply.body().payload,
+            Payload::RaftStateOk {
+                term: 1,
+                role: Role::Leader,
+                last_log_index: 1,
+                commit_index: 0,
+                ref match_index,
+                ..
+            } if match_index.len() == 2
+        ));
+    }
+
+    #[test]
+    fn test_batched_writes() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.write_batch_interval = Duration::from_millis(1);
+        let write = |client: &str, value| {
+            Message::new(
+                client.to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(1), None, Payload::Write { key: value, value }),
+            )
+        };
+
+        for (client, value) in [("c1", 1), ("c2", 2), ("c3", 3)] {
+            node.handle_message(write(client, value), &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+        assert_eq!(node.log.last_index(), 1);
+        node.flush_writes(&mut sender).unwrap();
+        assert_eq!(node.log.last_index(), 2);
+        assert!(matches!(
+            &node.log.get(2).unwrap().operation,
+            Operation::Batch { entries } if entries.len() == 3
+        ));
+
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: 2,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.store, [(1, 1), (2, 2), (3, 3)].into());
+        let acknowledged = |sent: &Sent| {
+            sent.lock()
+                .unwrap()
+                .iter()
+                .filter(|m| matches!(m.body().payload, Payload::WriteOk))
+                .map(|m| m.dest().to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(acknowledged(&sent), ["c1", "c2", "c3"]);
+
+        // A retry is answered without appending anything
+        node.handle_message(write("c2", 2), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        node.flush_writes(&mut sender).unwrap();
+        assert_eq!(node.log.last_index(), 2);
+        assert_eq!(acknowledged(&sent), ["c1", "c2", "c3", "c2"]);
+    }
+
+    #[test]
+    fn test_check_quorum() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        let long_ago = Instant::now() - Duration::from_secs(1);
+        node.handle_heartbeat(&mut sender).unwrap();
+        assert_eq!(node.role, Role::Leader);
+
+        // n2 answering makes a majority
+        node.heard_from.insert("n2".to_owned(), long_ago);
+        node.heard_from.insert("n3".to_owned(), long_ago);
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: false,
+            match_index: 0,
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        node.handle_heartbeat(&mut sender).unwrap();
+        assert_eq!(node.role, Role::Leader);
+
+        node.handle_message(
+            message("c1", None, Payload::Read { key: 1 }),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        node.heard_from.insert("n2".to_owned(), long_ago);
+        node.handle_heartbeat(&mut sender).unwrap();
+        assert_eq!(node.role, Role::Follower);
+        assert_eq!(node.leader, None);
+        // The read waiting for a round fails rather than hanging
+        let reply = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.dest(), "c1");
+        assert!(matches!(
+            reply.body().payload,
+            Payload::Error {
+                code: ErrorCode::TemporarilyUnavailable,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_election_stats() {
+        let (mut sender, _) = new_sender();
+        let mut node = leader(&mut sender);
+        let stats = node.stats();
+        assert_eq!(stats["role"], "leader");
+        assert_eq!(stats["pre_votes_started"], 1);
+        assert_eq!(stats["elections_won"], 1);
+        assert_eq!(stats["leader_changes"], 1);
+        for role in ["follower", "pre_candidate", "candidate", "leader"] {
+            assert!(stats["ms_in_role"][role].is_u64(), "No time in {role}");
+        }
+
+        // Deposed by n2, which it votes for
+        let request_vote = serde_json::from_str(REQUEST_VOTE).unwrap();
+        node.handle_message(request_vote, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let append_entries = Payload::AppendEntries {
+            term: 3,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: Vec::new(),
+            leader_commit: 0,
+            round: 1,
+        };
+        node.handle_message(
+            message("n2", None, append_entries),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let stats = node.stats();
+        assert_eq!(stats["role"], "follower");
+        assert_eq!(stats["leader"], "n2");
+        assert_eq!(stats["elections_started"], 1);
+        assert_eq!(stats["votes_granted"], 1);
+        assert_eq!(stats["leader_changes"], 2);
+    }
+
+    #[test]
+    fn test_quorum_reads() {
+        let config = Config {
+            quorum_reads: true,
+            ..Config::default()
+        };
+        let (mut sender, sent) = new_sender();
+        let mut node = RaftNode::new(init(), &config);
+
+        // The leader n2 acked a write once n3 got it, before n1 heard of it
+        let request = message("c1", None, Payload::Read { key: 1 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let read = match sent.lock().unwrap()[0].body().payload {
+            Payload::ReadReplica { read } => read,
+            _ => panic!("Expected a read_replica"),
+        };
+        assert_eq!(sent.lock().unwrap().len(), 2);
+        let reply = Payload::ReadReplicaOk { read, index: 1 };
+        node.handle_message(
+            message("n3", Some(0), reply),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+
+        // Served once n1 applied as far as n3's log goes
+        let append_entries = Payload::AppendEntries {
+            term: 1,
+            prev_log_index: 0,
+            prev_log_term: 0,
+            entries: vec![write(1, 5, 1)],
+            leader_commit: 1,
+            round: 1,
+        };
+        node.handle_message(
+            message("n2", None, append_entries),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        let read_ok = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|m| m.dest() == "c1")
+            .cloned()
+            .unwrap();
+        assert!(matches!(
+            read_ok.body().payload,
+            Payload::ReadOk { value: 5 }
+        ));
+        assert!(node.waiting_quorum_reads.is_empty());
+
+        // A read no quorum answers in time fails rather than hanging
+        let request = message("c2", None, Payload::Read { key: 1 });
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        for quorum_read in node.waiting_quorum_reads.values_mut() {
+            quorum_read.started -= FORWARD_TIMEOUT;
+        }
+        node.handle_heartbeat(&mut sender).unwrap();
+        let reply = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(reply.dest(), "c2");
+        assert!(matches!(
+            reply.body().payload,
+            Payload::Error {
+                code: ErrorCode::TemporarilyUnavailable,
+                ..
+            }
+        ));
+        assert!(node.waiting_quorum_reads.is_empty());
+    }
+
+    #[test]
+    fn test_fast_backtracking() {
+        let (mut sender, sent) = new_sender();
+        let mut follower = RaftNode::new(init(), &Config::default());
+        for term in [1, 1, 2, 2, 2] {
+            follower.log.append(write(1, 1, term));
+        }
+        let append_entries = |prev_log_index| Payload::AppendEntries {
+            term: 3,
+            prev_log_index,
+            prev_log_term: 3,
+            entries: Vec::new(),
+            leader_commit: 0,
+            round: 1,
+        };
+        for prev_log_index in [5, 8] {
+            follower
+                .handle_message(
+                    message("n2", None, append_entries(prev_log_index)),
+                    &mut NodeContext::new(&mut sender),
+                )
+                .unwrap();
+        }
+        let conflicts = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| match m.body().payload {
+                Payload::AppendEntriesOk {
+                    conflict_index,
+                    conflict_term,
+                    ..
+                } => (conflict_index, conflict_term),
+                _ => panic!("Expected an append_entries_ok"),
+            })
+            .collect::<Vec<_>>();
+        // The first entry of term 2, then right past its log
+        assert_eq!(conflicts, [(Some(3), Some(2)), (Some(6), None)]);
+
+        // The leader skips to past its own entries of the term, if it has
+        // any, else to the follower's first entry of it
+        let mut node = leader(&mut sender);
+        for _ in 0..3 {
+            node.log.append(write(1, 1, 1));
+        }
+        for (conflict_term, prev_log_index) in [(1, 4), (7, 1)] {
+            sent.lock().unwrap().clear();
+            let rejected = Payload::AppendEntriesOk {
+                term: 1,
+                success: false,
+                match_index: 0,
+                round: 0,
+                conflict_index: Some(2),
+                conflict_term: Some(conflict_term),
+            };
+            node.handle_message(
+                message("n3", Some(0), rejected),
+                &mut NodeContext::new(&mut sender),
+            )
+            .unwrap();
+            assert!(matches!(
+                sent.lock().unwrap()[0].body().payload,
+                Payload::AppendEntries { prev_log_index: prev, .. } if prev == prev_log_index
+            ));
+        }
+    }
+
+    #[test]
+    fn test_txns() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        let requests = [
+            r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":1,"key":1,"value":1}}"#,
+            r#"{"src":"c2","dest":"n1","body":{"type":"txn","msg_id":1,"txn":[["r",1,null],["cas",1,[1,2]],["w",2,3],["r",2,null]]}}"#,
+            // Fails as a whole, 3 isn't written
+            r#"{"src":"c3","dest":"n1","body":{"type":"txn","msg_id":1,"txn":[["w",3,1],["cas",1,[1,5]]]}}"#,
+        ];
+        for request in requests {
+            let request = serde_json::from_str::<Message<Payload>>(request).unwrap();
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let ack = Payload::AppendEntriesOk {
+            term: 1,
+            success: true,
+            match_index: node.log.last_index(),
+            round: node.round,
+            conflict_index: None,
+            conflict_term: None,
+        };
+        node.handle_message(
+            message("n2", Some(0), ack),
+            &mut NodeContext::new(&mut sender),
+        )
+        .unwrap();
+        assert_eq!(node.store, [(1, 2), (2, 3)].into());
+
+        let replies = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.dest().starts_with('c'))
+            .map(|m| serde_json::to_value(&m.body().payload).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies[1],
+            json!({ "type": "txn_ok", "txn": [["r", 1, 1], ["cas", 1, [1, 2]], ["w", 2, 3], ["r", 2, 3]] })
+        );
+        assert_eq!(replies[2]["code"], 22);
+    }
+
+    #[test]
+    fn test_lin_kv_conformance() {
+        let (mut sender, sent) = new_sender();
+        let mut node = leader(&mut sender);
+        node.strict_lin_kv = true;
+        // Each request, acknowledged by n2 right after, and the reply lin-kv
+        // expects to it
+        let matrix = [
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":1,"key":1}}"#,
+                json!({ "type": "error", "code": 20, "text": "Key 1 does not exist" }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":2,"key":1,"from":1,"to":2}}"#,
+                json!({ "type": "error", "code": 20, "text": "Key 1 does not exist" }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"write","msg_id":3,"key":1,"value":1}}"#,
+                json!({ "type": "write_ok" }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":4,"key":1,"from":2,"to":3}}"#,
+                json!({ "type": "error", "code": 22, "text": "Expected 2 but found 1" }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"cas","msg_id":5,"key":1,"from":1,"to":3}}"#,
+                json!({ "type": "cas_ok" }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"read","msg_id":6,"key":1}}"#,
+                json!({ "type": "read_ok", "value": 3 }),
+            ),
+            (
+                r#"{"src":"c1","dest":"n1","body":{"type":"txn","msg_id":7,"txn":[["r",1,null]]}}"#,
+                json!({ "type": "error", "code": 10, "text": "txn isn't part of lin-kv" }),
+            ),
+        ];
+
+        for (request, expected) in matrix {
+            let request = serde_json::from_str::<Message<Payload>>(request).unwrap();
+            // Refused right away, answered as the main loop would
+            if let Err(error) =
+                node.handle_message(request.clone(), &mut NodeContext::new(&mut sender))
+            {
+                sender.send(request.error_reply(&error).unwrap()).unwrap();
+            }
+            let ack = Payload::AppendEntriesOk {
+                term: 1,
+                success: true,
+                match_index: node.log.last_index(),
+                round: node.round,
+                conflict_index: None,
+                conflict_term: None,
+            };
+            node.handle_message(
+                message("n2", Some(0), ack),
+                &mut NodeContext::new(&mut sender),
+            )
+            .unwrap();
+
+            let reply = sent
+                .lock()
+                .unwrap()
+                .iter()
+                .rev()
+                .find(|m| m.dest() == "c1")
+                .cloned()
+                .unwrap();
+            assert_reply_to(&request, &reply);
+            assert_eq!(
+                serde_json::to_value(&reply.body().payload).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(
+            sent.lock()
+                .unwrap()
+                .iter()
+                .filter(|m| m.dest() == "c1")
+                .count(),
+            7
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// The nodes whose votes count, in elections and to commit entries. While
+/// moving to new members, both the old and the new ones must agree, so
+/// neither can decide on its own, see §6 of the Raft paper. Learners are sent
+/// the log too, but neither vote nor count towards a quorum until promoted.
+/// Witnesses are members that vote but are sent only the terms of entries,
+/// and never lead.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct Configuration {
+    pub(super) members: BTreeSet<String>,
+    /// The members being moved to, while joint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) joint: Option<BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(super) learners: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(super) witnesses: BTreeSet<String>,
+}
+
+impl Configuration {
+    pub(super) fn new<I: IntoIterator<Item = String>>(members: I) -> Self {
+        Self {
+            members: members.into_iter().collect(),
+            joint: None,
+            learners: BTreeSet::new(),
+            witnesses: BTreeSet::new(),
+        }
+    }
+
+    /// The joint configuration moving from these members to `members`,
+    /// which the learners among them stop being.
+    pub(super) fn moving_to(&self, members: BTreeSet<String>) -> Self {
+        Self {
+            members: self.members.clone(),
+            learners: self.learners.difference(&members).cloned().collect(),
+            witnesses: self.witnesses.clone(),
+            joint: Some(members),
+        }
+    }
+
+    /// The configuration of the new members alone, once joint.
+    pub(super) fn leaving_joint(&self) -> Option<Self> {
+        let members = self.joint.clone()?;
+
+        Some(Self {
+            witnesses: self.witnesses.intersection(&members).cloned().collect(),
+            members,
+            joint: None,
+            learners: self.learners.clone(),
+        })
+    }
+
+    /// Whether `node_id` votes, as an old or new member.
+    pub(super) fn is_voter(&self, node_id: &str) -> bool {
+        self.members.contains(node_id) || self.joint.iter().flatten().any(|m| m == node_id)
+    }
+
+    /// Whether `node_id` may stand for election: it votes and isn't a
+    /// witness.
+    pub(super) fn can_lead(&self, node_id: &str) -> bool {
+        self.is_voter(node_id) && !self.witnesses.contains(node_id)
+    }
+
+    /// Every member and learner but `node_id`, old and new ones alike,
+    /// sorted.
+    pub(super) fn peers(&self, node_id: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .chain(self.joint.iter().flatten())
+            .chain(&self.learners)
+            .filter(|member| *member != node_id)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the members `agree` are a majority of the members, and of
+    /// the new ones too while joint.
+    pub(super) fn is_quorum<F: Fn(&str) -> bool>(&self, agree: F) -> bool {
+        let is_majority = |members: &BTreeSet<String>| {
+            members.iter().filter(|member| agree(member)).count() > members.len() / 2
+        };
+
+        is_majority(&self.members) && self.joint.as_ref().is_none_or(is_majority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Configuration;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_joint_quorum() {
+        let members = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<BTreeSet<_>>();
+        let config = Configuration::new(members(&["n1", "n2", "n3"]));
+        assert!(config.is_quorum(|id| id != "n3"));
+        assert!(!config.is_quorum(|id| id == "n1"));
+
+        let joint = config.moving_to(members(&["n1", "n4", "n5"]));
+        assert_eq!(joint.peers("n1"), ["n2", "n3", "n4", "n5"]);
+        // A majority of the old members alone isn't enough
+        assert!(!joint.is_quorum(|id| ["n1", "n2"].contains(&id)));
+        assert!(!joint.is_quorum(|id| ["n1", "n4", "n5"].contains(&id)));
+        assert!(joint.is_quorum(|id| ["n1", "n2", "n4"].contains(&id)));
+    }
+
+    #[test]
+    fn test_learners() {
+        let members = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<BTreeSet<_>>();
+        let mut config = Configuration::new(members(&["n1", "n2", "n3"]));
+        config.learners = members(&["n4", "n5"]);
+        assert_eq!(config.peers("n1"), ["n2", "n3", "n4", "n5"]);
+        assert!(!config.is_voter("n4"));
+        // Learners acknowledging don't make up for a member
+        assert!(!config.is_quorum(|id| ["n1", "n4", "n5"].contains(&id)));
+
+        let joint = config.moving_to(members(&["n1", "n2", "n3", "n4"]));
+        assert!(joint.is_voter("n4"));
+        assert_eq!(joint.learners, members(&["n5"]));
+        let promoted = joint.leaving_joint().unwrap();
+        assert_eq!(promoted.members.len(), 4);
+        assert_eq!(promoted.learners, members(&["n5"]));
+        assert_eq!(promoted.leaving_joint(), None);
+    }
+
+    #[test]
+    fn test_witnesses() {
+        let members = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<BTreeSet<_>>();
+        let mut config = Configuration::new(members(&["n1", "n2", "n3"]));
+        config.witnesses = members(&["n3"]);
+        assert!(config.is_voter("n3"));
+        assert!(!config.can_lead("n3"));
+        assert!(config.can_lead("n1"));
+        assert!(config.is_quorum(|id| ["n1", "n3"].contains(&id)));
+
+        // A witness removed is no longer one
+        let joint = config.moving_to(members(&["n1", "n2", "n4"]));
+        assert!(!joint.can_lead("n3"));
+        assert!(joint.leaving_joint().unwrap().witnesses.is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::{ErrorCode, MaelstromError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::{configuration::Configuration, Applied, Key, Term, TxnOperation};
+
+/// What an entry of the log does to the store once applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub(super) enum Operation {
+    Write {
+        key: Key,
+        value: usize,
+    },
+    /// Writes `to` if the key holds `from` once applied, which every node
+    /// finds alike as they apply the same entries in the same order.
+    Cas {
+        key: Key,
+        from: usize,
+        to: usize,
+    },
+    /// Applied all at once or not at all, as of the entry.
+    Txn {
+        txn: Vec<TxnOperation>,
+    },
+    /// Requests of several clients appended as one entry, each applied as
+    /// its own entry would be.
+    Batch {
+        entries: Vec<Entry>,
+    },
+    /// Appended by leaders when elected, see §8 of the Raft paper.
+    Noop,
+    /// Takes effect as soon as it's appended, committed or not.
+    Configuration(Configuration),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct Entry {
+    /// Term of the leader that appended it.
+    pub(super) term: Term,
+    pub(super) operation: Operation,
+    /// Sender and `msg_id` of the request it was appended for, for a retry
+    /// of the request not to be applied twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) request: Option<(String, usize)>,
+}
+
+impl Entry {
+    /// The entry as sent to witnesses: its term alone, as a no-op, but for
+    /// configurations which they need as much as any member.
+    pub(super) fn for_witness(&self) -> Self {
+        match self.operation {
+            Operation::Configuration(_) => self.clone(),
+            _ => Self {
+                term: self.term,
+                operation: Operation::Noop,
+                request: None,
+            },
+        }
+    }
+}
+
+/// The last request of a client applied to the store, and what it ended in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(super) struct Session {
+    pub(super) msg_id: usize,
+    /// Code and text of the error the request failed with, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) error: Option<(ErrorCode, String)>,
+    /// The transaction completed with the values read, for `txn`s.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(super) txn: Option<Vec<TxnOperation>>,
+}
+
+impl Session {
+    pub(super) fn new(msg_id: usize, applied: &Applied) -> Self {
+        Self {
+            msg_id,
+            error: applied
+                .as_ref()
+                .err()
+                .map(|error| (error.code, error.text.clone())),
+            txn: applied.as_ref().ok().cloned().flatten(),
+        }
+    }
+
+    /// What applying the request ended in, again.
+    pub(super) fn result(&self) -> Applied {
+        match &self.error {
+            Some((code, text)) => Err(MaelstromError::new(*code, text.clone())),
+            None => Ok(self.txn.clone()),
+        }
+    }
+}
+
+/// The store as of the entry at `index`, which replaces every entry up to
+/// it in the log.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(super) struct Snapshot {
+    pub(super) index: usize,
+    pub(super) term: Term,
+    /// The pairs of key and value of the store. A list rather than a map,
+    /// as JSON maps take string keys alone.
+    pub(super) store: Vec<(Key, usize)>,
+    /// The configuration as of the entry at `index`.
+    pub(super) config: Configuration,
+    /// The last request of each client applied, by client.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(super) sessions: BTreeMap<String, Session>,
+}
+
+impl Snapshot {
+    /// The snapshot as sent to witnesses, without the store and sessions.
+    pub(super) fn for_witness(&self) -> Self {
+        Self {
+            store: Vec::new(),
+            sessions: BTreeMap::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The replicated log. Indexes start at 1, 0 standing for the empty prefix
+/// every log shares. Entries up to the last snapshot are dropped, see
+/// [`Log::compact`].
+#[derive(Debug, Default)]
+pub(super) struct Log {
+    entries: Vec<Entry>,
+    /// Index and term of the last entry of the last snapshot.
+    snapshot_index: usize,
+    snapshot_term: Term,
+    /// Lowest index appended or truncated since [`Log::take_unsaved`].
+    unsaved: Option<usize>,
+}
+
+impl Log {
+    pub(super) fn last_index(&self) -> usize {
+        self.snapshot_index + self.entries.len()
+    }
+
+    pub(super) fn snapshot_index(&self) -> usize {
+        self.snapshot_index
+    }
+
+    pub(super) fn last_term(&self) -> Term {
+        self.term(self.last_index()).unwrap_or_default()
+    }
+
+    /// Term of the entry at `index`, `None` past the end of the log and
+    /// before the last snapshot.
+    pub(super) fn term(&self, index: usize) -> Option<Term> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+
+        self.get(index).map(|entry| entry.term)
+    }
+
+    pub(super) fn get(&self, index: usize) -> Option<&Entry> {
+        let offset = index.checked_sub(self.snapshot_index + 1)?;
+        self.entries.get(offset)
+    }
+
+    /// First index of the entries of the same term as the one at `index`
+    /// right before it, after the last snapshot.
+    pub(super) fn first_index_of_term(&self, index: usize) -> usize {
+        let term = self.term(index);
+        let mut first = index;
+        while first > self.snapshot_index + 1 && self.term(first - 1) == term {
+            first -= 1;
+        }
+
+        first
+    }
+
+    /// Index of the last entry of `term`, if the log has any.
+    pub(super) fn last_index_of_term(&self, term: Term) -> Option<usize> {
+        (self.snapshot_index + 1..=self.last_index())
+            .rev()
+            .find(|index| self.term(*index) == Some(term))
+    }
+
+    /// Entries from `index` on, those after the last snapshot.
+    pub(super) fn entries_from(&self, index: usize) -> &[Entry] {
+        let offset = index.saturating_sub(self.snapshot_index + 1);
+        &self.entries[offset.min(self.entries.len())..]
+    }
+
+    /// Appends `entry`, returning its index.
+    pub(super) fn append(&mut self, entry: Entry) -> usize {
+        self.entries.push(entry);
+        self.changed(self.last_index());
+        self.last_index()
+    }
+
+    /// Drops the entry at `index` and every one after it.
+    pub(super) fn truncate(&mut self, index: usize) {
+        self.entries
+            .truncate(index.saturating_sub(self.snapshot_index + 1));
+        self.changed(index);
+    }
+
+    fn changed(&mut self, index: usize) {
+        self.unsaved = Some(self.unsaved.map_or(index, |unsaved| unsaved.min(index)));
+    }
+
+    /// The index entries were changed from since last time, and the
+    /// entries from there on, to save them.
+    pub(super) fn take_unsaved(&mut self) -> Option<(usize, &[Entry])> {
+        let index = self.unsaved.take()?.max(self.snapshot_index + 1);
+
+        Some((index, self.entries_from(index)))
+    }
+
+    /// Drops the entries up to `index`, which a snapshot replaces, keeping
+    /// the ones after it.
+    pub(super) fn compact(&mut self, index: usize) {
+        let Some(term) = self.term(index) else {
+            return;
+        };
+
+        self.entries.drain(..index - self.snapshot_index);
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+    }
+
+    /// Drops every entry for the log to start right after the entry at
+    /// `index`, of `term`, that a snapshot installed from the leader ends
+    /// with.
+    pub(super) fn reset(&mut self, index: usize, term: Term) {
+        self.entries.clear();
+        self.snapshot_index = index;
+        self.snapshot_term = term;
+        self.changed(index + 1);
+    }
+
+    /// Whether a log ending in `last_term` at `last_index` is at least as
+    /// up to date as this one, for its candidate to be voted for.
+    pub(super) fn is_up_to_date(&self, last_index: usize, last_term: Term) -> bool {
+        (last_term, last_index) >= (self.last_term(), self.last_index())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Log, Operation};
+
+    #[test]
+    fn test_log() {
+        let entry = |term| Entry {
+            term,
+            operation: Operation::Write { key: 1, value: 2 },
+            request: None,
+        };
+        let mut log = Log::default();
+        assert_eq!((log.last_index(), log.last_term()), (0, 0));
+        assert_eq!(log.term(1), None);
+
+        for term in [1, 1, 2] {
+            log.append(entry(term));
+        }
+        assert_eq!((log.last_index(), log.last_term()), (3, 2));
+        assert_eq!(log.term(0), Some(0));
+        assert_eq!(log.entries_from(2).len(), 2);
+        assert!(log.entries_from(4).is_empty());
+
+        assert!(log.is_up_to_date(3, 2));
+        assert!(log.is_up_to_date(1, 3));
+        assert!(!log.is_up_to_date(4, 1));
+
+        assert_eq!(log.first_index_of_term(2), 1);
+        assert_eq!(log.first_index_of_term(3), 3);
+        assert_eq!(log.last_index_of_term(1), Some(2));
+        assert_eq!(log.last_index_of_term(3), None);
+
+        log.truncate(2);
+        assert_eq!((log.last_index(), log.last_term()), (1, 1));
+    }
+
+    #[test]
+    fn test_compaction() {
+        let entry = |term| Entry {
+            term,
+            operation: Operation::Noop,
+            request: None,
+        };
+        let mut log = Log::default();
+        for term in [1, 1, 2, 2] {
+            log.append(entry(term));
+        }
+
+        log.compact(3);
+        assert_eq!((log.last_index(), log.last_term()), (4, 2));
+        assert_eq!(log.term(3), Some(2));
+        assert_eq!(log.term(2), None);
+        assert_eq!(log.entries_from(1).len(), 1);
+        assert_eq!(log.get(4), Some(&entry(2)));
+        assert_eq!(log.append(entry(3)), 5);
+
+        log.truncate(5);
+        assert_eq!(log.last_index(), 4);
+
+        log.reset(10, 3);
+        assert_eq!((log.last_index(), log.last_term()), (10, 3));
+        assert!(log.entries_from(11).is_empty());
+    }
+}
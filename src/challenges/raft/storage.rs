@@ -0,0 +1,376 @@
+use super::{
+    log::{Entry, Log, Snapshot},
+    Term,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Bytes before every record: the length of the record and its CRC-32, both
+/// little endian `u32`s.
+const HEADER_LEN: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    State {
+        term: Term,
+        voted_for: Option<String>,
+    },
+    /// Replaces the log up to the snapshot, as [`Log::compact`] or
+    /// [`Log::reset`] do.
+    Snapshot { snapshot: Snapshot },
+    /// Replaces the log from `index` on.
+    Append { index: usize, entries: Vec<Entry> },
+}
+
+/// What a previous run of the node saved.
+#[derive(Debug, Default)]
+pub(super) struct Recovered {
+    pub(super) term: Term,
+    pub(super) voted_for: Option<String>,
+    pub(super) snapshot: Snapshot,
+    pub(super) log: Log,
+}
+
+/// The term, vote and log of a node, one JSON record per change appended
+/// after its length and checksum, synced to disk before the node answers
+/// anyone so a restarted node never votes twice in a term nor forgets
+/// entries it acknowledged.
+#[derive(Debug)]
+pub(super) struct Storage {
+    path: PathBuf,
+    file: File,
+    /// What was last saved, to save only what changed.
+    term: Term,
+    voted_for: Option<String>,
+    snapshot_index: usize,
+}
+
+impl Storage {
+    /// Opens the file at `path`, recovering what the previous run saved.
+    /// The first record cut short by a crash or whose checksum doesn't
+    /// match, and every one after it, are cut off the file, which new
+    /// records are then appended to.
+    pub(super) fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<(Self, Recovered)> {
+        let path = path.as_ref().to_owned();
+        let Some((mut recovered, len)) = recover(&path)? else {
+            let mut recovered = Recovered::default();
+            let storage = Self::create(&path, &mut recovered)?;
+
+            return Ok((storage, recovered));
+        };
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Error opening {}", path.display()))?;
+        file.set_len(len)
+            .with_context(|| format!("Error truncating {}", path.display()))?;
+        recovered.log.take_unsaved();
+        let mut storage = Self {
+            path,
+            file,
+            term: recovered.term,
+            voted_for: recovered.voted_for.clone(),
+            snapshot_index: recovered.snapshot.index,
+        };
+        storage.sync()?;
+
+        Ok((storage, recovered))
+    }
+
+    /// Storage at [`super::RAFT_FILE`], if set.
+    pub(super) fn from_env(node_id: &str) -> anyhow::Result<Option<(Self, Recovered)>> {
+        match std::env::var(super::RAFT_FILE) {
+            Ok(path) => Self::open(path.replace("{node_id}", node_id)).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Writes `recovered` to a new file, which then replaces the one at
+    /// `path`.
+    fn create(path: &Path, recovered: &mut Recovered) -> anyhow::Result<Self> {
+        let mut tmp = path.to_owned().into_os_string();
+        tmp.push(".tmp");
+
+        let mut storage = Self {
+            path: path.to_owned(),
+            file: File::create(&tmp)
+                .with_context(|| format!("Error creating {}", path.display()))?,
+            term: recovered.term,
+            voted_for: recovered.voted_for.clone(),
+            snapshot_index: recovered.snapshot.index,
+        };
+        storage.append(&Record::State {
+            term: recovered.term,
+            voted_for: recovered.voted_for.clone(),
+        })?;
+        storage.append(&Record::Snapshot {
+            snapshot: recovered.snapshot.clone(),
+        })?;
+        let index = recovered.log.snapshot_index() + 1;
+        storage.append(&Record::Append {
+            index,
+            entries: recovered.log.entries_from(index).to_vec(),
+        })?;
+        recovered.log.take_unsaved();
+        storage.sync()?;
+
+        std::fs::rename(&tmp, path).with_context(|| format!("Error writing {}", path.display()))?;
+        // The rename itself only survives a crash once the directory is synced
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Error syncing {}", dir.display()))?;
+
+        Ok(storage)
+    }
+
+    /// Saves what changed since last time and syncs it to disk. A new
+    /// snapshot rewrites the file instead, so it doesn't grow for good.
+    pub(super) fn save(
+        &mut self,
+        term: Term,
+        voted_for: &Option<String>,
+        snapshot: &Snapshot,
+        log: &mut Log,
+    ) -> anyhow::Result<()> {
+        if snapshot.index != self.snapshot_index {
+            let mut recovered = Recovered {
+                term,
+                voted_for: voted_for.clone(),
+                snapshot: snapshot.clone(),
+                log: std::mem::take(log),
+            };
+            let storage = Self::create(&self.path, &mut recovered);
+            *log = recovered.log;
+            *self = storage?;
+            return Ok(());
+        }
+
+        let mut changed = false;
+        if term != self.term || *voted_for != self.voted_for {
+            self.append(&Record::State {
+                term,
+                voted_for: voted_for.clone(),
+            })?;
+            self.term = term;
+            self.voted_for = voted_for.clone();
+            changed = true;
+        }
+        if let Some((index, entries)) = log.take_unsaved() {
+            self.append(&Record::Append {
+                index,
+                entries: entries.to_vec(),
+            })?;
+            changed = true;
+        }
+
+        if changed {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> anyhow::Result<()> {
+        let record = serde_json::to_vec(record).context("Error serializing raft record")?;
+        let len = u32::try_from(record.len()).context("Raft record too long")?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + record.len());
+        bytes.extend(len.to_le_bytes());
+        bytes.extend(checksum(&record).to_le_bytes());
+        bytes.extend(record);
+
+        self.file
+            .write_all(&bytes)
+            .with_context(|| format!("Error writing {}", self.path.display()))
+    }
+
+    fn sync(&mut self) -> anyhow::Result<()> {
+        self.file
+            .sync_data()
+            .with_context(|| format!("Error syncing {}", self.path.display()))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(bytes);
+
+    crc.sum()
+}
+
+/// The record `bytes` start with and the bytes it takes, `None` if it was
+/// cut short or corrupted.
+fn read_record(bytes: &[u8]) -> Option<(Record, usize)> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().ok()?) as usize;
+    let sum = u32::from_le_bytes(header[4..].try_into().ok()?);
+    let record = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if checksum(record) != sum {
+        return None;
+    }
+
+    let record = serde_json::from_slice(record).ok()?;
+    Some((record, HEADER_LEN + len))
+}
+
+/// What the file at `path` holds and the length of its valid records,
+/// `None` if there's no file.
+fn recover(path: &Path) -> anyhow::Result<Option<(Recovered, u64)>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Error reading {}", path.display())),
+    };
+
+    let mut recovered = Recovered::default();
+    let mut offset = 0;
+    while let Some((record, len)) = read_record(&bytes[offset..]) {
+        offset += len;
+
+        match record {
+            Record::State { term, voted_for } => {
+                recovered.term = term;
+                recovered.voted_for = voted_for;
+            }
+            Record::Snapshot { snapshot } => {
+                if recovered.log.term(snapshot.index) == Some(snapshot.term) {
+                    recovered.log.compact(snapshot.index);
+                } else {
+                    recovered.log.reset(snapshot.index, snapshot.term);
+                }
+                recovered.snapshot = snapshot;
+            }
+            Record::Append { index, entries } => {
+                recovered.log.truncate(index);
+                for entry in entries {
+                    recovered.log.append(entry);
+                }
+            }
+        }
+    }
+    if offset < bytes.len() {
+        tracing::warn!(
+            offset,
+            dropped = bytes.len() - offset,
+            "Dropping the records of {} from a corrupted one on",
+            path.display()
+        );
+    }
+
+    Ok(Some((recovered, offset as u64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Storage;
+    use crate::challenges::raft::log::{Entry, Operation, Snapshot};
+    use std::io::Write;
+
+    #[test]
+    fn test_storage() {
+        let path = std::env::temp_dir().join(format!("raft-{}.log", std::process::id()));
+        let entry = |term| Entry {
+            term,
+            operation: Operation::Noop,
+            request: None,
+        };
+
+        let (mut storage, mut recovered) = Storage::open(&path).unwrap();
+        assert_eq!(recovered.term, 0);
+        for term in [1, 1, 2] {
+            recovered.log.append(entry(term));
+        }
+        let voted_for = Some("n2".to_owned());
+        storage
+            .save(2, &voted_for, &recovered.snapshot, &mut recovered.log)
+            .unwrap();
+        recovered.log.truncate(3);
+        recovered.log.append(entry(3));
+        storage
+            .save(3, &None, &recovered.snapshot, &mut recovered.log)
+            .unwrap();
+        drop(storage);
+
+        // Crashed halfway through a record
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, b'{']).unwrap();
+
+        let (mut storage, mut recovered) = Storage::open(&path).unwrap();
+        assert_eq!((recovered.term, recovered.voted_for.as_deref()), (3, None));
+        assert_eq!(recovered.log.last_index(), 3);
+        assert_eq!(recovered.log.last_term(), 3);
+
+        let snapshot = Snapshot {
+            index: 2,
+            term: 1,
+            store: vec![(1, 2)],
+            ..Snapshot::default()
+        };
+        recovered.log.compact(2);
+        storage
+            .save(3, &None, &snapshot, &mut recovered.log)
+            .unwrap();
+        drop(storage);
+
+        let (_, recovered) = Storage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recovered.snapshot, snapshot);
+        assert_eq!(recovered.log.snapshot_index(), 2);
+        assert_eq!(recovered.log.last_index(), 3);
+    }
+
+    #[test]
+    fn test_corrupted_records_are_dropped() {
+        let path = std::env::temp_dir().join(format!("raft-corrupted-{}.log", std::process::id()));
+        let entry = |term| Entry {
+            term,
+            operation: Operation::Noop,
+            request: None,
+        };
+
+        let (mut storage, mut recovered) = Storage::open(&path).unwrap();
+        for term in [1, 2] {
+            recovered.log.append(entry(term));
+            storage
+                .save(term, &None, &recovered.snapshot, &mut recovered.log)
+                .unwrap();
+        }
+        drop(storage);
+
+        // A bit flipped in the last record
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 2;
+        bytes[last] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+
+        let (mut storage, mut recovered) = Storage::open(&path).unwrap();
+        assert_eq!(recovered.log.last_index(), 1);
+        recovered.log.append(entry(3));
+        storage
+            .save(3, &None, &recovered.snapshot, &mut recovered.log)
+            .unwrap();
+        drop(storage);
+
+        // What's appended after the records cut off is recovered
+        let (_, recovered) = Storage::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recovered.term, 3);
+        assert_eq!(
+            (recovered.log.last_index(), recovered.log.last_term()),
+            (2, 3)
+        );
+    }
+}
@@ -0,0 +1,679 @@
+use crate::{
+    config::Config,
+    conflict::{resolver_from_env, ConflictResolver, Versioned},
+    main_loop_with_config,
+    membership::Membership,
+    middleware::{Dedup, Middleware},
+    outbox::Outbox,
+    persistence::Persistent,
+    scheduler::{Scheduler, TimerId},
+    session::{Session, Windows},
+    ErrorCode, Event, Init, MaelstromError, Message, MessageSender, Node, NodeBase, NodeContext,
+};
+use serde::{
+    self,
+    de::{Error, SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+type KeyId = usize;
+
+const DEDUP_CAPACITY: usize = 10_000;
+/// Replicated transactions in flight per peer. Below the session's
+/// [`crate::session::WINDOW_SIZE`] so the retry of a lost transaction isn't
+/// rejected as too old by its peer.
+const REPLICATION_WINDOW: usize = 32;
+const REPLICATION_RETRY_TIMEOUT: Duration = Duration::from_millis(300);
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Txn {
+        txn: Vec<Operation>,
+    },
+    TxnOk {
+        txn: Vec<Operation>,
+    },
+    InternalTxn {
+        txn: Vec<Operation>,
+        timestamp: u64,
+        sequence: u64,
+    },
+    InternalTxnOk,
+}
+
+/// A step of a transaction, `["r", key, value]` or `["w", key, value]`,
+/// or `["cas", key, [from, to]]` for nodes that can compare, e.g. the
+/// `lin-kv` one's `txn`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Operation {
+    Read { key: KeyId, value: Option<usize> },
+    Write { key: KeyId, value: usize },
+    Cas { key: KeyId, from: usize, to: usize },
+}
+
+impl<'de> Deserialize<'de> for Operation {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(OperationVisitor)
+    }
+}
+
+impl Serialize for Operation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(3))?;
+        match self {
+            Operation::Read { key, value } => {
+                seq.serialize_element("r")?;
+                seq.serialize_element(key)?;
+                seq.serialize_element(value)?;
+            }
+            Operation::Write { key, value } => {
+                seq.serialize_element("w")?;
+                seq.serialize_element(key)?;
+                seq.serialize_element(value)?;
+            }
+            Operation::Cas { key, from, to } => {
+                seq.serialize_element("cas")?;
+                seq.serialize_element(key)?;
+                seq.serialize_element(&(from, to))?;
+            }
+        }
+        seq.end()
+    }
+}
+
+struct OperationVisitor;
+
+impl<'de> Visitor<'de> for OperationVisitor {
+    type Value = Operation;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "Invalid operation format. Expected [\"r\" or \"w\", key, value] or \
+             [\"cas\", key, [from, to]]"
+        )
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let op_type: String = seq
+            .next_element()?
+            .ok_or_else(|| Error::custom("missing operation type"))?;
+        let key: KeyId = seq
+            .next_element()?
+            .ok_or_else(|| Error::custom("missing key"))?;
+
+        match op_type.as_str() {
+            "r" => {
+                let value = seq.next_element::<usize>().unwrap_or_default();
+                Ok(Operation::Read { key, value })
+            }
+            "w" => {
+                let value: usize = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::custom("missing value"))?;
+                Ok(Operation::Write { key, value })
+            }
+            "cas" => {
+                let (from, to): (usize, usize) = seq
+                    .next_element()?
+                    .ok_or_else(|| Error::custom("missing values"))?;
+                Ok(Operation::Cas { key, from, to })
+            }
+            _ => Err(Error::unknown_variant(&op_type, &["r", "w", "cas"])),
+        }
+    }
+}
+
+/// What survives a restart. The replay windows of the session are saved
+/// with the store they protect, the session keeps its sequence itself.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    clock: u64,
+    log_store: HashMap<KeyId, Versioned<usize>>,
+    windows: Windows,
+}
+
+struct TotallyAvailableTransactionsNode {
+    base: NodeBase,
+    clock: u64,
+    log_store: Arc<Mutex<HashMap<KeyId, Versioned<usize>>>>,
+    resolver: Box<dyn ConflictResolver<usize> + Send>,
+    session: Session,
+    outbox: Outbox<Payload>,
+    /// Ticks the retries of the replication outbox, set at init.
+    retry_timer: Option<TimerId>,
+}
+
+impl TotallyAvailableTransactionsNode {
+    fn new(
+        init: Init,
+        resolver: Box<dyn ConflictResolver<usize> + Send>,
+        session: Session,
+    ) -> Self {
+        Self {
+            base: NodeBase::new(&init),
+            clock: 0,
+            log_store: Arc::new(Mutex::new(HashMap::new())),
+            resolver,
+            session,
+            outbox: Outbox::new(REPLICATION_WINDOW, REPLICATION_RETRY_TIMEOUT),
+            retry_timer: None,
+        }
+    }
+
+    fn handle_txn(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        txn: &Vec<Operation>,
+    ) -> anyhow::Result<()> {
+        self.clock += 1;
+        let timestamp = self.clock;
+        let node_id = self.base.node_id().to_owned();
+
+        let processed_txn = self.apply_txn(txn, timestamp, &node_id)?;
+        let sequence = self.session.next_sequence()?;
+        self.broadcast_txn(sender, &processed_txn, timestamp, sequence)?;
+
+        let reply = message.reply(Payload::TxnOk { txn: processed_txn });
+
+        sender.send(reply)
+    }
+
+    fn handle_internal_txn(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+        txn: &Vec<Operation>,
+        timestamp: u64,
+        sequence: u64,
+    ) -> anyhow::Result<()> {
+        // Duplicates are acked too, the first ack may have been dropped
+        if self.session.accept(message.src(), sequence) {
+            self.clock = self.clock.max(timestamp);
+            self.apply_txn(txn, timestamp, message.src())?;
+        }
+
+        sender.send(message.reply(Payload::InternalTxnOk))
+    }
+
+    fn apply_txn(
+        &mut self,
+        txn: &Vec<Operation>,
+        timestamp: u64,
+        node_id: &str,
+    ) -> anyhow::Result<Vec<Operation>> {
+        // Comparing needs every replica to agree on the value first
+        if txn
+            .iter()
+            .any(|operation| matches!(operation, Operation::Cas { .. }))
+        {
+            return Err(MaelstromError::new(
+                ErrorCode::NotSupported,
+                "cas isn't supported by totally available transactions",
+            )
+            .into());
+        }
+
+        let mut processed_txn = Vec::new();
+
+        let cloned_log_store = self.log_store.clone();
+        let mut log_store = cloned_log_store.lock().unwrap();
+
+        for operation in txn {
+            let tx = match operation {
+                Operation::Read { key, .. } => self.process_read(*key, &mut log_store)?,
+                Operation::Write { key, value } => self.process_write(
+                    *key,
+                    Versioned::new(*value, timestamp, node_id),
+                    &mut log_store,
+                )?,
+                Operation::Cas { .. } => unreachable!("Refused above"),
+            };
+
+            processed_txn.push(tx);
+        }
+
+        Ok(processed_txn)
+    }
+
+    fn process_read(
+        &mut self,
+        key: KeyId,
+        log_store: &mut MutexGuard<HashMap<usize, Versioned<usize>>>,
+    ) -> anyhow::Result<Operation> {
+        let value = log_store.get(&key).map(|versioned| versioned.value);
+
+        Ok(Operation::Read { key, value })
+    }
+
+    fn process_write(
+        &mut self,
+        key: KeyId,
+        versioned: Versioned<usize>,
+        log_store: &mut MutexGuard<HashMap<usize, Versioned<usize>>>,
+    ) -> anyhow::Result<Operation> {
+        let value = versioned.value;
+
+        let winner = match log_store.get(&key) {
+            Some(current) => self.resolver.resolve(current, &versioned),
+            None => versioned,
+        };
+        log_store.insert(key, winner);
+
+        Ok(Operation::Write { key, value })
+    }
+
+    fn broadcast_txn(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        txn: &[Operation],
+        timestamp: u64,
+        sequence: u64,
+    ) -> anyhow::Result<()> {
+        for neighbor in self.base.peers() {
+            let internal_txn = self.base.message(
+                &neighbor,
+                Payload::InternalTxn {
+                    txn: txn.to_vec(),
+                    timestamp,
+                    sequence,
+                },
+            );
+            self.outbox.push(internal_txn, sender)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Persistent for TotallyAvailableTransactionsNode {
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+        let snapshot = Snapshot {
+            clock: self.clock,
+            log_store: self.log_store.lock().unwrap().clone(),
+            windows: self.session.windows().clone(),
+        };
+
+        Ok(serde_json::to_vec(&snapshot)?)
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+        let snapshot = serde_json::from_slice::<Snapshot>(snapshot)?;
+        self.clock = snapshot.clock;
+        *self.log_store.lock().unwrap() = snapshot.log_store;
+        self.session.restore_windows(snapshot.windows);
+
+        Ok(())
+    }
+}
+
+impl Node<Payload> for TotallyAvailableTransactionsNode {
+    fn middleware(&mut self) -> Vec<Box<dyn Middleware<Payload>>> {
+        vec![Box::new(Dedup::new(DEDUP_CAPACITY))]
+    }
+
+    fn membership(&mut self) -> Option<&mut Membership> {
+        Some(self.base.membership_mut())
+    }
+
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        Some(self)
+    }
+
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        self.retry_timer = Some(scheduler.tick_periodic(RETRY_INTERVAL).id());
+
+        Ok(())
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.retry_timer => self.outbox.retry_due(ctx),
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match &message.body().payload {
+            Payload::Txn { txn } => self.handle_txn(ctx, &message, txn),
+            Payload::TxnOk { txn: _ } => Ok(()),
+            Payload::InternalTxn {
+                txn,
+                timestamp,
+                sequence,
+            } => self.handle_internal_txn(ctx, &message, txn, *timestamp, *sequence),
+            Payload::InternalTxnOk => self.outbox.ack(&message, ctx).map(|_| ()),
+        }
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    let resolver = resolver_from_env()?;
+
+    main_loop_with_config(config, |init| {
+        let session = Session::from_env(&init.node_id)?;
+        Ok(TotallyAvailableTransactionsNode::new(
+            init, resolver, session,
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Operation, Payload, TotallyAvailableTransactionsNode, REPLICATION_RETRY_TIMEOUT,
+        REPLICATION_WINDOW,
+    };
+    use crate::{
+        conflict::LastWriteWins,
+        conformance::{
+            assert_message_round_trip, assert_reply_to, assert_round_trip, assert_serializes, init,
+            strategies,
+        },
+        persistence::Persistent,
+        session::{Session, WINDOW_SIZE},
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+    use proptest::{collection::vec, option, prelude::*};
+
+    const JSON_MESSAGE: &str = r#"{"src":"c0","dest":"n1","body":{"msg_id":3,"in_reply_to":null,"type":"txn","txn":[["r",1,null],["r",2,5],["w",3,6]]}}"#;
+
+    #[test]
+    fn test_deserialization() {
+        let message = serde_json::from_str::<Message<Payload>>(JSON_MESSAGE).unwrap();
+
+        assert_eq!(message.msg_id().unwrap(), 3);
+
+        match &message.body().payload {
+            Payload::Txn { txn } => {
+                assert_eq!(txn.len(), 3);
+
+                let tx_0 = txn[0].clone();
+                assert!(matches!(
+                    tx_0,
+                    Operation::Read {
+                        key: 1,
+                        value: None,
+                    }
+                ));
+                let tx_1 = txn[1].clone();
+                assert!(matches!(
+                    tx_1,
+                    Operation::Read {
+                        key: 2,
+                        value: Some(5),
+                    }
+                ));
+                let tx_2 = txn[2].clone();
+                assert!(matches!(tx_2, Operation::Write { key: 3, value: 6 }));
+            }
+            _ => panic!("Invalid payload type found"),
+        }
+    }
+
+    #[test]
+    fn test_serialization() {
+        let txn = [
+            Operation::Read {
+                key: 1,
+                value: None,
+            },
+            Operation::Read {
+                key: 2,
+                value: Some(5),
+            },
+            Operation::Write { key: 3, value: 6 },
+        ];
+        let payload = Payload::Txn { txn: txn.to_vec() };
+        let message = Message::new(
+            "c0".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(3), None, payload),
+        );
+
+        let serialized_message = serde_json::to_string(&message).unwrap();
+
+        assert_eq!(JSON_MESSAGE, serialized_message);
+    }
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[JSON_MESSAGE]);
+
+        assert_serializes(&[(
+            Message::new(
+                "n1".to_owned(),
+                "c0".to_owned(),
+                Body::new(
+                    Some(0),
+                    Some(3),
+                    Payload::TxnOk {
+                        txn: vec![
+                            Operation::Read {
+                                key: 1,
+                                value: None,
+                            },
+                            Operation::Write { key: 3, value: 6 },
+                        ],
+                    },
+                ),
+            ),
+            r#"{"src":"n1","dest":"c0","body":{"type":"txn_ok","msg_id":0,"in_reply_to":3,"txn":[["r",1,null],["w",3,6]]}}"#,
+        )]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = TotallyAvailableTransactionsNode::new(
+            init(),
+            Box::new(LastWriteWins),
+            Session::in_memory(),
+        );
+
+        let requests = [JSON_MESSAGE].map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        // Replication traffic to other nodes is interleaved with the replies
+        let sent = sent.lock().unwrap();
+        let replies = sent
+            .iter()
+            .filter(|m| m.dest().starts_with('c'))
+            .collect::<Vec<_>>();
+
+        assert_eq!(replies.len(), requests.len());
+        for (request, reply) in requests.iter().zip(replies) {
+            assert_reply_to(request, reply);
+        }
+    }
+
+    #[test]
+    fn test_lost_txn_is_retried_within_the_replay_window() {
+        const TXNS: usize = 100;
+        assert!((REPLICATION_WINDOW as u64) < WINDOW_SIZE);
+
+        let node = || {
+            TotallyAvailableTransactionsNode::new(
+                init(),
+                Box::new(LastWriteWins),
+                Session::in_memory(),
+            )
+        };
+        let (mut replica, mut peer) = (node(), node());
+        let replica_writter = MemoryWritter::new();
+        let replica_sent = replica_writter.messages();
+        let mut replica_sender = MessageSender::new(replica_writter);
+        let peer_writter = MemoryWritter::new();
+        let peer_sent = peer_writter.messages();
+        let mut peer_sender = MessageSender::new(peer_writter);
+
+        for key in 0..TXNS {
+            let txn = Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(
+                    Some(key),
+                    None,
+                    Payload::Txn {
+                        txn: vec![Operation::Write { key, value: key }],
+                    },
+                ),
+            );
+            replica
+                .handle_message(txn, &mut NodeContext::new(&mut replica_sender))
+                .unwrap();
+        }
+
+        // Delivers what the replica replicated to n2 and hands back the acks,
+        // dropping the very first transaction it sent
+        let mut lost = false;
+        let mut exchange =
+            |replica: &mut TotallyAvailableTransactionsNode,
+             replica_sender: &mut MessageSender<Payload>,
+             peer: &mut TotallyAvailableTransactionsNode| {
+                loop {
+                    let internal = std::mem::take(&mut *replica_sent.lock().unwrap())
+                        .into_iter()
+                        .filter(|m| m.dest() == "n2")
+                        .collect::<Vec<_>>();
+                    if internal.is_empty() {
+                        return;
+                    }
+
+                    for message in internal {
+                        if !lost {
+                            lost = true;
+                            continue;
+                        }
+                        peer.handle_message(message, &mut NodeContext::new(&mut peer_sender))
+                            .unwrap();
+                    }
+                    for ack in std::mem::take(&mut *peer_sent.lock().unwrap()) {
+                        replica
+                            .handle_message(ack, &mut NodeContext::new(replica_sender))
+                            .unwrap();
+                    }
+                }
+            };
+
+        // Transactions after the lost one wait on it instead of leaving it
+        // too far behind
+        exchange(&mut replica, &mut replica_sender, &mut peer);
+        assert_eq!(peer.log_store.lock().unwrap().len(), REPLICATION_WINDOW - 1);
+
+        std::thread::sleep(REPLICATION_RETRY_TIMEOUT);
+        replica.outbox.retry_due(&mut replica_sender).unwrap();
+        exchange(&mut replica, &mut replica_sender, &mut peer);
+
+        let log_store = peer.log_store.lock().unwrap();
+        assert_eq!(log_store.len(), TXNS);
+        assert!(log_store.contains_key(&0));
+    }
+
+    #[test]
+    fn test_snapshot_keeps_replay_windows() {
+        let writter = MemoryWritter::new();
+        let mut sender = MessageSender::new(writter);
+        let node = || {
+            TotallyAvailableTransactionsNode::new(
+                init(),
+                Box::new(LastWriteWins),
+                Session::in_memory(),
+            )
+        };
+        let internal_txn = |value, timestamp| {
+            Message::new(
+                "n2".to_owned(),
+                "n1".to_owned(),
+                Body::new(
+                    Some(value),
+                    None,
+                    Payload::InternalTxn {
+                        txn: vec![Operation::Write { key: 1, value }],
+                        timestamp,
+                        sequence: 1,
+                    },
+                ),
+            )
+        };
+        let value = |node: &TotallyAvailableTransactionsNode| {
+            node.log_store.lock().unwrap().get(&1).map(|v| v.value)
+        };
+
+        let mut crashed = node();
+        let before = crashed.snapshot().unwrap();
+        crashed
+            .handle_message(internal_txn(7, 1), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        let after = crashed.snapshot().unwrap();
+
+        // Restored with the transaction, its retransmission is a duplicate
+        let mut restored = node();
+        restored.restore(&after).unwrap();
+        restored
+            .handle_message(internal_txn(8, 2), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(value(&restored), Some(7));
+
+        // Restored without it, the retransmission is applied
+        let mut restored = node();
+        restored.restore(&before).unwrap();
+        restored
+            .handle_message(internal_txn(7, 1), &mut NodeContext::new(&mut sender))
+            .unwrap();
+        assert_eq!(value(&restored), Some(7));
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            (any::<usize>(), option::of(any::<usize>()))
+                .prop_map(|(key, value)| Operation::Read { key, value }),
+            (any::<usize>(), any::<usize>())
+                .prop_map(|(key, value)| Operation::Write { key, value }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_txn_round_trip(message in strategies::message(
+            vec(operation(), 0..8).prop_map(|txn| Payload::Txn { txn }),
+        )) {
+            assert_message_round_trip(&message);
+        }
+    }
+}
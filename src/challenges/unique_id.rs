@@ -0,0 +1,110 @@
+use crate::{
+    config::Config, main_loop_with_config, scheduler::Scheduler, Init, Message, MessageSender,
+    Node, NodeBase, NodeContext,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    Generate,
+    GenerateOk { id: String },
+}
+
+struct UniqueIdNode {
+    base: NodeBase,
+}
+
+impl UniqueIdNode {
+    fn new(init: Init) -> Self {
+        Self {
+            base: NodeBase::new(&init),
+        }
+    }
+
+    fn handle_generate(
+        &mut self,
+        sender: &mut MessageSender<Payload>,
+        message: &Message<Payload>,
+    ) -> anyhow::Result<()> {
+        let id = format!("{}-{}", self.base.node_id(), uuid::Uuid::new_v4().simple());
+        let reply = message.reply(Payload::GenerateOk { id });
+
+        sender.send(reply)
+    }
+}
+
+impl Node<Payload> for UniqueIdNode {
+    fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match &message.body().payload {
+            Payload::Generate => self.handle_generate(ctx, &message),
+            Payload::GenerateOk { .. } => Ok(()),
+        }
+    }
+}
+
+/// Runs the node as [`crate::main_loop_with_config`] does.
+pub fn run(config: Config) -> anyhow::Result<()> {
+    main_loop_with_config(config, |init| Ok(UniqueIdNode::new(init)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Payload, UniqueIdNode};
+    use crate::{
+        conformance::{assert_reply_to, assert_round_trip, assert_serializes, init},
+        writters::MemoryWritter,
+        Body, Message, MessageSender, Node, NodeContext,
+    };
+
+    const GENERATE: &str = r#"{"src":"c1","dest":"n1","body":{"type":"generate","msg_id":2}}"#;
+
+    #[test]
+    fn test_wire_format() {
+        assert_round_trip::<Payload>(&[GENERATE]);
+
+        assert_serializes(&[(
+            Message::new(
+                "n1".to_owned(),
+                "c1".to_owned(),
+                Body::new(
+                    Some(0),
+                    Some(2),
+                    Payload::GenerateOk {
+                        id: "n1-abc".to_owned(),
+                    },
+                ),
+            ),
+            r#"{"src":"n1","dest":"c1","body":{"type":"generate_ok","msg_id":0,"in_reply_to":2,"id":"n1-abc"}}"#,
+        )]);
+    }
+
+    #[test]
+    fn test_reply_correlation() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = UniqueIdNode::new(init());
+
+        let requests = [GENERATE].map(|r| serde_json::from_str::<Message<Payload>>(r).unwrap());
+        for request in requests.iter().cloned() {
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), requests.len());
+        for (request, reply) in requests.iter().zip(sent.iter()) {
+            assert_reply_to(request, reply);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Logical clocks to order events across nodes without synchronized time.
+
+use serde::{Deserialize, Serialize};
+use std::{cmp::Ordering, collections::BTreeMap};
+
+/// Scalar logical clock. If an event happened before another its timestamp
+/// is lower, the opposite doesn't hold: use a [`VectorClock`] to tell
+/// concurrent events apart.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// Advances the clock for a local event or a send, returning the
+    /// event's timestamp.
+    pub fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    /// Advances the clock past `remote`, the timestamp of a received
+    /// message, returning the timestamp of the receive.
+    pub fn observe(&mut self, remote: u64) -> u64 {
+        self.time = self.time.max(remote);
+        self.tick()
+    }
+}
+
+/// One counter per node, tracking how many events of each node an event
+/// depends on. Clocks are only partially ordered: two of them not ordered
+/// either way belong to concurrent events.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VectorClock {
+    entries: BTreeMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, node_id: &str) -> u64 {
+        self.entries.get(node_id).copied().unwrap_or_default()
+    }
+
+    /// Counts a new event of `node_id`, returning its counter.
+    pub fn increment(&mut self, node_id: &str) -> u64 {
+        let counter = self.entries.entry(node_id.to_owned()).or_default();
+        *counter += 1;
+
+        *counter
+    }
+
+    /// Takes the highest counter of every node, as done when receiving a
+    /// message carrying `other`.
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node_id, counter) in &other.entries {
+            let own = self.entries.entry(node_id.clone()).or_default();
+            *own = (*own).max(*counter);
+        }
+    }
+
+    pub fn happens_before(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Less)
+    }
+
+    pub fn is_concurrent(&self, other: &VectorClock) -> bool {
+        self.partial_cmp(other).is_none()
+    }
+}
+
+impl PartialOrd for VectorClock {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut nodes = self.entries.keys().chain(other.entries.keys());
+
+        nodes.try_fold(Ordering::Equal, |ordering, node_id| {
+            match (ordering, self.get(node_id).cmp(&other.get(node_id))) {
+                (ordering, Ordering::Equal) => Some(ordering),
+                (Ordering::Equal, cmp) => Some(cmp),
+                (ordering, cmp) if ordering == cmp => Some(ordering),
+                _ => None,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LamportClock, VectorClock};
+
+    #[test]
+    fn test_lamport_clock() {
+        let mut n1 = LamportClock::new();
+        let mut n2 = LamportClock::new();
+
+        let sent = n1.tick();
+        n2.tick();
+        n2.tick();
+        assert_eq!(n1.observe(n2.tick()), 4);
+        assert!(n2.observe(sent) > sent);
+
+        assert_eq!(serde_json::to_string(&n1).unwrap(), "4");
+    }
+
+    #[test]
+    fn test_vector_clock() {
+        let mut n1 = VectorClock::new();
+        n1.increment("n1");
+        let sent = n1.clone();
+
+        let mut n2 = VectorClock::new();
+        n2.increment("n2");
+        assert!(n2.is_concurrent(&sent));
+
+        n2.merge(&sent);
+        n2.increment("n2");
+        assert!(sent.happens_before(&n2));
+        assert!(!n2.happens_before(&sent));
+
+        n1.increment("n1");
+        assert!(n1.is_concurrent(&n2));
+        assert!(sent <= sent.clone());
+
+        let json = serde_json::to_string(&n2).unwrap();
+        assert_eq!(json, r#"{"n1":1,"n2":2}"#);
+        assert_eq!(serde_json::from_str::<VectorClock>(&json).unwrap(), n2);
+    }
+}
@@ -0,0 +1,246 @@
+//! Wire formats of the readers and writters. Maelstrom speaks [`Json`], one
+//! document per line, with or without the `null` fields of bodies; nodes talking to each other on their own can use a
+//! binary format instead, [`MessagePack`] with the `msgpack` feature or
+//! [`Cbor`] with the `cbor` feature, both framed by a 4 bytes big endian
+//! length.
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::io::BufRead;
+
+pub trait Codec {
+    /// Appends `message`, framed, to `out`.
+    fn encode<T: Serialize>(&self, message: &T, out: &mut Vec<u8>) -> anyhow::Result<()>;
+
+    /// Reads the next frame of `input`, `None` once it's exhausted.
+    fn read_frame<R: BufRead>(&self, input: &mut R) -> std::io::Result<Option<Vec<u8>>>;
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> anyhow::Result<T>;
+}
+
+/// One JSON document per line, blank lines being skipped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json {
+    omit_nulls: bool,
+}
+
+impl Json {
+    /// Leaves out the fields of message bodies that are `null`, e.g. the
+    /// `msg_id` of a message sent without one, as in Maelstrom's examples.
+    /// Decoding takes messages either way, absent fields are `None`.
+    pub fn omit_nulls(mut self, omit_nulls: bool) -> Self {
+        self.omit_nulls = omit_nulls;
+        self
+    }
+}
+
+impl Codec for Json {
+    fn encode<T: Serialize>(&self, message: &T, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.omit_nulls {
+            let mut message = serde_json::to_value(message).context("Error serializing message")?;
+            if let Some(body) = message.get_mut("body").and_then(Value::as_object_mut) {
+                body.retain(|_, value| !value.is_null());
+            }
+            serde_json::to_writer(&mut *out, &message)?;
+        } else {
+            serde_json::to_writer(&mut *out, message).context("Error serializing message")?;
+        }
+        out.push(b'\n');
+
+        Ok(())
+    }
+
+    fn read_frame<R: BufRead>(&self, input: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+
+        loop {
+            line.clear();
+
+            match input.read_until(b'\n', &mut line)? {
+                0 => return Ok(None),
+                _ if line.trim_ascii().is_empty() => {}
+                _ => return Ok(Some(line)),
+            }
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> anyhow::Result<T> {
+        serde_json::from_slice(frame).with_context(|| {
+            format!(
+                "Failed to parse input message: {}",
+                String::from_utf8_lossy(frame).trim_end()
+            )
+        })
+    }
+}
+
+/// MessagePack with field names, so payloads keep the same shape as in
+/// JSON.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, message: &T, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let encoded = rmp_serde::to_vec_named(message).context("Error serializing message")?;
+
+        write_frame(&encoded, out)
+    }
+
+    fn read_frame<R: BufRead>(&self, input: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+        read_length_prefixed(input)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> anyhow::Result<T> {
+        rmp_serde::from_slice(frame).context("Failed to parse MessagePack input message")
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, message: &T, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(message, &mut encoded).context("Error serializing message")?;
+
+        write_frame(&encoded, out)
+    }
+
+    fn read_frame<R: BufRead>(&self, input: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+        read_length_prefixed(input)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, frame: &[u8]) -> anyhow::Result<T> {
+        ciborium::from_reader(frame).context("Failed to parse CBOR input message")
+    }
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn write_frame(encoded: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+    let len = u32::try_from(encoded.len()).context("Message too big to be framed")?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(encoded);
+
+    Ok(())
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn read_length_prefixed<R: BufRead>(input: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut frame)?;
+
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, Json};
+    use crate::{
+        readers::{CodecReader, MessageReader},
+        Body, Message,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    fn assert_round_trip<C: Codec>(codec: C) {
+        let messages = ["hi", "bye"].map(|echo| {
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(
+                    Some(1),
+                    None,
+                    Payload::Echo {
+                        echo: echo.to_owned(),
+                    },
+                ),
+            )
+        });
+
+        let mut encoded = Vec::new();
+        for message in &messages {
+            codec.encode(message, &mut encoded).unwrap();
+        }
+
+        let mut reader = CodecReader::new(encoded.as_slice(), codec);
+        for message in &messages {
+            let decoded: Message<Payload> = reader.read_message().unwrap().unwrap();
+            assert_eq!(decoded.body().payload, message.body().payload);
+            assert_eq!(decoded.msg_id(), message.msg_id());
+        }
+        assert!(MessageReader::<Message<Payload>>::read_message(&mut reader).is_none());
+    }
+
+    #[test]
+    fn test_codecs() {
+        assert_round_trip(Json::default());
+        assert_round_trip(Json::default().omit_nulls(true));
+
+        let message = Message::new(
+            "n1".to_owned(),
+            "c1".to_owned(),
+            Body::new(
+                Some(1),
+                None,
+                Payload::Echo {
+                    echo: "hi".to_owned(),
+                },
+            ),
+        );
+        let mut encoded = Vec::new();
+        Json::default()
+            .omit_nulls(true)
+            .encode(&message, &mut encoded)
+            .unwrap();
+        let encoded = String::from_utf8(encoded).unwrap();
+        assert!(!encoded.contains("in_reply_to"));
+        assert!(encoded.contains(r#""msg_id":1"#));
+
+        // Blank lines and trailing whitespace, through a buffer smaller
+        // than a line
+        let input = concat!(
+            "\n",
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"hi"}}   "#,
+            "\r\n  \n\t\n",
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","echo":"bye"}}"#,
+        );
+        let input = std::io::BufReader::with_capacity(4, input.as_bytes());
+        let mut reader = CodecReader::new(input, Json::default());
+        let echoes = std::iter::from_fn(|| reader.read_message())
+            .map(|message: anyhow::Result<Message<Payload>>| {
+                message.unwrap().body().payload.clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            echoes,
+            ["hi", "bye"].map(|echo| Payload::Echo {
+                echo: echo.to_owned()
+            })
+        );
+
+        #[cfg(feature = "msgpack")]
+        assert_round_trip(super::MessagePack);
+
+        #[cfg(feature = "cbor")]
+        assert_round_trip(super::Cbor);
+    }
+}
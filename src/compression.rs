@@ -0,0 +1,304 @@
+//! Gzip for the large messages nodes exchange, e.g. gossip carrying whole
+//! logs. A node started with [`crate::config::GZIP_THRESHOLD`] announces to
+//! its peers right after `init` that it takes compressed payloads, with a
+//! `{"type": "gzip_accept"}` message, and from then on compresses the
+//! payloads over the threshold of what it sends to the peers that announced
+//! the same. Compressed payloads keep their `type` and carry the rest as
+//! base64 gzipped JSON, e.g. `{"type": "gossip", "gzip": "H4sIAAAAAAAA..."}`.
+//! Clients and Maelstrom's services never get compressed payloads.
+
+use crate::{readers::MessageReader, writters::MessageWritter, Body, Incoming, Message};
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    sync::{Arc, RwLock},
+};
+
+/// A compressed payload, as read from the wire.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compressed {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub gzip: String,
+}
+
+/// Announcement that the sender takes compressed payloads.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum GzipAccept {
+    GzipAccept,
+}
+
+/// The threshold and the peers that take compressed payloads, shared by a
+/// [`CompressingWritter`] and a [`DecompressingReader`].
+#[derive(Debug, Clone, Default)]
+pub struct Compression {
+    /// Smallest payload compressed, in bytes of JSON. Off when `None`.
+    threshold: Option<usize>,
+    accepting: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Compression {
+    pub fn new(threshold: Option<usize>) -> Self {
+        Self {
+            threshold,
+            accepting: Arc::default(),
+        }
+    }
+
+    /// What `node_id` sends `peers` to announce it takes compressed
+    /// payloads, nothing when compression is off.
+    pub fn announcements(&self, node_id: &str, peers: &[String]) -> Vec<Message<Value>> {
+        if self.threshold.is_none() {
+            return Vec::new();
+        }
+
+        peers
+            .iter()
+            .filter(|peer| *peer != node_id)
+            .map(|peer| {
+                let payload = serde_json::json!({ "type": "gzip_accept" });
+                Message::new(
+                    node_id.to_owned(),
+                    peer.clone(),
+                    Body::new(None, None, payload),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether `peer` announced it takes compressed payloads.
+    pub fn accepts(&self, peer: &str) -> bool {
+        self.accepting.read().unwrap().contains(peer)
+    }
+
+    fn accept(&self, peer: &str) {
+        self.accepting.write().unwrap().insert(peer.to_owned());
+    }
+
+    /// `message` with its payload compressed, if it goes to a peer that
+    /// takes compressed payloads and its payload is over the threshold.
+    pub fn compress<P: Serialize>(
+        &self,
+        message: &Message<P>,
+    ) -> anyhow::Result<Option<Message<Value>>> {
+        let Some(threshold) = self.threshold else {
+            return Ok(None);
+        };
+        if !self.accepts(message.dest()) {
+            return Ok(None);
+        }
+
+        let payload =
+            serde_json::to_value(&message.body().payload).context("Error serializing payload")?;
+        let json = serde_json::to_vec(&payload)?;
+        if json.len() < threshold {
+            return Ok(None);
+        }
+
+        let kind = payload["type"].as_str().unwrap_or_default().to_owned();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&json)?;
+        let gzip = STANDARD.encode(encoder.finish().context("Error compressing payload")?);
+
+        let compressed = serde_json::to_value(Compressed { kind, gzip })?;
+        let mut body = Body::new(message.msg_id(), message.in_reply_to(), compressed);
+        if let Some(trace) = message.trace() {
+            body = body.with_trace(trace.clone());
+        }
+
+        Ok(Some(Message::new(
+            message.src().to_owned(),
+            message.dest().to_owned(),
+            body,
+        )))
+    }
+}
+
+/// The payload `compressed` holds.
+pub fn decompress(compressed: &Compressed) -> anyhow::Result<Value> {
+    let gzip = STANDARD
+        .decode(&compressed.gzip)
+        .context("Invalid base64 in compressed payload")?;
+
+    let mut payload = Vec::new();
+    GzDecoder::new(gzip.as_slice())
+        .read_to_end(&mut payload)
+        .context("Error decompressing payload")?;
+
+    serde_json::from_slice(&payload).context("Invalid JSON in compressed payload")
+}
+
+/// Writes messages through `inner`, compressing their payloads as
+/// [`Compression::compress`] says.
+pub struct CompressingWritter<W> {
+    inner: W,
+    compression: Compression,
+}
+
+impl<W> CompressingWritter<W> {
+    pub fn new(inner: W, compression: Compression) -> Self {
+        Self { inner, compression }
+    }
+}
+
+impl<P, W> MessageWritter<Message<P>> for CompressingWritter<W>
+where
+    P: Serialize,
+    W: MessageWritter<Message<P>> + MessageWritter<Message<Value>>,
+{
+    fn send_message(&mut self, message: &Message<P>) -> anyhow::Result<()> {
+        match self.compression.compress(message)? {
+            Some(compressed) => self.inner.send_message(&compressed),
+            None => self.inner.send_message(message),
+        }
+    }
+
+    fn send_messages(&mut self, messages: &[Message<P>]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        MessageWritter::<Message<P>>::flush(&mut self.inner)
+    }
+}
+
+/// Reads messages from `inner`, decompressing their payloads and taking
+/// note of the peers that announce they take compressed payloads. The
+/// announcements themselves never reach the node.
+pub struct DecompressingReader<R> {
+    inner: R,
+    compression: Compression,
+}
+
+impl<R> DecompressingReader<R> {
+    pub fn new(inner: R, compression: Compression) -> Self {
+        Self { inner, compression }
+    }
+}
+
+impl<P, R> MessageReader<Message<Incoming<P>>> for DecompressingReader<R>
+where
+    P: DeserializeOwned,
+    R: MessageReader<Message<Incoming<P>>>,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<Message<Incoming<P>>>> {
+        loop {
+            let message = match self.inner.read_message()? {
+                Ok(message) => message,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match &message.body().payload {
+                Incoming::GzipAccept(_) => self.compression.accept(message.src()),
+                Incoming::Compressed(compressed) => {
+                    let payload = decompress(compressed).and_then(|payload| {
+                        serde_json::from_value(payload)
+                            .context("Error parsing decompressed payload")
+                    });
+
+                    return Some(payload.map(|payload| {
+                        let mut body = Body::new(message.msg_id(), message.in_reply_to(), payload);
+                        if let Some(trace) = message.trace() {
+                            body = body.with_trace(trace.clone());
+                        }
+
+                        Message::new(message.src().to_owned(), message.dest().to_owned(), body)
+                    }));
+                }
+                _ => return Some(Ok(message)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompressingWritter, Compression, DecompressingReader};
+    use crate::{
+        readers::{MemoryReader, MessageReader},
+        writters::{MemoryWritter, MessageWritter},
+        Body, Incoming, Message,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Gossip { seen: Vec<u64> },
+    }
+
+    #[test]
+    fn test_compression() {
+        let compression = Compression::new(Some(100));
+        let peers = ["n1", "n2"].map(str::to_owned);
+        let announcements = compression.announcements("n1", &peers);
+        assert_eq!(announcements.len(), 1);
+
+        // n2 announced it takes compressed payloads, n3 didn't
+        let lines = [
+            r#"{"src":"n2","dest":"n1","body":{"type":"gzip_accept","msg_id":1}}"#.to_owned(),
+            r#"{"src":"n3","dest":"n1","body":{"type":"gossip","seen":[1]}}"#.to_owned(),
+        ];
+        let mut reader = DecompressingReader::new(MemoryReader::new(lines), compression.clone());
+        let read = std::iter::from_fn(|| {
+            MessageReader::<Message<Incoming<Payload>>>::read_message(&mut reader)
+        })
+        .collect::<Vec<_>>();
+        assert_eq!(read.len(), 1);
+        assert!(compression.accepts("n2"));
+        assert!(!compression.accepts("n3"));
+
+        let writter = MemoryWritter::<Message<Value>>::new();
+        let sent = writter.messages();
+        let mut writter = CompressingWritter::new(writter, compression.clone());
+        let big = Payload::Gossip {
+            seen: (0..1000).collect(),
+        };
+        for (dest, payload) in [
+            ("n2", big.clone()),
+            ("n3", big.clone()),
+            ("n2", Payload::Gossip { seen: vec![1] }),
+        ] {
+            let message = Message::new(
+                "n1".to_owned(),
+                dest.to_owned(),
+                Body::new(Some(7), None, payload),
+            );
+            let message = serde_json::to_value(message).unwrap();
+            let message = serde_json::from_value::<Message<Value>>(message).unwrap();
+            writter.send_message(&message).unwrap();
+        }
+
+        let sent = sent.lock().unwrap();
+        let compressed = sent
+            .iter()
+            .map(|m| m.body().payload.get("gzip").is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(compressed, [true, false, false]);
+        assert_eq!(sent[0].body().payload["type"], "gossip");
+        let size = |message| serde_json::to_string(message).unwrap().len();
+        assert!(size(&sent[0]) < size(&sent[1]));
+
+        // Read back as it was written
+        let line = serde_json::to_string(&sent[0]).unwrap();
+        let mut reader = DecompressingReader::new(MemoryReader::new([line]), compression);
+        let message = MessageReader::<Message<Incoming<Payload>>>::read_message(&mut reader)
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.msg_id(), Some(7));
+        assert!(matches!(&message.body().payload, Incoming::Known(payload) if *payload == big));
+    }
+}
@@ -0,0 +1,335 @@
+//! Alternative to [`crate::main_loop`] handling messages on a pool of
+//! worker threads. Messages from the same source are handled by the same
+//! worker, in the order they arrived, while messages from different sources
+//! are handled in parallel, so a handler blocked on a slow call (Redis, a
+//! reply from a peer) only holds up its own source.
+
+use crate::{
+    config::Config, handle, handle_event, handle_unknown, metrics::Metrics, persistence::Snapshots,
+    readers::StdinJsonReader, run, stdout_writter, writters::MessageWritter, Event, Init, Inputs,
+    MalformedInput, Message, Node, NodeContext, RETRY_TICK,
+};
+use anyhow::anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+};
+
+/// How many `msg_id`s a worker takes at once, so workers numbering their
+/// messages don't all contend on the node's counter.
+const MSG_ID_BLOCK: usize = 64;
+
+/// Same as [`crate::main_loop_with_config`], handling messages on `workers`
+/// threads.
+///
+/// Every worker handles its messages with its own clone of the node, made
+/// once [`Node::init`] returned, so state the workers must agree on goes
+/// behind an `Arc`. Events other than messages, e.g. timer ticks, go to the
+/// first worker. Middleware, acks and retransmissions stay on the main
+/// thread, like [`Node::on_shutdown`] and [`Node::handle_unknown`], which
+/// are called on the original node.
+pub fn concurrent_main_loop<N, P, F>(
+    workers: usize,
+    config: Config,
+    new_node: F,
+) -> anyhow::Result<()>
+where
+    N: Node<P> + Clone + Send,
+    P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+{
+    let writter = stdout_writter(&config);
+
+    run(
+        StdinJsonReader::with_capacity(config.read_buffer),
+        writter,
+        MalformedInput::default(),
+        config,
+        new_node,
+        |node, inputs, ctx, metrics, snapshots| {
+            dispatch(workers, node, inputs, ctx, metrics, snapshots)
+        },
+    )
+}
+
+enum Input<P> {
+    /// Read from stdin or fired by a timer.
+    Inbound(Event<P>),
+    /// Sent by a worker.
+    Outbound(Message<P>),
+    /// A worker's handler failed, the worker stopped.
+    Failed(anyhow::Error),
+    /// Stdin is closed and every inbound message was forwarded.
+    Closed,
+}
+
+/// Hands what a worker sends to the main thread.
+struct ChannelWritter<P> {
+    tx: Sender<Input<P>>,
+}
+
+impl<P: Clone> MessageWritter<Message<P>> for ChannelWritter<P> {
+    fn send_message(&mut self, message: &Message<P>) -> anyhow::Result<()> {
+        self.tx
+            .send(Input::Outbound(message.clone()))
+            .map_err(|_| anyhow!("Main loop is gone"))
+    }
+
+    fn send_messages(&mut self, messages: &[Message<P>]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+/// Worker handling the messages of `src`.
+fn worker_of(src: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    src.hash(&mut hasher);
+
+    (hasher.finish() % workers as u64) as usize
+}
+
+fn dispatch<N, P>(
+    workers: usize,
+    node: &mut N,
+    inputs: Inputs<P>,
+    ctx: &mut NodeContext<P>,
+    metrics: &Metrics,
+    snapshots: &mut Snapshots,
+) -> anyhow::Result<()>
+where
+    N: Node<P> + Clone + Send,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    let (tx, rx) = channel();
+
+    // Inbound messages are moved to the same channel as the workers' output
+    // so the main thread waits on a single channel. This thread isn't
+    // scoped, it only ends once the reader thread and the timers are done
+    let mut messages = inputs.messages;
+    let forward = tx.clone();
+    std::thread::spawn(move || {
+        while let Some(event) = messages.recv() {
+            if forward.send(Input::Inbound(event)).is_err() {
+                return;
+            }
+        }
+
+        let _ = forward.send(Input::Closed);
+    });
+
+    std::thread::scope(|scope| {
+        let mut queues = (0..workers.max(1))
+            .map(|_| {
+                let (queue_tx, queue_rx) = channel();
+                let mut node = node.clone();
+                let shared = ctx.share();
+                let scheduler = ctx.scheduler().cloned();
+                let panic_policy = ctx.panic_policy();
+                let versions = ctx.versions().clone();
+                let tx = tx.clone();
+                let span = tracing::Span::current();
+
+                scope.spawn(move || {
+                    let _span = span.enter();
+                    let mut sender = shared.sender(ChannelWritter { tx: tx.clone() });
+                    sender.set_msg_id_block(MSG_ID_BLOCK);
+                    let mut ctx = NodeContext::new(&mut sender)
+                        .with_panic_policy(panic_policy)
+                        .with_versions(versions);
+                    if let Some(scheduler) = &scheduler {
+                        ctx = ctx.with_scheduler(scheduler);
+                    }
+
+                    for event in queue_rx {
+                        let result = match event {
+                            Event::Message(message) => {
+                                handle(&mut node, message, &mut ctx, metrics)
+                            }
+                            event => handle_event(&mut node, event, &mut ctx, metrics),
+                        };
+                        if let Err(error) = result {
+                            let _ = tx.send(Input::Failed(error));
+                            return;
+                        }
+                    }
+                });
+
+                queue_tx
+            })
+            .collect::<Vec<_>>();
+
+        // Once the forwarder and the workers are done the channel is closed
+        drop(tx);
+
+        loop {
+            let received = rx.recv_timeout(RETRY_TICK);
+
+            inputs.malformed.check()?;
+            // Workers have clones of their own, so the node's stats are
+            // the ones of the main thread's
+            for message in inputs.unknown.try_iter() {
+                handle_unknown(node, message, ctx, metrics, None)?;
+            }
+
+            match received {
+                Ok(Input::Inbound(Event::Message(message))) => {
+                    if let Some(message) = ctx.receive(message)? {
+                        ctx.ack(&message);
+
+                        let worker = worker_of(message.src(), queues.len());
+                        let _ = queues[worker].send(Event::Message(message));
+                    }
+                }
+                Ok(Input::Inbound(event)) => {
+                    if let Some(queue) = queues.first() {
+                        let _ = queue.send(event);
+                    }
+                }
+                Ok(Input::Outbound(message)) => ctx.send(message)?,
+                Ok(Input::Failed(error)) => return Err(error),
+                // Workers stop once their queue is drained
+                Ok(Input::Closed) => queues.clear(),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            ctx.retry_due()?;
+            ctx.flush()?;
+            metrics.tick();
+            snapshots.tick(node)?;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dispatch, worker_of};
+    use crate::{
+        lanes::{LanePolicy, Lanes},
+        metrics::Metrics,
+        persistence::Snapshots,
+        scheduler::Scheduler,
+        writters::MemoryWritter,
+        Body, Inputs, Malformed, MalformedInput, Message, MessageSender, Node, NodeContext,
+    };
+    use crossbeam_channel::unbounded;
+    use std::{
+        sync::{Arc, Condvar, Mutex},
+        time::Duration,
+    };
+
+    #[derive(Clone, Default)]
+    struct SlowNode {
+        handled: Arc<Mutex<Vec<(String, usize)>>>,
+        // Set once the fast client was served
+        served: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl Node<usize> for SlowNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<usize>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<usize>,
+            ctx: &mut NodeContext<usize>,
+        ) -> anyhow::Result<()> {
+            let (served, condvar) = &*self.served;
+
+            if message.src() == "slow" && message.body().payload == 0 {
+                // Blocks the slow client's worker until the other one is done
+                let served = served.lock().unwrap();
+                let timed_out = condvar
+                    .wait_timeout_while(served, Duration::from_secs(5), |served| !*served)
+                    .unwrap()
+                    .1
+                    .timed_out();
+                assert!(!timed_out, "Clients were handled one after the other");
+            } else if message.src() != "slow" {
+                *served.lock().unwrap() = true;
+                condvar.notify_all();
+            }
+
+            self.handled
+                .lock()
+                .unwrap()
+                .push((message.src().to_owned(), message.body().payload));
+
+            ctx.send(message.reply(message.body().payload))
+        }
+    }
+
+    #[test]
+    fn test_concurrent_dispatch() {
+        const WORKERS: usize = 4;
+
+        let fast = (0..)
+            .map(|i| format!("c{i}"))
+            .find(|src| worker_of(src, WORKERS) != worker_of("slow", WORKERS))
+            .unwrap();
+
+        let (tx, rx) = unbounded();
+        for i in 0..20 {
+            for src in ["slow", fast.as_str()] {
+                let request =
+                    Message::new(src.to_owned(), "n1".to_owned(), Body::new(Some(i), None, i));
+                tx.send(request).unwrap();
+            }
+        }
+        drop(tx);
+
+        let (_unknown_tx, unknown) = unbounded();
+        let (_errors_tx, errors) = unbounded();
+        let inputs = Inputs {
+            messages: Lanes::new(rx, LanePolicy::default()),
+            unknown,
+            malformed: Malformed {
+                errors,
+                policy: MalformedInput::Fail,
+            },
+        };
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut node = SlowNode::default();
+
+        let mut snapshots = Snapshots::new(None, Duration::ZERO);
+        dispatch(
+            WORKERS,
+            &mut node,
+            inputs,
+            &mut NodeContext::new(&mut sender),
+            &Metrics::new(),
+            &mut snapshots,
+        )
+        .unwrap();
+
+        let handled = node.handled.lock().unwrap();
+        for src in ["slow", fast.as_str()] {
+            let order = handled
+                .iter()
+                .filter(|(s, _)| s == src)
+                .map(|(_, i)| *i)
+                .collect::<Vec<_>>();
+            assert_eq!(order, (0..20).collect::<Vec<_>>());
+        }
+
+        let mut msg_ids = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|reply| reply.msg_id().unwrap())
+            .collect::<Vec<_>>();
+        // Unique, though not contiguous as workers take them in blocks
+        msg_ids.sort();
+        msg_ids.dedup();
+        assert_eq!(msg_ids.len(), 40);
+    }
+}
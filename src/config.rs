@@ -0,0 +1,420 @@
+//! Runtime settings of the nodes and `main_loop`, read from env vars and
+//! command line flags so they can be tuned without recompiling.
+
+use crate::{lanes::LanePolicy, logging::LOG_LEVEL, writters::BATCH_INTERVAL, PanicPolicy};
+use anyhow::{bail, Context};
+use std::{collections::HashMap, ops::Range, str::FromStr, time::Duration};
+
+pub const GOSSIP_INTERVAL: &str = "GOSSIP_INTERVAL";
+pub const GOSSIP_JITTER: &str = "GOSSIP_JITTER";
+pub const BATCH_SIZE: &str = "BATCH_SIZE";
+pub const RETRY_TIMEOUT: &str = "RETRY_TIMEOUT";
+pub const REDIS_URL: &str = "REDIS_URL";
+pub const LISTEN: &str = "LISTEN";
+pub const PEERS: &str = "PEERS";
+pub const VALIDATE: &str = "VALIDATE";
+pub const MAX_MESSAGE_SIZE: &str = "MAX_MESSAGE_SIZE";
+pub const REPLY_CACHE: &str = "REPLY_CACHE";
+pub const RATE_LIMIT: &str = "RATE_LIMIT";
+pub const LANE_POLICY: &str = "LANE_POLICY";
+pub const GZIP_THRESHOLD: &str = "GZIP_THRESHOLD";
+pub const HANDLER_BUDGET: &str = "HANDLER_BUDGET";
+pub const PANIC_POLICY: &str = "PANIC_POLICY";
+pub const OMIT_NULLS: &str = "OMIT_NULLS";
+pub const READ_BUFFER: &str = "READ_BUFFER";
+pub const ELECTION_TIMEOUT: &str = "ELECTION_TIMEOUT";
+pub const HEARTBEAT_INTERVAL: &str = "HEARTBEAT_INTERVAL";
+pub const LEASE_READS: &str = "LEASE_READS";
+pub const SNAPSHOT_THRESHOLD: &str = "SNAPSHOT_THRESHOLD";
+pub const REPLICATION_WINDOW: &str = "REPLICATION_WINDOW";
+pub const QUORUM_READS: &str = "QUORUM_READS";
+pub const STRICT_LIN_KV: &str = "STRICT_LIN_KV";
+pub const CATCH_UP_BATCH: &str = "CATCH_UP_BATCH";
+pub const CATCH_UP_INTERVAL: &str = "CATCH_UP_INTERVAL";
+pub const WITNESSES: &str = "WITNESSES";
+pub const WRITE_BATCH_INTERVAL: &str = "WRITE_BATCH_INTERVAL";
+
+const SETTINGS: [&str; 30] = [
+    GOSSIP_INTERVAL,
+    GOSSIP_JITTER,
+    BATCH_SIZE,
+    BATCH_INTERVAL,
+    RETRY_TIMEOUT,
+    REDIS_URL,
+    LOG_LEVEL,
+    LISTEN,
+    PEERS,
+    VALIDATE,
+    MAX_MESSAGE_SIZE,
+    REPLY_CACHE,
+    RATE_LIMIT,
+    LANE_POLICY,
+    GZIP_THRESHOLD,
+    HANDLER_BUDGET,
+    PANIC_POLICY,
+    OMIT_NULLS,
+    READ_BUFFER,
+    ELECTION_TIMEOUT,
+    HEARTBEAT_INTERVAL,
+    LEASE_READS,
+    SNAPSHOT_THRESHOLD,
+    REPLICATION_WINDOW,
+    QUORUM_READS,
+    STRICT_LIN_KV,
+    CATCH_UP_BATCH,
+    CATCH_UP_INTERVAL,
+    WITNESSES,
+    WRITE_BATCH_INTERVAL,
+];
+
+/// Every setting can be given as an env var, e.g. `GOSSIP_INTERVAL=100`, or
+/// as a flag named after it, e.g. `--gossip-interval 100` or
+/// `--gossip-interval=100`, flags taking precedence. Intervals and timeouts
+/// are in milliseconds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// How often nodes gossip with their peers.
+    pub gossip_interval: Duration,
+    /// Most a gossip round is moved earlier or later, at random, so nodes
+    /// don't all gossip at once.
+    pub gossip_jitter: Duration,
+    /// Most messages written to stdout together.
+    pub batch_size: usize,
+    /// How long a message can be held back to be written with others, see
+    /// [`crate::writters::BatchingJsonWritter`].
+    pub batch_interval: Duration,
+    /// How long [`crate::MessageSender::send_with_retry`] waits for a reply
+    /// before the first retransmission.
+    pub retry_timeout: Duration,
+    pub redis_url: String,
+    /// `tracing` filter of the logs, logging is off when `None`.
+    pub log_level: Option<String>,
+    /// Address to listen on for TCP connections instead of using stdin and
+    /// stdout, see [`crate::transport::TcpTransport`], `unix:` and the path
+    /// of a Unix socket, or `udp:` and an address to exchange datagrams.
+    pub listen: Option<String>,
+    /// Addresses of the other nodes when running over TCP, given as
+    /// `n2=host:port,n3=host:port`, or their socket paths.
+    pub peers: HashMap<String, String>,
+    /// Whether inbound messages go through [`crate::middleware::Validation`]
+    /// before the node's middleware.
+    pub validate: bool,
+    /// Largest message, in bytes, accepted when validating.
+    pub max_message_size: Option<usize>,
+    /// How many requests to remember the reply of, so retries get the same
+    /// reply instead of being handled again, see
+    /// [`crate::middleware::Dedup`]. Off when `None`.
+    pub reply_cache: Option<usize>,
+    /// Most messages per second sent to each other node, see
+    /// [`crate::rate_limit::RateLimit`]. Unlimited when `None`.
+    pub rate_limit: Option<u32>,
+    /// Whether `main_loop` favours client requests or internal traffic when
+    /// both are waiting, `fair`, `client-first` or `internal-first`.
+    pub lane_policy: LanePolicy,
+    /// Smallest payload, in bytes, compressed on its way to other nodes,
+    /// see [`crate::compression`]. Off when `None`.
+    pub gzip_threshold: Option<usize>,
+    /// Longest a handler should take: slower ones are reported on stderr as
+    /// they happen, and the latencies of every payload type once the node
+    /// stops, see [`crate::metrics::Metrics::with_budget`]. Off when `None`.
+    pub handler_budget: Option<Duration>,
+    /// Whether a handler that panics only fails the request it handled,
+    /// `isolate`, or takes the node down, `abort`.
+    pub panic_policy: PanicPolicy,
+    /// Whether the messages written to stdout leave out the `null` fields of
+    /// their bodies, see [`crate::codec::Json::omit_nulls`].
+    pub omit_nulls: bool,
+    /// Size, in bytes, of the buffer stdin is read through, see
+    /// [`crate::readers::StdinJsonReader::with_capacity`].
+    pub read_buffer: usize,
+    /// How long a Raft follower waits to hear from a leader before it
+    /// stands for election, picked at random within the range, given as
+    /// `150-300`.
+    pub election_timeout: Range<Duration>,
+    /// How often a Raft leader tells its followers it's still there.
+    pub heartbeat_interval: Duration,
+    /// Whether a Raft leader serves reads on its own while a majority
+    /// acknowledged it recently, rather than confirming it still leads for
+    /// every read. Only safe while the clocks of the nodes run at about the
+    /// same rate.
+    pub lease_reads: bool,
+    /// Entries a Raft node keeps in its log, once applied, before it
+    /// replaces them with a snapshot of its store.
+    pub snapshot_threshold: usize,
+    /// `append_entries` a Raft leader sends a follower ahead of its
+    /// acknowledgements, each with the entries appended since the last.
+    pub replication_window: usize,
+    /// Whether Raft nodes serve reads from their own store once they applied
+    /// as far as the longest log of a majority of the cluster goes, rather
+    /// than having the leader confirm it still leads.
+    pub quorum_reads: bool,
+    /// Whether the Raft node takes only the `read`, `write` and `cas` of
+    /// Maelstrom's `lin-kv` workload from clients, refusing `txn`s as not
+    /// supported.
+    pub strict_lin_kv: bool,
+    /// Most entries a Raft leader sends a follower in one `append_entries`.
+    /// A follower missing more than that is caught up one batch at a time.
+    pub catch_up_batch: usize,
+    /// Least time between two batches, or snapshots, a Raft leader sends a
+    /// follower catching up, so it doesn't crowd out the others.
+    pub catch_up_interval: Duration,
+    /// Raft nodes that vote and count towards quorums but keep nothing of
+    /// the log but the terms of its entries, and never lead, given as
+    /// `n3,n5`. Two nodes and a witness keep going without the node that
+    /// isn't leading, but once the leader and the witness commit entries
+    /// the other node lacks, losing the leader stalls the cluster for good:
+    /// the witness won't vote for the other node, and can't send it the
+    /// entries.
+    pub witnesses: Vec<String>,
+    /// How long a Raft leader gathers the writes of clients for, to append
+    /// them to its log as one entry. Zero, the default, appends every write
+    /// on its own as it arrives.
+    pub write_batch_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gossip_interval: Duration::from_millis(300),
+            gossip_jitter: Duration::from_millis(30),
+            batch_size: 64,
+            batch_interval: Duration::ZERO,
+            retry_timeout: Duration::from_millis(100),
+            redis_url: "redis://localhost/".to_owned(),
+            log_level: None,
+            listen: None,
+            peers: HashMap::new(),
+            validate: false,
+            max_message_size: None,
+            reply_cache: None,
+            rate_limit: None,
+            lane_policy: LanePolicy::default(),
+            gzip_threshold: None,
+            handler_budget: None,
+            panic_policy: PanicPolicy::default(),
+            omit_nulls: false,
+            read_buffer: 64 * 1024,
+            election_timeout: Duration::from_millis(150)..Duration::from_millis(300),
+            heartbeat_interval: Duration::from_millis(50),
+            lease_reads: false,
+            snapshot_threshold: 1000,
+            replication_window: 4,
+            quorum_reads: false,
+            strict_lin_kv: false,
+            catch_up_batch: 100,
+            catch_up_interval: Duration::from_millis(20),
+            witnesses: Vec::new(),
+            write_batch_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl Config {
+    /// Config from the process' env vars and command line.
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::parse(|name| std::env::var(name).ok(), std::env::args().skip(1))
+    }
+
+    /// Config from the env vars `env` returns and the flags in `args`.
+    pub fn parse<E, A>(env: E, args: A) -> anyhow::Result<Self>
+    where
+        E: Fn(&str) -> Option<String>,
+        A: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+
+        for name in SETTINGS {
+            if let Some(value) = env(name) {
+                config.set(name, &value)?;
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(flag) = arg.strip_prefix("--") else {
+                bail!("Unexpected argument {arg}");
+            };
+            let (flag, value) = match flag.split_once('=') {
+                Some((flag, value)) => (flag.to_owned(), value.to_owned()),
+                None => (
+                    flag.to_owned(),
+                    args.next()
+                        .with_context(|| format!("Missing value of --{flag}"))?,
+                ),
+            };
+
+            let name = flag.replace('-', "_").to_uppercase();
+            match SETTINGS.iter().find(|setting| **setting == name) {
+                Some(name) => config.set(name, &value)?,
+                None => bail!("Unknown flag --{flag}"),
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        match name {
+            GOSSIP_INTERVAL => self.gossip_interval = millis(name, value)?,
+            GOSSIP_JITTER => self.gossip_jitter = millis(name, value)?,
+            BATCH_SIZE => self.batch_size = parse(name, value)?,
+            BATCH_INTERVAL => self.batch_interval = millis(name, value)?,
+            RETRY_TIMEOUT => self.retry_timeout = millis(name, value)?,
+            REDIS_URL => self.redis_url = value.to_owned(),
+            LOG_LEVEL => self.log_level = Some(value.to_owned()),
+            LISTEN => self.listen = Some(value.to_owned()),
+            PEERS => self.peers = peers(value)?,
+            VALIDATE => self.validate = parse(name, value)?,
+            MAX_MESSAGE_SIZE => self.max_message_size = Some(parse(name, value)?),
+            REPLY_CACHE => self.reply_cache = Some(parse(name, value)?),
+            RATE_LIMIT => self.rate_limit = Some(parse(name, value)?),
+            LANE_POLICY => self.lane_policy = parse(name, value)?,
+            GZIP_THRESHOLD => self.gzip_threshold = Some(parse(name, value)?),
+            HANDLER_BUDGET => self.handler_budget = Some(millis(name, value)?),
+            PANIC_POLICY => self.panic_policy = parse(name, value)?,
+            OMIT_NULLS => self.omit_nulls = parse(name, value)?,
+            READ_BUFFER => self.read_buffer = parse(name, value)?,
+            ELECTION_TIMEOUT => self.election_timeout = millis_range(name, value)?,
+            HEARTBEAT_INTERVAL => self.heartbeat_interval = millis(name, value)?,
+            LEASE_READS => self.lease_reads = parse(name, value)?,
+            SNAPSHOT_THRESHOLD => self.snapshot_threshold = parse(name, value)?,
+            REPLICATION_WINDOW => self.replication_window = parse(name, value)?,
+            QUORUM_READS => self.quorum_reads = parse(name, value)?,
+            STRICT_LIN_KV => self.strict_lin_kv = parse(name, value)?,
+            CATCH_UP_BATCH => self.catch_up_batch = parse(name, value)?,
+            CATCH_UP_INTERVAL => self.catch_up_interval = millis(name, value)?,
+            WITNESSES => self.witnesses = node_ids(value),
+            WRITE_BATCH_INTERVAL => self.write_batch_interval = millis(name, value)?,
+            _ => unreachable!("{name} isn't a setting"),
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<T>(name: &str, value: &str) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid {name} {value}: {e}"))
+}
+
+fn millis(name: &str, value: &str) -> anyhow::Result<Duration> {
+    parse(name, value).map(Duration::from_millis)
+}
+
+fn millis_range(name: &str, value: &str) -> anyhow::Result<Range<Duration>> {
+    let Some((start, end)) = value.split_once('-') else {
+        bail!("Invalid {name} {value}, expected min-max");
+    };
+    let range = millis(name, start)?..millis(name, end)?;
+    if range.is_empty() {
+        bail!("Invalid {name} {value}, min must be under max");
+    }
+
+    Ok(range)
+}
+
+fn node_ids(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter(|node_id| !node_id.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn peers(value: &str) -> anyhow::Result<HashMap<String, String>> {
+    value
+        .split(',')
+        .filter(|peer| !peer.is_empty())
+        .map(|peer| match peer.split_once('=') {
+            Some((node_id, addr)) => Ok((node_id.to_owned(), addr.to_owned())),
+            None => bail!("Invalid {PEERS} entry {peer}, expected node_id=host:port"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::{lanes::LanePolicy, PanicPolicy};
+    use std::{collections::HashMap, time::Duration};
+
+    #[test]
+    fn test_config() {
+        let env = |name: &str| match name {
+            "GOSSIP_INTERVAL" => Some("100".to_owned()),
+            "BATCH_SIZE" => Some("8".to_owned()),
+            "LOG_LEVEL" => Some("debug".to_owned()),
+            _ => None,
+        };
+        let args = [
+            "--gossip-interval=50",
+            "--gossip-jitter=5",
+            "--redis-url",
+            "redis://redis:6379/",
+            "--peers=n2=localhost:7002,n3=localhost:7003",
+            "--reply-cache=1000",
+            "--lane-policy=client-first",
+            "--handler-budget=20",
+            "--panic-policy=abort",
+            "--omit-nulls=true",
+            "--read-buffer=1048576",
+            "--election-timeout=300-600",
+            "--lease-reads=true",
+            "--replication-window=8",
+            "--quorum-reads=true",
+            "--strict-lin-kv=true",
+            "--catch-up-batch=10",
+            "--catch-up-interval=5",
+            "--witnesses=n3",
+            "--write-batch-interval=2",
+        ];
+
+        let config = Config::parse(env, args.map(str::to_owned)).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                gossip_interval: Duration::from_millis(50),
+                gossip_jitter: Duration::from_millis(5),
+                batch_size: 8,
+                redis_url: "redis://redis:6379/".to_owned(),
+                log_level: Some("debug".to_owned()),
+                peers: HashMap::from([
+                    ("n2".to_owned(), "localhost:7002".to_owned()),
+                    ("n3".to_owned(), "localhost:7003".to_owned()),
+                ]),
+                reply_cache: Some(1000),
+                lane_policy: LanePolicy::ClientFirst,
+                handler_budget: Some(Duration::from_millis(20)),
+                panic_policy: PanicPolicy::Abort,
+                omit_nulls: true,
+                read_buffer: 1048576,
+                election_timeout: Duration::from_millis(300)..Duration::from_millis(600),
+                lease_reads: true,
+                replication_window: 8,
+                quorum_reads: true,
+                strict_lin_kv: true,
+                catch_up_batch: 10,
+                catch_up_interval: Duration::from_millis(5),
+                witnesses: vec!["n3".to_owned()],
+                write_batch_interval: Duration::from_millis(2),
+                ..Config::default()
+            }
+        );
+
+        let invalid = |args: &[&str]| {
+            Config::parse(|_| None, args.iter().map(|arg| arg.to_string())).is_err()
+        };
+        assert!(invalid(&["--retry-timeout", "soon"]));
+        assert!(invalid(&["--workers", "4"]));
+        assert!(invalid(&["--batch-size"]));
+        assert!(invalid(&["--peers", "n2"]));
+        assert!(invalid(&["--lane-policy", "lifo"]));
+        assert!(invalid(&["--election-timeout", "300"]));
+        assert!(invalid(&["--election-timeout", "300-150"]));
+    }
+}
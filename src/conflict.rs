@@ -0,0 +1,180 @@
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+/// A replicated value tagged with the logical timestamp and the node that
+/// wrote it, which is what resolvers use to pick a winner.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Versioned<V> {
+    pub value: V,
+    pub timestamp: u64,
+    pub node_id: String,
+}
+
+impl<V> Versioned<V> {
+    pub fn new(value: V, timestamp: u64, node_id: &str) -> Self {
+        Self {
+            value,
+            timestamp,
+            node_id: node_id.to_owned(),
+        }
+    }
+}
+
+/// Decides what a key converges to when two concurrent versions meet during
+/// replication or anti-entropy. Implementations must be commutative so every
+/// replica ends up with the same value regardless of delivery order.
+pub trait ConflictResolver<V> {
+    fn resolve(&self, local: &Versioned<V>, remote: &Versioned<V>) -> Versioned<V>;
+}
+
+/// Highest timestamp wins, ties broken by node id.
+pub struct LastWriteWins;
+
+impl<V: Clone> ConflictResolver<V> for LastWriteWins {
+    fn resolve(&self, local: &Versioned<V>, remote: &Versioned<V>) -> Versioned<V> {
+        if (remote.timestamp, &remote.node_id) > (local.timestamp, &local.node_id) {
+            remote.clone()
+        } else {
+            local.clone()
+        }
+    }
+}
+
+/// Writes from the highest node id always win, ties broken by timestamp.
+/// Node ids compare by their numeric suffix, so `n10` beats `n9`.
+pub struct HighestNodeId;
+
+impl<V: Clone> ConflictResolver<V> for HighestNodeId {
+    fn resolve(&self, local: &Versioned<V>, remote: &Versioned<V>) -> Versioned<V> {
+        if (node_order(&remote.node_id), remote.timestamp)
+            > (node_order(&local.node_id), local.timestamp)
+        {
+            remote.clone()
+        } else {
+            local.clone()
+        }
+    }
+}
+
+/// Sort key of `node_id`: its numeric suffix, then the whole id for ids that
+/// share one.
+fn node_order(node_id: &str) -> (Option<u64>, &str) {
+    let prefix = node_id.trim_end_matches(|c: char| c.is_ascii_digit());
+
+    (node_id[prefix.len()..].parse().ok(), node_id)
+}
+
+type MergeFn<V> = dyn Fn(&Versioned<V>, &Versioned<V>) -> Versioned<V> + Send;
+
+/// Delegates to a user supplied merge function.
+pub struct CustomMerge<V> {
+    merge: Box<MergeFn<V>>,
+}
+
+impl<V> CustomMerge<V> {
+    pub fn new<F>(merge: F) -> Self
+    where
+        F: Fn(&Versioned<V>, &Versioned<V>) -> Versioned<V> + Send + 'static,
+    {
+        Self {
+            merge: Box::new(merge),
+        }
+    }
+}
+
+impl<V> ConflictResolver<V> for CustomMerge<V> {
+    fn resolve(&self, local: &Versioned<V>, remote: &Versioned<V>) -> Versioned<V> {
+        (self.merge)(local, remote)
+    }
+}
+
+/// Builds a resolver from its configuration name: `lww`, `highest-node-id`
+/// or `max-value` (a custom merge keeping the largest value).
+pub fn resolver_from_name<V>(name: &str) -> anyhow::Result<Box<dyn ConflictResolver<V> + Send>>
+where
+    V: Ord + Clone + 'static,
+{
+    let resolver: Box<dyn ConflictResolver<V> + Send> = match name {
+        "lww" => Box::new(LastWriteWins),
+        "highest-node-id" => Box::new(HighestNodeId),
+        "max-value" => Box::new(CustomMerge::new(|local: &Versioned<V>, remote| {
+            if (&remote.value, remote.timestamp, &remote.node_id)
+                > (&local.value, local.timestamp, &local.node_id)
+            {
+                remote.clone()
+            } else {
+                local.clone()
+            }
+        })),
+        _ => bail!("Unknown conflict resolver {name}"),
+    };
+
+    Ok(resolver)
+}
+
+/// Reads the resolver name from the `CONFLICT_RESOLVER` environment variable,
+/// defaulting to last-write-wins.
+pub fn resolver_from_env<V>() -> anyhow::Result<Box<dyn ConflictResolver<V> + Send>>
+where
+    V: Ord + Clone + 'static,
+{
+    let name = std::env::var("CONFLICT_RESOLVER").unwrap_or_else(|_| "lww".to_owned());
+
+    resolver_from_name(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolver_from_name, ConflictResolver, HighestNodeId, LastWriteWins, Versioned};
+
+    /// Resolves both ways round, checking both replicas converge.
+    fn resolve<R>(resolver: &R, a: &Versioned<usize>, b: &Versioned<usize>) -> Versioned<usize>
+    where
+        R: ConflictResolver<usize> + ?Sized,
+    {
+        let resolved = resolver.resolve(a, b);
+        assert_eq!(resolved, resolver.resolve(b, a));
+
+        resolved
+    }
+
+    #[test]
+    fn test_last_write_wins() {
+        let older = Versioned::new(1, 1, "n2");
+        let newer = Versioned::new(2, 2, "n1");
+        assert_eq!(resolve(&LastWriteWins, &older, &newer), newer);
+
+        // Same timestamp, the node id decides
+        let tied = Versioned::new(3, 2, "n3");
+        assert_eq!(resolve(&LastWriteWins, &newer, &tied), tied);
+    }
+
+    #[test]
+    fn test_highest_node_id() {
+        let n9 = Versioned::new(1, 5, "n9");
+        let n10 = Versioned::new(2, 1, "n10");
+        assert_eq!(resolve(&HighestNodeId, &n9, &n10), n10);
+
+        // Same node, the timestamp decides
+        let later = Versioned::new(3, 2, "n10");
+        assert_eq!(resolve(&HighestNodeId, &n10, &later), later);
+
+        // Ids without a number come before those with one
+        let unnumbered = Versioned::new(4, 9, "n");
+        assert_eq!(resolve(&HighestNodeId, &unnumbered, &n9), n9);
+    }
+
+    #[test]
+    fn test_max_value() {
+        let resolver = resolver_from_name::<usize>("max-value").unwrap();
+        let small = Versioned::new(1, 5, "n2");
+        let large = Versioned::new(7, 1, "n1");
+        assert_eq!(resolve(resolver.as_ref(), &small, &large), large);
+
+        // Same value, the timestamp then the node id decide
+        let tied = Versioned::new(7, 1, "n3");
+        assert_eq!(resolve(resolver.as_ref(), &large, &tied), tied);
+
+        assert!(resolver_from_name::<usize>("oldest").is_err());
+    }
+}
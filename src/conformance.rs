@@ -0,0 +1,209 @@
+//! Helpers for checking payload enums against the Maelstrom wire format.
+//! Challenges use them from their test modules with table-driven cases so a
+//! renamed field or variant can't silently break the workload checker, and
+//! with arbitrary payloads from [`strategies`] so hand-written serializers
+//! read back what they write. They panic on failure, so they're only built
+//! for tests and with the `testing` feature.
+
+use crate::{Init, InitPayload, Message};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+/// `init` exactly as Maelstrom sends it.
+pub const INIT: &str = r#"{"src":"c0","dest":"n1","body":{"type":"init","msg_id":1,"node_id":"n1","node_ids":["n1","n2","n3"]}}"#;
+
+/// `init_ok` as expected by Maelstrom in response to [`INIT`].
+pub const INIT_OK: &str = r#"{"src":"n1","dest":"c0","body":{"type":"init_ok","in_reply_to":1}}"#;
+
+/// `error` exactly as shown in Maelstrom's protocol docs.
+pub const ERROR: &str = r#"{"src":"n1","dest":"c1","body":{"type":"error","in_reply_to":5,"code":11,"text":"Node n5 is waiting for quorum and cannot service requests yet"}}"#;
+
+/// The node described by [`INIT`], for building nodes in tests.
+pub fn init() -> Init {
+    let message = serde_json::from_str::<Message<InitPayload>>(INIT).expect("Invalid init message");
+    let InitPayload::Init(init) = message.body.payload else {
+        panic!("INIT is not an init message");
+    };
+
+    init
+}
+
+/// Parses every case as a `Message<P>`, serializes it back and checks the
+/// result is the same JSON document: same envelope, same snake_case `type`
+/// tag and same field names. Absent and `null` optional fields are treated
+/// as equivalent.
+pub fn assert_round_trip<P>(cases: &[&str])
+where
+    P: Serialize + DeserializeOwned,
+{
+    for case in cases {
+        let message = serde_json::from_str::<Message<P>>(case)
+            .unwrap_or_else(|e| panic!("Failed to parse {case}: {e}"));
+        let serialized = serde_json::to_value(&message).expect("Failed to serialize message");
+
+        assert_envelope(&serialized);
+        assert_eq!(
+            normalize(serde_json::from_str(case).expect("Invalid JSON case")),
+            normalize(serialized),
+            "Round trip changed {case}"
+        );
+    }
+}
+
+/// Serializes `message`, parses it back as a `Message<P>` and checks that
+/// serializing it again gives the same JSON document, so nothing is lost or
+/// changed on the way. Meant for property tests feeding it arbitrary
+/// messages.
+pub fn assert_message_round_trip<P>(message: &Message<P>)
+where
+    P: Serialize + DeserializeOwned,
+{
+    let serialized = serde_json::to_string(message).expect("Failed to serialize message");
+    let parsed = serde_json::from_str::<Message<P>>(&serialized)
+        .unwrap_or_else(|e| panic!("Failed to parse {serialized}: {e}"));
+    let reserialized = serde_json::to_value(&parsed).expect("Failed to serialize message");
+
+    assert_envelope(&reserialized);
+    assert_eq!(
+        normalize(serde_json::from_str(&serialized).expect("Invalid JSON")),
+        normalize(reserialized),
+        "Round trip changed {serialized}"
+    );
+}
+
+/// Checks that outbound messages serialize to the expected JSON documents.
+pub fn assert_serializes<P>(cases: &[(Message<P>, &str)])
+where
+    P: Serialize,
+{
+    for (message, expected) in cases {
+        let serialized = serde_json::to_value(message).expect("Failed to serialize message");
+
+        assert_envelope(&serialized);
+        assert_eq!(
+            normalize(serde_json::from_str(expected).expect("Invalid JSON case")),
+            normalize(serialized),
+            "Unexpected wire format for {expected}"
+        );
+    }
+}
+
+/// Checks `reply` is addressed back to the sender of `request` and
+/// correlated with it through `in_reply_to`.
+pub fn assert_reply_to<P>(request: &Message<P>, reply: &Message<P>) {
+    assert_eq!(
+        reply.src(),
+        request.dest(),
+        "Reply sent from the wrong node"
+    );
+    assert_eq!(reply.dest(), request.src(), "Reply sent to the wrong node");
+    assert_eq!(
+        reply.in_reply_to(),
+        request.msg_id(),
+        "Reply not correlated with its request"
+    );
+}
+
+fn assert_envelope(message: &Value) {
+    for field in ["src", "dest", "body"] {
+        assert!(message.get(field).is_some(), "Missing {field} in {message}");
+    }
+
+    let kind = message["body"]["type"]
+        .as_str()
+        .unwrap_or_else(|| panic!("Missing body type in {message}"));
+
+    assert!(
+        kind.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+        "Type {kind} is not snake_case"
+    );
+}
+
+fn normalize(mut message: Value) -> Value {
+    if let Some(body) = message.get_mut("body").and_then(Value::as_object_mut) {
+        body.retain(|_, value| !value.is_null());
+    }
+
+    message
+}
+
+/// Proptest strategies for the envelope of messages, to wrap around
+/// strategies of payloads. Available to tests and with the `proptest`
+/// feature, which enables `testing` too.
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies {
+    use crate::{Body, Message};
+    use proptest::{option, prelude::*};
+
+    /// Ids as Maelstrom names nodes, clients and its own services.
+    pub fn node_id() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (1..=5u8).prop_map(|i| format!("n{i}")),
+            (1..=5u8).prop_map(|i| format!("c{i}")),
+            Just("lin-kv".to_owned()),
+        ]
+    }
+
+    /// Messages between any two of [`node_id`], carrying payloads of
+    /// `payload`.
+    pub fn message<P, S>(payload: S) -> impl Strategy<Value = Message<P>>
+    where
+        P: std::fmt::Debug,
+        S: Strategy<Value = P>,
+    {
+        (
+            node_id(),
+            node_id(),
+            option::of(any::<usize>()),
+            option::of(any::<usize>()),
+            payload,
+        )
+            .prop_map(|(src, dest, msg_id, in_reply_to, payload)| {
+                Message::new(src, dest, Body::new(msg_id, in_reply_to, payload))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        assert_message_round_trip, assert_round_trip, assert_serializes, init, strategies, ERROR,
+        INIT, INIT_OK,
+    };
+    use crate::{Body, ErrorPayload, InitPayload, Message};
+    use proptest::prelude::*;
+
+    #[test]
+    fn test_init_wire_format() {
+        assert_round_trip::<InitPayload>(&[INIT]);
+        assert_serializes(&[(
+            Message::new(
+                "n1".to_owned(),
+                "c0".to_owned(),
+                Body::new(None, Some(1), InitPayload::InitOk),
+            ),
+            INIT_OK,
+        )]);
+
+        assert_eq!(init().node_id, "n1");
+        assert_eq!(init().node_ids, ["n1", "n2", "n3"]);
+    }
+
+    #[test]
+    fn test_error_wire_format() {
+        assert_round_trip::<ErrorPayload>(&[ERROR]);
+    }
+
+    proptest! {
+        #[test]
+        fn test_error_round_trip(message in strategies::message(
+            (any::<u64>(), ".*").prop_map(|(code, text)| ErrorPayload::Error {
+                code: code.into(),
+                text,
+            }),
+        )) {
+            assert_message_round_trip(&message);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+//! State-based CRDTs. Replicas converge by merging each other's state, in
+//! any order and any number of times, so they can be gossiped without any
+//! coordination. Mutations return the delta they made, which is enough to
+//! replicate them.
+
+use crate::conflict::{ConflictResolver, LastWriteWins, Versioned};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    hash::Hash,
+};
+
+pub trait Crdt {
+    /// Merges `other` into this state. Must be commutative, associative and
+    /// idempotent.
+    fn merge(&mut self, other: &Self);
+
+    /// The part of this state `known` is missing: merging it into `known`
+    /// has the same effect as merging the whole state.
+    fn delta(&self, known: &Self) -> Self;
+}
+
+/// Counter that only grows, one count per node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `n` to `node_id`'s count, returning the delta.
+    pub fn increment(&mut self, node_id: &str, n: u64) -> Self {
+        let count = self.counts.entry(node_id.to_owned()).or_default();
+        *count += n;
+
+        Self {
+            counts: BTreeMap::from([(node_id.to_owned(), *count)]),
+        }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        for (node_id, count) in &other.counts {
+            let own = self.counts.entry(node_id.clone()).or_default();
+            *own = (*own).max(*count);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Self {
+        let counts = self
+            .counts
+            .iter()
+            .filter(|(node_id, count)| known.counts.get(*node_id).is_none_or(|c| c < count))
+            .map(|(node_id, count)| (node_id.clone(), *count))
+            .collect();
+
+        Self { counts }
+    }
+}
+
+/// Counter that can also be decremented, as a pair of [`GCounter`]s.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(&mut self, node_id: &str, n: u64) -> Self {
+        Self {
+            increments: self.increments.increment(node_id, n),
+            decrements: GCounter::new(),
+        }
+    }
+
+    pub fn decrement(&mut self, node_id: &str, n: u64) -> Self {
+        Self {
+            increments: GCounter::new(),
+            decrements: self.decrements.increment(node_id, n),
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn delta(&self, known: &Self) -> Self {
+        Self {
+            increments: self.increments.delta(&known.increments),
+            decrements: self.decrements.delta(&known.decrements),
+        }
+    }
+}
+
+/// Unique tag of one add to an [`OrSet`]: the node that made it and how
+/// many adds that node had made.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Dot {
+    pub node_id: String,
+    pub counter: u64,
+}
+
+/// Observed-remove set. A remove only cancels the adds its replica had seen,
+/// so an add concurrent with a remove of the same item wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "T: Serialize + Eq + Hash",
+    deserialize = "T: Deserialize<'de> + Eq + Hash"
+))]
+pub struct OrSet<T> {
+    added: HashSet<(T, Dot)>,
+    removed: HashSet<Dot>,
+    counters: BTreeMap<String, u64>,
+}
+
+impl<T: Eq + Hash> PartialEq for OrSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.added == other.added
+            && self.removed == other.removed
+            && self.counters == other.counters
+    }
+}
+
+impl<T: Eq + Hash> Eq for OrSet<T> {}
+
+impl<T> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            counters: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + Eq + Hash> OrSet<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, node_id: &str, item: T) -> Self {
+        let counter = self.counters.entry(node_id.to_owned()).or_default();
+        *counter += 1;
+
+        let dot = Dot {
+            node_id: node_id.to_owned(),
+            counter: *counter,
+        };
+        self.added.insert((item.clone(), dot.clone()));
+
+        Self {
+            added: HashSet::from([(item, dot)]),
+            removed: HashSet::new(),
+            counters: BTreeMap::from([(node_id.to_owned(), *counter)]),
+        }
+    }
+
+    /// Removes every add of `item` this replica has seen.
+    pub fn remove(&mut self, item: &T) -> Self {
+        let removed = self
+            .added
+            .iter()
+            .filter(|(added, dot)| added == item && !self.removed.contains(dot))
+            .map(|(_, dot)| dot.clone())
+            .collect::<HashSet<_>>();
+        self.removed.extend(removed.iter().cloned());
+
+        Self {
+            added: HashSet::new(),
+            removed,
+            counters: BTreeMap::new(),
+        }
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.added
+            .iter()
+            .any(|(added, dot)| added == item && !self.removed.contains(dot))
+    }
+
+    pub fn items(&self) -> HashSet<T> {
+        self.added
+            .iter()
+            .filter(|(_, dot)| !self.removed.contains(dot))
+            .map(|(item, _)| item.clone())
+            .collect()
+    }
+}
+
+impl<T: Clone + Eq + Hash> Crdt for OrSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.extend(other.added.iter().cloned());
+        self.removed.extend(other.removed.iter().cloned());
+
+        for (node_id, counter) in &other.counters {
+            let own = self.counters.entry(node_id.clone()).or_default();
+            *own = (*own).max(*counter);
+        }
+    }
+
+    fn delta(&self, known: &Self) -> Self {
+        Self {
+            added: self.added.difference(&known.added).cloned().collect(),
+            removed: self.removed.difference(&known.removed).cloned().collect(),
+            counters: self
+                .counters
+                .iter()
+                .filter(|(node_id, counter)| {
+                    known.counters.get(*node_id).is_none_or(|c| c < counter)
+                })
+                .map(|(node_id, counter)| (node_id.clone(), *counter))
+                .collect(),
+        }
+    }
+}
+
+/// Register keeping the write with the highest timestamp, ties broken by
+/// node id, like [`crate::conflict::LastWriteWins`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LwwRegister<V> {
+    current: Option<Versioned<V>>,
+}
+
+impl<V> Default for LwwRegister<V> {
+    fn default() -> Self {
+        Self { current: None }
+    }
+}
+
+impl<V: Clone> LwwRegister<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes `value`, returning the delta. The write is lost if the
+    /// register already holds a later one.
+    pub fn set(&mut self, value: V, timestamp: u64, node_id: &str) -> Self {
+        let delta = Self {
+            current: Some(Versioned::new(value, timestamp, node_id)),
+        };
+        self.merge(&delta);
+
+        delta
+    }
+
+    pub fn get(&self) -> Option<&V> {
+        self.current.as_ref().map(|versioned| &versioned.value)
+    }
+
+    pub fn version(&self) -> Option<&Versioned<V>> {
+        self.current.as_ref()
+    }
+}
+
+impl<V: Clone> Crdt for LwwRegister<V> {
+    fn merge(&mut self, other: &Self) {
+        self.current = match (&self.current, &other.current) {
+            (Some(local), Some(remote)) => Some(LastWriteWins.resolve(local, remote)),
+            (local, remote) => local.clone().or_else(|| remote.clone()),
+        };
+    }
+
+    fn delta(&self, known: &Self) -> Self {
+        let (Some(local), Some(known)) = (&self.current, &known.current) else {
+            return self.clone();
+        };
+
+        match (local.timestamp, &local.node_id) > (known.timestamp, &known.node_id) {
+            true => self.clone(),
+            false => Self::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crdt, GCounter, LwwRegister, OrSet, PnCounter};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::collections::HashSet;
+
+    /// Merges the replicas in both orders, checks they converge and that
+    /// deltas carry everything, then round trips the result through JSON.
+    fn assert_converges<C>(a: &C, b: &C) -> C
+    where
+        C: Crdt + Clone + PartialEq + std::fmt::Debug + Serialize + DeserializeOwned,
+    {
+        let mut ab = a.clone();
+        ab.merge(b);
+        let mut ba = b.clone();
+        ba.merge(a);
+        assert_eq!(ab, ba);
+
+        let mut again = ab.clone();
+        again.merge(b);
+        assert_eq!(again, ab);
+
+        let mut from_delta = a.clone();
+        from_delta.merge(&b.delta(a));
+        assert_eq!(from_delta, ab);
+
+        let json = serde_json::to_string(&ab).unwrap();
+        assert_eq!(serde_json::from_str::<C>(&json).unwrap(), ab);
+
+        ab
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut a = GCounter::new();
+        let mut b = GCounter::new();
+        a.increment("n1", 2);
+        let delta = b.increment("n2", 3);
+        b.increment("n2", 1);
+        assert_eq!(delta.value(), 3);
+        assert_eq!(assert_converges(&a, &b).value(), 6);
+
+        let mut a = PnCounter::new();
+        let mut b = PnCounter::new();
+        a.increment("n1", 5);
+        b.decrement("n2", 7);
+        assert_eq!(assert_converges(&a, &b).value(), -2);
+    }
+
+    #[test]
+    fn test_or_set() {
+        let mut a = OrSet::new();
+        a.insert("n1", 1);
+        a.insert("n1", 2);
+
+        let mut b = a.clone();
+        b.remove(&1);
+        // Concurrent with the remove, so it survives it
+        a.insert("n1", 1);
+        b.remove(&2);
+        b.insert("n2", 3);
+
+        let merged = assert_converges(&a, &b);
+        assert_eq!(merged.items(), HashSet::from([1, 3]));
+        assert!(!merged.contains(&2));
+    }
+
+    #[test]
+    fn test_lww_register() {
+        let mut a = LwwRegister::new();
+        let mut b = LwwRegister::new();
+        a.set("first".to_owned(), 1, "n1");
+        b.set("second".to_owned(), 2, "n1");
+        a.set("tie".to_owned(), 2, "n2");
+
+        assert_eq!(assert_converges(&a, &b).get().unwrap(), "tie");
+        assert_eq!(b.delta(&a), LwwRegister::new());
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{
+    scheduler::{Scheduler, TimerHandle},
+    Body, Message, MessageSender,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Heartbeat failure detector. Every interval the node sends each peer a
+/// heartbeat and any message a peer sends, heartbeat or not, counts as a sign
+/// of life. Peers not heard from within the suspicion timeout are suspected
+/// until they're heard from again, so a partition heals on its own.
+///
+/// Nodes tick rounds with [`FailureDetector::start`], wrap the heartbeats
+/// in their own payload in [`FailureDetector::heartbeat`] and call
+/// [`FailureDetector::observe`] for every message they receive from a peer.
+/// Heartbeats aren't replied to.
+pub struct FailureDetector {
+    node_id: String,
+    interval: Duration,
+    suspicion_timeout: Duration,
+    last_heard: HashMap<String, Instant>,
+}
+
+impl FailureDetector {
+    /// Every peer starts out alive, as if heard from right now.
+    pub fn new(node_id: &str, interval: Duration, suspicion_timeout: Duration) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            interval,
+            suspicion_timeout,
+            last_heard: HashMap::new(),
+        }
+    }
+
+    /// Ticks every interval, the node calls [`FailureDetector::heartbeat`]
+    /// when handling the [`crate::Event::Tick`] of the returned timer.
+    pub fn start<M>(&self, scheduler: &Scheduler<M>) -> TimerHandle
+    where
+        M: Clone + Send + 'static,
+    {
+        scheduler.tick_periodic(self.interval)
+    }
+
+    /// Starts watching `peers`, forgetting the ones left out.
+    pub fn set_peers<I: IntoIterator<Item = String>>(&mut self, peers: I) {
+        let now = Instant::now();
+        let mut last_heard = HashMap::new();
+
+        for peer in peers {
+            let heard = self.last_heard.get(&peer).copied().unwrap_or(now);
+            last_heard.insert(peer, heard);
+        }
+
+        self.last_heard = last_heard;
+    }
+
+    /// Sends every peer a heartbeat, made by `heartbeat`.
+    pub fn heartbeat<P, F>(&self, sender: &mut MessageSender<P>, heartbeat: F) -> anyhow::Result<()>
+    where
+        F: Fn() -> P,
+    {
+        let messages = self.last_heard.keys().map(|peer| {
+            Message::new(
+                self.node_id.clone(),
+                peer.clone(),
+                Body::new(None, None, heartbeat()),
+            )
+        });
+
+        sender.send_all(messages)
+    }
+
+    /// Records that `src` is alive. Messages from anyone but the peers, e.g.
+    /// clients, are ignored.
+    pub fn observe(&mut self, src: &str) {
+        if let Some(heard) = self.last_heard.get_mut(src) {
+            *heard = Instant::now();
+        }
+    }
+
+    /// Whether `peer` was heard from within the suspicion timeout. Unknown
+    /// peers are never alive.
+    pub fn is_alive(&self, peer: &str) -> bool {
+        self.last_heard
+            .get(peer)
+            .is_some_and(|heard| heard.elapsed() < self.suspicion_timeout)
+    }
+
+    /// Peers heard from within the suspicion timeout, sorted.
+    pub fn alive(&self) -> Vec<&str> {
+        self.peers(true)
+    }
+
+    /// Peers not heard from within the suspicion timeout, sorted.
+    pub fn suspected(&self) -> Vec<&str> {
+        self.peers(false)
+    }
+
+    fn peers(&self, alive: bool) -> Vec<&str> {
+        let mut peers = self
+            .last_heard
+            .keys()
+            .map(String::as_str)
+            .filter(|peer| self.is_alive(peer) == alive)
+            .collect::<Vec<_>>();
+        peers.sort_unstable();
+
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FailureDetector;
+    use crate::{writters::MemoryWritter, MessageSender};
+    use std::time::Duration;
+
+    #[test]
+    fn test_failure_detector() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+
+        let timeout = Duration::from_millis(20);
+        let mut detector = FailureDetector::new("n1", Duration::from_millis(5), timeout);
+        detector.set_peers(["n2".to_owned(), "n3".to_owned()]);
+        assert_eq!(detector.alive(), ["n2", "n3"]);
+        assert!(!detector.is_alive("n4"));
+
+        detector.heartbeat(&mut sender, || "heartbeat").unwrap();
+        let mut dests = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.dest().to_owned())
+            .collect::<Vec<_>>();
+        dests.sort();
+        assert_eq!(dests, ["n2", "n3"]);
+
+        std::thread::sleep(timeout);
+        detector.observe("n3");
+        detector.observe("c1");
+        assert_eq!(detector.alive(), ["n3"]);
+        assert_eq!(detector.suspected(), ["n2"]);
+
+        // Heard from again, no longer suspected
+        detector.observe("n2");
+        assert!(detector.suspected().is_empty());
+    }
+}
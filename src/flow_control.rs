@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Messages a node lets each peer have unacknowledged, and assumes a peer
+/// lets it have until the peer advertises otherwise.
+pub const RECEIVE_WINDOW: usize = 4;
+/// How long a message counts against the window of its peer without an ack.
+pub const GOSSIP_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The ack of a gossip message, advertising the window of its sender, see
+/// [`FlowControl::ack`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {
+    pub window: usize,
+}
+
+/// Credit-based flow control for internal traffic. Every peer advertises a
+/// receive window in its acks and senders stop pushing messages to that peer
+/// once the number of unacknowledged messages reaches the window.
+///
+/// Messages whose ack never arrives (dropped by a partition) stop counting
+/// against the window after `timeout`, so a peer can't get stuck at zero credit.
+pub struct FlowControl {
+    default_window: usize,
+    timeout: Duration,
+    peers: HashMap<String, PeerWindow>,
+}
+
+struct PeerWindow {
+    window: usize,
+    /// When each message still unacknowledged was sent, by `msg_id`.
+    in_flight: HashMap<usize, Instant>,
+}
+
+impl FlowControl {
+    pub fn new(default_window: usize, timeout: Duration) -> Self {
+        Self {
+            default_window,
+            timeout,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Takes one credit from `peer`'s window for the message `msg_id`,
+    /// returning `false` if the window is exhausted and nothing should be
+    /// sent to it right now.
+    pub fn try_acquire(&mut self, peer: &str, msg_id: usize) -> bool {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let peer = self.peer_mut(peer);
+
+        peer.in_flight
+            .retain(|_, sent_at| now.duration_since(*sent_at) < timeout);
+
+        if peer.in_flight.len() >= peer.window {
+            return false;
+        }
+
+        peer.in_flight.insert(msg_id, now);
+        true
+    }
+
+    /// Returns the credit `msg_id` took from `peer` and records the window
+    /// the peer advertised in its ack.
+    pub fn on_ack(&mut self, peer: &str, msg_id: usize, window: usize) {
+        let peer = self.peer_mut(peer);
+
+        peer.in_flight.remove(&msg_id);
+        peer.window = window;
+    }
+
+    pub fn in_flight(&self, peer: &str) -> usize {
+        self.peers.get(peer).map_or(0, |p| p.in_flight.len())
+    }
+
+    /// The ack to send for a message from a peer: the whole window, or a
+    /// single message while this node is `catching_up` through a state
+    /// transfer, so peers don't bury it while it recovers.
+    pub fn ack(&self, catching_up: bool) -> Ack {
+        let window = if catching_up { 1 } else { self.default_window };
+
+        Ack { window }
+    }
+
+    fn peer_mut(&mut self, peer: &str) -> &mut PeerWindow {
+        let default_window = self.default_window;
+
+        self.peers
+            .entry(peer.to_owned())
+            .or_insert_with(|| PeerWindow {
+                window: default_window,
+                in_flight: HashMap::new(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ack, FlowControl};
+    use std::time::Duration;
+
+    #[test]
+    fn test_flow_control() {
+        let mut flow_control = FlowControl::new(2, Duration::from_secs(60));
+        assert!(flow_control.try_acquire("n2", 1));
+        assert!(flow_control.try_acquire("n2", 2));
+        assert!(!flow_control.try_acquire("n2", 3));
+        // Windows are per peer
+        assert!(flow_control.try_acquire("n3", 4));
+
+        // The ack frees the credit of the message it answers
+        flow_control.on_ack("n2", 2, 2);
+        assert_eq!(flow_control.in_flight("n2"), 1);
+        flow_control.on_ack("n2", 2, 2);
+        assert_eq!(flow_control.in_flight("n2"), 1);
+        flow_control.on_ack("n2", 1, 2);
+        assert_eq!(flow_control.in_flight("n2"), 0);
+
+        // Peers advertise smaller windows when they need to
+        flow_control.on_ack("n3", 4, 1);
+        assert!(flow_control.try_acquire("n3", 5));
+        assert!(!flow_control.try_acquire("n3", 6));
+    }
+
+    #[test]
+    fn test_unacknowledged_messages_time_out() {
+        let mut flow_control = FlowControl::new(1, Duration::from_millis(20));
+        assert!(flow_control.try_acquire("n2", 1));
+        assert!(!flow_control.try_acquire("n2", 2));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(flow_control.try_acquire("n2", 3));
+        assert_eq!(flow_control.in_flight("n2"), 1);
+    }
+
+    #[test]
+    fn test_ack() {
+        let flow_control = FlowControl::new(4, Duration::from_secs(1));
+        assert_eq!(flow_control.ack(false), Ack { window: 4 });
+        assert_eq!(flow_control.ack(true), Ack { window: 1 });
+    }
+}
@@ -0,0 +1,238 @@
+use crate::{
+    flow_control::{Ack, FlowControl},
+    scheduler::{Scheduler, TimerHandle},
+    Body, Message, MessageSender,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    time::{Duration, Instant},
+};
+
+struct InFlight<T> {
+    peer: String,
+    delta: Vec<T>,
+    sent_at: Instant,
+}
+
+/// Anti-entropy gossip of a growing set of items. The engine keeps the set,
+/// what every peer is known to hold and the gossip sent to each of them, so
+/// every round only carries what a peer is missing. Peers stop getting
+/// gossip once their receive window is full, see [`FlowControl`].
+///
+/// Nodes tick rounds with [`GossipEngine::start`], turn the deltas into
+/// their own payload in [`GossipEngine::round`] and feed back what peers
+/// send and ack; what a node does with new items is up to the merge
+/// function passed to [`GossipEngine::receive`].
+pub struct GossipEngine<T> {
+    node_id: String,
+    interval: Duration,
+    /// Most rounds are moved earlier or later, see
+    /// [`Scheduler::tick_jittered`].
+    jitter: Duration,
+    ack_timeout: Duration,
+    items: HashSet<T>,
+    peers: Vec<String>,
+    known: HashMap<String, HashSet<T>>,
+    in_flight: HashMap<usize, InFlight<T>>,
+    flow_control: FlowControl,
+}
+
+impl<T: Clone + Eq + Hash> GossipEngine<T> {
+    pub fn new(node_id: &str, interval: Duration, window: usize, ack_timeout: Duration) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            interval,
+            jitter: Duration::ZERO,
+            ack_timeout,
+            items: HashSet::new(),
+            peers: Vec::new(),
+            known: HashMap::new(),
+            in_flight: HashMap::new(),
+            flow_control: FlowControl::new(window, ack_timeout),
+        }
+    }
+
+    /// Moves every round up to `jitter` earlier or later, so the nodes of a
+    /// cluster don't all gossip at the same time.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Ticks every interval, give or take the jitter, the node calls
+    /// [`GossipEngine::round`] when handling the [`crate::Event::Tick`] of
+    /// the returned timer.
+    pub fn start<M>(&self, scheduler: &Scheduler<M>) -> TimerHandle
+    where
+        M: Clone + Send + 'static,
+    {
+        if self.jitter.is_zero() {
+            return scheduler.tick_periodic(self.interval);
+        }
+
+        scheduler.tick_jittered(self.interval, self.jitter)
+    }
+
+    pub fn set_peers(&mut self, peers: Vec<String>) {
+        self.peers = peers;
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn items(&self) -> &HashSet<T> {
+        &self.items
+    }
+
+    /// Adds an item learned from a client, returning whether it is new.
+    pub fn insert(&mut self, item: T) -> bool {
+        self.items.insert(item)
+    }
+
+    /// Records that `peer` holds every item of `delta` and adds the new ones,
+    /// calling `merge` for each of them.
+    pub fn receive<I, F>(&mut self, peer: &str, delta: I, mut merge: F)
+    where
+        I: IntoIterator<Item = T>,
+        F: FnMut(&T),
+    {
+        let known = self.known.entry(peer.to_owned()).or_default();
+
+        for item in delta {
+            if self.items.insert(item.clone()) {
+                merge(&item);
+            }
+            known.insert(item);
+        }
+    }
+
+    /// Sends every peer with credit left the items it isn't known to hold,
+    /// wrapped in a payload by `gossip`. Peers missing nothing are skipped.
+    pub fn round<P, F>(
+        &mut self,
+        sender: &mut MessageSender<P>,
+        mut gossip: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Vec<T>) -> P,
+    {
+        let ack_timeout = self.ack_timeout;
+        self.in_flight
+            .retain(|_, in_flight| in_flight.sent_at.elapsed() < ack_timeout);
+
+        let mut messages = Vec::new();
+        for peer in &self.peers {
+            let known = self.known.entry(peer.clone()).or_default();
+            let delta = self
+                .items
+                .iter()
+                .filter(|item| !known.contains(item))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            if delta.is_empty() {
+                continue;
+            }
+            let msg_id = sender.next_msg_id();
+            if !self.flow_control.try_acquire(peer, msg_id) {
+                continue;
+            }
+
+            messages.push(Message::new(
+                self.node_id.clone(),
+                peer.clone(),
+                Body::new(Some(msg_id), None, gossip(delta.clone())),
+            ));
+            self.in_flight.insert(
+                msg_id,
+                InFlight {
+                    peer: peer.clone(),
+                    delta,
+                    sent_at: Instant::now(),
+                },
+            );
+        }
+
+        sender.send_all(messages)
+    }
+
+    /// Handles the ack of a gossip message: the peer holds its delta now and
+    /// advertised `window`. Returns `false` if `reply` doesn't answer a
+    /// gossip still in flight.
+    pub fn on_ack<P>(&mut self, reply: &Message<P>, window: usize) -> bool {
+        let Some((msg_id, in_flight)) = reply
+            .in_reply_to()
+            .and_then(|msg_id| self.in_flight.remove_entry(&msg_id))
+        else {
+            return false;
+        };
+
+        self.flow_control.on_ack(&in_flight.peer, msg_id, window);
+        self.known
+            .entry(in_flight.peer)
+            .or_default()
+            .extend(in_flight.delta);
+
+        true
+    }
+
+    /// The ack to send for gossip from a peer, see [`FlowControl::ack`].
+    pub fn ack(&self, catching_up: bool) -> Ack {
+        self.flow_control.ack(catching_up)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GossipEngine;
+    use crate::{writters::MemoryWritter, MessageSender};
+    use std::{collections::HashSet, time::Duration};
+
+    #[test]
+    fn test_gossip_engine() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+
+        let mut engine =
+            GossipEngine::new("n1", Duration::from_millis(100), 1, Duration::from_secs(60));
+        engine.set_peers(vec!["n2".to_owned(), "n3".to_owned()]);
+        engine.insert(1);
+        engine.receive("n2", [2], |_| {});
+
+        engine.round(&mut sender, |delta| delta).unwrap();
+        let round = sent.lock().unwrap().drain(..).collect::<Vec<_>>();
+        let deltas = round
+            .iter()
+            .map(|m| {
+                (
+                    m.dest().to_owned(),
+                    m.body().payload.iter().copied().collect(),
+                )
+            })
+            .collect::<Vec<(String, HashSet<_>)>>();
+        assert_eq!(
+            deltas,
+            [
+                ("n2".to_owned(), HashSet::from([1])),
+                ("n3".to_owned(), HashSet::from([1, 2])),
+            ]
+        );
+
+        // No credit left until the gossip is acked
+        engine.insert(3);
+        engine.round(&mut sender, |delta| delta).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        assert!(engine.on_ack(&round[0].reply(()), 1));
+        assert!(!engine.on_ack(&round[0].reply(()), 1));
+        engine.round(&mut sender, |delta| delta).unwrap();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].dest(), "n2");
+        assert_eq!(sent[0].body().payload, [3]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Versions of the payloads nodes exchange among themselves, e.g. kafka's
+//! `internal_send` or the transactions' `internal_txn`. Right after `init`
+//! every node tells its peers which version it speaks, with a
+//! `{"type": "protocol_version", "version": 1}` message. A node that hears
+//! from a peer of another version, e.g. one built from an older checkout,
+//! reports it on stderr and drops what that peer sends it that it can't
+//! parse, saying why, instead of reporting it as an unknown message. Nodes
+//! may also check [`crate::NodeContext::peer_version`] to fall back to what
+//! older peers understand.
+
+use crate::{Body, Message};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// Version of the internal payloads of this build, bumped whenever one of
+/// them changes in a way older nodes can't parse.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Announcement of the version the sender speaks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum ProtocolVersion {
+    ProtocolVersion { version: u32 },
+}
+
+/// The version of this node and the ones its peers announced, shared by the
+/// main loop and the workers of [`crate::concurrent`].
+#[derive(Debug, Clone)]
+pub struct Versions {
+    version: u32,
+    peers: Arc<RwLock<HashMap<String, u32>>>,
+}
+
+impl Default for Versions {
+    fn default() -> Self {
+        Self::new(PROTOCOL_VERSION)
+    }
+}
+
+impl Versions {
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            peers: Arc::default(),
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// What `node_id` sends `peers` to announce its version.
+    pub fn announcements(&self, node_id: &str, peers: &[String]) -> Vec<Message<Value>> {
+        peers
+            .iter()
+            .filter(|peer| *peer != node_id)
+            .map(|peer| {
+                let payload = serde_json::json!({
+                    "type": "protocol_version",
+                    "version": self.version,
+                });
+                Message::new(
+                    node_id.to_owned(),
+                    peer.clone(),
+                    Body::new(None, None, payload),
+                )
+            })
+            .collect()
+    }
+
+    /// Version `peer` announced, `None` until it does. Nodes that predate
+    /// the handshake never do.
+    pub fn peer_version(&self, peer: &str) -> Option<u32> {
+        self.peers.read().unwrap().get(peer).copied()
+    }
+
+    /// Version `peer` announced, if it isn't this node's.
+    pub fn incompatible(&self, peer: &str) -> Option<u32> {
+        self.peer_version(peer)
+            .filter(|version| *version != self.version)
+    }
+
+    /// Records the version `peer` announced, reporting on stderr when it
+    /// isn't this node's.
+    pub(crate) fn observe(&self, peer: &str, version: u32) {
+        if version != self.version {
+            eprintln!(
+                "{peer} speaks protocol version {version} but this node speaks {}, \
+                 its internal messages may not parse",
+                self.version
+            );
+        }
+
+        self.peers.write().unwrap().insert(peer.to_owned(), version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Versions;
+
+    #[test]
+    fn test_versions() {
+        let versions = Versions::new(2);
+        let peers = ["n1", "n2", "n3"].map(str::to_owned);
+        let announcements = versions.announcements("n1", &peers);
+        assert_eq!(announcements.len(), 2);
+        assert_eq!(announcements[0].body().payload["version"], 2);
+
+        versions.observe("n2", 2);
+        versions.observe("n3", 1);
+        assert_eq!(versions.peer_version("n2"), Some(2));
+        assert_eq!(versions.incompatible("n2"), None);
+        assert_eq!(versions.incompatible("n3"), Some(1));
+        // Never announced, assumed to speak the same
+        assert_eq!(versions.incompatible("n4"), None);
+    }
+}
@@ -0,0 +1,369 @@
+use crate::{convert_payload, rpc::Rpc, Body, ErrorCode, Message, MessageSender};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+/// The key-value stores Maelstrom runs next to the nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvService {
+    /// Linearizable.
+    Lin,
+    /// Sequentially consistent.
+    Seq,
+    /// Last write wins, reads may be stale.
+    Lww,
+}
+
+impl KvService {
+    /// Node id the service is addressed as.
+    pub fn address(&self) -> &'static str {
+        match self {
+            KvService::Lin => "lin-kv",
+            KvService::Seq => "seq-kv",
+            KvService::Lww => "lww-kv",
+        }
+    }
+}
+
+/// Messages of the KV services. Node payloads carry them through a last
+/// `#[serde(untagged)] Kv(KvPayload)` variant, or through variants of their
+/// own with the same wire format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum KvPayload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+/// Why a KV operation failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvError {
+    KeyDoesNotExist,
+    /// A `cas` found a value other than `from`.
+    PreconditionFailed(String),
+    /// Any other error the service answered with.
+    Service {
+        code: ErrorCode,
+        text: String,
+    },
+    /// The reply isn't one the operation can answer with.
+    UnexpectedReply(String),
+}
+
+impl std::fmt::Display for KvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "Key does not exist"),
+            KvError::PreconditionFailed(text) => write!(f, "Precondition failed: {text}"),
+            KvError::Service { code, text } => {
+                write!(f, "KV error {}: {text}", u64::from(*code))
+            }
+            KvError::UnexpectedReply(reply) => write!(f, "Unexpected KV reply: {reply}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl KvError {
+    fn from_reply(code: ErrorCode, text: String) -> Self {
+        match code {
+            ErrorCode::KeyDoesNotExist => KvError::KeyDoesNotExist,
+            ErrorCode::PreconditionFailed => KvError::PreconditionFailed(text),
+            _ => KvError::Service { code, text },
+        }
+    }
+}
+
+/// Client for one of the KV services. Replies arrive through the node's
+/// [`Rpc`], so every operation takes a callback invoked with the node and
+/// the outcome once the service answers.
+#[derive(Debug, Clone)]
+pub struct KvClient {
+    service: KvService,
+    node_id: String,
+}
+
+impl KvClient {
+    pub fn new(service: KvService, node_id: &str) -> Self {
+        Self {
+            service,
+            node_id: node_id.to_owned(),
+        }
+    }
+
+    pub fn read<N, P, K, V, F>(
+        &self,
+        sender: &mut MessageSender<P>,
+        rpc: &mut Rpc<N, P>,
+        key: K,
+        callback: F,
+    ) -> anyhow::Result<()>
+    where
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        K: Serialize,
+        V: DeserializeOwned,
+        F: FnOnce(&mut N, Result<V, KvError>) -> anyhow::Result<()> + Send + 'static,
+    {
+        let request = KvPayload::Read {
+            key: serde_json::to_value(key).context("Error serializing KV key")?,
+        };
+
+        self.call(sender, rpc, request, |node, reply| {
+            let result = match reply {
+                Ok(KvPayload::ReadOk { value }) => serde_json::from_value(value)
+                    .map_err(|e| KvError::UnexpectedReply(e.to_string())),
+                Ok(other) => Err(KvError::UnexpectedReply(format!("{other:?}"))),
+                Err(e) => Err(e),
+            };
+
+            callback(node, result)
+        })
+    }
+
+    pub fn write<N, P, K, V, F>(
+        &self,
+        sender: &mut MessageSender<P>,
+        rpc: &mut Rpc<N, P>,
+        key: K,
+        value: V,
+        callback: F,
+    ) -> anyhow::Result<()>
+    where
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut N, Result<(), KvError>) -> anyhow::Result<()> + Send + 'static,
+    {
+        let request = KvPayload::Write {
+            key: serde_json::to_value(key).context("Error serializing KV key")?,
+            value: serde_json::to_value(value).context("Error serializing KV value")?,
+        };
+
+        self.call(sender, rpc, request, |node, reply| {
+            callback(node, expect(reply, KvPayload::WriteOk))
+        })
+    }
+
+    /// Sets `key` to `to` if it currently holds `from`.
+    pub fn cas<N, P, K, V, F>(
+        &self,
+        sender: &mut MessageSender<P>,
+        rpc: &mut Rpc<N, P>,
+        key: K,
+        from: V,
+        to: V,
+        callback: F,
+    ) -> anyhow::Result<()>
+    where
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut N, Result<(), KvError>) -> anyhow::Result<()> + Send + 'static,
+    {
+        let request = cas_request(key, from, to, false)?;
+
+        self.call(sender, rpc, request, |node, reply| {
+            callback(node, expect(reply, KvPayload::CasOk))
+        })
+    }
+
+    /// Same as [`KvClient::cas`], creating `key` with `to` if missing.
+    pub fn cas_or_create<N, P, K, V, F>(
+        &self,
+        sender: &mut MessageSender<P>,
+        rpc: &mut Rpc<N, P>,
+        key: K,
+        from: V,
+        to: V,
+        callback: F,
+    ) -> anyhow::Result<()>
+    where
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(&mut N, Result<(), KvError>) -> anyhow::Result<()> + Send + 'static,
+    {
+        let request = cas_request(key, from, to, true)?;
+
+        self.call(sender, rpc, request, |node, reply| {
+            callback(node, expect(reply, KvPayload::CasOk))
+        })
+    }
+
+    fn call<N, P, F>(
+        &self,
+        sender: &mut MessageSender<P>,
+        rpc: &mut Rpc<N, P>,
+        request: KvPayload,
+        callback: F,
+    ) -> anyhow::Result<()>
+    where
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce(&mut N, Result<KvPayload, KvError>) -> anyhow::Result<()> + Send + 'static,
+    {
+        let msg_id = sender.next_msg_id();
+        let message = Message::new(
+            self.node_id.clone(),
+            self.service.address().to_owned(),
+            Body::new(
+                Some(msg_id),
+                None,
+                convert_payload::<_, P>(&request).context("Payload has no KV counterpart")?,
+            ),
+        );
+
+        rpc.register(msg_id, move |node, reply: Message<P>| {
+            let reply = match convert_payload::<_, KvPayload>(&reply.body().payload) {
+                Ok(KvPayload::Error { code, text }) => Err(KvError::from_reply(code, text)),
+                Ok(payload) => Ok(payload),
+                Err(e) => Err(KvError::UnexpectedReply(e.to_string())),
+            };
+
+            callback(node, reply)
+        });
+
+        sender.send(message)
+    }
+}
+
+fn cas_request<K: Serialize, V: Serialize>(
+    key: K,
+    from: V,
+    to: V,
+    create_if_not_exists: bool,
+) -> anyhow::Result<KvPayload> {
+    Ok(KvPayload::Cas {
+        key: serde_json::to_value(key).context("Error serializing KV key")?,
+        from: serde_json::to_value(from).context("Error serializing KV value")?,
+        to: serde_json::to_value(to).context("Error serializing KV value")?,
+        create_if_not_exists,
+    })
+}
+
+fn expect(reply: Result<KvPayload, KvError>, expected: KvPayload) -> Result<(), KvError> {
+    match reply? {
+        payload if payload == expected => Ok(()),
+        other => Err(KvError::UnexpectedReply(format!("{other:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KvClient, KvError, KvPayload, KvService};
+    use crate::{
+        conformance::assert_round_trip, rpc::Rpc, writters::MemoryWritter, ErrorCode, Message,
+        MessageSender,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Add {
+            delta: usize,
+        },
+        #[serde(untagged)]
+        Kv(KvPayload),
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        value: Option<Result<usize, KvError>>,
+        written: Option<Result<(), KvError>>,
+    }
+
+    #[test]
+    fn test_kv_wire_format() {
+        assert_round_trip::<Payload>(&[
+            r#"{"src":"n1","dest":"lin-kv","body":{"type":"read","msg_id":1,"key":"counter"}}"#,
+            r#"{"src":"lin-kv","dest":"n1","body":{"type":"read_ok","in_reply_to":1,"value":3}}"#,
+            r#"{"src":"n1","dest":"seq-kv","body":{"type":"cas","msg_id":2,"key":"counter","from":3,"to":4,"create_if_not_exists":true}}"#,
+            r#"{"src":"seq-kv","dest":"n1","body":{"type":"error","in_reply_to":2,"code":22,"text":"expected 3, had 5"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"add","msg_id":3,"delta":1}}"#,
+        ]);
+    }
+
+    #[test]
+    fn test_kv_client() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut rpc = Rpc::<Counter, Payload>::new();
+        let mut counter = Counter::default();
+
+        let kv = KvClient::new(KvService::Seq, "n1");
+        kv.read(
+            &mut sender,
+            &mut rpc,
+            "counter",
+            |node: &mut Counter, value| {
+                node.value = Some(value);
+                Ok(())
+            },
+        )
+        .unwrap();
+        kv.cas(
+            &mut sender,
+            &mut rpc,
+            "counter",
+            3,
+            4,
+            |node: &mut Counter, written| {
+                node.written = Some(written);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let requests = sent.lock().unwrap().clone();
+        assert_eq!(requests[0].dest(), "seq-kv");
+
+        let replies = [
+            requests[0].reply(Payload::Kv(KvPayload::ReadOk { value: 3.into() })),
+            requests[1].reply(Payload::Kv(KvPayload::Error {
+                code: ErrorCode::PreconditionFailed,
+                text: "expected 3, had 5".to_owned(),
+            })),
+        ];
+        for reply in replies {
+            let reply =
+                serde_json::from_str::<Message<Payload>>(&serde_json::to_string(&reply).unwrap())
+                    .unwrap();
+            let callback = rpc.take_callback(&reply).expect("Reply not correlated");
+            callback(&mut counter, reply).unwrap();
+        }
+
+        assert_eq!(counter.value, Some(Ok(3)));
+        assert_eq!(
+            counter.written,
+            Some(Err(KvError::PreconditionFailed(
+                "expected 3, had 5".to_owned()
+            )))
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{Event, Message};
+use crossbeam_channel::{never, select, Receiver};
+use std::{collections::VecDeque, str::FromStr};
+
+/// Which of the messages waiting in [`Lanes`] `main_loop` handles first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LanePolicy {
+    /// Alternates between the lanes, so neither starves the other.
+    #[default]
+    Fair,
+    /// Client requests first, internal traffic only once there's none.
+    ClientFirst,
+    /// Internal traffic first, client requests only once there's none.
+    InternalFirst,
+}
+
+impl FromStr for LanePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "fair" => Ok(Self::Fair),
+            "client-first" => Ok(Self::ClientFirst),
+            "internal-first" => Ok(Self::InternalFirst),
+            _ => Err("expected fair, client-first or internal-first".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lane {
+    Client,
+    Internal,
+}
+
+/// Splits the events of the main loop's channels in two lanes: requests
+/// from clients, and internal traffic, i.e. timers and messages from other
+/// nodes. Events of a lane are handled in the order they arrived.
+pub(crate) struct Lanes<P> {
+    /// Messages read from the input, closed once it is.
+    inbound: Receiver<Message<P>>,
+    /// Timers that fired, never closed until the scheduler is gone.
+    timers: Receiver<Event<P>>,
+    policy: LanePolicy,
+    client: VecDeque<Event<P>>,
+    internal: VecDeque<Event<P>>,
+    /// Lane of the last message handed out, for [`LanePolicy::Fair`].
+    last: Lane,
+}
+
+impl<P> Lanes<P> {
+    pub(crate) fn new(inbound: Receiver<Message<P>>, policy: LanePolicy) -> Self {
+        Self {
+            inbound,
+            timers: never(),
+            policy,
+            client: VecDeque::new(),
+            internal: VecDeque::new(),
+            last: Lane::Internal,
+        }
+    }
+
+    /// Also takes the timers that fire from `timers`.
+    pub(crate) fn with_timers(mut self, timers: Receiver<Event<P>>) -> Self {
+        self.timers = timers;
+        self
+    }
+
+    pub(crate) fn inbound(&self) -> &Receiver<Message<P>> {
+        &self.inbound
+    }
+
+    pub(crate) fn timers(&self) -> &Receiver<Event<P>> {
+        &self.timers
+    }
+
+    /// Stops waiting on the timers, once the scheduler is gone.
+    pub(crate) fn close_timers(&mut self) {
+        self.timers = never();
+    }
+
+    /// Next event according to the policy, if any is waiting.
+    pub(crate) fn try_recv(&mut self) -> Option<Event<P>> {
+        self.fill();
+        self.pop()
+    }
+
+    /// Same as [`Lanes::try_recv`], waiting for an event if there's none,
+    /// `None` once the input is closed and both lanes are empty.
+    pub(crate) fn recv(&mut self) -> Option<Event<P>> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return Some(event);
+            }
+
+            select! {
+                recv(self.inbound) -> message => self.push(Event::Message(message.ok()?)),
+                recv(self.timers) -> event => match event {
+                    Ok(event) => self.push(event),
+                    Err(_) => self.close_timers(),
+                },
+            }
+        }
+    }
+
+    /// Events waiting, in the lanes or still in the channels.
+    pub(crate) fn len(&mut self) -> usize {
+        self.fill();
+        self.client.len() + self.internal.len()
+    }
+
+    /// Moves whatever is waiting in the channels to the lanes.
+    fn fill(&mut self) {
+        while let Ok(message) = self.inbound.try_recv() {
+            self.push(Event::Message(message));
+        }
+        while let Ok(event) = self.timers.try_recv() {
+            self.push(event);
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: Event<P>) {
+        match &event {
+            // Maelstrom names clients c1, c2...
+            Event::Message(message) if message.src().starts_with('c') => {
+                self.client.push_back(event)
+            }
+            _ => self.internal.push_back(event),
+        }
+    }
+
+    fn pop(&mut self) -> Option<Event<P>> {
+        let client_first = match self.policy {
+            LanePolicy::Fair => self.last == Lane::Internal,
+            LanePolicy::ClientFirst => true,
+            LanePolicy::InternalFirst => false,
+        };
+
+        let lane = if self.internal.is_empty() || (client_first && !self.client.is_empty()) {
+            Lane::Client
+        } else {
+            Lane::Internal
+        };
+        self.last = lane;
+
+        match lane {
+            Lane::Client => self.client.pop_front(),
+            Lane::Internal => self.internal.pop_front(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LanePolicy, Lanes};
+    use crate::{scheduler::TimerId, Body, Event, Message};
+    use crossbeam_channel::unbounded;
+
+    #[test]
+    fn test_lanes() {
+        let order = |policy| {
+            let message = |src: &str, n| {
+                Message::new(src.to_owned(), "n1".to_owned(), Body::new(None, None, n))
+            };
+            let (tx, rx) = unbounded();
+            for (src, n) in [("n1", 1), ("n2", 2), ("n2", 3), ("c1", 4), ("c2", 5)] {
+                tx.send(message(src, n)).unwrap();
+            }
+            drop(tx);
+            // Timers, internal traffic too
+            let (timers_tx, timers) = unbounded();
+            timers_tx.send(Event::Message(message("n1", 6))).unwrap();
+            timers_tx.send(Event::Tick(TimerId(7))).unwrap();
+
+            let mut lanes = Lanes::new(rx, policy).with_timers(timers);
+            std::iter::from_fn(|| lanes.recv())
+                .map(|event| match event {
+                    Event::Message(message) => message.body().payload,
+                    Event::Tick(TimerId(id)) => id,
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(order(LanePolicy::Fair), [4, 1, 5, 2, 3, 6, 7]);
+        assert_eq!(order(LanePolicy::ClientFirst), [4, 5, 1, 2, 3, 6, 7]);
+        assert_eq!(order(LanePolicy::InternalFirst), [1, 2, 3, 6, 7, 4, 5]);
+        assert_eq!("client-first".parse(), Ok(LanePolicy::ClientFirst));
+    }
+}
@@ -1,8 +1,78 @@
 use anyhow::{bail, Context};
+use codec::Json;
+use compression::{Compressed, CompressingWritter, Compression, DecompressingReader, GzipAccept};
+use config::Config;
+use crossbeam_channel::{never, select, tick, unbounded, Receiver, Sender};
+use handshake::{ProtocolVersion, Versions};
+use lanes::Lanes;
+use membership::{Membership, MembershipChange};
+use metrics::Metrics;
+use middleware::{Dedup, Middleware, Received, Validation};
+use persistence::{Persistent, Snapshots};
+use rate_limit::RateLimit;
+use readers::{MessageReader, StdinJsonReader};
+use record::{Direction, Recorder};
+use replay::{Replay, Timing, REPLAY_FILE};
+use rpc::{Replies, ReplyHandle};
+use scheduler::{Scheduler, TimerId, TimerSink};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use serde_json::Value;
-use std::sync::mpsc::Sender;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::StdoutLock,
+    ops::Range,
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use transport::{TcpTransport, UdpTransport};
+use wal::{Recovered, Wal, WalHandle};
+use writters::{BatchingJsonWritter, MessageWritter, SharedWritter, TeeWritter};
 
+#[cfg(feature = "async")]
+pub mod async_loop;
+pub mod challenges;
+pub mod clocks;
+pub mod codec;
+pub mod compression;
+pub mod concurrent;
+pub mod config;
+pub mod conflict;
+#[cfg(any(test, feature = "testing"))]
+pub mod conformance;
+pub mod crdt;
+pub mod failure_detector;
+pub mod flow_control;
+pub mod gossip;
+pub mod handshake;
+pub mod kv;
+pub mod lanes;
+pub mod logging;
+pub mod membership;
+pub mod metrics;
+pub mod middleware;
+pub mod outbox;
+pub mod persistence;
+pub mod protocols;
+pub mod rate_limit;
+pub mod readers;
+pub mod record;
+pub mod registry;
+pub mod replay;
+pub mod ring;
+pub mod routing;
+pub mod rpc;
+pub mod scheduler;
+pub mod session;
+pub mod simulator;
+pub mod stability;
+pub mod state_transfer;
+pub mod transport;
+pub mod wal;
 pub mod writters;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +91,10 @@ impl<Payload> Message<Payload> {
         self.body.msg_id
     }
 
+    pub fn in_reply_to(&self) -> Option<usize> {
+        self.body.in_reply_to
+    }
+
     pub fn src(&self) -> &str {
         &self.src
     }
@@ -32,12 +106,30 @@ impl<Payload> Message<Payload> {
     pub fn body(&self) -> &Body<Payload> {
         &self.body
     }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.body.trace()
+    }
+
+    /// Reply to this message: addressed back to its sender and correlated
+    /// with it through `in_reply_to`. The `msg_id` is left for the sender to
+    /// assign.
+    pub fn reply<R>(&self, payload: R) -> Message<R> {
+        Message::new(
+            self.dest.clone(),
+            self.src.clone(),
+            self.body.reply_to(payload),
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Body<Payload> {
     msg_id: Option<usize>,
     in_reply_to: Option<usize>,
+    /// Boxed, most messages carry none.
+    #[serde(flatten)]
+    trace: Option<Box<Trace>>,
 
     #[serde(flatten)]
     pub payload: Payload,
@@ -48,58 +140,2696 @@ impl<Payload> Body<Payload> {
         Self {
             msg_id,
             in_reply_to,
+            trace: None,
             payload,
         }
     }
+
+    pub fn with_trace(mut self, trace: Trace) -> Self {
+        self.trace = Some(Box::new(trace));
+        self
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_deref()
+    }
+
+    /// Body of a reply to the message this body belongs to.
+    pub fn reply_to<R>(&self, payload: R) -> Body<R> {
+        Body::new(None, self.msg_id, payload)
+    }
+
+    /// Same body with `payload` made by `f`, ids and trace included.
+    pub fn map<R, F: FnOnce(Payload) -> R>(self, f: F) -> Body<R> {
+        Body {
+            msg_id: self.msg_id,
+            in_reply_to: self.in_reply_to,
+            trace: self.trace,
+            payload: f(self.payload),
+        }
+    }
 }
 
-pub trait Node<Payload> {
-    fn init(&mut self, tx: Sender<Message<Payload>>) -> anyhow::Result<()>;
+/// Where a message sits in a causal chain across nodes, e.g. the client
+/// request a broadcast storm started from. A message carrying one hands it
+/// to everything sent while handling it, so following a `trace_id` through
+/// the nodes' logs shows everything that request caused. Both ids go in
+/// the body, next to `msg_id`, and only when there's a trace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Trace {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+/// First retransmission delay of [`MessageSender::send_with_retry`], unless
+/// [`MessageSender::set_retry_timeout`] changed it.
+const RETRY_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const RETRY_MAX_BACKOFF: Duration = Duration::from_millis(1600);
+
+/// How often `main_loop` checks for unacknowledged messages when no input
+/// arrives.
+const RETRY_TICK: Duration = Duration::from_millis(50);
+
+struct Unacked<Payload> {
+    message: Message<Payload>,
+    backoff: Duration,
+    resend_at: Instant,
+}
+
+/// See [`MessageSender::share`].
+pub(crate) struct Shared<Payload> {
+    node_id: Option<String>,
+    next_msg_id: Arc<AtomicUsize>,
+    unacked: Arc<Mutex<HashMap<usize, Unacked<Payload>>>>,
+    replies: Replies<Payload>,
+    retry_timeout: Duration,
+    wal: Option<WalHandle<Payload>>,
+}
+
+impl<Payload> Shared<Payload> {
+    /// Sender writing to `writter` without any middleware. Messages it sends
+    /// with retry are acked and retransmitted by every sender sharing them.
+    pub(crate) fn sender<'a, W>(self, writter: W) -> MessageSender<'a, Payload>
+    where
+        W: MessageWritter<Message<Payload>> + 'a,
+    {
+        MessageSender {
+            writter: Box::new(writter),
+            raw: None,
+            node_id: self.node_id,
+            next_msg_id: self.next_msg_id,
+            unacked: self.unacked,
+            replies: self.replies,
+            retry_timeout: self.retry_timeout,
+            middleware: Vec::new(),
+            held: None,
+            msg_ids: Cell::new(0..0),
+            msg_id_block: 1,
+            trace: None,
+            wal: self.wal,
+        }
+    }
+}
+
+/// Writes the messages of a node, numbering every one of them from a single
+/// atomic counter so handlers never deal with `msg_id`s themselves.
+pub struct MessageSender<'a, Payload> {
+    writter: Box<dyn MessageWritter<Message<Payload>> + 'a>,
+    /// Writes the replies `main_loop` sends on its own, e.g. to `stats`,
+    /// whose payload isn't one of the node's.
+    raw: Option<Box<dyn MessageWritter<Message<serde_json::Value>> + 'a>>,
+    /// `src` of [`MessageSender::rpc`] requests.
+    node_id: Option<String>,
+    next_msg_id: Arc<AtomicUsize>,
+    unacked: Arc<Mutex<HashMap<usize, Unacked<Payload>>>>,
+    replies: Replies<Payload>,
+    retry_timeout: Duration,
+    middleware: Vec<Box<dyn Middleware<Payload> + 'a>>,
+    /// What the handler running sent so far, see [`MessageSender::hold`].
+    held: Option<Vec<Message<Payload>>>,
+    /// `msg_id`s taken from `next_msg_id` and not handed out yet.
+    msg_ids: Cell<Range<usize>>,
+    /// How many `msg_id`s are taken from `next_msg_id` at once, see
+    /// [`MessageSender::set_msg_id_block`].
+    msg_id_block: usize,
+    /// Trace of the message being handled, see [`MessageSender::set_trace`].
+    trace: Option<Trace>,
+    /// See [`MessageSender::set_wal`].
+    wal: Option<WalHandle<Payload>>,
+}
+
+impl<'a, Payload> MessageSender<'a, Payload> {
+    pub fn new<W>(writter: W) -> Self
+    where
+        W: MessageWritter<Message<Payload>> + 'a,
+    {
+        Self {
+            writter: Box::new(writter),
+            raw: None,
+            node_id: None,
+            next_msg_id: Arc::new(AtomicUsize::new(0)),
+            unacked: Arc::default(),
+            replies: Replies::default(),
+            retry_timeout: RETRY_INITIAL_BACKOFF,
+            middleware: Vec::new(),
+            held: None,
+            msg_ids: Cell::new(0..0),
+            msg_id_block: 1,
+            trace: None,
+            wal: None,
+        }
+    }
+
+    /// Handle to the `msg_id`s and retransmissions of this sender, to build
+    /// senders on other threads that share them.
+    pub(crate) fn share(&self) -> Shared<Payload> {
+        Shared {
+            node_id: self.node_id.clone(),
+            next_msg_id: self.next_msg_id.clone(),
+            unacked: self.unacked.clone(),
+            replies: self.replies.clone(),
+            retry_timeout: self.retry_timeout,
+            wal: self.wal.clone(),
+        }
+    }
+
+    /// Sender of another payload type writing to `writter`, without any
+    /// middleware. Its `msg_id`s come from the same counter as this one's,
+    /// its retransmissions and [`MessageSender::rpc`] replies are its own.
+    pub(crate) fn numbered_with<'b, Q, W>(&self, writter: W) -> MessageSender<'b, Q>
+    where
+        W: MessageWritter<Message<Q>> + 'b,
+    {
+        Shared {
+            node_id: self.node_id.clone(),
+            next_msg_id: self.next_msg_id.clone(),
+            unacked: Arc::default(),
+            replies: Replies::default(),
+            retry_timeout: self.retry_timeout,
+            wal: None,
+        }
+        .sender(writter)
+    }
+
+    /// See [`MessageSender::send_raw`].
+    pub(crate) fn set_raw_writter<W>(&mut self, writter: W)
+    where
+        W: MessageWritter<Message<serde_json::Value>> + 'a,
+    {
+        self.raw = Some(Box::new(writter));
+    }
+
+    /// Sends a message of no payload type of the node's, skipping the
+    /// middleware. Fails unless `main_loop` set a writter for them.
+    pub(crate) fn send_raw(
+        &mut self,
+        mut message: Message<serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        if message.body.msg_id.is_none() {
+            message.body.msg_id = Some(self.next_msg_id());
+        }
+
+        self.raw
+            .as_mut()
+            .context("No writter for messages of other payload types")?
+            .send_message(&message)
+    }
+
+    /// Sets the node's id, the `src` of [`MessageSender::rpc`] requests.
+    /// `main_loop` sets it right after `init`.
+    pub fn set_node_id(&mut self, node_id: &str) {
+        self.node_id = Some(node_id.to_owned());
+    }
+
+    /// Sets how long [`MessageSender::send_with_retry`] waits for a reply
+    /// before the first retransmission. The backoff doubles from there.
+    pub fn set_retry_timeout(&mut self, timeout: Duration) {
+        self.retry_timeout = timeout;
+    }
+
+    /// Runs every message sent and received from now on through
+    /// `middleware`, after the ones added before it.
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware<Payload> + 'a>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Takes `msg_id`s from the shared counter `size` at a time, handing
+    /// them out one by one before taking more. Senders on several threads
+    /// then rarely touch the counter, at the cost of `msg_id`s no longer
+    /// growing in the order messages are sent across senders.
+    pub(crate) fn set_msg_id_block(&mut self, size: usize) {
+        self.msg_id_block = size.max(1);
+    }
+
+    /// Hands `trace` to everything sent from now on that carries none of its
+    /// own. `main_loop` sets the trace of every message it handles.
+    pub(crate) fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    /// Reserves a `msg_id`, for requests whose id must be known before they
+    /// are sent, e.g. to correlate the reply.
+    pub fn next_msg_id(&self) -> usize {
+        let mut msg_ids = self.msg_ids.take();
+
+        let msg_id = match msg_ids.next() {
+            Some(msg_id) => msg_id,
+            None => {
+                let start = self
+                    .next_msg_id
+                    .fetch_add(self.msg_id_block, Ordering::Relaxed);
+                msg_ids = start + 1..start + self.msg_id_block;
+                start
+            }
+        };
+
+        self.msg_ids.set(msg_ids);
+        msg_id
+    }
+
+    /// Sends `message`, assigning it a `msg_id` unless it already has one.
+    pub fn send(&mut self, mut message: Message<Payload>) -> anyhow::Result<()> {
+        self.stamp(&mut message);
+
+        if let Some(held) = &mut self.held {
+            held.push(message);
+            return Ok(());
+        }
 
-    fn handle_message(&mut self, message: Message<Payload>) -> anyhow::Result<()>;
+        self.log_sent(std::slice::from_ref(&message))?;
+        match self.outbound(message) {
+            Some(message) => self.writter.send_message(&message),
+            None => Ok(()),
+        }
+    }
+
+    pub fn send_all<I>(&mut self, messages: I) -> anyhow::Result<()>
+    where
+        I: IntoIterator<Item = Message<Payload>>,
+    {
+        let mut messages = messages.into_iter().collect::<Vec<_>>();
+        for message in &mut messages {
+            self.stamp(message);
+        }
+
+        if let Some(held) = &mut self.held {
+            held.extend(messages);
+            return Ok(());
+        }
+
+        self.log_sent(&messages)?;
+        let messages = messages
+            .into_iter()
+            .filter_map(|message| self.outbound(message))
+            .collect::<Vec<_>>();
+
+        self.writter.send_messages(&messages)
+    }
+
+    /// Holds back everything sent from now on, until
+    /// [`MessageSender::release`] sends it or [`MessageSender::discard`]
+    /// drops it. `main_loop` holds what every handler sends so the node
+    /// either updates its state and replies, or fails and replies nothing
+    /// but the error.
+    pub(crate) fn hold(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+
+    /// Sends what was held back, all at once, and stops holding.
+    pub(crate) fn release(&mut self) -> anyhow::Result<()> {
+        let Some(held) = self.held.take() else {
+            return Ok(());
+        };
+
+        self.log_sent(&held)?;
+        let messages = held
+            .into_iter()
+            .filter_map(|message| self.outbound(message))
+            .collect::<Vec<_>>();
+
+        self.writter.send_messages(&messages)
+    }
+
+    /// Drops what was held back, retransmissions included, and stops
+    /// holding. Returns how many messages were dropped.
+    pub(crate) fn discard(&mut self) -> usize {
+        let held = self.held.take().unwrap_or_default();
+
+        let mut unacked = self.unacked.lock().unwrap();
+        for msg_id in held.iter().filter_map(Message::msg_id) {
+            unacked.remove(&msg_id);
+        }
+
+        held.len()
+    }
+
+    /// Writes out the messages the writter held back, if any.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writter.flush()?;
+
+        match &mut self.raw {
+            Some(raw) => raw.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Messages sent with [`MessageSender::send_with_retry`] still waiting
+    /// for their reply.
+    pub fn pending_retries(&self) -> usize {
+        self.unacked.lock().unwrap().len()
+    }
+
+    /// Whether `msg_id` was sent with [`MessageSender::send_with_retry`] and
+    /// is still waiting for its reply.
+    pub fn is_unacked(&self, msg_id: usize) -> bool {
+        self.unacked.lock().unwrap().contains_key(&msg_id)
+    }
+
+    /// Stops retrying the message `reply` answers, if any. `main_loop` calls
+    /// it for every incoming message.
+    pub fn ack(&mut self, reply: &Message<Payload>) -> bool {
+        let Some(msg_id) = reply.in_reply_to() else {
+            return false;
+        };
+        if self.unacked.lock().unwrap().remove(&msg_id).is_none() {
+            return false;
+        }
+
+        if let Some(wal) = &self.wal
+            && let Err(error) = wal.acked(msg_id)
+        {
+            tracing::warn!(msg_id, error = format!("{error:#}"), "Failed to log ack");
+        }
+
+        true
+    }
+
+    /// Runs an inbound message through the middleware, returning it if the
+    /// node should handle it.
+    pub(crate) fn receive(
+        &mut self,
+        mut message: Message<Payload>,
+    ) -> anyhow::Result<Option<Message<Payload>>> {
+        for i in 0..self.middleware.len() {
+            message = match self.middleware[i].on_receive(message) {
+                Received::Handle(message) => message,
+                Received::Drop => return Ok(None),
+                Received::Reply(reply) => {
+                    self.send(reply)?;
+                    return Ok(None);
+                }
+            };
+        }
+
+        Ok(Some(message))
+    }
+
+    /// Logs `messages` to the WAL, if any, before they're written out, see
+    /// [`MessageSender::set_wal`].
+    fn log_sent(&self, messages: &[Message<Payload>]) -> anyhow::Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+
+        let unacked = self.unacked.lock().unwrap();
+        for message in messages {
+            let retry = message
+                .msg_id()
+                .is_some_and(|msg_id| unacked.contains_key(&msg_id));
+            wal.sent(message, retry)?;
+        }
+
+        Ok(())
+    }
+
+    fn outbound(&mut self, message: Message<Payload>) -> Option<Message<Payload>> {
+        self.outbound_from(0, message)
+    }
+
+    /// Runs `message` through the middleware from the `start`th on.
+    fn outbound_from(
+        &mut self,
+        start: usize,
+        mut message: Message<Payload>,
+    ) -> Option<Message<Payload>> {
+        for middleware in &mut self.middleware[start..] {
+            message = middleware.on_send(message)?;
+        }
+
+        Some(message)
+    }
+
+    /// Assigns `message` a `msg_id` and the trace of the message being
+    /// handled, unless it has its own.
+    fn stamp(&self, message: &mut Message<Payload>) {
+        if message.body.msg_id.is_none() {
+            message.body.msg_id = Some(self.next_msg_id());
+        }
+
+        if message.body.trace.is_none() {
+            message.body.trace = self.trace.clone().map(Box::new);
+        }
+    }
 }
 
-pub fn main_loop<M, N, P>(node: &mut N) -> anyhow::Result<()>
+impl<Payload> MessageSender<'_, Payload>
 where
-    M: Serialize + Deserialize<'static>,
-    N: Node<P>,
-    P: std::fmt::Debug + Serialize + DeserializeOwned + Send + 'static,
+    Payload: Serialize + DeserializeOwned + 'static,
 {
-    let (tx, rx) = std::sync::mpsc::channel();
+    /// Logs everything sent from now on to `wal`, see [`wal`], after taking
+    /// over the retransmissions `recovered` from the previous run, due right
+    /// away, and skipping the `msg_id`s it used.
+    pub fn set_wal(&mut self, wal: Wal, recovered: Recovered<Payload>) {
+        self.next_msg_id
+            .fetch_max(recovered.next_msg_id, Ordering::Relaxed);
 
-    let tx_cloned = tx.clone();
+        let now = Instant::now();
+        let mut unacked = self.unacked.lock().unwrap();
+        for message in recovered.pending {
+            let Some(msg_id) = message.msg_id() else {
+                continue;
+            };
+            let unacked_message = Unacked {
+                message,
+                backoff: self.retry_timeout,
+                resend_at: now,
+            };
+            unacked.insert(msg_id, unacked_message);
+        }
+        drop(unacked);
+
+        self.wal = Some(WalHandle::new(wal));
+    }
 
-    node.init(tx_cloned)?;
+    /// Sends `request` to `dest` and returns a handle to block on for the
+    /// reply, parsed as `Resp`, e.g. to read from `lin-kv` or ask a peer
+    /// something within a handler. The request is flushed right away.
+    ///
+    /// The reply is handed to the handle as soon as it's read, without going
+    /// through the main loop, which is blocked by the handler meanwhile. It
+    /// isn't retransmitted: a lost request just times out.
+    pub fn rpc<Req, Resp>(&mut self, dest: &str, request: Req) -> anyhow::Result<ReplyHandle<Resp>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned + Send + 'static,
+    {
+        let src = self
+            .node_id
+            .clone()
+            .context("Sending an rpc before the node id is known")?;
+        let msg_id = self.next_msg_id();
+        let payload = convert_payload(&request)?;
 
-    let reciver_thread = std::thread::spawn(move || {
-        let stdin = std::io::stdin().lock();
-        let inputs = serde_json::Deserializer::from_reader(stdin).into_iter::<Value>();
+        // Never held back, the handler waits on the reply
+        let held = self.held.take();
+        let handle = self.replies.register(msg_id);
+        let sent = self.send(Message::new(
+            src,
+            dest.to_owned(),
+            Body::new(Some(msg_id), None, payload),
+        ));
+        self.held = held;
+        sent?;
+        self.flush()?;
+
+        Ok(handle)
+    }
+
+    /// See [`Replies`].
+    pub(crate) fn replies(&self) -> Replies<Payload> {
+        self.replies.clone()
+    }
+}
+
+impl<Payload: Clone> MessageSender<'_, Payload> {
+    /// Sends `message` and keeps retransmitting it with exponential backoff
+    /// until a reply with the matching `in_reply_to` arrives. Returns the
+    /// `msg_id` the reply will carry.
+    pub fn send_with_retry(&mut self, mut message: Message<Payload>) -> anyhow::Result<usize> {
+        self.stamp(&mut message);
+        let msg_id = message.body.msg_id.expect("msg_id just assigned");
+
+        // Before sending, for the WAL to log it as retried
+        self.unacked.lock().unwrap().insert(
+            msg_id,
+            Unacked {
+                message: message.clone(),
+                backoff: self.retry_timeout,
+                resend_at: Instant::now() + self.retry_timeout,
+            },
+        );
+        self.send(message)?;
 
-        for message in inputs {
-            let message = message
-                .context("Failed to parse message as Value")
-                .expect("Failed to parse message as Value");
+        Ok(msg_id)
+    }
+
+    /// Retransmits every unacknowledged message whose backoff has elapsed.
+    pub fn retry_due(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
 
-            let message: Message<P> = serde_json::from_value(message)
-                .context("Failed to parse stdin input message")
-                .expect("Failed to parse stdin input message");
+        let due = self
+            .unacked
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter(|unacked| unacked.resend_at <= now)
+            .map(|unacked| {
+                unacked.backoff = (unacked.backoff * 2).min(RETRY_MAX_BACKOFF.max(unacked.backoff));
+                unacked.resend_at = now + unacked.backoff;
+                unacked.message.clone()
+            })
+            .collect::<Vec<_>>();
+
+        for message in due {
+            if let Some(message) = self.outbound(message) {
+                self.writter.send_message(&message)?;
+            }
+        }
 
-            if tx.send(message).is_err() {
-                bail!("Failed to send message to main thread");
+        for i in 0..self.middleware.len() {
+            for message in self.middleware[i].release() {
+                if let Some(message) = self.outbound_from(i + 1, message) {
+                    self.writter.send_message(&message)?;
+                }
             }
         }
 
         Ok(())
-    });
+    }
+}
+
+/// What a node handles a message with: the sender anything it sends goes
+/// through, replies included, and the scheduler of its timers. Derefs to
+/// the sender, so `ctx.send(reply)` sends a reply.
+pub struct NodeContext<'s, 'a, Payload> {
+    sender: &'s mut MessageSender<'a, Payload>,
+    /// `None` when the node is driven by hand, e.g. in tests.
+    scheduler: Option<&'s Scheduler<Message<Payload>>>,
+    panic_policy: PanicPolicy,
+    versions: Versions,
+}
+
+impl<'s, 'a, Payload> NodeContext<'s, 'a, Payload> {
+    pub fn new(sender: &'s mut MessageSender<'a, Payload>) -> Self {
+        Self {
+            sender,
+            scheduler: None,
+            panic_policy: PanicPolicy::default(),
+            versions: Versions::default(),
+        }
+    }
 
-    for message in rx {
-        node.handle_message(message)?;
+    pub fn with_scheduler(mut self, scheduler: &'s Scheduler<Message<Payload>>) -> Self {
+        self.scheduler = Some(scheduler);
+        self
     }
 
-    reciver_thread
-        .join()
-        .expect("Failed to join reciver thread")
-        .context("Failed to join reciver thread")?;
+    pub(crate) fn with_panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
 
-    Ok(())
+    pub(crate) fn panic_policy(&self) -> PanicPolicy {
+        self.panic_policy
+    }
+
+    pub(crate) fn with_versions(mut self, versions: Versions) -> Self {
+        self.versions = versions;
+        self
+    }
+
+    pub(crate) fn versions(&self) -> &Versions {
+        &self.versions
+    }
+
+    /// Version of the internal payloads `peer` announced, see [`handshake`],
+    /// for nodes to fall back to what older peers understand.
+    pub fn peer_version(&self, peer: &str) -> Option<u32> {
+        self.versions.peer_version(peer)
+    }
+
+    pub fn sender(&mut self) -> &mut MessageSender<'a, Payload> {
+        self.sender
+    }
+
+    /// The scheduler the node was given at [`Node::init`], to set timers
+    /// while handling a message.
+    pub fn scheduler(&self) -> Option<&'s Scheduler<Message<Payload>>> {
+        self.scheduler
+    }
+}
+
+impl<'a, Payload> std::ops::Deref for NodeContext<'_, 'a, Payload> {
+    type Target = MessageSender<'a, Payload>;
+
+    fn deref(&self) -> &Self::Target {
+        self.sender
+    }
+}
+
+impl<Payload> std::ops::DerefMut for NodeContext<'_, '_, Payload> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.sender
+    }
+}
+
+/// What a node handles, see [`Node::handle_event`]: the messages it
+/// receives, and the events of the node itself that never go over the wire.
+#[derive(Debug, Clone)]
+pub enum Event<Payload> {
+    /// Read from the input, or delivered by a timer of the node's.
+    Message(Message<Payload>),
+    /// A timer set with [`Scheduler::tick_once`] and the like fired.
+    Tick(TimerId),
+    /// The input is closed and every message was handled, right before
+    /// [`Node::on_shutdown`]. Timers are already stopped.
+    Shutdown,
+    /// Sent with [`Scheduler::notify`], e.g. by a thread the node spawned.
+    Custom(serde_json::Value),
+}
+
+/// The main loop's timers come as events: items as messages, ticks and
+/// custom events as themselves.
+impl<P: Send + 'static> TimerSink<Message<P>> for Sender<Event<P>> {
+    fn deliver(&self, message: Message<P>) -> bool {
+        self.send(Event::Message(message)).is_ok()
+    }
+
+    fn tick(&self, id: TimerId) -> bool {
+        self.send(Event::Tick(id)).is_ok()
+    }
+
+    fn custom(&self, event: serde_json::Value) -> bool {
+        self.send(Event::Custom(event)).is_ok()
+    }
+}
+
+pub trait Node<Payload> {
+    /// Called once before any message is handled. Nodes register their
+    /// timers here and may keep the scheduler to set more timers later on.
+    fn init(&mut self, scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()>;
+
+    /// Handles one message. Anything the node sends, replies included, goes
+    /// through `ctx`, whose sender is owned by `main_loop`. `main_loop` only
+    /// sends it once the handler returned, all of it if it succeeded and
+    /// none of it if it failed; [`MessageSender::rpc`] requests alone go out
+    /// right away.
+    fn handle_message(
+        &mut self,
+        message: Message<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()>;
+
+    /// Handles one event. Messages go to [`Node::handle_message`] and every
+    /// other event is ignored by default; nodes driven by timer ticks or
+    /// custom events handle them here.
+    fn handle_event(
+        &mut self,
+        event: Event<Payload>,
+        ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            _ => Ok(()),
+        }
+    }
+
+    /// Middleware to run every message this node receives and sends through,
+    /// in order. Called once, before [`Node::init`].
+    fn middleware(&mut self) -> Vec<Box<dyn Middleware<Payload>>> {
+        Vec::new()
+    }
+
+    /// Handles a message whose payload isn't one of the node's, e.g. of a
+    /// type it doesn't know, to proxy it or answer with an error. Such
+    /// messages are reported on stderr and dropped by default.
+    fn handle_unknown(
+        &mut self,
+        message: Message<serde_json::Value>,
+        _ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        eprintln!(
+            "Unknown message: {}",
+            serde_json::to_string(&message).context("Error serializing message")?
+        );
+
+        Ok(())
+    }
+
+    /// The node's state to save while it runs and restore when it restarts,
+    /// see [`persistence`]. Nodes are stateless by default.
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        None
+    }
+
+    /// The nodes of the cluster, for `main_loop` to keep up to date as nodes
+    /// join and leave, see [`membership`]. Nodes that don't keep one ignore
+    /// such changes and the cluster stays as it was at `init`.
+    fn membership(&mut self) -> Option<&mut Membership> {
+        None
+    }
+
+    /// Called after a node joined or left, once [`Node::membership`]
+    /// reflects it.
+    fn on_membership_change(
+        &mut self,
+        _change: &MembershipChange,
+        _ctx: &mut NodeContext<Payload>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Summary of the node's state, e.g. how many values it holds, for the
+    /// replies to `stats` requests, see [`StatsPayload`].
+    fn stats(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// Called once stdin is closed and every pending message was handled,
+    /// right before `main_loop` returns. Timers are already stopped.
+    fn on_shutdown(&mut self, _ctx: &mut NodeContext<Payload>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Boxed nodes are nodes too, so `main_loop` runs a `Box<dyn Node<P>>`
+/// picked at runtime, e.g. from a [`registry::Registry`], like any other.
+impl<P, N: Node<P> + ?Sized> Node<P> for Box<N> {
+    fn init(&mut self, scheduler: Scheduler<Message<P>>) -> anyhow::Result<()> {
+        (**self).init(scheduler)
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<P>,
+        ctx: &mut NodeContext<P>,
+    ) -> anyhow::Result<()> {
+        (**self).handle_message(message, ctx)
+    }
+
+    fn handle_event(&mut self, event: Event<P>, ctx: &mut NodeContext<P>) -> anyhow::Result<()> {
+        (**self).handle_event(event, ctx)
+    }
+
+    fn middleware(&mut self) -> Vec<Box<dyn Middleware<P>>> {
+        (**self).middleware()
+    }
+
+    fn handle_unknown(
+        &mut self,
+        message: Message<serde_json::Value>,
+        ctx: &mut NodeContext<P>,
+    ) -> anyhow::Result<()> {
+        (**self).handle_unknown(message, ctx)
+    }
+
+    fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+        (**self).persistent()
+    }
+
+    fn membership(&mut self) -> Option<&mut Membership> {
+        (**self).membership()
+    }
+
+    fn on_membership_change(
+        &mut self,
+        change: &MembershipChange,
+        ctx: &mut NodeContext<P>,
+    ) -> anyhow::Result<()> {
+        (**self).on_membership_change(change, ctx)
+    }
+
+    fn stats(&self) -> serde_json::Value {
+        (**self).stats()
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<P>) -> anyhow::Result<()> {
+        (**self).on_shutdown(ctx)
+    }
+}
+
+/// `type` of the requests `main_loop` answers on its own, whatever the node.
+pub const STATS: &str = "stats";
+
+/// Debug request any node answers, to inspect live runs: send
+/// `{"type": "stats"}` to a node and it replies with how many messages it
+/// handled and sent, how many wait for a retransmission or to be handled,
+/// and the node's own [`Node::stats`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum StatsPayload {
+    Stats,
+    StatsOk {
+        handled: u64,
+        sent: u64,
+        pending_retries: usize,
+        queued: Option<usize>,
+        node: serde_json::Value,
+    },
+}
+
+/// `init` message Maelstrom sends before anything else. `main_loop` handles
+/// it, so node payloads only need their own message types.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum InitPayload {
+    Init(Init),
+    InitOk,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Init {
+    pub node_id: String,
+    pub node_ids: Vec<String>,
+}
+
+/// What every node knows from `init`: its id and the nodes of the cluster.
+/// Nodes embed one rather than keeping their own copies of `init`, and hand
+/// [`NodeBase::membership_mut`] to `main_loop` from [`Node::membership`] for
+/// their peers to follow the nodes joining and leaving.
+#[derive(Debug, Clone)]
+pub struct NodeBase {
+    membership: Membership,
+}
+
+impl NodeBase {
+    pub fn new(init: &Init) -> Self {
+        Self {
+            membership: Membership::new(&init.node_id, init.node_ids.iter().cloned()),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        self.membership.node_id()
+    }
+
+    /// Every node but this one, sorted.
+    pub fn peers(&self) -> Vec<String> {
+        self.membership.peers()
+    }
+
+    pub fn membership(&self) -> &Membership {
+        &self.membership
+    }
+
+    pub fn membership_mut(&mut self) -> &mut Membership {
+        &mut self.membership
+    }
+
+    /// Message from this node to `dest`, its `msg_id` left for the sender to
+    /// assign.
+    pub fn message<P>(&self, dest: &str, payload: P) -> Message<P> {
+        Message::new(
+            self.node_id().to_owned(),
+            dest.to_owned(),
+            Body::new(None, None, payload),
+        )
+    }
+}
+
+/// Maelstrom's error codes, see its protocol docs. Serialized as the bare
+/// integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "u64", from = "u64")]
+pub enum ErrorCode {
+    /// The requested operation didn't complete in time.
+    Timeout,
+    /// The node the request was sent to doesn't exist.
+    NodeNotFound,
+    /// The node doesn't support the requested operation.
+    NotSupported,
+    /// The operation can't be performed now but may succeed if retried,
+    /// e.g. there's no leader.
+    TemporarilyUnavailable,
+    /// The request is malformed.
+    MalformedRequest,
+    /// The node failed in an unexpected way, the operation may or may not
+    /// have happened.
+    Crash,
+    /// The operation definitely didn't happen, for any reason.
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    /// An assertion of the request, e.g. the `from` of a `cas`, didn't hold.
+    PreconditionFailed,
+    /// The transaction was aborted because it conflicted with another.
+    TxnConflict,
+    /// Any code this enum doesn't know of.
+    Other(u64),
+}
+
+impl ErrorCode {
+    /// Whether the operation is known not to have happened. Clients may
+    /// retry definite errors freely.
+    pub fn is_definite(&self) -> bool {
+        !matches!(self, ErrorCode::Timeout | ErrorCode::Crash)
+    }
+}
+
+impl From<ErrorCode> for u64 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<u64> for ErrorCode {
+    fn from(code: u64) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+/// An error a handler wants answered to the client as is. Handlers return
+/// it through `anyhow`, [`Message::error_reply`] finds it in the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaelstromError {
+    pub code: ErrorCode,
+    pub text: String,
+}
+
+impl MaelstromError {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: text.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for MaelstromError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error {}: {}", u64::from(self.code), self.text)
+    }
+}
+
+impl std::error::Error for MaelstromError {}
+
+/// Maelstrom's `error` message. Node payloads carry it through an
+/// `Error { code: ErrorCode, text: String }` variant of their own.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum ErrorPayload {
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+impl ErrorPayload {
+    /// A [`MaelstromError`] anywhere in the chain of `error` as is, anything
+    /// else as a [`ErrorCode::Crash`].
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<MaelstromError>())
+        {
+            Some(error) => ErrorPayload::Error {
+                code: error.code,
+                text: error.text.clone(),
+            },
+            None => ErrorPayload::Error {
+                code: ErrorCode::Crash,
+                text: format!("{error:#}"),
+            },
+        }
+    }
+}
+
+impl<Payload> Message<Payload> {
+    /// `error` reply to this message for the error a handler returned. A
+    /// [`MaelstromError`] anywhere in the chain is answered as is, anything
+    /// else as a [`ErrorCode::Crash`].
+    pub fn error_reply(&self, error: &anyhow::Error) -> anyhow::Result<Message<Payload>>
+    where
+        Payload: DeserializeOwned,
+    {
+        let payload = ErrorPayload::from_error(error);
+
+        Ok(self.reply(convert_payload(&payload).context("Payload has no error variant")?))
+    }
+}
+
+/// Moves a payload between two payload types sharing its wire format, e.g.
+/// from [`ErrorPayload`] to a node's own payload.
+pub(crate) fn convert_payload<A: Serialize, B: DeserializeOwned>(payload: &A) -> anyhow::Result<B> {
+    let value = serde_json::to_value(payload).context("Error serializing payload")?;
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Same as [`convert_payload`] for a whole message.
+pub(crate) fn convert_message<A: Serialize, B: DeserializeOwned>(
+    message: &Message<A>,
+) -> anyhow::Result<Message<B>> {
+    Ok(Message::new(
+        message.src.clone(),
+        message.dest.clone(),
+        Body::new(
+            message.msg_id(),
+            message.in_reply_to(),
+            convert_payload(&message.body.payload)?,
+        ),
+    ))
+}
+
+/// Payload of a message `main_loop` read: one of the node's or, when it
+/// doesn't parse as one, e.g. because of a `type` the node doesn't know,
+/// the raw JSON, handed to [`Node::handle_unknown`]. The requests
+/// `main_loop` answers on its own are never the node's, so nodes whose
+/// payload is any JSON, e.g. [`protocols::Protocols`], get them answered
+/// too.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Incoming<P> {
+    Stats(StatsPayload),
+    Membership(MembershipChange),
+    /// Read back by [`compression::DecompressingReader`].
+    Compressed(Compressed),
+    GzipAccept(GzipAccept),
+    ProtocolVersion(ProtocolVersion),
+    Known(P),
+    Unknown(serde_json::Value),
+}
+
+impl<P> Message<Incoming<P>> {
+    fn parsed(self) -> Result<Message<P>, Message<serde_json::Value>> {
+        let Body {
+            msg_id,
+            in_reply_to,
+            trace,
+            payload,
+        } = self.body;
+
+        let payload = match payload {
+            Incoming::Known(payload) => Ok(payload),
+            Incoming::Stats(payload) => Err(control_payload(&payload)),
+            Incoming::Membership(payload) => Err(control_payload(&payload)),
+            Incoming::Compressed(payload) => Err(control_payload(&payload)),
+            Incoming::GzipAccept(payload) => Err(control_payload(&payload)),
+            Incoming::ProtocolVersion(payload) => Err(control_payload(&payload)),
+            Incoming::Unknown(payload) => Err(payload),
+        };
+
+        match payload {
+            Ok(payload) => Ok(Message::new(
+                self.src,
+                self.dest,
+                Body {
+                    msg_id,
+                    in_reply_to,
+                    trace,
+                    payload,
+                },
+            )),
+            Err(payload) => Err(Message::new(
+                self.src,
+                self.dest,
+                Body {
+                    msg_id,
+                    in_reply_to,
+                    trace,
+                    payload,
+                },
+            )),
+        }
+    }
+}
+
+impl<P: DeserializeOwned> Message<Incoming<P>> {
+    /// Parses a JSON message straight into one of the node's, skipping the
+    /// tries of [`Incoming`]'s variants that every other message goes
+    /// through. Only messages the node doesn't know, or that `main_loop`
+    /// answers on its own, are parsed as [`Incoming`].
+    pub fn from_json(json: &[u8]) -> serde_json::Result<Self> {
+        // Cheap, every field but the body's `type` and `gzip` is skipped
+        let control = serde_json::from_slice::<Envelope>(json)
+            .is_ok_and(|envelope| envelope.body.is_control());
+
+        if !control && let Ok(message) = serde_json::from_slice::<Message<P>>(json) {
+            return Ok(Message::new(
+                message.src,
+                message.dest,
+                message.body.map(Incoming::Known),
+            ));
+        }
+
+        serde_json::from_slice(json)
+    }
+}
+
+/// Just enough of a message to tell whether it's one [`Incoming`] keeps
+/// apart from the node's.
+#[derive(Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow)]
+    body: EnvelopeBody<'a>,
+}
+
+#[derive(Deserialize)]
+struct EnvelopeBody<'a> {
+    #[serde(rename = "type", borrow)]
+    kind: std::borrow::Cow<'a, str>,
+    gzip: Option<serde::de::IgnoredAny>,
+}
+
+impl EnvelopeBody<'_> {
+    fn is_control(&self) -> bool {
+        self.gzip.is_some()
+            || matches!(
+                &*self.kind,
+                STATS | "node_added" | "node_removed" | "gzip_accept" | "protocol_version"
+            )
+    }
+}
+
+fn control_payload<T: Serialize>(payload: &T) -> serde_json::Value {
+    serde_json::to_value(payload).expect("Control payloads serialize to JSON")
+}
+
+/// What `main_loop` does with stdin lines that aren't a valid message for
+/// the node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MalformedInput {
+    /// Drop the line silently.
+    Skip,
+    /// Report the line on stderr and drop it.
+    #[default]
+    LogAndSkip,
+    /// Stop the node, `main_loop` returns the parse error.
+    Fail,
+}
+
+/// What `main_loop` does when a handler panics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PanicPolicy {
+    /// Drop what the handler sent, answer the request with a
+    /// [`ErrorCode::Crash`] error and keep handling the others. Whatever
+    /// the handler changed before panicking stays as it was left.
+    #[default]
+    Isolate,
+    /// Let the panic take the node down.
+    Abort,
+}
+
+impl FromStr for PanicPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "isolate" => Ok(Self::Isolate),
+            "abort" => Ok(Self::Abort),
+            _ => Err("expected isolate or abort".to_owned()),
+        }
+    }
+}
+
+/// Error a handler that panicked fails with, under [`PanicPolicy::Isolate`].
+#[derive(Debug)]
+struct Panicked(String);
+
+impl std::fmt::Display for Panicked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handler panicked: {}", self.0)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+/// Runs `handler`, turning a panic into a [`Panicked`] error unless the
+/// policy is to abort.
+fn isolate<F>(policy: PanicPolicy, handler: F) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<()>,
+{
+    if policy == PanicPolicy::Abort {
+        return handler();
+    }
+
+    std::panic::catch_unwind(AssertUnwindSafe(handler)).unwrap_or_else(|panic| {
+        let reason = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(reason), _) => reason.to_string(),
+            (_, Some(reason)) => reason.clone(),
+            _ => "unknown reason".to_owned(),
+        };
+
+        Err(Panicked(reason).into())
+    })
+}
+
+/// Waits for the `init` message, builds the node from it with `new_node`,
+/// replies `init_ok` and then dispatches every other message to the node
+/// until stdin is closed. Malformed input is logged and skipped.
+///
+/// When [`REPLAY_FILE`] is set the node is fed that capture instead, and
+/// `main_loop` fails if it sends anything other than what was recorded. When
+/// [`Config::listen`] is set it runs over TCP, a Unix socket for `unix:`
+/// paths or UDP for `udp:` addresses instead, see [`TcpTransport`].
+///
+/// Settings come from [`Config::from_env`].
+pub fn main_loop<N, P, F>(new_node: F) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+{
+    main_loop_with_config(Config::from_env()?, new_node)
+}
+
+/// Same as [`main_loop`] with settings the caller already loaded, usually
+/// because the node needs some of them too.
+pub fn main_loop_with_config<N, P, F>(config: Config, new_node: F) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+{
+    if let Ok(path) = std::env::var(REPLAY_FILE) {
+        logging::init(config.log_level.as_deref());
+
+        return Replay::open(path)?
+            .run(Timing::from_env()?, new_node)?
+            .check();
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = config
+        .listen
+        .as_deref()
+        .and_then(|addr| addr.strip_prefix("unix:"))
+    {
+        return transport::UnixTransport::bind(path)?
+            .with_peers(config.peers.clone())
+            .main_loop(config, new_node);
+    }
+
+    if let Some(addr) = config
+        .listen
+        .as_deref()
+        .and_then(|addr| addr.strip_prefix("udp:"))
+    {
+        return UdpTransport::bind(addr)?
+            .with_peers(config.peers.clone())
+            .main_loop(config, new_node);
+    }
+
+    if let Some(addr) = &config.listen {
+        return TcpTransport::bind(addr)?
+            .with_peers(config.peers.clone())
+            .main_loop(config, new_node);
+    }
+
+    main_loop_with(
+        StdinJsonReader::with_capacity(config.read_buffer),
+        MalformedInput::default(),
+        config,
+        new_node,
+    )
+}
+
+/// Same as [`main_loop_with_config`], reading the `init` message and
+/// everything after it from `reader` and handling malformed input according
+/// to `policy`.
+pub fn main_loop_with<R, N, P, F>(
+    reader: R,
+    policy: MalformedInput,
+    config: Config,
+    new_node: F,
+) -> anyhow::Result<()>
+where
+    R: MessageReader<Message<InitPayload>> + MessageReader<Message<Incoming<P>>> + Send + 'static,
+    N: Node<P>,
+    P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+{
+    let writter = stdout_writter(&config);
+
+    run(reader, writter, policy, config, new_node, dispatch)
+}
+
+/// Writes to stdout, as Maelstrom expects, batching as `config` says.
+pub(crate) fn stdout_writter(config: &Config) -> BatchingJsonWritter<StdoutLock<'static>> {
+    BatchingJsonWritter::with_codec(
+        std::io::stdout().lock(),
+        Json::default().omit_nulls(config.omit_nulls),
+        config.batch_size,
+        config.batch_interval,
+    )
+}
+
+/// Messages read by the reader thread and timers that fired, those with a
+/// payload of no type the node knows and the input that failed to parse,
+/// each on a channel of its own the main loop waits on all at once.
+pub(crate) struct Inputs<P> {
+    messages: Lanes<P>,
+    unknown: Receiver<Message<serde_json::Value>>,
+    malformed: Malformed,
+}
+
+pub(crate) struct Malformed {
+    errors: Receiver<anyhow::Error>,
+    policy: MalformedInput,
+}
+
+impl Malformed {
+    /// Deals with the input that failed to parse so far according to the
+    /// policy.
+    pub(crate) fn check(&self) -> anyhow::Result<()> {
+        self.errors
+            .try_iter()
+            .try_for_each(|error| self.handle(error))
+    }
+
+    /// Deals with `error` according to the policy.
+    fn handle(&self, error: anyhow::Error) -> anyhow::Result<()> {
+        match self.policy {
+            MalformedInput::Skip => Ok(()),
+            MalformedInput::LogAndSkip => {
+                eprintln!("{error:#}");
+                Ok(())
+            }
+            MalformedInput::Fail => Err(error),
+        }
+    }
+}
+
+/// Everything `main_loop` does around the dispatch of messages to the node:
+/// the `init` handshake, the reader thread, timers and shutdown.
+pub(crate) fn run<R, W, N, P, F, D>(
+    mut reader: R,
+    writter: W,
+    policy: MalformedInput,
+    config: Config,
+    new_node: F,
+    dispatch: D,
+) -> anyhow::Result<()>
+where
+    R: MessageReader<Message<InitPayload>> + MessageReader<Message<Incoming<P>>> + Send + 'static,
+    W: MessageWritter<Message<InitPayload>>
+        + MessageWritter<Message<P>>
+        + MessageWritter<Message<serde_json::Value>>
+        + 'static,
+    N: Node<P>,
+    P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+    F: FnOnce(Init) -> anyhow::Result<N>,
+    D: FnOnce(
+        &mut N,
+        Inputs<P>,
+        &mut NodeContext<P>,
+        &Metrics,
+        &mut Snapshots,
+    ) -> anyhow::Result<()>,
+{
+    let mut writter = TeeWritter::from_env(writter)?;
+
+    logging::init(config.log_level.as_deref());
+
+    let init = MessageReader::<Message<InitPayload>>::read_message(&mut reader)
+        .context("Input closed before the init message")?
+        .context("Failed to parse init message")?;
+    let InitPayload::Init(payload) = &init.body.payload else {
+        bail!("Expected init as the first message");
+    };
+
+    // Tags everything logged from here on with the node id
+    let _span = tracing::info_span!("node", node_id = %payload.node_id).entered();
+
+    let mut recorder = Recorder::from_env(&payload.node_id)?;
+    if let Some(recorder) = &mut recorder {
+        recorder.record(Direction::In, &init)?;
+    }
+
+    let mut node = new_node(payload.clone())?;
+
+    let mut snapshots = Snapshots::from_env(&payload.node_id)?;
+    snapshots.restore(&mut node)?;
+
+    let init_ok = init.reply(InitPayload::InitOk);
+    writter.send_message(&init_ok)?;
+    MessageWritter::<Message<InitPayload>>::flush(&mut writter)?;
+    if let Some(recorder) = &mut recorder {
+        recorder.record(Direction::Out, &init_ok)?;
+    }
+
+    let compression = Compression::new(config.gzip_threshold);
+    let writter = SharedWritter::new(CompressingWritter::new(writter, compression.clone()));
+    let mut sender = MessageSender::new(writter.clone());
+    sender.set_raw_writter(writter);
+    sender.set_node_id(&payload.node_id);
+    sender.set_retry_timeout(config.retry_timeout);
+    if let Some((wal, recovered)) = Wal::from_env(&payload.node_id)? {
+        tracing::info!(
+            pending = recovered.pending.len(),
+            "Resuming retransmissions from the WAL"
+        );
+        sender.set_wal(wal, recovered);
+    }
+    // First, so the middleware after it only sees what it holds back once
+    // it's released
+    if let Some(rate) = config.rate_limit {
+        let rate = f64::from(rate);
+        let limit = payload
+            .node_ids
+            .iter()
+            .filter(|node_id| **node_id != payload.node_id)
+            .fold(RateLimit::new(), |limit, peer| {
+                limit.with_limit(peer, rate, rate.max(1.0))
+            });
+        sender.add_middleware(Box::new(limit));
+    }
+    if let Some(recorder) = recorder {
+        sender.add_middleware(Box::new(recorder));
+    }
+    // Before validation, so retries get the cached reply rather than being
+    // rejected as duplicates
+    if let Some(capacity) = config.reply_cache {
+        sender.add_middleware(Box::new(Dedup::new(capacity)));
+    }
+    if config.validate {
+        let validation = Validation::new(&payload.node_id, config.max_message_size);
+        sender.add_middleware(Box::new(validation));
+    }
+    for middleware in node.middleware() {
+        sender.add_middleware(middleware);
+    }
+
+    let metrics = Metrics::from_env()?.with_budget(config.handler_budget);
+    sender.add_middleware(Box::new(metrics.counter()));
+
+    let versions = Versions::default();
+    for announcement in versions.announcements(&payload.node_id, &payload.node_ids) {
+        sender.send_raw(announcement)?;
+    }
+    for announcement in compression.announcements(&payload.node_id, &payload.node_ids) {
+        sender.send_raw(announcement)?;
+    }
+
+    let (tx, rx) = unbounded();
+
+    let (timers_tx, timers_rx) = unbounded();
+    let scheduler = Scheduler::new(timers_tx);
+
+    if let Err(e) = node.init(scheduler.clone()) {
+        scheduler.shutdown();
+        return Err(e);
+    }
+
+    let (unknown_tx, unknown_rx) = unbounded();
+    let (errors_tx, errors_rx) = unbounded();
+
+    let mut reader = DecompressingReader::new(reader, compression);
+    let replies = sender.replies();
+    let reciver_scheduler = scheduler.clone();
+    let reciver_thread = std::thread::spawn(move || {
+        let result = read_messages(&mut reader, &tx, &replies, &unknown_tx, &errors_tx);
+
+        // No more timers either, the main loop handles what's left and
+        // returns
+        reciver_scheduler.shutdown();
+
+        result
+    });
+
+    let inputs = Inputs {
+        messages: Lanes::new(rx, config.lane_policy).with_timers(timers_rx),
+        unknown: unknown_rx,
+        malformed: Malformed {
+            errors: errors_rx,
+            policy,
+        },
+    };
+    let mut ctx = NodeContext::new(&mut sender)
+        .with_scheduler(&scheduler)
+        .with_panic_policy(config.panic_policy)
+        .with_versions(versions);
+    let result = dispatch(&mut node, inputs, &mut ctx, &metrics, &mut snapshots);
+
+    // Also cancels the threads nodes spawned on their own, if they watch
+    // the scheduler's cancellation token, when a handler failed
+    scheduler.shutdown();
+    result?;
+
+    node.handle_event(Event::Shutdown, &mut ctx)?;
+    node.on_shutdown(&mut ctx)?;
+    ctx.flush()?;
+    snapshots.save(&mut node)?;
+
+    // The latencies of every payload type, to see where the budget went
+    if metrics.is_periodic() || metrics.budget().is_some() {
+        metrics.dump();
+    }
+
+    reciver_thread
+        .join()
+        .expect("Failed to join reciver thread")
+        .context("Failed to join reciver thread")?;
+
+    Ok(())
+}
+
+/// Forwards every message of `reader` to `tx`, or to `unknown` when its
+/// payload isn't one of the node's. Messages that fail to parse go to
+/// `errors` instead, so the main thread decides what to do with them.
+fn read_messages<P, R>(
+    reader: &mut R,
+    tx: &Sender<Message<P>>,
+    replies: &Replies<P>,
+    unknown: &Sender<Message<serde_json::Value>>,
+    errors: &Sender<anyhow::Error>,
+) -> anyhow::Result<()>
+where
+    R: MessageReader<Message<Incoming<P>>>,
+    P: Serialize + 'static,
+{
+    while let Some(message) = reader.read_message() {
+        match message.map(Message::parsed) {
+            Ok(Ok(message)) => {
+                let Some(message) = replies.deliver(message) else {
+                    continue;
+                };
+                if tx.send(message).is_err() {
+                    bail!("Failed to send message to main thread");
+                }
+            }
+            Ok(Err(message)) => {
+                if unknown.send(message).is_err() {
+                    bail!("Failed to send message to main thread");
+                }
+            }
+            Err(error) => {
+                if errors.send(error).is_err() {
+                    bail!("Failed to send error to main thread");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn dispatch<N, P>(
+    node: &mut N,
+    mut inputs: Inputs<P>,
+    ctx: &mut NodeContext<P>,
+    metrics: &Metrics,
+    snapshots: &mut Snapshots,
+) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: Clone + Serialize + DeserializeOwned,
+{
+    let retry = tick(RETRY_TICK);
+
+    loop {
+        // Errors are reported before the messages that followed them
+        inputs.malformed.check()?;
+        for message in inputs.unknown.try_iter() {
+            let queued = inputs.messages.len();
+            handle_unknown(node, message, ctx, metrics, Some(queued))?;
+        }
+
+        match inputs.messages.try_recv() {
+            Some(event) => handle_event(node, event, ctx, metrics)?,
+            // Nothing to handle, wait for any input or the next retry
+            None => select! {
+                recv(inputs.messages.inbound()) -> message => match message {
+                    Ok(message) => inputs.messages.push(Event::Message(message)),
+                    Err(_) => return Ok(()),
+                },
+                recv(inputs.messages.timers()) -> event => match event {
+                    Ok(event) => inputs.messages.push(event),
+                    Err(_) => inputs.messages.close_timers(),
+                },
+                recv(inputs.unknown) -> message => match message {
+                    Ok(message) => {
+                        let queued = inputs.messages.len();
+                        handle_unknown(node, message, ctx, metrics, Some(queued))?;
+                    }
+                    Err(_) => inputs.unknown = never(),
+                },
+                recv(inputs.malformed.errors) -> error => match error {
+                    Ok(error) => inputs.malformed.handle(error)?,
+                    Err(_) => inputs.malformed.errors = never(),
+                },
+                recv(retry) -> _ => {}
+            },
+        }
+
+        ctx.retry_due()?;
+        ctx.flush()?;
+        metrics.tick();
+        snapshots.tick(node)?;
+    }
+}
+
+/// Answers `stats` requests, see [`StatsPayload`], applies membership
+/// changes, see [`membership`], records the versions peers announce, see
+/// [`handshake`], and passes every other message of an unknown payload type
+/// on to the node, but for those of peers of another version, which are
+/// dropped. `queued` is how many messages wait to be handled, if the
+/// dispatch knows.
+pub(crate) fn handle_unknown<N, P>(
+    node: &mut N,
+    message: Message<serde_json::Value>,
+    ctx: &mut NodeContext<P>,
+    metrics: &Metrics,
+    queued: Option<usize>,
+) -> anyhow::Result<()>
+where
+    N: Node<P>,
+{
+    if node.membership().is_some()
+        && let Ok(change) =
+            serde_json::from_value::<MembershipChange>(message.body().payload.clone())
+    {
+        let membership = node.membership().expect("Node keeps its membership");
+        if membership.apply(&change) {
+            node.on_membership_change(&change, ctx)?;
+        }
+
+        return ctx.send_raw(message.reply(change.reply()));
+    }
+
+    if let Ok(ProtocolVersion::ProtocolVersion { version }) =
+        serde_json::from_value(message.body().payload.clone())
+    {
+        ctx.versions().observe(message.src(), version);
+        return Ok(());
+    }
+
+    if message.body().payload["type"] != STATS {
+        if let Some(version) = ctx.versions().incompatible(message.src()) {
+            eprintln!(
+                "Dropping message of {}, which speaks protocol version {version} but this node \
+                 speaks {}: {}",
+                message.src(),
+                ctx.versions().version(),
+                serde_json::to_string(&message).context("Error serializing message")?
+            );
+            return Ok(());
+        }
+
+        return node.handle_unknown(message, ctx);
+    }
+
+    let stats = StatsPayload::StatsOk {
+        handled: metrics.total_handled(),
+        sent: metrics.total_sent(),
+        pending_retries: ctx.pending_retries(),
+        queued,
+        node: node.stats(),
+    };
+    ctx.send_raw(message.reply(convert_payload(&stats)?))
+}
+
+/// Handles `event`: messages once they went through the middleware and
+/// acked what they reply to, see [`handle`], anything else straight through
+/// [`Node::handle_event`], skipped if it panics, see [`PanicPolicy`].
+pub(crate) fn handle_event<N, P>(
+    node: &mut N,
+    event: Event<P>,
+    ctx: &mut NodeContext<P>,
+    metrics: &Metrics,
+) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: Serialize + DeserializeOwned,
+{
+    let Event::Message(message) = event else {
+        let policy = ctx.panic_policy();
+        let result = atomically(ctx, |ctx| isolate(policy, || node.handle_event(event, ctx)));
+
+        return match result {
+            Err(error) if error.is::<Panicked>() => {
+                tracing::error!(error = format!("{error:#}"), "Skipping the event");
+                Ok(())
+            }
+            result => result,
+        };
+    };
+
+    match ctx.receive(message)? {
+        Some(message) => {
+            ctx.ack(&message);
+            handle(node, message, ctx, metrics)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Handles `message`, recording how long it took and reporting it on stderr
+/// when over the handler budget, see [`Config::handler_budget`]. What the
+/// node sends meanwhile carries the message's [`Trace`], if any. When
+/// handling a request fails the error is answered, see
+/// [`Message::error_reply`], and the node keeps running; failing to handle
+/// anything else, e.g. a timer, stops it. Handlers that panic are answered
+/// and skipped the same way, unless the [`PanicPolicy`] is to abort.
+pub(crate) fn handle<N, P>(
+    node: &mut N,
+    message: Message<P>,
+    ctx: &mut NodeContext<P>,
+    metrics: &Metrics,
+) -> anyhow::Result<()>
+where
+    N: Node<P>,
+    P: Serialize + DeserializeOwned,
+{
+    let msg_id = message.msg_id();
+    let payload_type = logging::payload_type(&message.body().payload);
+    let src = tracing::enabled!(tracing::Level::DEBUG).then(|| message.src().to_owned());
+    let trace = message.trace().cloned();
+    let request = (msg_id.is_some() && message.in_reply_to().is_none()).then(|| {
+        Message::new(
+            message.src().to_owned(),
+            message.dest().to_owned(),
+            Body::new(msg_id, None, ()),
+        )
+    });
+
+    ctx.set_trace(trace.clone());
+    let policy = ctx.panic_policy();
+    let started = Instant::now();
+    let result = atomically(ctx, |ctx| {
+        isolate(policy, || node.handle_event(Event::Message(message), ctx))
+    });
+    let elapsed = started.elapsed();
+
+    metrics.record_handled(payload_type.as_deref().unwrap_or("unknown"), elapsed);
+
+    if let Some(budget) = metrics.budget()
+        && elapsed > budget
+    {
+        eprintln!(
+            "Handling {} (msg_id {msg_id:?}) took {elapsed:?}, over the {budget:?} budget",
+            payload_type.as_deref().unwrap_or("unknown"),
+        );
+    }
+
+    if let Some(src) = src {
+        tracing::debug!(
+            msg_id,
+            src,
+            payload_type,
+            trace_id = trace.as_ref().map(|trace| trace.trace_id.as_str()),
+            span_id = trace.as_ref().map(|trace| trace.span_id.as_str()),
+            elapsed_us = elapsed.as_micros() as u64,
+            ok = result.is_ok(),
+            "Handled message"
+        );
+    }
+
+    let result = match (result, request) {
+        (Err(error), Some(request)) => reply_error(&request, error, ctx),
+        (Err(error), None) if error.is::<Panicked>() => {
+            tracing::error!(msg_id, error = format!("{error:#}"), "Skipping the message");
+            Ok(())
+        }
+        (result, _) => result,
+    };
+    ctx.set_trace(None);
+
+    result
+}
+
+/// Runs `handler`, holding back what it sends until it returns, see
+/// [`MessageSender::hold`]: all of it goes out if it succeeds, none of it if
+/// it fails.
+pub(crate) fn atomically<P, F>(ctx: &mut NodeContext<P>, handler: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut NodeContext<P>) -> anyhow::Result<()>,
+{
+    ctx.hold();
+
+    match handler(ctx) {
+        Ok(()) => ctx.release(),
+        Err(error) => {
+            let dropped = ctx.discard();
+            if dropped > 0 {
+                tracing::warn!(dropped, "Dropped what the failed handler sent");
+            }
+
+            Err(error)
+        }
+    }
+}
+
+/// Answers `request` with `error`, through the node's payload if it has an
+/// error variant. Returns `error` if it can't be answered.
+fn reply_error<P>(
+    request: &Message<()>,
+    error: anyhow::Error,
+    sender: &mut MessageSender<P>,
+) -> anyhow::Result<()>
+where
+    P: Serialize + DeserializeOwned,
+{
+    tracing::warn!(
+        msg_id = request.msg_id(),
+        src = request.src(),
+        error = format!("{error:#}"),
+        "Replying with an error"
+    );
+
+    let reply = request.reply(ErrorPayload::from_error(&error));
+    let sent = match convert_message::<_, P>(&reply) {
+        Ok(reply) => sender.send(reply),
+        Err(_) => convert_message(&reply).and_then(|reply| sender.send_raw(reply)),
+    };
+
+    sent.map_err(|_| error)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        handle, handle_event, handle_unknown,
+        kv::KvPayload,
+        membership::MembershipChange,
+        metrics::Metrics,
+        read_messages,
+        readers::MemoryReader,
+        rpc::Replies,
+        scheduler::{Scheduler, TimerId},
+        writters::MemoryWritter,
+        Body, ErrorCode, ErrorPayload, Event, Incoming, Init, MaelstromError, Message,
+        MessageSender, Node, NodeBase, NodeContext, PanicPolicy, StatsPayload, Trace,
+        RETRY_INITIAL_BACKOFF, RETRY_MAX_BACKOFF,
+    };
+    use crossbeam_channel::unbounded;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn test_sender_assigns_msg_ids() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+
+        let message = |msg_id| {
+            Message::new(
+                "n1".to_owned(),
+                "n2".to_owned(),
+                Body::new(msg_id, None, ()),
+            )
+        };
+
+        let reserved = sender.next_msg_id();
+        sender.send(message(None)).unwrap();
+        sender.send(message(Some(reserved))).unwrap();
+        sender.send_all([message(None), message(None)]).unwrap();
+
+        let msg_ids = sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|m| m.msg_id().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(msg_ids, [1, 0, 2, 3]);
+    }
+
+    #[test]
+    fn test_msg_id_blocks() {
+        let sender = MessageSender::<()>::new(MemoryWritter::new());
+        let shared = sender.share();
+
+        let threads = (0..4)
+            .map(|_| {
+                let shared = sender.share();
+                std::thread::spawn(move || {
+                    let mut sender = shared.sender(MemoryWritter::<Message<()>>::new());
+                    sender.set_msg_id_block(8);
+                    (0..20).map(|_| sender.next_msg_id()).collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut msg_ids = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+
+        // Unique across threads, 3 blocks taken by each
+        msg_ids.sort_unstable();
+        msg_ids.dedup();
+        assert_eq!(msg_ids.len(), 80);
+        let next = shared
+            .sender(MemoryWritter::<Message<()>>::new())
+            .next_msg_id();
+        assert_eq!(next, 4 * 3 * 8);
+    }
+
+    #[test]
+    fn test_node_base() {
+        let init = Init {
+            node_id: "n2".to_owned(),
+            node_ids: ["n1", "n2", "n3"].map(str::to_owned).to_vec(),
+        };
+        let mut base = NodeBase::new(&init);
+        assert_eq!(base.node_id(), "n2");
+        assert_eq!(base.peers(), ["n1", "n3"]);
+
+        let message = base.message("n3", "ping");
+        assert_eq!((message.src(), message.dest()), ("n2", "n3"));
+        assert_eq!(message.msg_id(), None);
+
+        let removed = MembershipChange::NodeRemoved {
+            node_id: "n1".to_owned(),
+        };
+        base.membership_mut().apply(&removed);
+        assert_eq!(base.peers(), ["n3"]);
+    }
+
+    #[test]
+    fn test_reply() {
+        let request = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(7), None, "ping"),
+        );
+        let reply = request.reply("pong");
+
+        assert_eq!(reply.src(), "n1");
+        assert_eq!(reply.dest(), "c1");
+        assert_eq!(reply.msg_id(), None);
+        assert_eq!(reply.in_reply_to(), Some(7));
+        assert_eq!(reply.body().payload, "pong");
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let request = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(
+                Some(3),
+                None,
+                ErrorPayload::Error {
+                    code: ErrorCode::Abort,
+                    text: String::new(),
+                },
+            ),
+        );
+
+        let error = anyhow::Error::new(MaelstromError::new(ErrorCode::TxnConflict, "k1 changed"))
+            .context("Error committing txn");
+        let reply = request.error_reply(&error).unwrap();
+        assert_eq!(
+            serde_json::to_string(&reply).unwrap(),
+            r#"{"src":"n1","dest":"c1","body":{"msg_id":null,"in_reply_to":3,"type":"error","code":30,"text":"k1 changed"}}"#
+        );
+
+        let reply = request.error_reply(&anyhow::anyhow!("boom")).unwrap();
+        assert_eq!(
+            reply.body().payload,
+            ErrorPayload::Error {
+                code: ErrorCode::Crash,
+                text: "boom".to_owned(),
+            }
+        );
+        assert!(!ErrorCode::Crash.is_definite());
+        assert_eq!(ErrorCode::from(42), ErrorCode::Other(42));
+    }
+
+    #[test]
+    fn test_malformed_lines_are_reported() {
+        let mut reader = MemoryReader::new([
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":1}}"#,
+            "not json",
+            "",
+            r#"{"src":"c1","dest":"n1"}"#,
+            r#"{"src":"c1","dest":"n1","body":{"msg_id":2}}"#,
+        ]);
+
+        let (tx, rx) = unbounded::<Message<serde_json::Value>>();
+        let (unknown_tx, _unknown_rx) = unbounded();
+        let (errors_tx, errors_rx) = unbounded();
+        read_messages(
+            &mut reader,
+            &tx,
+            &Replies::default(),
+            &unknown_tx,
+            &errors_tx,
+        )
+        .unwrap();
+
+        let msg_ids = rx.try_iter().map(|m| m.msg_id()).collect::<Vec<_>>();
+        assert_eq!(msg_ids, [Some(1), Some(2)]);
+        assert_eq!(errors_rx.try_iter().count(), 2);
+    }
+
+    #[test]
+    fn test_rpc_replies_skip_the_main_loop() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::<serde_json::Value>::new(writter);
+
+        let read = KvPayload::Read { key: "k".into() };
+        assert!(sender.rpc::<_, KvPayload>("lin-kv", read.clone()).is_err());
+
+        sender.set_node_id("n1");
+        let handle = sender.rpc::<_, KvPayload>("lin-kv", read).unwrap();
+        let request = sent.lock().unwrap().last().cloned().unwrap();
+        assert_eq!(request.src(), "n1");
+        assert_eq!(request.body().payload["type"], "read");
+
+        let mut reader = MemoryReader::new([
+            format!(
+                r#"{{"src":"lin-kv","dest":"n1","body":{{"type":"read_ok","in_reply_to":{},"value":3}}}}"#,
+                request.msg_id().unwrap()
+            ),
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1}}"#.to_owned(),
+        ]);
+        let (tx, rx) = unbounded();
+        let (unknown_tx, _unknown_rx) = unbounded();
+        let (errors_tx, _errors_rx) = unbounded();
+        read_messages(&mut reader, &tx, &sender.replies(), &unknown_tx, &errors_tx).unwrap();
+
+        let reply = handle.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply.body().payload, KvPayload::ReadOk { value: 3.into() });
+        assert_eq!(
+            rx.try_iter().map(|m| m.msg_id()).collect::<Vec<_>>(),
+            [Some(1)]
+        );
+    }
+
+    #[test]
+    fn test_stats_requests_are_answered() {
+        struct StatsNode {
+            unknown: usize,
+        }
+
+        impl Node<()> for StatsNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<()>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                _message: Message<()>,
+                _ctx: &mut NodeContext<()>,
+            ) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_unknown(
+                &mut self,
+                _message: Message<serde_json::Value>,
+                _ctx: &mut NodeContext<()>,
+            ) -> anyhow::Result<()> {
+                self.unknown += 1;
+                Ok(())
+            }
+
+            fn stats(&self) -> serde_json::Value {
+                json!({ "unknown": self.unknown })
+            }
+        }
+
+        let raw = MemoryWritter::new();
+        let replies = raw.messages();
+        let mut sender = MessageSender::new(MemoryWritter::new());
+        sender.set_raw_writter(raw);
+        let metrics = Metrics::new();
+        metrics.record_handled("echo", Duration::from_millis(1));
+        let mut node = StatsNode { unknown: 0 };
+        let mut ctx = NodeContext::new(&mut sender);
+
+        let request = |payload| {
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(1), None, payload),
+            )
+        };
+        handle_unknown(
+            &mut node,
+            request(json!({ "type": "topology" })),
+            &mut ctx,
+            &metrics,
+            None,
+        )
+        .unwrap();
+        // Of a peer of another version, dropped rather than handed to the node
+        let old_version = crate::handshake::PROTOCOL_VERSION - 1;
+        for payload in [
+            json!({ "type": "protocol_version", "version": old_version }),
+            json!({ "type": "internal_send" }),
+        ] {
+            let message = Message::new(
+                "n2".to_owned(),
+                "n1".to_owned(),
+                Body::new(None, None, payload),
+            );
+            handle_unknown(&mut node, message, &mut ctx, &metrics, None).unwrap();
+        }
+        assert_eq!(ctx.peer_version("n2"), Some(old_version));
+        handle_unknown(
+            &mut node,
+            request(json!({ "type": "stats" })),
+            &mut ctx,
+            &metrics,
+            Some(3),
+        )
+        .unwrap();
+
+        let replies = replies.lock().unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].in_reply_to(), Some(1));
+        assert_eq!(
+            serde_json::from_value::<StatsPayload>(replies[0].body().payload.clone()).unwrap(),
+            StatsPayload::StatsOk {
+                handled: 1,
+                sent: 0,
+                pending_retries: 0,
+                queued: Some(3),
+                node: json!({ "unknown": 1 }),
+            }
+        );
+    }
+
+    #[test]
+    fn test_incoming_from_json() {
+        #[derive(Debug, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        #[serde(tag = "type")]
+        enum Payload {
+            Read { key: Option<u64> },
+        }
+
+        let parse = |body: &str| {
+            let json = format!(r#"{{"src":"c1","dest":"n1","body":{body}}}"#);
+            let direct = Message::<Incoming<Payload>>::from_json(json.as_bytes()).unwrap();
+            let erased = Message::<Incoming<Value>>::from_json(json.as_bytes()).unwrap();
+            (direct.body.payload, erased.body.payload)
+        };
+
+        let (direct, erased) = parse(r#"{"type":"read","msg_id":1,"key":3}"#);
+        assert!(matches!(
+            direct,
+            Incoming::Known(Payload::Read { key: Some(3) })
+        ));
+        assert!(matches!(erased, Incoming::Known(_)));
+
+        // Never taken for the node's, even by a node of any payload
+        let (direct, erased) = parse(r#"{"type":"stats","msg_id":2}"#);
+        assert!(matches!(direct, Incoming::Stats(_)));
+        assert!(matches!(erased, Incoming::Stats(_)));
+        let (direct, _) = parse(r#"{"type":"read","gzip":"H4sI"}"#);
+        assert!(matches!(direct, Incoming::Compressed(_)));
+
+        let (direct, _) = parse(r#"{"type":"write","msg_id":3}"#);
+        assert!(matches!(direct, Incoming::Unknown(_)));
+        assert!(Message::<Incoming<Payload>>::from_json(b"{}").is_err());
+    }
+
+    #[test]
+    fn test_node_context() {
+        // Replies to every message and asks for a reminder of it
+        struct RemindingNode;
+
+        impl Node<&'static str> for RemindingNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<&'static str>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                message: Message<&'static str>,
+                ctx: &mut NodeContext<&'static str>,
+            ) -> anyhow::Result<()> {
+                if let Some(scheduler) = ctx.scheduler() {
+                    scheduler.schedule_once(Duration::ZERO, message.clone());
+                }
+
+                ctx.send(message.reply("ok"))
+            }
+        }
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let (tx, rx) = unbounded::<Message<&str>>();
+        let scheduler = Scheduler::new(tx);
+
+        let request = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(1), None, "ping"),
+        );
+        let mut ctx = NodeContext::new(&mut sender);
+        RemindingNode
+            .handle_message(request.clone(), &mut ctx)
+            .unwrap();
+        let mut ctx = NodeContext::new(&mut sender).with_scheduler(&scheduler);
+        RemindingNode.handle_message(request, &mut ctx).unwrap();
+
+        let reminder = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        scheduler.shutdown();
+        assert_eq!(reminder.body().payload, "ping");
+        assert!(rx.try_recv().is_err());
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_timer_events() {
+        // Counts the ticks of its timer, ignoring any other
+        struct TickingNode {
+            timer: Option<TimerId>,
+            ticks: usize,
+        }
+
+        impl Node<()> for TickingNode {
+            fn init(&mut self, scheduler: Scheduler<Message<()>>) -> anyhow::Result<()> {
+                self.timer = Some(scheduler.tick_once(Duration::ZERO).id());
+                scheduler.tick_once(Duration::ZERO);
+                scheduler.notify(json!({ "type": "wake_up" }));
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                _message: Message<()>,
+                _ctx: &mut NodeContext<()>,
+            ) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_event(
+                &mut self,
+                event: Event<()>,
+                _ctx: &mut NodeContext<()>,
+            ) -> anyhow::Result<()> {
+                if matches!(event, Event::Tick(id) if Some(id) == self.timer) {
+                    self.ticks += 1;
+                }
+                Ok(())
+            }
+        }
+
+        let (tx, rx) = unbounded::<Event<()>>();
+        let scheduler = Scheduler::new(tx);
+        let mut node = TickingNode {
+            timer: None,
+            ticks: 0,
+        };
+        node.init(scheduler.clone()).unwrap();
+
+        let mut sender = MessageSender::new(MemoryWritter::new());
+        let mut ctx = NodeContext::new(&mut sender).with_scheduler(&scheduler);
+        let mut custom = Vec::new();
+        for _ in 0..3 {
+            let event = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            if let Event::Custom(event) = &event {
+                custom.push(event.clone());
+            }
+            handle_event(&mut node, event, &mut ctx, &Metrics::new()).unwrap();
+        }
+        scheduler.shutdown();
+
+        assert_eq!(node.ticks, 1);
+        assert_eq!(custom, [json!({ "type": "wake_up" })]);
+    }
+
+    #[test]
+    fn test_handler_errors_are_replied() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        #[serde(tag = "type")]
+        enum Payload {
+            Read { key: u64 },
+            Tick,
+            Error { code: ErrorCode, text: String },
+        }
+
+        struct FailingNode;
+
+        impl Node<Payload> for FailingNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                message: Message<Payload>,
+                _ctx: &mut NodeContext<Payload>,
+            ) -> anyhow::Result<()> {
+                match message.body().payload {
+                    Payload::Read { key: 0 } => {
+                        Err(MaelstromError::new(ErrorCode::KeyDoesNotExist, "No key 0").into())
+                    }
+                    Payload::Read { .. } => anyhow::bail!("boom"),
+                    _ => anyhow::bail!("Timer failed"),
+                }
+            }
+        }
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut ctx = NodeContext::new(&mut sender);
+        let metrics = Metrics::new();
+        let message = |msg_id, payload| {
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(msg_id, None, payload),
+            )
+        };
+
+        for (msg_id, key) in [(1, 0), (2, 1)] {
+            let request = message(Some(msg_id), Payload::Read { key });
+            handle(&mut FailingNode, request, &mut ctx, &metrics).unwrap();
+        }
+        // Nothing to answer, the node stops
+        let timer = message(None, Payload::Tick);
+        assert!(handle(&mut FailingNode, timer, &mut ctx, &metrics).is_err());
+
+        let sent = sent.lock().unwrap();
+        let replies = sent
+            .iter()
+            .map(|m| (m.in_reply_to(), m.body().payload.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            [
+                (
+                    Some(1),
+                    Payload::Error {
+                        code: ErrorCode::KeyDoesNotExist,
+                        text: "No key 0".to_owned()
+                    }
+                ),
+                (
+                    Some(2),
+                    Payload::Error {
+                        code: ErrorCode::Crash,
+                        text: "boom".to_owned()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_panicking_handlers_are_isolated() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        #[serde(tag = "type")]
+        enum Payload {
+            Read { key: u64 },
+            ReadOk { value: u64 },
+            Tick,
+            Error { code: ErrorCode, text: String },
+        }
+
+        // Replies, then panics on key 1 and ticks
+        struct PanickingNode;
+
+        impl Node<Payload> for PanickingNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                message: Message<Payload>,
+                ctx: &mut NodeContext<Payload>,
+            ) -> anyhow::Result<()> {
+                ctx.send(message.reply(Payload::ReadOk { value: 0 }))?;
+
+                match message.body().payload {
+                    Payload::Read { key: 1 } => panic!("Key 1 is cursed"),
+                    Payload::Tick => panic!("Timer panicked"),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let metrics = Metrics::new();
+        let message = |msg_id, payload| {
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(msg_id, None, payload),
+            )
+        };
+
+        let mut ctx = NodeContext::new(&mut sender);
+        for (msg_id, key) in [(1, 1), (2, 0)] {
+            let request = message(Some(msg_id), Payload::Read { key });
+            handle(&mut PanickingNode, request, &mut ctx, &metrics).unwrap();
+        }
+        // Nothing to answer, skipped
+        let timer = message(None, Payload::Tick);
+        handle(&mut PanickingNode, timer, &mut ctx, &metrics).unwrap();
+
+        {
+            let sent = sent.lock().unwrap();
+            let replies = sent
+                .iter()
+                .map(|m| (m.in_reply_to(), m.body().payload.clone()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                replies,
+                [
+                    (
+                        Some(1),
+                        Payload::Error {
+                            code: ErrorCode::Crash,
+                            text: "Handler panicked: Key 1 is cursed".to_owned()
+                        }
+                    ),
+                    (Some(2), Payload::ReadOk { value: 0 }),
+                ]
+            );
+        }
+
+        let mut ctx = NodeContext::new(&mut sender).with_panic_policy(PanicPolicy::Abort);
+        let request = message(Some(3), Payload::Read { key: 1 });
+        let aborted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            handle(&mut PanickingNode, request, &mut ctx, &metrics)
+        }));
+        assert!(aborted.is_err());
+    }
+
+    #[test]
+    fn test_failed_handlers_send_nothing() {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        #[serde(tag = "type")]
+        enum Payload {
+            Write { fail: bool },
+            WriteOk,
+            Replicate,
+            Error { code: ErrorCode, text: String },
+        }
+
+        // Replies and replicates, then fails if asked to
+        struct ReplicatingNode;
+
+        impl Node<Payload> for ReplicatingNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                message: Message<Payload>,
+                ctx: &mut NodeContext<Payload>,
+            ) -> anyhow::Result<()> {
+                let Payload::Write { fail } = message.body().payload else {
+                    return Ok(());
+                };
+
+                ctx.send(message.reply(Payload::WriteOk))?;
+                let replicate = Message::new(
+                    "n1".to_owned(),
+                    "n2".to_owned(),
+                    Body::new(None, None, Payload::Replicate),
+                );
+                ctx.send_with_retry(replicate)?;
+                assert_eq!(ctx.pending_retries(), 1);
+
+                if fail {
+                    anyhow::bail!("Disk full");
+                }
+                Ok(())
+            }
+        }
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut ctx = NodeContext::new(&mut sender);
+        let metrics = Metrics::new();
+        let write = |msg_id, fail| {
+            Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(msg_id), None, Payload::Write { fail }),
+            )
+        };
+
+        handle(&mut ReplicatingNode, write(1, true), &mut ctx, &metrics).unwrap();
+        assert_eq!(ctx.pending_retries(), 0);
+        {
+            let sent = sent.lock().unwrap();
+            assert_eq!(sent.len(), 1);
+            assert!(matches!(sent[0].body().payload, Payload::Error { .. }));
+        }
+
+        handle(&mut ReplicatingNode, write(2, false), &mut ctx, &metrics).unwrap();
+        assert_eq!(ctx.pending_retries(), 1);
+        let sent = sent.lock().unwrap();
+        let payloads = sent[1..]
+            .iter()
+            .map(|m| m.body().payload.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, [Payload::WriteOk, Payload::Replicate]);
+    }
+
+    #[test]
+    fn test_trace_is_propagated() {
+        // Replies and forwards to n2
+        struct ForwardingNode;
+
+        impl Node<Value> for ForwardingNode {
+            fn init(&mut self, _scheduler: Scheduler<Message<Value>>) -> anyhow::Result<()> {
+                Ok(())
+            }
+
+            fn handle_message(
+                &mut self,
+                message: Message<Value>,
+                ctx: &mut NodeContext<Value>,
+            ) -> anyhow::Result<()> {
+                ctx.send(message.reply(json!({ "type": "echo_ok" })))?;
+                let forward = Message::new(
+                    "n1".to_owned(),
+                    "n2".to_owned(),
+                    Body::new(None, None, json!({ "type": "echo" })),
+                );
+                ctx.send(forward)
+            }
+        }
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut ctx = NodeContext::new(&mut sender);
+        let metrics = Metrics::new();
+
+        let traced = serde_json::from_str::<Message<Value>>(
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"trace_id":"t1","span_id":"s1"}}"#,
+        )
+        .unwrap();
+        assert_eq!(traced.body().payload, json!({ "type": "echo" }));
+        handle(&mut ForwardingNode, traced, &mut ctx, &metrics).unwrap();
+
+        let untraced = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(2), None, json!({ "type": "echo" })),
+        );
+        handle(&mut ForwardingNode, untraced, &mut ctx, &metrics).unwrap();
+
+        let sent = sent.lock().unwrap();
+        let trace = Trace {
+            trace_id: "t1".to_owned(),
+            span_id: "s1".to_owned(),
+        };
+        let traces = sent.iter().map(Message::trace).collect::<Vec<_>>();
+        assert_eq!(traces, [Some(&trace), Some(&trace), None, None]);
+
+        let json = serde_json::to_value(&sent[0]).unwrap();
+        assert_eq!(json["body"]["trace_id"], "t1");
+        let json = serde_json::to_value(&sent[2]).unwrap();
+        assert!(json["body"].get("trace_id").is_none());
+    }
+
+    #[test]
+    fn test_unknown_payloads_are_passed_through() {
+        #[derive(Debug, PartialEq, Deserialize, Serialize)]
+        #[serde(rename_all = "snake_case")]
+        #[serde(tag = "type")]
+        enum Payload {
+            Echo { echo: String },
+        }
+
+        let mut reader = MemoryReader::new([
+            r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#,
+            r#"{"src":"c1","dest":"n1","body":{"type":"topology","msg_id":2,"topology":{}}}"#,
+        ]);
+
+        let (tx, rx) = unbounded::<Message<Payload>>();
+        let (unknown_tx, unknown_rx) = unbounded();
+        let (errors_tx, errors_rx) = unbounded();
+        read_messages(
+            &mut reader,
+            &tx,
+            &Replies::default(),
+            &unknown_tx,
+            &errors_tx,
+        )
+        .unwrap();
+
+        let known = rx.try_iter().map(|m| m.body.payload).collect::<Vec<_>>();
+        assert_eq!(
+            known,
+            [Payload::Echo {
+                echo: "hi".to_owned()
+            }]
+        );
+        assert_eq!(errors_rx.try_iter().count(), 0);
+
+        let unknown = unknown_rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].msg_id(), Some(2));
+        assert_eq!(unknown[0].body().payload["type"], "topology");
+    }
+
+    #[test]
+    fn test_send_with_retry_until_ack() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+
+        let request = Message::new(
+            "n1".to_owned(),
+            "n2".to_owned(),
+            Body::new(None, None, "ping"),
+        );
+        let msg_id = sender.send_with_retry(request).unwrap();
+
+        sender.retry_due().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        std::thread::sleep(RETRY_INITIAL_BACKOFF);
+        sender.retry_due().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+        assert!(sent
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|m| m.msg_id() == Some(msg_id)));
+
+        let reply = sent.lock().unwrap()[0].reply("pong");
+        assert!(sender.ack(&reply));
+        assert!(!sender.is_unacked(msg_id));
+
+        std::thread::sleep(RETRY_MAX_BACKOFF);
+        sender.retry_due().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
 }
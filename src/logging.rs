@@ -0,0 +1,92 @@
+use serde::Serialize;
+use std::io::Write;
+use tracing_subscriber::EnvFilter;
+
+/// Env var holding the log filter, e.g. `debug` or
+/// `distributed_system_challenges=debug,warn`. Logging is off when unset.
+/// See [`crate::config::Config`].
+pub const LOG_LEVEL: &str = "LOG_LEVEL";
+
+/// Sends `tracing` events passing `filter` to stderr as one JSON object per
+/// line, stdout being reserved for Maelstrom. `main_loop` calls it once the
+/// node id is known; calling it again, or after another subscriber was
+/// installed, does nothing.
+pub fn init(filter: Option<&str>) {
+    let filter = filter
+        .and_then(|filter| EnvFilter::try_new(filter).ok())
+        .unwrap_or_else(|| EnvFilter::new("off"));
+
+    let _ = tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_current_span(true)
+        .with_span_list(false)
+        .try_init();
+}
+
+const TAG_PREFIX: &[u8] = br#"{"type":""#;
+
+/// Collects serialized JSON until the `type` tag is known and then fails the
+/// serialization, so big payloads aren't serialized just to read their tag.
+#[derive(Default)]
+struct TagWritter {
+    buf: Vec<u8>,
+}
+
+impl TagWritter {
+    fn tag(&self) -> Option<&str> {
+        let rest = self.buf.strip_prefix(TAG_PREFIX)?;
+        let end = rest.iter().position(|b| *b == b'"')?;
+
+        std::str::from_utf8(&rest[..end]).ok()
+    }
+
+    fn is_done(&self) -> bool {
+        let prefix = self.buf.len().min(TAG_PREFIX.len());
+
+        self.buf[..prefix] != TAG_PREFIX[..prefix] || self.tag().is_some()
+    }
+}
+
+impl Write for TagWritter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+
+        if self.is_done() {
+            return Err(std::io::Error::other("Tag found"));
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The `type` tag a payload is serialized with, e.g. `broadcast_ok`.
+/// Internally tagged enums write their tag first, so only that much of the
+/// payload is serialized.
+pub fn payload_type<P: Serialize>(payload: &P) -> Option<String> {
+    let mut writter = TagWritter::default();
+    let _ = serde_json::to_writer(&mut writter, payload);
+
+    writter.tag().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::payload_type;
+    use crate::InitPayload;
+
+    #[test]
+    fn test_payload_type() {
+        assert_eq!(
+            payload_type(&InitPayload::InitOk).as_deref(),
+            Some("init_ok")
+        );
+        assert_eq!(payload_type(&42), None);
+        assert_eq!(payload_type(&serde_json::json!({"kind": "read"})), None);
+    }
+}
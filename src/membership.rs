@@ -0,0 +1,128 @@
+//! Nodes joining and leaving after `init`. Maelstrom fixes the cluster at
+//! `init`, these changes come as control messages of their own,
+//! `{"type": "node_added", "node_id": "n4"}` and
+//! `{"type": "node_removed", "node_id": "n4"}`, which `main_loop` applies to
+//! the node's [`crate::Node::membership`] before calling
+//! [`crate::Node::on_membership_change`] and replying with
+//! `node_added_ok`/`node_removed_ok`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, RwLock},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum MembershipChange {
+    NodeAdded { node_id: String },
+    NodeRemoved { node_id: String },
+}
+
+impl MembershipChange {
+    pub fn node_id(&self) -> &str {
+        match self {
+            MembershipChange::NodeAdded { node_id } | MembershipChange::NodeRemoved { node_id } => {
+                node_id
+            }
+        }
+    }
+
+    /// Payload of the reply acknowledging the change.
+    pub(crate) fn reply(&self) -> serde_json::Value {
+        let reply_type = match self {
+            MembershipChange::NodeAdded { .. } => "node_added_ok",
+            MembershipChange::NodeRemoved { .. } => "node_removed_ok",
+        };
+
+        serde_json::json!({ "type": reply_type })
+    }
+}
+
+/// The nodes of the cluster, the node itself included, as of the last
+/// membership change. Binaries keep one instead of their own copy of
+/// `node_ids` and derive who to gossip or replicate with from it. Clones
+/// share the same members, so nodes handling messages on several threads
+/// see changes on all of them.
+#[derive(Debug, Clone)]
+pub struct Membership {
+    node_id: String,
+    members: Arc<RwLock<BTreeSet<String>>>,
+}
+
+impl Membership {
+    pub fn new<I: IntoIterator<Item = String>>(node_id: &str, node_ids: I) -> Self {
+        let mut members = node_ids.into_iter().collect::<BTreeSet<_>>();
+        members.insert(node_id.to_owned());
+
+        Self {
+            node_id: node_id.to_owned(),
+            members: Arc::new(RwLock::new(members)),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    /// Every node, this one included, sorted.
+    pub fn members(&self) -> Vec<String> {
+        self.members.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Every node but this one, sorted.
+    pub fn peers(&self) -> Vec<String> {
+        self.members
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|member| **member != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains(&self, node_id: &str) -> bool {
+        self.members.read().unwrap().contains(node_id)
+    }
+
+    /// Applies `change`, returning whether the membership changed. Nodes
+    /// never remove themselves.
+    pub fn apply(&mut self, change: &MembershipChange) -> bool {
+        let mut members = self.members.write().unwrap();
+
+        match change {
+            MembershipChange::NodeAdded { node_id } => members.insert(node_id.clone()),
+            MembershipChange::NodeRemoved { node_id } if *node_id == self.node_id => false,
+            MembershipChange::NodeRemoved { node_id } => members.remove(node_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Membership, MembershipChange};
+
+    #[test]
+    fn test_membership() {
+        let mut membership = Membership::new("n2", ["n1".to_owned(), "n3".to_owned()]);
+        let clone = membership.clone();
+        assert_eq!(membership.peers(), ["n1", "n3"]);
+
+        let added: MembershipChange =
+            serde_json::from_str(r#"{"type":"node_added","node_id":"n4"}"#).unwrap();
+        assert!(membership.apply(&added));
+        assert!(!membership.apply(&added));
+        assert!(membership.contains("n4"));
+
+        let removed = MembershipChange::NodeRemoved {
+            node_id: "n1".to_owned(),
+        };
+        assert!(membership.apply(&removed));
+        assert!(!membership.apply(&MembershipChange::NodeRemoved {
+            node_id: "n2".to_owned()
+        }));
+        assert_eq!(membership.members(), ["n2", "n3", "n4"]);
+        assert_eq!(clone.peers(), ["n3", "n4"]);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::{logging::payload_type, middleware::Middleware, Message};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Env var with the interval, in milliseconds, at which `main_loop` dumps
+/// its metrics to stderr. Nothing is dumped when unset.
+pub const METRICS_INTERVAL: &str = "METRICS_INTERVAL";
+
+const BUCKETS: usize = 32;
+
+/// Handler latencies in power of two buckets of microseconds, precise enough
+/// to tell a 10µs handler from a 1ms one without keeping every sample.
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+    /// How many took longer than the budget, see [`Metrics::with_budget`].
+    over_budget: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros().max(1) as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    /// Upper bound, in microseconds, of the bucket holding the `q` quantile.
+    fn quantile(&self, q: f64) -> u64 {
+        let rank = (self.count as f64 * q).ceil() as u64;
+        let mut seen = 0;
+
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return 1 << bucket;
+            }
+        }
+
+        self.max.as_micros() as u64
+    }
+
+    fn report(&self) -> Value {
+        json!({
+            "count": self.count,
+            "mean_us": self.sum.as_micros() as u64 / self.count.max(1),
+            "p50_us": self.quantile(0.5),
+            "p99_us": self.quantile(0.99),
+            "max_us": self.max.as_micros() as u64,
+            "over_budget": self.over_budget,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    handled: BTreeMap<String, u64>,
+    sent: BTreeMap<String, u64>,
+    latency: BTreeMap<String, Histogram>,
+}
+
+/// Per payload type counters of messages handled and sent, and handler
+/// latencies. Clones share the same counters.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+    interval: Option<Duration>,
+    budget: Option<Duration>,
+    last_dump: Arc<Mutex<Instant>>,
+}
+
+impl Metrics {
+    /// Metrics that are only dumped on demand.
+    pub fn new() -> Self {
+        Self {
+            registry: Arc::default(),
+            interval: None,
+            budget: None,
+            last_dump: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Metrics dumped every [`METRICS_INTERVAL`] milliseconds, if set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let interval = match std::env::var(METRICS_INTERVAL) {
+            Ok(millis) => Some(Duration::from_millis(millis.parse().map_err(|e| {
+                anyhow::anyhow!("Invalid {METRICS_INTERVAL} {millis}: {e}")
+            })?)),
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            interval,
+            ..Self::new()
+        })
+    }
+
+    /// Counts the handlers slower than `budget`, per payload type, see
+    /// [`Metrics::is_over_budget`]. Off when `None`.
+    pub fn with_budget(mut self, budget: Option<Duration>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn budget(&self) -> Option<Duration> {
+        self.budget
+    }
+
+    /// Whether a handler taking `elapsed` went over the budget, if any.
+    pub fn is_over_budget(&self, elapsed: Duration) -> bool {
+        self.budget.is_some_and(|budget| elapsed > budget)
+    }
+
+    pub fn record_handled(&self, payload_type: &str, elapsed: Duration) {
+        let over_budget = self.is_over_budget(elapsed);
+        let mut registry = self.registry.lock().unwrap();
+
+        *registry.handled.entry(payload_type.to_owned()).or_default() += 1;
+        let histogram = registry.latency.entry(payload_type.to_owned()).or_default();
+        histogram.record(elapsed);
+        histogram.over_budget += u64::from(over_budget);
+    }
+
+    pub fn record_sent(&self, payload_type: &str) {
+        *self
+            .registry
+            .lock()
+            .unwrap()
+            .sent
+            .entry(payload_type.to_owned())
+            .or_default() += 1;
+    }
+
+    pub fn handled(&self, payload_type: &str) -> u64 {
+        let registry = self.registry.lock().unwrap();
+        registry.handled.get(payload_type).copied().unwrap_or(0)
+    }
+
+    pub fn sent(&self, payload_type: &str) -> u64 {
+        let registry = self.registry.lock().unwrap();
+        registry.sent.get(payload_type).copied().unwrap_or(0)
+    }
+
+    pub fn total_handled(&self) -> u64 {
+        self.registry.lock().unwrap().handled.values().sum()
+    }
+
+    pub fn total_sent(&self) -> u64 {
+        self.registry.lock().unwrap().sent.values().sum()
+    }
+
+    /// Middleware counting every message the node sends.
+    pub fn counter(&self) -> SentCounter {
+        SentCounter {
+            metrics: self.clone(),
+        }
+    }
+
+    pub fn report(&self) -> Value {
+        let registry = self.registry.lock().unwrap();
+
+        let latency = registry
+            .latency
+            .iter()
+            .map(|(payload_type, histogram)| (payload_type.clone(), histogram.report()))
+            .collect::<serde_json::Map<_, _>>();
+
+        json!({
+            "handled": registry.handled,
+            "sent": registry.sent,
+            "latency": latency,
+        })
+    }
+
+    /// Writes the report to stderr as one JSON line.
+    pub fn dump(&self) {
+        eprintln!("{}", json!({ "metrics": self.report() }));
+        *self.last_dump.lock().unwrap() = Instant::now();
+    }
+
+    /// Dumps the report if the interval elapsed since the last dump.
+    pub fn tick(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+
+        if self.last_dump.lock().unwrap().elapsed() >= interval {
+            self.dump();
+        }
+    }
+
+    pub fn is_periodic(&self) -> bool {
+        self.interval.is_some()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// See [`Metrics::counter`].
+pub struct SentCounter {
+    metrics: Metrics,
+}
+
+impl<P: Serialize> Middleware<P> for SentCounter {
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        let payload_type = payload_type(&message.body().payload);
+        self.metrics
+            .record_sent(payload_type.as_deref().unwrap_or("unknown"));
+
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::{middleware::Middleware, Body, InitPayload, Message};
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+
+        metrics.record_handled("read", Duration::from_micros(3));
+        metrics.record_handled("read", Duration::from_micros(900));
+        metrics.record_handled("write", Duration::from_millis(2));
+
+        let mut counter = metrics.counter();
+        let init_ok = Message::new(
+            "n1".to_owned(),
+            "c0".to_owned(),
+            Body::new(None, Some(1), InitPayload::InitOk),
+        );
+        counter.on_send(init_ok.clone());
+        counter.on_send(init_ok);
+
+        assert_eq!(metrics.handled("read"), 2);
+        assert_eq!(metrics.sent("init_ok"), 2);
+        assert_eq!(metrics.total_sent(), 2);
+
+        let report = metrics.report();
+        assert_eq!(report["latency"]["read"]["count"], 2);
+        assert_eq!(report["latency"]["read"]["p50_us"], 4);
+        assert_eq!(report["latency"]["read"]["p99_us"], 1024);
+        assert_eq!(report["latency"]["write"]["max_us"], 2000);
+        assert_eq!(report["latency"]["write"]["over_budget"], 0);
+
+        let metrics = Metrics::new().with_budget(Some(Duration::from_millis(1)));
+        assert!(metrics.is_over_budget(Duration::from_millis(2)));
+        metrics.record_handled("send", Duration::from_micros(10));
+        metrics.record_handled("send", Duration::from_millis(5));
+        assert_eq!(metrics.report()["latency"]["send"]["over_budget"], 1);
+        assert!(!Metrics::new().is_over_budget(Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::{ErrorCode, MaelstromError, Message};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::Write,
+};
+
+/// What a [`Middleware`] decided to do with an inbound message.
+#[derive(Debug)]
+pub enum Received<P> {
+    /// Pass it on to the next middleware and eventually the node.
+    Handle(Message<P>),
+    /// Drop it, the node never sees it.
+    Drop,
+    /// Answer it right away instead of handling it.
+    Reply(Message<P>),
+}
+
+/// Hook around everything a node receives and sends. Nodes return theirs
+/// from [`crate::Node::middleware`] and `main_loop` runs every message
+/// through them in that order, so cross-cutting concerns (logging,
+/// deduplication, ...) don't need to live in every handler.
+pub trait Middleware<P> {
+    fn on_receive(&mut self, message: Message<P>) -> Received<P> {
+        Received::Handle(message)
+    }
+
+    /// Called for every outbound message, retransmissions included, once it
+    /// has its `msg_id`. Returning `None` drops it, or holds it back for
+    /// [`Middleware::release`].
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        Some(message)
+    }
+
+    /// Messages held back by [`Middleware::on_send`] that can go out now.
+    /// Called every time the sender retransmits what's due, they go through
+    /// the middleware after this one only.
+    fn release(&mut self) -> Vec<Message<P>> {
+        Vec::new()
+    }
+}
+
+type MessageKey = (String, usize);
+
+/// The last `capacity` messages received, by sender and `msg_id`.
+struct Window {
+    capacity: usize,
+    order: VecDeque<MessageKey>,
+    keys: HashSet<MessageKey>,
+}
+
+impl Window {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::new(),
+            keys: HashSet::new(),
+        }
+    }
+
+    fn contains(&self, key: &MessageKey) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Remembers `key`, returning the one forgotten to make room, if any.
+    fn insert(&mut self, key: MessageKey) -> Option<MessageKey> {
+        let evicted = if self.order.len() == self.capacity {
+            self.order.pop_front()
+        } else {
+            None
+        };
+        if let Some(evicted) = &evicted {
+            self.keys.remove(evicted);
+        }
+
+        self.order.push_back(key.clone());
+        self.keys.insert(key);
+
+        evicted
+    }
+}
+
+/// Handles every message at most once, recognizing retransmissions by their
+/// sender and `msg_id`. A retransmitted request is answered with the reply
+/// the node sent the first time, if any, so peers retrying until they get
+/// a reply don't retry forever.
+///
+/// Only the last `capacity` messages are remembered.
+pub struct Dedup<P> {
+    seen: Window,
+    replies: HashMap<MessageKey, Message<P>>,
+}
+
+impl<P> Dedup<P> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: Window::new(capacity),
+            replies: HashMap::new(),
+        }
+    }
+}
+
+impl<P: Clone> Middleware<P> for Dedup<P> {
+    fn on_receive(&mut self, message: Message<P>) -> Received<P> {
+        // Timers and other local messages carry no msg_id
+        let Some(msg_id) = message.msg_id() else {
+            return Received::Handle(message);
+        };
+
+        let key = (message.src().to_owned(), msg_id);
+        if self.seen.contains(&key) {
+            return match self.replies.get(&key) {
+                Some(reply) => Received::Reply(reply.clone()),
+                None => Received::Drop,
+            };
+        }
+
+        if let Some(oldest) = self.seen.insert(key) {
+            self.replies.remove(&oldest);
+        }
+
+        Received::Handle(message)
+    }
+
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        if let Some(in_reply_to) = message.in_reply_to() {
+            let key = (message.dest().to_owned(), in_reply_to);
+            if self.seen.contains(&key) {
+                self.replies.insert(key, message.clone());
+            }
+        }
+
+        Some(message)
+    }
+}
+
+/// How many messages [`Validation`] remembers to recognize duplicates.
+pub const VALIDATION_WINDOW: usize = 10_000;
+
+/// Rejects what a node shouldn't handle: messages addressed to another
+/// node, duplicates of one of the last [`VALIDATION_WINDOW`] messages
+/// received and messages bigger than the maximum size, if any, once
+/// serialized. Requests are answered with a `node-not-found`, `abort` or
+/// `malformed-request` error respectively, if the payload has an error
+/// variant; replies are dropped.
+///
+/// Messages without a `msg_id`, like timers, aren't checked.
+pub struct Validation {
+    node_id: String,
+    max_size: Option<usize>,
+    seen: Window,
+}
+
+impl Validation {
+    pub fn new(node_id: &str, max_size: Option<usize>) -> Self {
+        Self {
+            node_id: node_id.to_owned(),
+            max_size,
+            seen: Window::new(VALIDATION_WINDOW),
+        }
+    }
+
+    fn check<P: Serialize>(
+        &mut self,
+        message: &Message<P>,
+        msg_id: usize,
+    ) -> Result<(), MaelstromError> {
+        if message.dest() != self.node_id {
+            return Err(MaelstromError::new(
+                ErrorCode::NodeNotFound,
+                format!(
+                    "Message for {} received by {}",
+                    message.dest(),
+                    self.node_id
+                ),
+            ));
+        }
+
+        if let Some(max_size) = self.max_size {
+            let mut size = ByteCount(0);
+            if serde_json::to_writer(&mut size, message).is_ok() && size.0 > max_size {
+                return Err(MaelstromError::new(
+                    ErrorCode::MalformedRequest,
+                    format!("Message of {} bytes, at most {max_size} accepted", size.0),
+                ));
+            }
+        }
+
+        let key = (message.src().to_owned(), msg_id);
+        if self.seen.contains(&key) {
+            return Err(MaelstromError::new(
+                ErrorCode::Abort,
+                format!("Duplicate of message {msg_id} from {}", message.src()),
+            ));
+        }
+        self.seen.insert(key);
+
+        Ok(())
+    }
+}
+
+impl<P: Serialize + DeserializeOwned> Middleware<P> for Validation {
+    fn on_receive(&mut self, message: Message<P>) -> Received<P> {
+        let Some(msg_id) = message.msg_id() else {
+            return Received::Handle(message);
+        };
+
+        let Err(error) = self.check(&message, msg_id) else {
+            return Received::Handle(message);
+        };
+        tracing::warn!(src = message.src(), msg_id, error = %error, "Rejected message");
+
+        if message.in_reply_to().is_some() {
+            return Received::Drop;
+        }
+
+        match message.error_reply(&error.into()) {
+            Ok(reply) => Received::Reply(reply),
+            Err(_) => Received::Drop,
+        }
+    }
+}
+
+/// Counts the bytes written to it.
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes every message the node receives and sends to stderr.
+#[derive(Debug, Default)]
+pub struct Logging;
+
+impl<P: std::fmt::Debug> Middleware<P> for Logging {
+    fn on_receive(&mut self, message: Message<P>) -> Received<P> {
+        eprintln!("<- {message:?}");
+        Received::Handle(message)
+    }
+
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        eprintln!("-> {message:?}");
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dedup, Middleware, Received, Validation};
+    use crate::{Body, ErrorCode, ErrorPayload, Message};
+
+    #[test]
+    fn test_dedup() {
+        let mut dedup = Dedup::new(2);
+        let request = |src: &str, msg_id| {
+            Message::new(
+                src.to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(msg_id), None, "ping"),
+            )
+        };
+
+        assert!(matches!(
+            dedup.on_receive(request("n2", 1)),
+            Received::Handle(_)
+        ));
+        assert!(matches!(dedup.on_receive(request("n2", 1)), Received::Drop));
+        assert!(matches!(
+            dedup.on_receive(request("n3", 1)),
+            Received::Handle(_)
+        ));
+
+        let reply = request("n3", 1).reply("pong");
+        assert!(dedup.on_send(reply).is_some());
+        let Received::Reply(reply) = dedup.on_receive(request("n3", 1)) else {
+            panic!("Expected the cached reply");
+        };
+        assert_eq!(reply.dest(), "n3");
+        assert_eq!(reply.in_reply_to(), Some(1));
+
+        // Evicts the first request from n2
+        assert!(matches!(
+            dedup.on_receive(request("n2", 2)),
+            Received::Handle(_)
+        ));
+        assert!(matches!(
+            dedup.on_receive(request("n2", 1)),
+            Received::Handle(_)
+        ));
+    }
+
+    #[test]
+    fn test_validation() {
+        let mut validation = Validation::new("n1", Some(128));
+        let request = |dest: &str, msg_id, text: &str| {
+            Message::new(
+                "c1".to_owned(),
+                dest.to_owned(),
+                Body::new(
+                    Some(msg_id),
+                    None,
+                    ErrorPayload::Error {
+                        code: ErrorCode::Crash,
+                        text: text.to_owned(),
+                    },
+                ),
+            )
+        };
+        let rejected = |received| match received {
+            Received::Reply(reply) => {
+                let ErrorPayload::Error { code, .. } = reply.body().payload;
+                Some(code)
+            }
+            _ => None,
+        };
+
+        assert!(matches!(
+            validation.on_receive(request("n1", 1, "")),
+            Received::Handle(_)
+        ));
+        assert_eq!(
+            rejected(validation.on_receive(request("n1", 1, ""))),
+            Some(ErrorCode::Abort)
+        );
+        assert_eq!(
+            rejected(validation.on_receive(request("n2", 2, ""))),
+            Some(ErrorCode::NodeNotFound)
+        );
+        assert_eq!(
+            rejected(validation.on_receive(request("n1", 3, &"x".repeat(128)))),
+            Some(ErrorCode::MalformedRequest)
+        );
+
+        // Nothing is answered to replies
+        let reply = Message::new(
+            "c1".to_owned(),
+            "n2".to_owned(),
+            Body::new(
+                Some(4),
+                Some(1),
+                ErrorPayload::Error {
+                    code: ErrorCode::Crash,
+                    text: String::new(),
+                },
+            ),
+        );
+        assert!(matches!(validation.on_receive(reply), Received::Drop));
+    }
+}
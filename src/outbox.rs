@@ -0,0 +1,236 @@
+use crate::{Message, MessageSender};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// One FIFO queue of outbound messages per destination. Messages stay
+/// queued until their reply acks them and are retransmitted, oldest first,
+/// every `retry_timeout` until then, so fanouts survive dropped messages.
+///
+/// Only the `window` oldest messages of a destination are in flight, counted
+/// from the oldest one not acked yet, the rest wait their turn: a message
+/// that keeps getting lost holds up the ones queued `window` places behind
+/// it, so a destination never gets messages queued further apart than that.
+/// With a window of 1 a destination gets every message in the order it was
+/// queued.
+pub struct Outbox<P> {
+    window: usize,
+    retry_timeout: Duration,
+    queues: HashMap<String, VecDeque<Queued<P>>>,
+}
+
+struct Queued<P> {
+    message: Message<P>,
+    /// Place in the queue, relative to the messages queued before it.
+    position: usize,
+    /// `None` until sent for the first time.
+    sent_at: Option<Instant>,
+}
+
+impl<P: Clone> Outbox<P> {
+    pub fn new(window: usize, retry_timeout: Duration) -> Self {
+        Self {
+            window: window.max(1),
+            retry_timeout,
+            queues: HashMap::new(),
+        }
+    }
+
+    /// Queues `message` for its destination, sending it right away if the
+    /// destination's window isn't full. Returns its `msg_id`, which its
+    /// reply must carry as `in_reply_to`.
+    pub fn push(
+        &mut self,
+        mut message: Message<P>,
+        sender: &mut MessageSender<P>,
+    ) -> anyhow::Result<usize> {
+        // Fixed now so every retransmission carries the same one
+        let msg_id = *message
+            .body
+            .msg_id
+            .get_or_insert_with(|| sender.next_msg_id());
+
+        let queue = self.queues.entry(message.dest().to_owned()).or_default();
+        let position = queue.back().map_or(0, |queued| queued.position + 1);
+        queue.push_back(Queued {
+            message,
+            position,
+            sent_at: None,
+        });
+        Self::send_window(queue, self.window, sender)?;
+
+        Ok(msg_id)
+    }
+
+    /// Removes the message `reply` answers from its queue, making room for
+    /// the next one. Returns whether there was such a message.
+    pub fn ack(
+        &mut self,
+        reply: &Message<P>,
+        sender: &mut MessageSender<P>,
+    ) -> anyhow::Result<bool> {
+        let Some(in_reply_to) = reply.in_reply_to() else {
+            return Ok(false);
+        };
+        let Some(queue) = self.queues.get_mut(reply.src()) else {
+            return Ok(false);
+        };
+        let Some(position) = queue
+            .iter()
+            .position(|queued| queued.message.msg_id() == Some(in_reply_to))
+        else {
+            return Ok(false);
+        };
+
+        queue.remove(position);
+        Self::send_window(queue, self.window, sender)?;
+
+        Ok(true)
+    }
+
+    /// Retransmits, in queue order, every message in flight for longer than
+    /// the retry timeout.
+    pub fn retry_due(&mut self, sender: &mut MessageSender<P>) -> anyhow::Result<()> {
+        let now = Instant::now();
+
+        for queue in self.queues.values_mut() {
+            for queued in Self::in_window(queue, self.window) {
+                if queued
+                    .sent_at
+                    .is_some_and(|sent_at| now.duration_since(sent_at) >= self.retry_timeout)
+                {
+                    sender.send(queued.message.clone())?;
+                    queued.sent_at = Some(now);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Messages queued for `dest` and not acked yet, in flight or waiting.
+    pub fn depth(&self, dest: &str) -> usize {
+        self.queues.get(dest).map_or(0, VecDeque::len)
+    }
+
+    /// Messages queued for every destination.
+    pub fn total_depth(&self) -> usize {
+        self.queues.values().map(VecDeque::len).sum()
+    }
+
+    /// Sends the messages of the window that weren't sent yet.
+    fn send_window(
+        queue: &mut VecDeque<Queued<P>>,
+        window: usize,
+        sender: &mut MessageSender<P>,
+    ) -> anyhow::Result<()> {
+        for queued in Self::in_window(queue, window) {
+            if queued.sent_at.is_none() {
+                sender.send(queued.message.clone())?;
+                queued.sent_at = Some(Instant::now());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Messages of `queue` less than `window` places behind its head.
+    fn in_window(
+        queue: &mut VecDeque<Queued<P>>,
+        window: usize,
+    ) -> impl Iterator<Item = &mut Queued<P>> {
+        let end = queue.front().map_or(0, |head| head.position + window);
+
+        queue
+            .iter_mut()
+            .take_while(move |queued| queued.position < end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Outbox;
+    use crate::{writters::MemoryWritter, Body, Message, MessageSender};
+    use std::time::Duration;
+
+    #[test]
+    fn test_outbox() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut outbox = Outbox::new(1, Duration::ZERO);
+
+        let message = |dest: &str, n| {
+            Message::new("n1".to_owned(), dest.to_owned(), Body::new(None, None, n))
+        };
+        let first = outbox.push(message("n2", 1), &mut sender).unwrap();
+        outbox.push(message("n2", 2), &mut sender).unwrap();
+        outbox.push(message("n3", 3), &mut sender).unwrap();
+        assert_eq!(outbox.depth("n2"), 2);
+        assert_eq!(outbox.total_depth(), 3);
+
+        // Only the head of each queue is in flight
+        let payloads = |sent: &[Message<i32>]| {
+            sent.iter()
+                .map(|m| (m.dest().to_owned(), m.body().payload))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            payloads(&sent.lock().unwrap()),
+            [("n2".to_owned(), 1), ("n3".to_owned(), 3)]
+        );
+
+        sent.lock().unwrap().clear();
+        outbox.retry_due(&mut sender).unwrap();
+        let retransmitted = sent.lock().unwrap().clone();
+        assert_eq!(retransmitted.len(), 2);
+        assert!(retransmitted.iter().any(|m| m.msg_id() == Some(first)));
+
+        // The ack makes room for the next message to n2
+        sent.lock().unwrap().clear();
+        let reply = retransmitted
+            .iter()
+            .find(|m| m.dest() == "n2")
+            .unwrap()
+            .reply(0);
+        assert!(outbox.ack(&reply, &mut sender).unwrap());
+        assert!(!outbox.ack(&reply, &mut sender).unwrap());
+        assert_eq!(payloads(&sent.lock().unwrap()), [("n2".to_owned(), 2)]);
+        assert_eq!(outbox.depth("n2"), 1);
+    }
+
+    #[test]
+    fn test_lost_message_holds_up_the_queue() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let mut outbox = Outbox::new(2, Duration::ZERO);
+
+        for n in 1..=5 {
+            outbox
+                .push(
+                    Message::new("n1".to_owned(), "n2".to_owned(), Body::new(None, None, n)),
+                    &mut sender,
+                )
+                .unwrap();
+        }
+
+        // 1 is lost, acking 2 doesn't let 3 overtake it
+        let payloads =
+            |sent: &[Message<i32>]| sent.iter().map(|m| m.body().payload).collect::<Vec<_>>();
+        let second = sent.lock().unwrap()[1].clone();
+        sent.lock().unwrap().clear();
+        outbox.ack(&second.reply(0), &mut sender).unwrap();
+        assert!(sent.lock().unwrap().is_empty());
+
+        outbox.retry_due(&mut sender).unwrap();
+        assert_eq!(payloads(&sent.lock().unwrap()), [1]);
+
+        // Once 1 gets through the queue moves on
+        let first = sent.lock().unwrap()[0].clone();
+        sent.lock().unwrap().clear();
+        outbox.ack(&first.reply(0), &mut sender).unwrap();
+        assert_eq!(payloads(&sent.lock().unwrap()), [3, 4]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Node state surviving restarts: nodes implementing [`Persistent`] have
+//! their state saved to a [`FileStore`] while they run and restored from it
+//! when `main_loop` starts them again.
+
+use crate::Node;
+use anyhow::Context;
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Env var with the path of the file `main_loop` saves the node's state to.
+/// `{node_id}` is replaced with the node's id. State isn't saved when unset.
+pub const STATE_FILE: &str = "STATE_FILE";
+
+/// Env var with the interval, in milliseconds, at which `main_loop` saves
+/// the node's state, [`DEFAULT_SNAPSHOT_INTERVAL`] when unset.
+pub const SNAPSHOT_INTERVAL: &str = "SNAPSHOT_INTERVAL";
+
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// State a node can save and be restored from. Whatever changed since the
+/// last snapshot is lost when the node crashes.
+pub trait Persistent {
+    fn snapshot(&self) -> anyhow::Result<Vec<u8>>;
+
+    /// Replaces the node's state with a snapshot, called before
+    /// [`Node::init`].
+    fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Keeps the last snapshot in a file. The file is replaced atomically, so a
+/// crash while saving leaves the previous snapshot.
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// Store at [`STATE_FILE`], if set.
+    pub fn from_env(node_id: &str) -> Option<Self> {
+        std::env::var(STATE_FILE)
+            .ok()
+            .map(|path| Self::new(path.replace("{node_id}", node_id)))
+    }
+
+    /// The last snapshot saved, `None` if there's none yet.
+    pub fn load(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        match std::fs::read(&self.path) {
+            Ok(snapshot) => Ok(Some(snapshot)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Error reading {}", self.path.display())),
+        }
+    }
+
+    pub fn save(&self, snapshot: &[u8]) -> anyhow::Result<()> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+
+        std::fs::write(&tmp, snapshot)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Error writing {}", self.path.display()))
+    }
+}
+
+/// Restores a node when `main_loop` starts it and saves it every interval
+/// while it runs. Does nothing for nodes that aren't [`Persistent`] or when
+/// there's no store.
+pub(crate) struct Snapshots {
+    store: Option<FileStore>,
+    interval: Duration,
+    saved_at: Instant,
+}
+
+impl Snapshots {
+    pub(crate) fn new(store: Option<FileStore>, interval: Duration) -> Self {
+        Self {
+            store,
+            interval,
+            saved_at: Instant::now(),
+        }
+    }
+
+    pub(crate) fn from_env(node_id: &str) -> anyhow::Result<Self> {
+        let interval = match std::env::var(SNAPSHOT_INTERVAL) {
+            Ok(millis) => Duration::from_millis(
+                millis
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("Invalid {SNAPSHOT_INTERVAL} {millis}: {e}"))?,
+            ),
+            Err(_) => DEFAULT_SNAPSHOT_INTERVAL,
+        };
+
+        Ok(Self::new(FileStore::from_env(node_id), interval))
+    }
+
+    pub(crate) fn restore<N: Node<P>, P>(&self, node: &mut N) -> anyhow::Result<()> {
+        let (Some(store), Some(persistent)) = (&self.store, node.persistent()) else {
+            return Ok(());
+        };
+
+        if let Some(snapshot) = store.load()? {
+            persistent
+                .restore(&snapshot)
+                .context("Error restoring node state")?;
+            tracing::info!(bytes = snapshot.len(), "Restored node state");
+        }
+
+        Ok(())
+    }
+
+    /// Saves the node if the interval elapsed since the last snapshot.
+    pub(crate) fn tick<N: Node<P>, P>(&mut self, node: &mut N) -> anyhow::Result<()> {
+        if self.saved_at.elapsed() < self.interval {
+            return Ok(());
+        }
+
+        self.save(node)
+    }
+
+    pub(crate) fn save<N: Node<P>, P>(&mut self, node: &mut N) -> anyhow::Result<()> {
+        let (Some(store), Some(persistent)) = (&self.store, node.persistent()) else {
+            return Ok(());
+        };
+
+        store.save(&persistent.snapshot()?)?;
+        self.saved_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileStore, Persistent, Snapshots};
+    use crate::{scheduler::Scheduler, Message, Node, NodeContext};
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CounterNode {
+        value: u64,
+    }
+
+    impl Persistent for CounterNode {
+        fn snapshot(&self) -> anyhow::Result<Vec<u8>> {
+            Ok(self.value.to_le_bytes().to_vec())
+        }
+
+        fn restore(&mut self, snapshot: &[u8]) -> anyhow::Result<()> {
+            self.value = u64::from_le_bytes(snapshot.try_into()?);
+            Ok(())
+        }
+    }
+
+    impl Node<()> for CounterNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<()>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            _message: Message<()>,
+            _ctx: &mut NodeContext<()>,
+        ) -> anyhow::Result<()> {
+            self.value += 1;
+            Ok(())
+        }
+
+        fn persistent(&mut self) -> Option<&mut dyn Persistent> {
+            Some(self)
+        }
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let path = std::env::temp_dir().join(format!("state-{}.bin", std::process::id()));
+        let store = FileStore::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+
+        let mut snapshots = Snapshots::new(Some(store.clone()), Duration::from_secs(60));
+        let mut node = CounterNode { value: 3 };
+        snapshots.tick(&mut node).unwrap();
+        assert_eq!(store.load().unwrap(), None);
+
+        snapshots.save(&mut node).unwrap();
+        node.value = 5;
+        snapshots.tick(&mut node).unwrap();
+
+        let mut restarted = CounterNode::default();
+        snapshots.restore(&mut restarted).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restarted.value, 3);
+    }
+}
@@ -0,0 +1,562 @@
+//! Serves several protocols, each with its own payload enum, from a single
+//! node: e.g. to answer both broadcast and counter workloads from one
+//! process, or to keep the messages of clients apart from the ones nodes
+//! exchange among themselves.
+
+use crate::{
+    convert_message,
+    membership::{Membership, MembershipChange},
+    middleware::Middleware,
+    scheduler::{Scheduler, TimerId},
+    writters::MessageWritter,
+    Event, Message, MessageSender, Node, NodeContext, RETRY_TICK,
+};
+use anyhow::anyhow;
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Node dispatching every message to the registered protocol its `type`
+/// belongs to, the first one whose payload it parses as. Protocols shouldn't
+/// share message types: those go to the one registered first. Messages no
+/// protocol knows go to [`Node::handle_unknown`].
+///
+/// Every protocol gets its own scheduler and sender, all numbering their
+/// messages from the node's counter. Middleware of the protocols only sees
+/// their own messages. The membership is the one of the first protocol
+/// keeping one, and every protocol hears of its changes. Ticks go to the
+/// protocol whose timer fired, custom events to every protocol.
+#[derive(Default)]
+pub struct Protocols {
+    protocols: Vec<Box<dyn Protocol>>,
+    /// Index of the protocol of each `type` seen so far.
+    routes: HashMap<String, usize>,
+    /// Timer driving the retransmissions of the protocols.
+    retry: Option<TimerId>,
+}
+
+impl Protocols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a protocol, handled by `node`.
+    pub fn with<N, P>(mut self, node: N) -> Self
+    where
+        N: Node<P> + 'static,
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+    {
+        self.protocols.push(Box::new(Registered::new(node)));
+        self
+    }
+
+    fn route(
+        &mut self,
+        kind: &str,
+        message: Message<Value>,
+        sender: &mut MessageSender<Value>,
+    ) -> anyhow::Result<Option<Message<Value>>> {
+        if let Some(&index) = self.routes.get(kind) {
+            return self.protocols[index].handle(message, sender);
+        }
+
+        let mut message = message;
+        for (index, protocol) in self.protocols.iter_mut().enumerate() {
+            match protocol.handle(message, sender)? {
+                Some(unhandled) => message = unhandled,
+                None => {
+                    self.routes.insert(kind.to_owned(), index);
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(message))
+    }
+}
+
+impl Node<Value> for Protocols {
+    fn init(&mut self, scheduler: Scheduler<Message<Value>>) -> anyhow::Result<()> {
+        for protocol in &mut self.protocols {
+            protocol.init(&scheduler)?;
+        }
+
+        self.retry = Some(scheduler.tick_periodic(RETRY_TICK).id());
+
+        Ok(())
+    }
+
+    fn handle_message(
+        &mut self,
+        message: Message<Value>,
+        ctx: &mut NodeContext<Value>,
+    ) -> anyhow::Result<()> {
+        let Some(kind) = message.body().payload["type"].as_str().map(str::to_owned) else {
+            return self.handle_unknown(message, ctx);
+        };
+
+        // Replies may come with a type another protocol knows too, e.g.
+        // `error`
+        if let Some(msg_id) = message.in_reply_to() {
+            for protocol in &mut self.protocols {
+                protocol.ack(msg_id);
+            }
+        }
+
+        match self.route(&kind, message, ctx)? {
+            Some(unhandled) => self.handle_unknown(unhandled, ctx),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Value>,
+        ctx: &mut NodeContext<Value>,
+    ) -> anyhow::Result<()> {
+        match event {
+            Event::Message(message) => self.handle_message(message, ctx),
+            Event::Tick(id) if Some(id) == self.retry => {
+                for protocol in &mut self.protocols {
+                    protocol.retry_due(ctx)?;
+                }
+
+                Ok(())
+            }
+            event => {
+                for protocol in &mut self.protocols {
+                    protocol.handle_event(event.clone(), ctx)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    fn membership(&mut self) -> Option<&mut Membership> {
+        self.protocols
+            .iter_mut()
+            .find_map(|protocol| protocol.membership())
+    }
+
+    fn on_membership_change(
+        &mut self,
+        change: &MembershipChange,
+        ctx: &mut NodeContext<Value>,
+    ) -> anyhow::Result<()> {
+        for protocol in &mut self.protocols {
+            protocol.on_membership_change(change, ctx)?;
+        }
+
+        Ok(())
+    }
+
+    /// The stats of the only protocol, or of every protocol in the order
+    /// they were registered.
+    fn stats(&self) -> Value {
+        match self.protocols.as_slice() {
+            [protocol] => protocol.stats(),
+            protocols => protocols.iter().map(|protocol| protocol.stats()).collect(),
+        }
+    }
+
+    fn on_shutdown(&mut self, ctx: &mut NodeContext<Value>) -> anyhow::Result<()> {
+        for protocol in &mut self.protocols {
+            protocol.on_shutdown(ctx)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A protocol's node behind its payload type, so protocols of different
+/// types can be kept together.
+trait Protocol {
+    fn init(&mut self, scheduler: &Scheduler<Message<Value>>) -> anyhow::Result<()>;
+
+    /// Handles `message` if its payload is one of the protocol's, returning
+    /// it otherwise.
+    fn handle(
+        &mut self,
+        message: Message<Value>,
+        sender: &mut MessageSender<Value>,
+    ) -> anyhow::Result<Option<Message<Value>>>;
+
+    /// Handles an event other than a message: ticks of the protocol's own
+    /// timers, every other tick is ignored.
+    fn handle_event(
+        &mut self,
+        event: Event<Value>,
+        sender: &mut MessageSender<Value>,
+    ) -> anyhow::Result<()>;
+
+    fn ack(&mut self, msg_id: usize);
+
+    fn retry_due(&mut self, sender: &mut MessageSender<Value>) -> anyhow::Result<()>;
+
+    fn membership(&mut self) -> Option<&mut Membership>;
+
+    fn on_membership_change(
+        &mut self,
+        change: &MembershipChange,
+        sender: &mut MessageSender<Value>,
+    ) -> anyhow::Result<()>;
+
+    fn stats(&self) -> Value;
+
+    fn on_shutdown(&mut self, sender: &mut MessageSender<Value>) -> anyhow::Result<()>;
+}
+
+struct Registered<N, P> {
+    node: N,
+    /// The node's own, made at [`Protocol::init`].
+    scheduler: Option<Scheduler<Message<P>>>,
+    /// Made from the node's sender the first time one is at hand.
+    sender: Option<MessageSender<'static, P>>,
+    middleware: Vec<Box<dyn Middleware<P>>>,
+    /// What `sender` wrote, to forward to the node's sender.
+    outbox: (Sender<Message<P>>, Receiver<Message<P>>),
+    /// The protocol's timers of the ticks forwarded through the node's
+    /// scheduler, by the id of the node's timer.
+    ticks: Ticks,
+}
+
+impl<N, P> Registered<N, P>
+where
+    N: Node<P>,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn new(mut node: N) -> Self {
+        let middleware = node.middleware();
+
+        Self {
+            node,
+            scheduler: None,
+            sender: None,
+            middleware,
+            outbox: unbounded(),
+            ticks: Ticks::default(),
+        }
+    }
+
+    fn sender(&mut self, outer: &MessageSender<Value>) -> &mut MessageSender<'static, P> {
+        self.sender.get_or_insert_with(|| {
+            let mut sender = outer.numbered_with(Outbox {
+                tx: self.outbox.0.clone(),
+            });
+            for middleware in self.middleware.drain(..) {
+                sender.add_middleware(middleware);
+            }
+
+            sender
+        })
+    }
+
+    /// Calls `f` with the node and a context of its own sender and
+    /// scheduler, then sends what the node wrote through `outer`.
+    fn with_context<F>(&mut self, outer: &mut MessageSender<Value>, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut N, &mut NodeContext<P>) -> anyhow::Result<()>,
+    {
+        self.sender(outer);
+        let sender = self.sender.as_mut().expect("sender just made");
+        let mut ctx = NodeContext::new(sender);
+        if let Some(scheduler) = &self.scheduler {
+            ctx = ctx.with_scheduler(scheduler);
+        }
+
+        // Forwarded even if it failed, the node's sender drops it then
+        let result = f(&mut self.node, &mut ctx);
+        self.forward(outer)?;
+
+        result
+    }
+
+    /// Sends what the protocol wrote through the node's sender.
+    fn forward(&mut self, outer: &mut MessageSender<Value>) -> anyhow::Result<()> {
+        for message in self.outbox.1.try_iter() {
+            outer.send(convert_message(&message)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<N, P> Protocol for Registered<N, P>
+where
+    N: Node<P>,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn init(&mut self, outer: &Scheduler<Message<Value>>) -> anyhow::Result<()> {
+        let (tx, rx) = unbounded();
+        let scheduler = Scheduler::new(tx);
+
+        let outer = outer.clone();
+        let timers = scheduler.clone();
+        let ticks = self.ticks.clone();
+        std::thread::spawn(move || forward_timers(&rx, &timers, &outer, &ticks));
+
+        self.scheduler = Some(scheduler.clone());
+        self.node.init(scheduler)
+    }
+
+    fn handle(
+        &mut self,
+        message: Message<Value>,
+        outer: &mut MessageSender<Value>,
+    ) -> anyhow::Result<Option<Message<Value>>> {
+        let Ok(parsed) = convert_message(&message) else {
+            return Ok(Some(message));
+        };
+
+        self.with_context(outer, |node, ctx| match ctx.receive(parsed)? {
+            Some(parsed) => node.handle_message(parsed, ctx),
+            None => Ok(()),
+        })?;
+
+        Ok(None)
+    }
+
+    fn handle_event(
+        &mut self,
+        event: Event<Value>,
+        outer: &mut MessageSender<Value>,
+    ) -> anyhow::Result<()> {
+        let event = match event {
+            Event::Message(message) => return self.handle(message, outer).map(drop),
+            Event::Tick(id) => match self.ticks.lock().unwrap().remove(&id) {
+                Some(id) => Event::Tick(id),
+                None => return Ok(()),
+            },
+            Event::Shutdown => Event::Shutdown,
+            Event::Custom(event) => Event::Custom(event),
+        };
+
+        self.with_context(outer, |node, ctx| node.handle_event(event, ctx))
+    }
+
+    fn ack(&mut self, msg_id: usize) {
+        if let Some(sender) = &self.sender {
+            sender.unacked.lock().unwrap().remove(&msg_id);
+        }
+    }
+
+    fn retry_due(&mut self, outer: &mut MessageSender<Value>) -> anyhow::Result<()> {
+        self.sender(outer).retry_due()?;
+        self.forward(outer)
+    }
+
+    fn membership(&mut self) -> Option<&mut Membership> {
+        self.node.membership()
+    }
+
+    fn on_membership_change(
+        &mut self,
+        change: &MembershipChange,
+        outer: &mut MessageSender<Value>,
+    ) -> anyhow::Result<()> {
+        self.with_context(outer, |node, ctx| node.on_membership_change(change, ctx))
+    }
+
+    fn stats(&self) -> Value {
+        self.node.stats()
+    }
+
+    fn on_shutdown(&mut self, outer: &mut MessageSender<Value>) -> anyhow::Result<()> {
+        self.with_context(outer, |node, ctx| node.on_shutdown(ctx))
+    }
+}
+
+/// Hands what a protocol sends to [`Registered::forward`].
+struct Outbox<P> {
+    tx: Sender<Message<P>>,
+}
+
+impl<P: Clone> MessageWritter<Message<P>> for Outbox<P> {
+    fn send_message(&mut self, message: &Message<P>) -> anyhow::Result<()> {
+        self.tx
+            .send(message.clone())
+            .map_err(|_| anyhow!("Protocol is gone"))
+    }
+
+    fn send_messages(&mut self, messages: &[Message<P>]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+/// Ticks of a protocol's timers, see [`Registered::ticks`].
+type Ticks = Arc<Mutex<HashMap<TimerId, TimerId>>>;
+
+/// Delivers the timers of a protocol through the node's scheduler, shutting
+/// the protocol's down along with it.
+fn forward_timers<P: Serialize>(
+    rx: &Receiver<Event<P>>,
+    timers: &Scheduler<Message<P>>,
+    outer: &Scheduler<Message<Value>>,
+    ticks: &Ticks,
+) {
+    let cancellation = outer.cancellation();
+
+    loop {
+        match rx.recv_timeout(RETRY_TICK) {
+            Ok(Event::Message(message)) => match convert_message(&message) {
+                Ok(message) => {
+                    outer.schedule_once(Duration::ZERO, message);
+                }
+                Err(e) => tracing::warn!(error = format!("{e:#}"), "Dropped timer"),
+            },
+            Ok(Event::Tick(id)) => {
+                // Locked until the tick is known, so the node can't handle
+                // it before
+                let mut ticks = ticks.lock().unwrap();
+                ticks.insert(outer.tick_once(Duration::ZERO).id(), id);
+            }
+            Ok(Event::Custom(event)) => {
+                outer.notify(event);
+            }
+            Ok(Event::Shutdown) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if cancellation.is_cancelled() {
+            timers.shutdown();
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Protocols;
+    use crate::{
+        scheduler::Scheduler, writters::MemoryWritter, Body, Message, MessageSender, Node,
+        NodeContext,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use std::sync::mpsc::channel;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum EchoPayload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode;
+
+    impl Node<EchoPayload> for EchoNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<EchoPayload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<EchoPayload>,
+            ctx: &mut NodeContext<EchoPayload>,
+        ) -> anyhow::Result<()> {
+            let EchoPayload::Echo { echo } = &message.body().payload else {
+                return Ok(());
+            };
+
+            ctx.send(message.reply(EchoPayload::EchoOk { echo: echo.clone() }))
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum CounterPayload {
+        Add { delta: u64 },
+        AddOk,
+        Read,
+        ReadOk { value: u64 },
+    }
+
+    #[derive(Default)]
+    struct CounterNode {
+        value: u64,
+    }
+
+    impl Node<CounterPayload> for CounterNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<CounterPayload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<CounterPayload>,
+            ctx: &mut NodeContext<CounterPayload>,
+        ) -> anyhow::Result<()> {
+            let reply = match message.body().payload {
+                CounterPayload::Add { delta } => {
+                    self.value += delta;
+                    CounterPayload::AddOk
+                }
+                CounterPayload::Read => CounterPayload::ReadOk { value: self.value },
+                _ => return Ok(()),
+            };
+
+            ctx.send(message.reply(reply))
+        }
+    }
+
+    #[test]
+    fn test_protocols() {
+        let mut node = Protocols::new().with(EchoNode).with(CounterNode::default());
+        let (tx, _rx) = channel();
+        let scheduler = Scheduler::new(tx);
+        node.init(scheduler.clone()).unwrap();
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+
+        let requests = [
+            json!({ "type": "echo", "echo": "hi" }),
+            json!({ "type": "add", "delta": 3 }),
+            json!({ "type": "topology", "topology": {} }),
+            json!({ "type": "read" }),
+        ];
+        for (msg_id, payload) in requests.into_iter().enumerate() {
+            let request = Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(Some(msg_id), None, payload),
+            );
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+        scheduler.shutdown();
+
+        let sent = sent.lock().unwrap();
+        let replies = sent
+            .iter()
+            .map(|m| (m.in_reply_to(), m.body().payload.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replies,
+            [
+                (Some(0), json!({ "type": "echo_ok", "echo": "hi" })),
+                (Some(1), json!({ "type": "add_ok" })),
+                (Some(3), json!({ "type": "read_ok", "value": 3 })),
+            ]
+        );
+
+        // Protocols number their messages from the node's counter
+        let msg_ids = sent.iter().map(|m| m.msg_id()).collect::<Vec<_>>();
+        assert_eq!(msg_ids, [Some(0), Some(1), Some(2)]);
+        assert_eq!(sender.next_msg_id(), 3);
+    }
+}
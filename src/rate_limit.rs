@@ -0,0 +1,177 @@
+use crate::{middleware::Middleware, Message};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+/// Most messages held back per destination, past which the oldest are
+/// dropped.
+const MAX_QUEUED: usize = 1_000;
+
+/// Allows bursts of up to `burst` messages and `rate` messages per second
+/// on average.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning `false` if there's none left right now.
+    pub fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Holds back what a node sends to a destination over its rate, so gossip
+/// heavy nodes stay within a message budget, and releases it in order as
+/// tokens come back. Retransmissions count too. Destinations without a
+/// limit, e.g. clients waiting for replies, are never limited.
+pub struct RateLimit<P> {
+    buckets: HashMap<String, TokenBucket>,
+    queued: HashMap<String, VecDeque<Message<P>>>,
+}
+
+impl<P> RateLimit<P> {
+    pub fn new() -> Self {
+        Self {
+            buckets: HashMap::new(),
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Limits messages to `dest` to `rate` per second, in bursts of up to
+    /// `burst`.
+    pub fn with_limit(mut self, dest: &str, rate: f64, burst: f64) -> Self {
+        self.buckets
+            .insert(dest.to_owned(), TokenBucket::new(rate, burst));
+        self
+    }
+}
+
+impl<P> Default for RateLimit<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P> Middleware<P> for RateLimit<P> {
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        let Some(bucket) = self.buckets.get_mut(message.dest()) else {
+            return Some(message);
+        };
+        let queued = self.queued.entry(message.dest().to_owned()).or_default();
+        // Behind what's held back already, to keep the order
+        if queued.is_empty() && bucket.try_take() {
+            return Some(message);
+        }
+
+        tracing::debug!(dest = message.dest(), "Rate limited message");
+        if queued.len() == MAX_QUEUED {
+            queued.pop_front();
+        }
+        queued.push_back(message);
+
+        None
+    }
+
+    fn release(&mut self) -> Vec<Message<P>> {
+        let mut released = Vec::new();
+        for (dest, queued) in &mut self.queued {
+            let Some(bucket) = self.buckets.get_mut(dest) else {
+                continue;
+            };
+            while !queued.is_empty() && bucket.try_take() {
+                released.extend(queued.pop_front());
+            }
+        }
+
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimit;
+    use crate::{middleware::Middleware, writters::MemoryWritter, Body, Message, MessageSender};
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::new().with_limit("n2", 0.0, 2.0);
+        let mut send = |dest: &str| {
+            let message = Message::new("n1".to_owned(), dest.to_owned(), Body::new(None, None, ()));
+            limit.on_send(message).is_some()
+        };
+
+        assert!(send("n2"));
+        assert!(send("n2"));
+        assert!(!send("n2"));
+        assert!((0..10).all(|_| send("c1")));
+    }
+
+    #[test]
+    fn test_held_back_messages_are_released() {
+        let mut limit = RateLimit::new().with_limit("n2", 100.0, 1.0);
+        let message = |msg_id| {
+            Message::new(
+                "n1".to_owned(),
+                "n2".to_owned(),
+                Body::new(Some(msg_id), None, ()),
+            )
+        };
+
+        assert!(limit.on_send(message(1)).is_some());
+        assert!(limit.on_send(message(2)).is_none());
+        assert!(limit.on_send(message(3)).is_none());
+
+        // In the order they were sent, as tokens come back
+        let mut released = Vec::new();
+        while released.len() < 2 {
+            std::thread::sleep(Duration::from_millis(1));
+            released.extend(limit.release().iter().filter_map(Message::msg_id));
+        }
+        assert_eq!(released, [2, 3]);
+        assert!(limit.release().is_empty());
+    }
+
+    #[test]
+    fn test_released_on_retry() {
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        sender.add_middleware(Box::new(RateLimit::new().with_limit("n2", 100.0, 1.0)));
+
+        for _ in 0..2 {
+            let message = Message::new("n1".to_owned(), "n2".to_owned(), Body::new(None, None, ()));
+            sender.send(message).unwrap();
+        }
+        assert_eq!(sent.lock().unwrap().len(), 1);
+
+        std::thread::sleep(Duration::from_millis(20));
+        sender.retry_due().unwrap();
+        assert_eq!(sent.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::{
+    codec::{Codec, Json},
+    Incoming, InitPayload, Message,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, Stdin},
+    path::Path,
+};
+
+/// Source of the messages a node handles. Errors are per message, so a
+/// reader can still be read after returning one; it returns `None` once its
+/// input is exhausted or broken.
+pub trait MessageReader<T> {
+    fn read_message(&mut self) -> Option<anyhow::Result<T>>;
+}
+
+/// Reads one JSON message per line from stdin, as Maelstrom sends them,
+/// skipping blank lines. The node's messages are parsed straight into its
+/// payload, see [`Message::from_json`].
+pub struct StdinJsonReader {
+    stdin: BufReader<Stdin>,
+    done: bool,
+}
+
+impl StdinJsonReader {
+    pub fn new() -> Self {
+        Self::with_capacity(crate::config::Config::default().read_buffer)
+    }
+
+    /// Reads stdin `capacity` bytes at a time, fewer reads under high
+    /// request rates for larger buffers.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            stdin: BufReader::with_capacity(capacity.max(1), std::io::stdin()),
+            done: false,
+        }
+    }
+}
+
+impl Default for StdinJsonReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageReader<Message<InitPayload>> for StdinJsonReader {
+    fn read_message(&mut self) -> Option<anyhow::Result<Message<InitPayload>>> {
+        read_frame(&mut self.stdin, &Json::default(), &mut self.done)
+    }
+}
+
+impl<P> MessageReader<Message<Incoming<P>>> for StdinJsonReader
+where
+    P: DeserializeOwned,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<Message<Incoming<P>>>> {
+        let line = read_raw_frame(&mut self.stdin, &Json::default(), &mut self.done)?;
+
+        Some(line.and_then(|line| {
+            Message::from_json(&line).with_context(|| {
+                format!(
+                    "Failed to parse input message: {}",
+                    String::from_utf8_lossy(&line).trim_end()
+                )
+            })
+        }))
+    }
+}
+
+/// Reads messages recorded one JSON document per line in a file.
+pub struct FileJsonReader {
+    file: BufReader<File>,
+    done: bool,
+}
+
+impl FileJsonReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .with_context(|| format!("Error opening messages file {}", path.display()))?;
+
+        Ok(Self {
+            file: BufReader::new(file),
+            done: false,
+        })
+    }
+}
+
+impl<T> MessageReader<T> for FileJsonReader
+where
+    T: DeserializeOwned,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<T>> {
+        read_frame(&mut self.file, &Json::default(), &mut self.done)
+    }
+}
+
+/// Reads messages in any [`Codec`] from `input`, e.g. a socket.
+pub struct CodecReader<R, C> {
+    input: R,
+    codec: C,
+    done: bool,
+}
+
+impl<R: BufRead, C: Codec> CodecReader<R, C> {
+    pub fn new(input: R, codec: C) -> Self {
+        Self {
+            input,
+            codec,
+            done: false,
+        }
+    }
+}
+
+impl<T, R, C> MessageReader<T> for CodecReader<R, C>
+where
+    T: DeserializeOwned,
+    R: BufRead,
+    C: Codec,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<T>> {
+        read_frame(&mut self.input, &self.codec, &mut self.done)
+    }
+}
+
+/// Serves JSON lines kept in memory, mostly useful to drive a node from
+/// tests. Lines are parsed as they are read, so they can hold the `init`
+/// message as well as the node's own messages.
+pub struct MemoryReader {
+    lines: VecDeque<String>,
+}
+
+impl MemoryReader {
+    pub fn new<I, S>(lines: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            lines: lines.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn from_messages<T: Serialize>(messages: &[T]) -> anyhow::Result<Self> {
+        let lines = messages
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error serializing message")?;
+
+        Ok(Self::new(lines))
+    }
+
+    /// Queues one more line after the ones not read yet.
+    pub fn push<S: Into<String>>(&mut self, line: S) {
+        self.lines.push_back(line.into());
+    }
+}
+
+impl<T> MessageReader<T> for MemoryReader
+where
+    T: DeserializeOwned,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<T>> {
+        let mut line = self.lines.pop_front()?;
+        while line.trim().is_empty() {
+            line = self.lines.pop_front()?;
+        }
+
+        Some(Json::default().decode(line.as_bytes()))
+    }
+}
+
+fn read_frame<T, R, C>(input: &mut R, codec: &C, done: &mut bool) -> Option<anyhow::Result<T>>
+where
+    T: DeserializeOwned,
+    R: BufRead,
+    C: Codec,
+{
+    let frame = read_raw_frame(input, codec, done)?;
+
+    Some(frame.and_then(|frame| codec.decode(&frame)))
+}
+
+fn read_raw_frame<R, C>(
+    input: &mut R,
+    codec: &C,
+    done: &mut bool,
+) -> Option<anyhow::Result<Vec<u8>>>
+where
+    R: BufRead,
+    C: Codec,
+{
+    if *done {
+        return None;
+    }
+
+    match codec.read_frame(input) {
+        Ok(Some(frame)) => Some(Ok(frame)),
+        Ok(None) => {
+            *done = true;
+            None
+        }
+        Err(e) => {
+            // Broken input won't get any better, report it once and stop
+            *done = true;
+            Some(Err(e).context("Error reading input"))
+        }
+    }
+}
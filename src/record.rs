@@ -0,0 +1,144 @@
+use crate::{
+    middleware::{Middleware, Received},
+    writters::{FileJsonWritter, MessageWritter},
+    Message,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, time::Instant};
+
+/// Env var with the path of the file `main_loop` records every message the
+/// node receives and sends to. `{node_id}` is replaced with the node's id,
+/// so each node of a test gets its own file. Nothing is recorded when unset.
+pub const RECORD_FILE: &str = "RECORD_FILE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// One line of a capture file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record<M> {
+    /// Position of the message in the capture, inbound and outbound
+    /// messages sharing the same sequence.
+    pub seq: u64,
+    /// Time since the capture started.
+    pub elapsed_us: u64,
+    pub direction: Direction,
+    pub message: M,
+}
+
+/// Captures every message of a node into a JSON lines file of [`Record`]s,
+/// the input of [`crate::replay::Replay`].
+///
+/// As a middleware it should come first, so inbound messages are recorded
+/// as they arrived, before other middleware drops or answers them.
+pub struct Recorder {
+    writter: FileJsonWritter,
+    seq: u64,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Ok(Self {
+            writter: FileJsonWritter::create(path)?,
+            seq: 0,
+            started: Instant::now(),
+        })
+    }
+
+    /// Recorder writing to [`RECORD_FILE`], if set.
+    pub fn from_env(node_id: &str) -> anyhow::Result<Option<Self>> {
+        match std::env::var(RECORD_FILE) {
+            Ok(path) => Ok(Some(Self::create(path.replace("{node_id}", node_id))?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn record<T: Serialize>(
+        &mut self,
+        direction: Direction,
+        message: &Message<T>,
+    ) -> anyhow::Result<()> {
+        let record = Record {
+            seq: self.seq,
+            elapsed_us: self.started.elapsed().as_micros() as u64,
+            direction,
+            message,
+        };
+        self.seq += 1;
+
+        self.writter.send_message(&record)
+    }
+
+    fn record_or_warn<T: Serialize>(&mut self, direction: Direction, message: &Message<T>) {
+        if let Err(error) = self.record(direction, message) {
+            tracing::warn!(error = format!("{error:#}"), "Failed to record message");
+        }
+    }
+}
+
+impl<P: Serialize> Middleware<P> for Recorder {
+    fn on_receive(&mut self, message: Message<P>) -> Received<P> {
+        self.record_or_warn(Direction::In, &message);
+        Received::Handle(message)
+    }
+
+    fn on_send(&mut self, message: Message<P>) -> Option<Message<P>> {
+        self.record_or_warn(Direction::Out, &message);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Direction, Record, Recorder};
+    use crate::{
+        middleware::Middleware,
+        readers::{FileJsonReader, MessageReader},
+        Body, Message,
+    };
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_recorder() {
+        let path = std::env::temp_dir().join(format!("record-{}.jsonl", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+
+        let request = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(1), None, json!({"type": "echo", "echo": "ping"})),
+        );
+        let reply = request.reply(json!({"type": "echo_ok", "echo": "ping"}));
+        recorder.on_receive(request);
+        recorder.on_send(reply);
+
+        let mut reader = FileJsonReader::open(&path).unwrap();
+        let records = std::iter::from_fn(|| reader.read_message())
+            .collect::<anyhow::Result<Vec<Record<Message<Value>>>>>()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let summary = records
+            .iter()
+            .map(|record| {
+                (
+                    record.seq,
+                    record.direction,
+                    record.message.body().payload["type"].clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (0, Direction::In, json!("echo")),
+                (1, Direction::Out, json!("echo_ok"))
+            ]
+        );
+    }
+}
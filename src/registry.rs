@@ -0,0 +1,173 @@
+//! Nodes picked by name at runtime. Every node is a [`Node`] of its own
+//! payload type, [`erase`] hides it behind `serde_json::Value` so nodes of
+//! different types fit in a [`Registry`] and a single binary can run any
+//! of them.
+
+use crate::{config::Config, main_loop_with_config, protocols::Protocols, Init, Node};
+use anyhow::bail;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// A node whose payload type was erased, see [`erase`].
+pub type DynNode = Box<dyn Node<Value>>;
+
+/// Wraps `node` to read and write its payload as JSON. Messages it doesn't
+/// parse go to [`Node::handle_unknown`], and its timers, retransmissions,
+/// stats and membership work as when it runs on its own.
+pub fn erase<N, P>(node: N) -> DynNode
+where
+    N: Node<P> + 'static,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    Box::new(Protocols::new().with(node))
+}
+
+type Constructor = Box<dyn Fn(Init, &Config) -> anyhow::Result<DynNode>>;
+
+/// Constructors of the nodes, by name.
+#[derive(Default)]
+pub struct Registry {
+    constructors: BTreeMap<String, Constructor>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the node `name`, built by `new_node`. Registering a name
+    /// again replaces its constructor.
+    pub fn with<N, P, F>(mut self, name: &str, new_node: F) -> Self
+    where
+        N: Node<P> + 'static,
+        P: Clone + Serialize + DeserializeOwned + Send + 'static,
+        F: Fn(Init, &Config) -> anyhow::Result<N> + 'static,
+    {
+        let constructor = move |init, config: &Config| Ok(erase(new_node(init, config)?));
+        self.constructors
+            .insert(name.to_owned(), Box::new(constructor));
+        self
+    }
+
+    /// Names of the registered nodes, sorted.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.constructors.keys().map(String::as_str)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.constructors.contains_key(name)
+    }
+
+    pub fn build(&self, name: &str, init: Init, config: &Config) -> anyhow::Result<DynNode> {
+        self.constructor(name)?(init, config)
+    }
+
+    /// Runs the node `name` as [`main_loop_with_config`] would. Unknown
+    /// names fail right away, before waiting for `init`.
+    pub fn main_loop(&self, name: &str, config: Config) -> anyhow::Result<()> {
+        let constructor = self.constructor(name)?;
+
+        let node_config = config.clone();
+        main_loop_with_config(config, |init| constructor(init, &node_config))
+    }
+
+    fn constructor(&self, name: &str) -> anyhow::Result<&Constructor> {
+        match self.constructors.get(name) {
+            Some(constructor) => Ok(constructor),
+            None => bail!(
+                "Unknown node {name}, expected one of {}",
+                self.names().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registry;
+    use crate::{
+        config::Config, scheduler::Scheduler, writters::MemoryWritter, Body, Init, Message,
+        MessageSender, Node, NodeContext,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use std::sync::mpsc::channel;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum EchoPayload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode {
+        node_id: String,
+    }
+
+    impl Node<EchoPayload> for EchoNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<EchoPayload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<EchoPayload>,
+            ctx: &mut NodeContext<EchoPayload>,
+        ) -> anyhow::Result<()> {
+            let EchoPayload::Echo { echo } = &message.body().payload else {
+                return Ok(());
+            };
+
+            ctx.send(message.reply(EchoPayload::EchoOk { echo: echo.clone() }))
+        }
+
+        fn stats(&self) -> serde_json::Value {
+            json!({ "node_id": self.node_id })
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new().with("echo", |init, _config| {
+            Ok(EchoNode {
+                node_id: init.node_id,
+            })
+        });
+        assert_eq!(registry.names().collect::<Vec<_>>(), ["echo"]);
+
+        let init = Init {
+            node_id: "n1".to_owned(),
+            node_ids: vec!["n1".to_owned()],
+        };
+        let config = Config::default();
+        assert!(registry.build("broadcast", init.clone(), &config).is_err());
+
+        let mut node = registry.build("echo", init, &config).unwrap();
+        let (tx, _rx) = channel();
+        let scheduler = Scheduler::new(tx);
+        node.init(scheduler.clone()).unwrap();
+        assert_eq!(node.stats(), json!({ "node_id": "n1" }));
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        let request = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(0), None, json!({ "type": "echo", "echo": "hi" })),
+        );
+        node.handle_message(request, &mut NodeContext::new(&mut sender))
+            .unwrap();
+        scheduler.shutdown();
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].in_reply_to(), Some(0));
+        assert_eq!(
+            sent[0].body().payload,
+            json!({ "type": "echo_ok", "echo": "hi" })
+        );
+    }
+}
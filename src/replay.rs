@@ -0,0 +1,329 @@
+use crate::{
+    handle,
+    metrics::Metrics,
+    readers::{FileJsonReader, MessageReader},
+    record::{Direction, Record},
+    scheduler::Scheduler,
+    writters::MemoryWritter,
+    Init, InitPayload, Message, MessageSender, Node, NodeContext,
+};
+use anyhow::{bail, Context};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Env var with the path of a capture file `main_loop` replays instead of
+/// reading stdin, see [`Replay`].
+pub const REPLAY_FILE: &str = "REPLAY_FILE";
+
+/// Env var set to `original` to replay at the pace messages were recorded.
+pub const REPLAY_TIMING: &str = "REPLAY_TIMING";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Timing {
+    /// Delivers every message right after the previous one was handled.
+    #[default]
+    Immediate,
+    /// Delivers every message when it arrived in the recorded run, relative
+    /// to the start of the replay, and retransmits unacknowledged messages
+    /// in between.
+    Original,
+}
+
+impl Timing {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(REPLAY_TIMING).as_deref() {
+            Ok("original") => Ok(Timing::Original),
+            Ok("immediate") | Err(_) => Ok(Timing::Immediate),
+            Ok(other) => bail!("Invalid {REPLAY_TIMING} {other}, expected immediate or original"),
+        }
+    }
+}
+
+/// Outbound message that differs between the recorded run and the replay,
+/// at the same position in the output. `None` when one of them sent fewer
+/// messages.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub index: usize,
+    pub recorded: Option<Value>,
+    pub replayed: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct ReplayReport<P> {
+    /// Everything the node sent during the replay, `init_ok` excluded.
+    pub sent: Vec<Message<P>>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl<P> ReplayReport<P> {
+    pub fn is_identical(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+
+    /// Fails if the replay differed from the capture, writing every
+    /// mismatch to stderr as a JSON line first.
+    pub fn check(&self) -> anyhow::Result<()> {
+        for mismatch in &self.mismatches {
+            eprintln!(
+                "{}",
+                serde_json::json!({
+                    "index": mismatch.index,
+                    "recorded": mismatch.recorded,
+                    "replayed": mismatch.replayed,
+                })
+            );
+        }
+
+        if !self.is_identical() {
+            bail!("{} messages differ from the capture", self.mismatches.len());
+        }
+
+        Ok(())
+    }
+}
+
+/// A capture written by [`crate::record::Recorder`], fed back into a fresh
+/// node to reproduce a run. Messages go through the node's middleware and
+/// are delivered in their recorded order, timers included since they were
+/// recorded like any other inbound message; the node's own timers never
+/// fire during a replay.
+pub struct Replay<P> {
+    init: Init,
+    inbound: Vec<Record<Message<P>>>,
+    outbound: Vec<Value>,
+}
+
+impl<P> Replay<P>
+where
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    pub fn open<T: AsRef<Path>>(path: T) -> anyhow::Result<Self> {
+        let mut reader = FileJsonReader::open(path)?;
+        let mut records = std::iter::from_fn(|| reader.read_message())
+            .collect::<anyhow::Result<Vec<Record<Value>>>>()
+            .context("Error reading capture")?
+            .into_iter();
+
+        let init = records.next().context("Capture is empty")?;
+        let init = serde_json::from_value::<Message<InitPayload>>(init.message)
+            .context("Capture doesn't start with the init message")?;
+        let InitPayload::Init(init) = init.body.payload else {
+            bail!("Capture doesn't start with the init message");
+        };
+
+        let mut inbound = Vec::new();
+        let mut outbound = Vec::new();
+        // Skips init_ok, which isn't sent through the node
+        for record in records.skip(1) {
+            match record.direction {
+                Direction::In => inbound.push(Record {
+                    seq: record.seq,
+                    elapsed_us: record.elapsed_us,
+                    direction: record.direction,
+                    message: serde_json::from_value(record.message).with_context(|| {
+                        format!("Error parsing recorded message {}", record.seq)
+                    })?,
+                }),
+                Direction::Out => outbound.push(record.message),
+            }
+        }
+
+        Ok(Self {
+            init,
+            inbound,
+            outbound,
+        })
+    }
+
+    /// Builds a node with `new_node` and replays the capture into it,
+    /// comparing what it sends with what was recorded.
+    pub fn run<N, F>(&self, timing: Timing, new_node: F) -> anyhow::Result<ReplayReport<P>>
+    where
+        N: Node<P>,
+        F: FnOnce(Init) -> anyhow::Result<N>,
+    {
+        let mut node = new_node(self.init.clone())?;
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        for middleware in node.middleware() {
+            sender.add_middleware(middleware);
+        }
+
+        // Timers are replayed from the capture, whatever the node schedules
+        // is dropped
+        let (tx, _rx) = std::sync::mpsc::channel();
+        let scheduler = Scheduler::new(tx);
+        node.init(scheduler.clone())?;
+
+        let metrics = Metrics::new();
+        let mut ctx = NodeContext::new(&mut sender).with_scheduler(&scheduler);
+        let started = Instant::now();
+        let result = self
+            .inbound
+            .iter()
+            .try_for_each(|record| -> anyhow::Result<()> {
+                if timing == Timing::Original {
+                    let due = Duration::from_micros(record.elapsed_us);
+                    std::thread::sleep(due.saturating_sub(started.elapsed()));
+                    ctx.retry_due()?;
+                }
+
+                if let Some(message) = ctx.receive(record.message.clone())? {
+                    ctx.ack(&message);
+                    handle(&mut node, message, &mut ctx, &metrics)?;
+                }
+
+                Ok(())
+            });
+
+        scheduler.shutdown();
+        result?;
+
+        let sent = sent.lock().unwrap().clone();
+        let mismatches = self.diff(&sent)?;
+
+        Ok(ReplayReport { sent, mismatches })
+    }
+
+    fn diff(&self, sent: &[Message<P>]) -> anyhow::Result<Vec<Mismatch>> {
+        let replayed = sent
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Error serializing replayed message")?;
+
+        let mismatches = (0..self.outbound.len().max(replayed.len()))
+            .filter_map(|index| {
+                let recorded = self.outbound.get(index);
+                let replayed = replayed.get(index);
+
+                (recorded != replayed).then(|| Mismatch {
+                    index,
+                    recorded: recorded.cloned(),
+                    replayed: replayed.cloned(),
+                })
+            })
+            .collect();
+
+        Ok(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Replay, Timing};
+    use crate::{
+        record::{Direction, Recorder},
+        scheduler::Scheduler,
+        writters::MemoryWritter,
+        Body, Init, InitPayload, Message, MessageSender, Node, NodeContext,
+    };
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode {
+        shout: bool,
+    }
+
+    impl Node<Payload> for EchoNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<Payload>,
+            ctx: &mut NodeContext<Payload>,
+        ) -> anyhow::Result<()> {
+            let Payload::Echo { echo } = &message.body().payload else {
+                return Ok(());
+            };
+            let echo = match self.shout {
+                true => echo.to_uppercase(),
+                false => echo.clone(),
+            };
+
+            ctx.send(message.reply(Payload::EchoOk { echo }))
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join(format!("replay-{}.jsonl", std::process::id()));
+        let mut recorder = Recorder::create(&path).unwrap();
+
+        let init = Message::new(
+            "c0".to_owned(),
+            "n1".to_owned(),
+            Body::new(
+                Some(0),
+                None,
+                InitPayload::Init(Init {
+                    node_id: "n1".to_owned(),
+                    node_ids: vec!["n1".to_owned()],
+                }),
+            ),
+        );
+        recorder.record(Direction::In, &init).unwrap();
+        recorder
+            .record(Direction::Out, &init.reply(InitPayload::InitOk))
+            .unwrap();
+
+        let mut sender = MessageSender::new(MemoryWritter::new());
+        sender.add_middleware(Box::new(recorder));
+        let mut node = EchoNode { shout: false };
+        for (msg_id, echo) in ["hi", "bye"].into_iter().enumerate() {
+            let request = Message::new(
+                "c1".to_owned(),
+                "n1".to_owned(),
+                Body::new(
+                    Some(msg_id),
+                    None,
+                    Payload::Echo {
+                        echo: echo.to_owned(),
+                    },
+                ),
+            );
+            let request = sender.receive(request).unwrap().unwrap();
+            node.handle_message(request, &mut NodeContext::new(&mut sender))
+                .unwrap();
+        }
+        drop(sender);
+
+        let replay = Replay::<Payload>::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let report = replay
+            .run(Timing::Immediate, |_| Ok(EchoNode { shout: false }))
+            .unwrap();
+        assert!(report.is_identical());
+        assert_eq!(report.sent.len(), 2);
+
+        let report = replay
+            .run(Timing::Immediate, |_| Ok(EchoNode { shout: true }))
+            .unwrap();
+        assert_eq!(report.mismatches.len(), 2);
+        assert_eq!(
+            report.mismatches[0].recorded.as_ref().unwrap()["body"]["echo"],
+            "hi"
+        );
+        assert_eq!(
+            report.mismatches[0].replayed.as_ref().unwrap()["body"]["echo"],
+            "HI"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Consistent hash ring with virtual nodes. Each member is placed on the ring
+/// `virtual_nodes` times and a key is owned by the first `replication_factor`
+/// distinct members found walking clockwise from the key's position, so
+/// membership changes only move the keys adjacent to the affected points.
+#[derive(Debug, Clone)]
+pub struct Ring {
+    virtual_nodes: usize,
+    replication_factor: usize,
+    members: BTreeSet<String>,
+    points: BTreeMap<u64, String>,
+}
+
+impl Ring {
+    pub fn new(virtual_nodes: usize, replication_factor: usize) -> Self {
+        Self {
+            virtual_nodes,
+            replication_factor,
+            members: BTreeSet::new(),
+            points: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_members<I>(virtual_nodes: usize, replication_factor: usize, members: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut ring = Self::new(virtual_nodes, replication_factor);
+        for member in members {
+            ring.add(&member);
+        }
+
+        ring
+    }
+
+    pub fn add(&mut self, member: &str) {
+        self.members.insert(member.to_owned());
+
+        for i in 0..self.virtual_nodes {
+            self.points
+                .insert(hash(&format!("{member}#{i}")), member.to_owned());
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) {
+        self.members.remove(member);
+        self.points.retain(|_, owner| owner != member);
+    }
+
+    pub fn members(&self) -> &BTreeSet<String> {
+        &self.members
+    }
+
+    /// Preference list for `key`: up to `replication_factor` distinct members,
+    /// the first one being the primary owner.
+    pub fn owners(&self, key: &str) -> Vec<String> {
+        let wanted = self.replication_factor.min(self.members().len());
+        let position = hash(key);
+
+        let mut owners: Vec<String> = Vec::with_capacity(wanted);
+        for owner in self
+            .points
+            .range(position..)
+            .chain(self.points.range(..position))
+            .map(|(_, owner)| owner)
+        {
+            if owners.len() == wanted {
+                break;
+            }
+
+            if !owners.contains(owner) {
+                owners.push(owner.clone());
+            }
+        }
+
+        owners
+    }
+
+    pub fn owner(&self, key: &str) -> Option<String> {
+        self.owners(key).into_iter().next()
+    }
+}
+
+/// FNV-1a followed by the murmur3 finalizer, chosen over the std hasher so
+/// every node (and every build) places members and keys on the same ring
+/// positions. The finalizer spreads similar strings across the whole ring.
+fn hash(value: &str) -> u64 {
+    let mut hash = value.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ring;
+    use std::collections::HashMap;
+
+    fn members(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("n{i}")).collect()
+    }
+
+    fn keys() -> Vec<String> {
+        (0..10_000).map(|i| format!("key-{i}")).collect()
+    }
+
+    #[test]
+    fn test_owners_are_distinct_and_replicated() {
+        let ring = Ring::with_members(64, 3, members(5));
+
+        for key in keys() {
+            let mut owners = ring.owners(&key);
+            assert_eq!(owners.len(), 3);
+
+            owners.sort();
+            owners.dedup();
+            assert_eq!(owners.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_replication_factor_capped_by_members() {
+        let ring = Ring::with_members(64, 3, members(2));
+
+        assert_eq!(ring.owners("key").len(), 2);
+        assert!(Ring::new(64, 3).owners("key").is_empty());
+    }
+
+    #[test]
+    fn test_balance() {
+        let ring = Ring::with_members(128, 1, members(5));
+
+        let mut load: HashMap<String, usize> = HashMap::new();
+        for key in keys() {
+            *load.entry(ring.owner(&key).unwrap()).or_default() += 1;
+        }
+
+        let expected = keys().len() / 5;
+        for (member, count) in load {
+            assert!(
+                count > expected / 2 && count < expected * 3 / 2,
+                "{member} owns {count} keys, expected about {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_minimal_movement_on_membership_change() {
+        let before = Ring::with_members(128, 1, members(5));
+        let mut after = before.clone();
+        after.add("n5");
+
+        let moved = keys()
+            .iter()
+            .filter(|key| before.owner(key) != after.owner(key))
+            .count();
+
+        // Ideally 1/6 of the keys move, and all of them to the new member
+        assert!(moved < keys().len() / 4, "{moved} keys moved");
+        for key in keys() {
+            if before.owner(&key) != after.owner(&key) {
+                assert_eq!(after.owner(&key).unwrap(), "n5");
+            }
+        }
+
+        after.remove("n5");
+        for key in keys() {
+            assert_eq!(before.owner(&key), after.owner(&key));
+        }
+    }
+}
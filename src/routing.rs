@@ -0,0 +1,187 @@
+use crate::{Body, Message};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+type OwnerFn = dyn Fn(&str) -> String + Send;
+
+/// What a node should do with a client request after asking the router.
+pub enum Route<P> {
+    /// This node owns the key and should handle the request itself.
+    Local(Message<P>),
+    /// The request must be sent to the owner.
+    Forward(Message<P>),
+}
+
+struct Forwarded<P> {
+    key: String,
+    request: Message<P>,
+    sent_at: Instant,
+}
+
+/// Lets any node accept a client request for a key it doesn't own. Requests
+/// are forwarded to the owner, the owner's reply is relayed back to the
+/// original client and forwards that go unanswered are retried against
+/// whoever owns the key at that point.
+pub struct Router<P> {
+    node_id: String,
+    owner_of: Box<OwnerFn>,
+    retry_timeout: Duration,
+    pending: HashMap<usize, Forwarded<P>>,
+}
+
+impl<P: Clone> Router<P> {
+    pub fn new<F>(node_id: &str, owner_of: F, retry_timeout: Duration) -> Self
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        Self {
+            node_id: node_id.to_owned(),
+            owner_of: Box::new(owner_of),
+            retry_timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Replaces the ownership function, e.g. after a membership change.
+    /// Pending forwards are re-routed on their next retry.
+    pub fn set_owner_fn<F>(&mut self, owner_of: F)
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        self.owner_of = Box::new(owner_of);
+    }
+
+    pub fn owner(&self, key: &str) -> String {
+        (self.owner_of)(key)
+    }
+
+    /// Routes `request` for `key`. When forwarding, `msg_id` is used for the
+    /// outgoing message so the owner's reply can be correlated.
+    pub fn route(&mut self, key: &str, request: &Message<P>, msg_id: usize) -> Route<P> {
+        let owner = self.owner(key);
+        if owner == self.node_id {
+            return Route::Local(request.clone());
+        }
+
+        self.pending.insert(
+            msg_id,
+            Forwarded {
+                key: key.to_owned(),
+                request: request.clone(),
+                sent_at: Instant::now(),
+            },
+        );
+
+        Route::Forward(Message::new(
+            self.node_id.clone(),
+            owner,
+            Body::new(Some(msg_id), None, request.body().payload.clone()),
+        ))
+    }
+
+    /// Turns an owner's reply into the reply for the original client, or
+    /// returns `None` if `reply` doesn't answer a forwarded request.
+    pub fn relay(&mut self, reply: &Message<P>) -> Option<Message<P>> {
+        let forwarded = self.pending.remove(&reply.in_reply_to()?)?;
+
+        Some(forwarded.request.reply(reply.body().payload.clone()))
+    }
+
+    /// Re-routes every forward that hasn't been answered within the retry
+    /// timeout. `next_msg_id` allocates ids for the new forwards.
+    pub fn retry_expired<F>(&mut self, mut next_msg_id: F) -> Vec<Route<P>>
+    where
+        F: FnMut() -> usize,
+    {
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, forwarded)| forwarded.sent_at.elapsed() >= self.retry_timeout)
+            .map(|(msg_id, _)| *msg_id)
+            .collect::<Vec<_>>();
+
+        let forwarded = expired
+            .into_iter()
+            .filter_map(|msg_id| self.pending.remove(&msg_id))
+            .collect::<Vec<_>>();
+
+        forwarded
+            .into_iter()
+            .map(|forwarded| self.route(&forwarded.key, &forwarded.request, next_msg_id()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Route, Router};
+    use crate::{Body, Message};
+    use std::time::Duration;
+
+    fn request(key: &str) -> Message<String> {
+        Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(4), None, key.to_owned()),
+        )
+    }
+
+    fn owner(key: &str) -> String {
+        if key.starts_with('a') { "n1" } else { "n2" }.to_owned()
+    }
+
+    #[test]
+    fn test_route() {
+        let mut router = Router::new("n1", owner, Duration::from_secs(60));
+        assert!(matches!(
+            router.route("a", &request("a"), 1),
+            Route::Local(_)
+        ));
+
+        let Route::Forward(forward) = router.route("b", &request("b"), 2) else {
+            panic!("Expected a forward");
+        };
+        assert_eq!(forward.dest(), "n2");
+        assert_eq!(forward.msg_id(), Some(2));
+        assert_eq!(forward.body().payload, "b");
+
+        // The owner's reply goes back to the client, once
+        let reply = forward.reply("b_ok".to_owned());
+        let relayed = router.relay(&reply).unwrap();
+        assert_eq!(relayed.dest(), "c1");
+        assert_eq!(relayed.in_reply_to(), Some(4));
+        assert_eq!(relayed.body().payload, "b_ok");
+        assert!(router.relay(&reply).is_none());
+    }
+
+    #[test]
+    fn test_retry_expired() {
+        let mut router = Router::new("n1", owner, Duration::from_millis(10));
+        assert!(matches!(
+            router.route("b", &request("b"), 1),
+            Route::Forward(_)
+        ));
+        assert!(router.retry_expired(|| 2).is_empty());
+
+        // Sent again to whoever owns the key by then, under a new msg_id
+        std::thread::sleep(Duration::from_millis(20));
+        router.set_owner_fn(|_| "n3".to_owned());
+        let mut retried = router.retry_expired(|| 2);
+        let Some(Route::Forward(forward)) = retried.pop() else {
+            panic!("Expected a forward");
+        };
+        assert_eq!(forward.dest(), "n3");
+        assert_eq!(forward.msg_id(), Some(2));
+
+        // Late replies to the first forward are ignored
+        let late = Message::new(
+            "n2".to_owned(),
+            "n1".to_owned(),
+            Body::new(None, Some(1), String::new()),
+        );
+        assert!(router.relay(&late).is_none());
+        assert!(router.relay(&forward.reply(String::new())).is_some());
+    }
+}
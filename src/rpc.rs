@@ -0,0 +1,299 @@
+use crate::{convert_message, Message};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Invoked with the node and the reply once a request is answered.
+pub type Callback<N, P> = Box<dyn FnOnce(&mut N, Message<P>) -> anyhow::Result<()> + Send>;
+
+enum Waiter<N, P> {
+    Callback(Callback<N, P>),
+    Handle(Sender<anyhow::Result<Message<P>>>),
+}
+
+struct Pending<N, P> {
+    waiter: Waiter<N, P>,
+    sent_at: Instant,
+}
+
+/// Receiving end for a reply registered with [`Rpc::register_handle`] or
+/// sent with [`crate::MessageSender::rpc`].
+pub struct ReplyHandle<P> {
+    rx: Receiver<anyhow::Result<Message<P>>>,
+    /// Stops waiting for the reply once the handle is dropped.
+    forget: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl<P> ReplyHandle<P> {
+    /// The reply if it arrived already. Fails if it doesn't parse as `P`.
+    pub fn try_recv(&self) -> Option<anyhow::Result<Message<P>>> {
+        self.rx.try_recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> anyhow::Result<Message<P>> {
+        self.rx.recv_timeout(timeout).map_err(|e| match e {
+            RecvTimeoutError::Timeout => anyhow::anyhow!("Timed out waiting for reply"),
+            RecvTimeoutError::Disconnected => anyhow::anyhow!("Request was cancelled"),
+        })?
+    }
+}
+
+impl<P> Drop for ReplyHandle<P> {
+    fn drop(&mut self) {
+        if let Some(forget) = self.forget.take() {
+            forget();
+        }
+    }
+}
+
+type Deliver<P> = Box<dyn FnOnce(Message<P>) + Send>;
+
+/// Requests sent with [`crate::MessageSender::rpc`] by `msg_id`, shared with
+/// the thread reading the node's input. Their replies go straight to their
+/// [`ReplyHandle`] instead of through the main loop, so a handler can block
+/// on them: they skip the node's middleware and `handle_message`.
+pub(crate) struct Replies<P> {
+    waiting: Arc<Mutex<HashMap<usize, Deliver<P>>>>,
+}
+
+impl<P> Clone for Replies<P> {
+    fn clone(&self) -> Self {
+        Self {
+            waiting: self.waiting.clone(),
+        }
+    }
+}
+
+impl<P> Default for Replies<P> {
+    fn default() -> Self {
+        Self {
+            waiting: Arc::default(),
+        }
+    }
+}
+
+impl<P: Serialize + 'static> Replies<P> {
+    /// Handle for the reply to `msg_id`, parsed as `R`.
+    pub(crate) fn register<R>(&self, msg_id: usize) -> ReplyHandle<R>
+    where
+        R: DeserializeOwned + Send + 'static,
+    {
+        let (tx, rx) = channel();
+        let deliver = move |reply: Message<P>| {
+            let _ = tx.send(convert_message(&reply));
+        };
+        self.waiting
+            .lock()
+            .unwrap()
+            .insert(msg_id, Box::new(deliver));
+
+        let waiting = Arc::downgrade(&self.waiting);
+        let forget = move || {
+            if let Some(waiting) = waiting.upgrade() {
+                waiting.lock().unwrap().remove(&msg_id);
+            }
+        };
+
+        ReplyHandle {
+            rx,
+            forget: Some(Box::new(forget)),
+        }
+    }
+
+    /// Hands `message` to the handle waiting for it, returning it back if
+    /// it isn't the reply to a request sent with
+    /// [`crate::MessageSender::rpc`].
+    pub(crate) fn deliver(&self, message: Message<P>) -> Option<Message<P>> {
+        let waiting = message
+            .in_reply_to()
+            .and_then(|msg_id| self.waiting.lock().unwrap().remove(&msg_id));
+
+        match waiting {
+            Some(deliver) => {
+                deliver(message);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// Tracks outstanding requests by `msg_id` and routes the matching `*_ok`
+/// replies (anything whose `in_reply_to` is pending) back to whoever sent the
+/// request, so nodes don't need their own pending-request bookkeeping.
+///
+/// Nodes keep an `Rpc<Self, Payload>` and, before dispatching a message, call
+/// [`Rpc::take_callback`] to find out whether it answers one of their requests.
+pub struct Rpc<N, P> {
+    pending: HashMap<usize, Pending<N, P>>,
+}
+
+impl<N, P> Default for Rpc<N, P> {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<N, P: Clone + Send + 'static> Rpc<N, P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<F>(&mut self, msg_id: usize, callback: F)
+    where
+        F: FnOnce(&mut N, Message<P>) -> anyhow::Result<()> + Send + 'static,
+    {
+        self.insert(msg_id, Waiter::Callback(Box::new(callback)));
+    }
+
+    pub fn register_handle(&mut self, msg_id: usize) -> ReplyHandle<P> {
+        let (tx, rx) = channel();
+        self.insert(msg_id, Waiter::Handle(tx));
+
+        ReplyHandle { rx, forget: None }
+    }
+
+    pub fn is_pending(&self, msg_id: usize) -> bool {
+        self.pending.contains_key(&msg_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn cancel(&mut self, msg_id: usize) {
+        self.pending.remove(&msg_id);
+    }
+
+    /// Forgets requests that went unanswered for longer than `timeout`.
+    /// Their handles observe the cancellation.
+    pub fn prune(&mut self, timeout: Duration) {
+        self.pending
+            .retain(|_, pending| pending.sent_at.elapsed() < timeout);
+    }
+
+    /// Returns the callback waiting for `message` if it is a reply to a
+    /// pending request. Replies awaited through a handle are delivered right
+    /// away and a no-op callback is returned, so either way the caller knows
+    /// the message was consumed.
+    pub fn take_callback(&mut self, message: &Message<P>) -> Option<Callback<N, P>> {
+        let pending = self.pending.remove(&message.in_reply_to()?)?;
+
+        match pending.waiter {
+            Waiter::Callback(callback) => Some(callback),
+            Waiter::Handle(tx) => {
+                let _ = tx.send(Ok(message.clone()));
+                Some(Box::new(|_, _| Ok(())))
+            }
+        }
+    }
+
+    fn insert(&mut self, msg_id: usize, waiter: Waiter<N, P>) {
+        self.pending.insert(
+            msg_id,
+            Pending {
+                waiter,
+                sent_at: Instant::now(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Replies, Rpc};
+    use crate::{Body, Message};
+    use std::time::Duration;
+
+    fn request(msg_id: usize) -> Message<u32> {
+        Message::new(
+            "n1".to_owned(),
+            "n2".to_owned(),
+            Body::new(Some(msg_id), None, 0),
+        )
+    }
+
+    #[test]
+    fn test_register() {
+        let mut rpc = Rpc::<Vec<u32>, u32>::new();
+        rpc.register(1, |replies: &mut Vec<u32>, reply| {
+            replies.push(reply.body().payload);
+            Ok(())
+        });
+        assert!(rpc.is_pending(1));
+        assert_eq!(rpc.len(), 1);
+
+        // Only replies to a pending request are taken
+        assert!(rpc.take_callback(&request(1)).is_none());
+        assert!(rpc.take_callback(&request(2).reply(7)).is_none());
+
+        let reply = request(1).reply(5);
+        let callback = rpc.take_callback(&reply).unwrap();
+        let mut replies = Vec::new();
+        callback(&mut replies, reply.clone()).unwrap();
+        assert_eq!(replies, [5]);
+
+        // Once
+        assert!(rpc.is_empty());
+        assert!(rpc.take_callback(&reply).is_none());
+    }
+
+    #[test]
+    fn test_register_handle() {
+        let mut rpc = Rpc::<(), u32>::new();
+        let handle = rpc.register_handle(1);
+        assert!(handle.try_recv().is_none());
+
+        // Delivered to the handle, the callback has nothing left to do
+        let callback = rpc.take_callback(&request(1).reply(5)).unwrap();
+        callback(&mut (), request(1).reply(5)).unwrap();
+        assert_eq!(handle.try_recv().unwrap().unwrap().body().payload, 5);
+
+        rpc.register(2, |_, _| Ok(()));
+        rpc.cancel(2);
+        assert!(rpc.is_empty());
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut rpc = Rpc::<(), u32>::new();
+        let handle = rpc.register_handle(1);
+        std::thread::sleep(Duration::from_millis(20));
+        rpc.register(2, |_, _| Ok(()));
+
+        rpc.prune(Duration::from_millis(10));
+        assert!(!rpc.is_pending(1));
+        assert!(rpc.is_pending(2));
+        assert!(rpc.take_callback(&request(1).reply(5)).is_none());
+        // The handle sees the request was given up on
+        assert!(handle.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+
+    #[test]
+    fn test_replies() {
+        let replies = Replies::<u32>::default();
+        let handle = replies.register::<u32>(1);
+
+        let other = request(2).reply(7);
+        assert!(replies.deliver(other).is_some());
+        assert!(replies.deliver(request(1).reply(5)).is_none());
+        assert_eq!(handle.try_recv().unwrap().unwrap().body().payload, 5);
+
+        // Dropping the handle stops waiting for the reply
+        let handle = replies.register::<u32>(3);
+        drop(handle);
+        assert!(replies.deliver(request(3).reply(5)).is_some());
+    }
+}
@@ -0,0 +1,440 @@
+use rand::Rng;
+use serde_json::Value;
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Identifies a timer of a [`Scheduler`], e.g. in the
+/// [`crate::Event::Tick`] of the timers set with [`Scheduler::tick_periodic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimerId(pub(crate) u64);
+
+/// Cancels the timer it was returned for. Dropping the handle leaves the
+/// timer running.
+#[derive(Debug, Clone)]
+pub struct TimerHandle {
+    pub(crate) id: TimerId,
+    pub(crate) cancelled: Arc<AtomicBool>,
+}
+
+impl TimerHandle {
+    pub fn id(&self) -> TimerId {
+        self.id
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/// Cancelled once, when `main_loop` shuts down, and shared by every thread
+/// it should stop. Threads spawned by nodes keep a clone, from
+/// [`Scheduler::cancellation`], and return once it's cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token and wakes up everyone waiting on it.
+    pub fn cancel(&self) {
+        let (cancelled, condvar) = &*self.inner;
+
+        *cancelled.lock().unwrap() = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Sleeps for `timeout` or until cancelled, whichever comes first, and
+    /// returns whether it was cancelled. Meant as the sleep of background
+    /// loops, e.g. `while !token.wait(interval) {}`.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (cancelled, condvar) = &*self.inner;
+        let cancelled = cancelled.lock().unwrap();
+
+        *condvar
+            .wait_timeout_while(cancelled, timeout, |cancelled| !*cancelled)
+            .unwrap()
+            .0
+    }
+}
+
+/// Where a [`Scheduler`] delivers the items of the timers that fire.
+pub trait TimerSink<T>: Send + 'static {
+    /// Hands `item` over, returning whether it is still received.
+    fn deliver(&self, item: T) -> bool;
+
+    /// Hands over the tick of the timer `id`, see [`Scheduler::tick_once`].
+    /// Sinks of items alone drop it.
+    fn tick(&self, _id: TimerId) -> bool {
+        true
+    }
+
+    /// Hands over `event`, see [`Scheduler::notify`]. Sinks of items alone
+    /// drop it.
+    fn custom(&self, _event: Value) -> bool {
+        true
+    }
+}
+
+impl<T: Send + 'static> TimerSink<T> for mpsc::Sender<T> {
+    fn deliver(&self, item: T) -> bool {
+        self.send(item).is_ok()
+    }
+}
+
+impl<T: Send + 'static> TimerSink<T> for crossbeam_channel::Sender<T> {
+    fn deliver(&self, item: T) -> bool {
+        self.send(item).is_ok()
+    }
+}
+
+/// What a timer delivers when it fires.
+#[derive(Clone)]
+enum Fire<T> {
+    Item(T),
+    Tick,
+    Custom(Value),
+}
+
+struct Timer<T> {
+    fire: Fire<T>,
+    interval: Option<Duration>,
+    /// Most every tick is moved earlier or later, see
+    /// [`Scheduler::schedule_jittered`].
+    jitter: Duration,
+    cancelled: Arc<AtomicBool>,
+}
+
+struct State<T> {
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    timers: HashMap<u64, Timer<T>>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+/// Single timer thread owned by `main_loop`. Nodes register one-shot and
+/// periodic timers and, when a timer fires, a clone of its item is delivered
+/// through the main loop's channel like any other message. Timers set with
+/// the `tick_*` methods deliver no item but an [`crate::Event::Tick`] of
+/// their id instead, so nodes need no message of their own for them.
+pub struct Scheduler<T> {
+    inner: Arc<(Mutex<State<T>>, Condvar)>,
+    cancellation: CancellationToken,
+}
+
+impl<T> Clone for Scheduler<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+impl<T> Scheduler<T> {
+    /// Token cancelled on shutdown, for threads spawned by nodes to stop
+    /// without keeping the whole scheduler around.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Whether the node is shutting down, see [`Scheduler::cancellation`].
+    pub fn is_shutdown(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Same as [`CancellationToken::wait`] on [`Scheduler::cancellation`].
+    pub fn wait_shutdown(&self, timeout: Duration) -> bool {
+        self.cancellation.wait(timeout)
+    }
+
+    /// Stops the timer thread and cancels [`Scheduler::cancellation`].
+    /// Pending timers never fire.
+    pub fn shutdown(&self) {
+        let (state, condvar) = &*self.inner;
+
+        state.lock().unwrap().shutdown = true;
+        condvar.notify_all();
+        self.cancellation.cancel();
+    }
+}
+
+impl<T: Clone + Send + 'static> Scheduler<T> {
+    pub fn new<S: TimerSink<T>>(tx: S) -> Self {
+        let scheduler = Self {
+            inner: Arc::new((
+                Mutex::new(State {
+                    deadlines: BinaryHeap::new(),
+                    timers: HashMap::new(),
+                    next_id: 0,
+                    shutdown: false,
+                }),
+                Condvar::new(),
+            )),
+            cancellation: CancellationToken::new(),
+        };
+
+        let inner = scheduler.inner.clone();
+        std::thread::spawn(move || run(&inner, &tx));
+
+        scheduler
+    }
+
+    /// Delivers `item` once after `delay`.
+    pub fn schedule_once(&self, delay: Duration, item: T) -> TimerHandle {
+        self.schedule(delay, None, Duration::ZERO, Fire::Item(item))
+    }
+
+    /// Delivers `item` every `interval`, starting one interval from now.
+    pub fn schedule_periodic(&self, interval: Duration, item: T) -> TimerHandle {
+        self.schedule(interval, Some(interval), Duration::ZERO, Fire::Item(item))
+    }
+
+    /// Same as [`Scheduler::schedule_periodic`], every tick moved up to
+    /// `jitter` earlier or later at random, and the first one anywhere
+    /// within the first interval. Nodes started together then drift apart
+    /// instead of all gossiping at once, every interval.
+    pub fn schedule_jittered(&self, interval: Duration, jitter: Duration, item: T) -> TimerHandle {
+        self.schedule_jittered_timer(interval, jitter, Fire::Item(item))
+    }
+
+    /// Ticks once after `delay`.
+    pub fn tick_once(&self, delay: Duration) -> TimerHandle {
+        self.schedule(delay, None, Duration::ZERO, Fire::Tick)
+    }
+
+    /// Ticks every `interval`, starting one interval from now.
+    pub fn tick_periodic(&self, interval: Duration) -> TimerHandle {
+        self.schedule(interval, Some(interval), Duration::ZERO, Fire::Tick)
+    }
+
+    /// Same as [`Scheduler::tick_periodic`], jittered as
+    /// [`Scheduler::schedule_jittered`] is.
+    pub fn tick_jittered(&self, interval: Duration, jitter: Duration) -> TimerHandle {
+        self.schedule_jittered_timer(interval, jitter, Fire::Tick)
+    }
+
+    /// Delivers `event` as an [`crate::Event::Custom`] right away, e.g. for
+    /// a thread the node spawned to wake it up.
+    pub fn notify(&self, event: Value) -> TimerHandle {
+        self.schedule(Duration::ZERO, None, Duration::ZERO, Fire::Custom(event))
+    }
+
+    fn schedule_jittered_timer(
+        &self,
+        interval: Duration,
+        jitter: Duration,
+        fire: Fire<T>,
+    ) -> TimerHandle {
+        let jitter = jitter.min(interval);
+        let delay = rand::thread_rng().gen_range(Duration::ZERO..=interval);
+
+        self.schedule(delay, Some(interval), jitter, fire)
+    }
+
+    fn schedule(
+        &self,
+        delay: Duration,
+        interval: Option<Duration>,
+        jitter: Duration,
+        fire: Fire<T>,
+    ) -> TimerHandle {
+        let (state, condvar) = &*self.inner;
+        let mut state = state.lock().unwrap();
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let id = state.next_id;
+        state.next_id += 1;
+
+        state.timers.insert(
+            id,
+            Timer {
+                fire,
+                interval,
+                jitter,
+                cancelled: cancelled.clone(),
+            },
+        );
+        state.deadlines.push(Reverse((Instant::now() + delay, id)));
+        condvar.notify_all();
+
+        TimerHandle {
+            id: TimerId(id),
+            cancelled,
+        }
+    }
+}
+
+fn run<T: Clone, S: TimerSink<T>>(inner: &(Mutex<State<T>>, Condvar), tx: &S) {
+    let (state, condvar) = inner;
+    let mut state = state.lock().unwrap();
+
+    while !state.shutdown {
+        let Some(Reverse((deadline, id))) = state.deadlines.peek().copied() else {
+            state = condvar.wait(state).unwrap();
+            continue;
+        };
+
+        let now = Instant::now();
+        if deadline > now {
+            state = condvar.wait_timeout(state, deadline - now).unwrap().0;
+            continue;
+        }
+
+        state.deadlines.pop();
+        let Some(timer) = state.timers.get(&id) else {
+            continue;
+        };
+
+        if timer.cancelled.load(Ordering::Relaxed) {
+            state.timers.remove(&id);
+            continue;
+        }
+
+        let delivered = match &timer.fire {
+            Fire::Item(item) => tx.deliver(item.clone()),
+            Fire::Tick => tx.tick(TimerId(id)),
+            Fire::Custom(event) => tx.custom(event.clone()),
+        };
+        if !delivered {
+            break;
+        }
+
+        match timer.interval {
+            Some(interval) => {
+                let interval = jittered(interval, timer.jitter);
+                // Skip missed ticks instead of firing them in a burst
+                let next = (deadline + interval).max(now);
+                state.deadlines.push(Reverse((next, id)));
+            }
+            None => {
+                state.timers.remove(&id);
+            }
+        }
+    }
+}
+
+/// `interval` plus or minus up to `jitter`, at random.
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+
+    interval - jitter + rand::thread_rng().gen_range(Duration::ZERO..=jitter * 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jittered, CancellationToken, Scheduler};
+    use std::{sync::mpsc::channel, time::Duration};
+
+    #[test]
+    fn test_once_and_periodic_timers() {
+        let (tx, rx) = channel();
+        let scheduler = Scheduler::new(tx);
+
+        scheduler.schedule_once(Duration::from_millis(10), "once");
+        let periodic = scheduler.schedule_periodic(Duration::from_millis(5), "tick");
+
+        let timeout = Duration::from_secs(1);
+        let mut ticks = 0;
+        let mut once = 0;
+        while ticks < 3 || once == 0 {
+            match rx.recv_timeout(timeout).unwrap() {
+                "once" => once += 1,
+                _ => ticks += 1,
+            }
+        }
+
+        periodic.cancel();
+        assert!(periodic.is_cancelled());
+
+        // Drop whatever fired before the cancellation landed
+        rx.try_iter().for_each(drop);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(rx.try_iter().count(), 0);
+        assert_eq!(once, 1);
+
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_jittered_timers() {
+        let interval = Duration::from_millis(10);
+        let jitter = Duration::from_millis(4);
+        for _ in 0..100 {
+            let delay = jittered(interval, jitter);
+            assert!(delay >= interval - jitter && delay <= interval + jitter);
+        }
+        assert_eq!(jittered(interval, Duration::ZERO), interval);
+
+        let (tx, rx) = channel();
+        let scheduler = Scheduler::new(tx);
+        let periodic = scheduler.schedule_jittered(interval, jitter, "tick");
+
+        let timeout = Duration::from_secs(1);
+        for _ in 0..3 {
+            assert_eq!(rx.recv_timeout(timeout).unwrap(), "tick");
+        }
+
+        periodic.cancel();
+        scheduler.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_wakes_up_waiters() {
+        let (tx, _rx) = channel::<()>();
+        let scheduler = Scheduler::new(tx);
+
+        let waiter = {
+            let scheduler = scheduler.clone();
+            std::thread::spawn(move || scheduler.wait_shutdown(Duration::from_secs(10)))
+        };
+
+        assert!(!scheduler.wait_shutdown(Duration::from_millis(5)));
+        scheduler.shutdown();
+
+        assert!(waiter.join().unwrap());
+        assert!(scheduler.is_shutdown());
+    }
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let ticks = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                let mut ticks = 0;
+                while !token.wait(Duration::from_millis(1)) {
+                    ticks += 1;
+                }
+                ticks
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(10));
+        token.cancel();
+
+        assert!(ticks.join().unwrap() > 0);
+        assert!(token.is_cancelled());
+        assert!(token.wait(Duration::from_secs(10)));
+    }
+}
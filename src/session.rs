@@ -0,0 +1,208 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, path::PathBuf};
+
+/// Env var with the directory sessions are persisted in, one file per node.
+/// Sessions live only as long as the process when unset.
+pub const SESSION_DIR: &str = "SESSION_DIR";
+
+/// Sequences accepted behind the highest one seen, senders must not let a
+/// message fall further behind than that, see [`crate::outbox::Outbox`].
+pub const WINDOW_SIZE: u64 = 64;
+const SEQUENCE_BLOCK: u64 = 1000;
+
+/// Anti-replay sliding window over a peer's sequence numbers. Sequences more
+/// than `WINDOW_SIZE` below the highest one seen are rejected as too old,
+/// anything inside the window is accepted exactly once.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ReplayWindow {
+    high: u64,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Returns `true` if `sequence` hasn't been accepted before.
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if sequence == 0 {
+            return false;
+        }
+
+        if sequence > self.high {
+            let shift = sequence - self.high;
+            self.seen = if shift >= WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.high = sequence;
+
+            return true;
+        }
+
+        let offset = self.high - sequence;
+        if offset >= WINDOW_SIZE {
+            return false;
+        }
+
+        let bit = 1 << offset;
+        if self.seen & bit != 0 {
+            return false;
+        }
+
+        self.seen |= bit;
+        true
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SessionState {
+    reserved: u64,
+}
+
+/// Replay windows of every peer, by node id.
+pub type Windows = HashMap<String, ReplayWindow>;
+
+/// Inter-node session giving exactly-once delivery of internal messages that
+/// survives restarts. Outgoing messages are numbered from a sequence that is
+/// reserved in blocks ahead of use and persisted, so after a crash a node
+/// doesn't reuse sequence numbers.
+///
+/// The replay windows of peers aren't persisted by the session: whoever
+/// applies the messages saves them with the state they changed, in the same
+/// write, and hands them back with [`Session::restore_windows`]. A crash then
+/// can't leave a message marked as accepted while its effect was lost.
+pub struct Session {
+    path: Option<PathBuf>,
+    next: u64,
+    state: SessionState,
+    windows: Windows,
+}
+
+impl Session {
+    /// Session that lives only as long as the process.
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            next: 1,
+            state: SessionState::default(),
+            windows: Windows::new(),
+        }
+    }
+
+    /// Opens the session persisted at `path`, creating it if missing.
+    pub fn open<P: Into<PathBuf>>(path: P) -> anyhow::Result<Self> {
+        let path = path.into();
+
+        let state = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<SessionState>(&bytes)
+                .with_context(|| format!("Error parsing session file {}", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => SessionState::default(),
+            Err(e) => return Err(e).context("Error reading session file"),
+        };
+
+        Ok(Self {
+            path: Some(path),
+            // Whatever was reserved before a crash may have been used already
+            next: state.reserved + 1,
+            state,
+            windows: Windows::new(),
+        })
+    }
+
+    /// Opens the session for `node_id` inside [`SESSION_DIR`], if set, or
+    /// one in memory otherwise.
+    pub fn from_env(node_id: &str) -> anyhow::Result<Self> {
+        match std::env::var_os(SESSION_DIR) {
+            Some(dir) => Self::open(PathBuf::from(dir).join(format!("{node_id}.session.json"))),
+            None => Ok(Self::in_memory()),
+        }
+    }
+
+    pub fn next_sequence(&mut self) -> anyhow::Result<u64> {
+        let sequence = self.next;
+
+        if sequence > self.state.reserved {
+            self.state.reserved = sequence + SEQUENCE_BLOCK;
+            self.persist()?;
+        }
+
+        self.next += 1;
+        Ok(sequence)
+    }
+
+    /// Returns `true` if the message numbered `sequence` from `peer` should be
+    /// applied, `false` for duplicates and messages too old to tell.
+    pub fn accept(&mut self, peer: &str, sequence: u64) -> bool {
+        self.windows
+            .entry(peer.to_owned())
+            .or_default()
+            .accept(sequence)
+    }
+
+    /// The replay windows to save along with the state of the node.
+    pub fn windows(&self) -> &Windows {
+        &self.windows
+    }
+
+    /// Replaces the replay windows with the ones saved along with the state
+    /// the node was restored from.
+    pub fn restore_windows(&mut self, windows: Windows) {
+        self.windows = windows;
+    }
+
+    fn persist(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let tmp = path.with_extension("tmp");
+        let bytes = serde_json::to_vec(&self.state).context("Error serializing session")?;
+
+        std::fs::write(&tmp, bytes).context("Error writing session file")?;
+        std::fs::rename(&tmp, path).context("Error replacing session file")?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReplayWindow, Session};
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+
+        assert!(window.accept(1));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        assert!(window.accept(2));
+        assert!(!window.accept(1));
+
+        assert!(window.accept(100));
+        assert!(window.accept(40));
+        assert!(!window.accept(36));
+    }
+
+    #[test]
+    fn test_session_survives_restart() {
+        let path = std::env::temp_dir().join(format!("{}.session.json", uuid::Uuid::new_v4()));
+
+        let mut session = Session::open(&path).unwrap();
+        let first = session.next_sequence().unwrap();
+        assert!(session.accept("n1", 5));
+        let windows = session.windows().clone();
+        drop(session);
+
+        let mut session = Session::open(&path).unwrap();
+        assert!(session.next_sequence().unwrap() > first);
+        // Windows come back with the state they were saved with
+        assert!(session.windows().is_empty());
+        session.restore_windows(windows);
+        assert!(!session.accept("n1", 5));
+        assert!(session.accept("n1", 4));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
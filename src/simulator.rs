@@ -0,0 +1,555 @@
+use crate::{
+    handle_event,
+    metrics::Metrics,
+    scheduler::{Scheduler, TimerId, TimerSink},
+    writters::MemoryWritter,
+    Event, Init, Message, MessageSender, Node, NodeContext,
+};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long [`Simulator::run_for`] waits for a timer before checking for
+/// retries again.
+const TIMER_TICK: Duration = Duration::from_millis(10);
+
+/// How long messages take to reach their destination.
+#[derive(Debug, Clone, Copy)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Anything in `min..=max`, uniformly.
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Exponentially distributed around `mean`, which gives the long tail
+    /// real networks have.
+    Exponential {
+        mean: Duration,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } => rng.gen_range(min..=max),
+            Latency::Exponential { mean } => {
+                let uniform = rng.gen_range(f64::EPSILON..1.0);
+                mean.mul_f64(-uniform.ln())
+            }
+        }
+    }
+}
+
+/// Faults the simulator injects between nodes. Every decision comes from a
+/// seeded generator, so the same seed fed the same messages drops and
+/// delays exactly the same ones.
+///
+/// Loss and partitions only apply to messages between nodes; latency applies
+/// to clients too.
+pub struct Network {
+    rng: StdRng,
+    loss: f64,
+    latency: Latency,
+    partition: HashMap<String, usize>,
+}
+
+impl Network {
+    /// Delivers everything, in order and without delay.
+    pub fn reliable() -> Self {
+        Self::seeded(0)
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            loss: 0.0,
+            latency: Latency::Fixed(Duration::ZERO),
+            partition: HashMap::new(),
+        }
+    }
+
+    /// Drops each message with probability `loss`.
+    pub fn with_loss(mut self, loss: f64) -> Self {
+        self.loss = loss.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Splits the nodes in `groups` that can only talk among themselves.
+    /// Nodes left out of every group can still reach everyone.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.partition = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, nodes)| nodes.iter().map(move |n| ((*n).to_owned(), group)))
+            .collect();
+    }
+
+    pub fn heal(&mut self) {
+        self.partition.clear();
+    }
+
+    /// Delay before a message from `src` reaches `dest`, `None` if it is lost.
+    fn route(&mut self, src: &str, dest: &str, between_nodes: bool) -> Option<Duration> {
+        if between_nodes {
+            let partitioned = matches!(
+                (self.partition.get(src), self.partition.get(dest)),
+                (Some(a), Some(b)) if a != b
+            );
+
+            // Always draw, so the fate of later messages doesn't depend on
+            // whether the partition is up
+            let lost = self.rng.gen_bool(self.loss);
+            if partitioned || lost {
+                return None;
+            }
+        }
+
+        Some(self.latency.sample(&mut self.rng))
+    }
+}
+
+struct SimulatedNode<N, P: 'static> {
+    node: N,
+    sender: MessageSender<'static, P>,
+    sent: Arc<Mutex<Vec<Message<P>>>>,
+    scheduler: Scheduler<Message<P>>,
+}
+
+/// Hands the timers of a node to the simulator, tagged with the node's id.
+struct Timers<P> {
+    node_id: String,
+    tx: Sender<(String, Event<P>)>,
+}
+
+impl<P: Send + 'static> TimerSink<Message<P>> for Timers<P> {
+    fn deliver(&self, message: Message<P>) -> bool {
+        self.send(Event::Message(message))
+    }
+
+    fn tick(&self, id: TimerId) -> bool {
+        self.send(Event::Tick(id))
+    }
+
+    fn custom(&self, event: Value) -> bool {
+        self.send(Event::Custom(event))
+    }
+}
+
+impl<P> Timers<P> {
+    fn send(&self, event: Event<P>) -> bool {
+        self.tx.send((self.node_id.clone(), event)).is_ok()
+    }
+}
+
+/// Runs a whole cluster of nodes in one process. Every node writes to its own
+/// in-memory writter and the simulator routes what they send: messages
+/// addressed to another node are delivered to it, anything else (client
+/// replies, requests to Maelstrom services) is kept for the test to inspect.
+///
+/// Messages go through a [`Network`] and are delivered one at a time in the
+/// order they arrive on a simulated clock, ties broken by the order they
+/// were sent. Timers are real ones, so protocols driven by them need
+/// [`Simulator::run_for`] and are only as reproducible as their timing.
+pub struct Simulator<N, P: 'static> {
+    nodes: BTreeMap<String, SimulatedNode<N, P>>,
+    network: Network,
+    /// Events by when they're due, each for the node it's for.
+    in_flight: BTreeMap<(Duration, u64), (String, Event<P>)>,
+    now: Duration,
+    sent: u64,
+    dropped: usize,
+    client_messages: Vec<Message<P>>,
+    metrics: Metrics,
+    timers: Receiver<(String, Event<P>)>,
+}
+
+impl<N, P> Simulator<N, P>
+where
+    N: Node<P>,
+    P: Clone + Serialize + DeserializeOwned + Send + 'static,
+{
+    /// Builds and initializes `nodes` nodes, named `n1`, `n2`, ... as
+    /// Maelstrom does, connected by a reliable network.
+    pub fn new<F>(nodes: usize, new_node: F) -> anyhow::Result<Self>
+    where
+        F: FnMut(Init) -> anyhow::Result<N>,
+    {
+        Self::with_network(nodes, Network::reliable(), new_node)
+    }
+
+    pub fn with_network<F>(nodes: usize, network: Network, mut new_node: F) -> anyhow::Result<Self>
+    where
+        F: FnMut(Init) -> anyhow::Result<N>,
+    {
+        let node_ids = (1..=nodes).map(|i| format!("n{i}")).collect::<Vec<_>>();
+
+        let (tx, timers) = unbounded();
+
+        let metrics = Metrics::new();
+
+        let mut simulated = BTreeMap::new();
+        for node_id in &node_ids {
+            let mut node = new_node(Init {
+                node_id: node_id.clone(),
+                node_ids: node_ids.clone(),
+            })?;
+
+            let writter = MemoryWritter::new();
+            let sent = writter.messages();
+
+            let mut sender = MessageSender::new(writter);
+            for middleware in node.middleware() {
+                sender.add_middleware(middleware);
+            }
+            sender.add_middleware(Box::new(metrics.counter()));
+
+            let scheduler = Scheduler::new(Timers {
+                node_id: node_id.clone(),
+                tx: tx.clone(),
+            });
+            node.init(scheduler.clone())?;
+
+            simulated.insert(
+                node_id.clone(),
+                SimulatedNode {
+                    node,
+                    sender,
+                    sent,
+                    scheduler,
+                },
+            );
+        }
+
+        Ok(Self {
+            nodes: simulated,
+            network,
+            in_flight: BTreeMap::new(),
+            now: Duration::ZERO,
+            sent: 0,
+            dropped: 0,
+            client_messages: Vec::new(),
+            metrics,
+            timers,
+        })
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    pub fn node(&self, node_id: &str) -> &N {
+        &self.nodes.get(node_id).expect("Unknown node").node
+    }
+
+    /// Messages the nodes sent to anything that isn't a node, in order.
+    pub fn client_messages(&self) -> &[Message<P>] {
+        &self.client_messages
+    }
+
+    /// The network, e.g. to partition it or heal it between runs.
+    pub fn network(&mut self) -> &mut Network {
+        &mut self.network
+    }
+
+    /// Messages handled and sent by the whole cluster, lost ones included.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// How many messages the network lost so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Time elapsed on the simulated clock.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Queues `message` for delivery, usually a client request.
+    pub fn send(&mut self, message: Message<P>) {
+        let between_nodes =
+            self.nodes.contains_key(message.src()) && self.nodes.contains_key(message.dest());
+
+        let Some(delay) = self
+            .network
+            .route(message.src(), message.dest(), between_nodes)
+        else {
+            self.dropped += 1;
+            return;
+        };
+
+        let dest = message.dest().to_owned();
+        self.in_flight.insert(
+            (self.now + delay, self.sent),
+            (dest, Event::Message(message)),
+        );
+        self.sent += 1;
+    }
+
+    /// Queues an event that doesn't go over the network, e.g. a timer of
+    /// `node_id`.
+    fn send_local(&mut self, node_id: String, event: Event<P>) {
+        self.in_flight
+            .insert((self.now, self.sent), (node_id, event));
+        self.sent += 1;
+    }
+
+    /// Delivers messages until none is in flight, timers that already fired
+    /// included. Returns how many messages were delivered.
+    pub fn run(&mut self) -> anyhow::Result<usize> {
+        let mut delivered = 0;
+
+        loop {
+            while let Ok((node_id, timer)) = self.timers.try_recv() {
+                self.send_local(node_id, timer);
+            }
+
+            let Some(((at, _), (dest, event))) = self.in_flight.pop_first() else {
+                return Ok(delivered);
+            };
+
+            self.now = self.now.max(at);
+            self.deliver(&dest, event)?;
+            delivered += 1;
+        }
+    }
+
+    /// Keeps the cluster running for `duration`, delivering timers and
+    /// retries as they come due.
+    pub fn run_for(&mut self, duration: Duration) -> anyhow::Result<usize> {
+        let deadline = Instant::now() + duration;
+        let mut delivered = 0;
+
+        loop {
+            for simulated in self.nodes.values_mut() {
+                simulated.sender.retry_due()?;
+            }
+            self.collect_sent();
+            delivered += self.run()?;
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(delivered);
+            }
+
+            match self.timers.recv_timeout(TIMER_TICK.min(deadline - now)) {
+                Ok((node_id, timer)) => self.send_local(node_id, timer),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(delivered),
+            }
+        }
+    }
+
+    fn deliver(&mut self, dest: &str, event: Event<P>) -> anyhow::Result<()> {
+        let Some(simulated) = self.nodes.get_mut(dest) else {
+            if let Event::Message(message) = event {
+                self.client_messages.push(message);
+            }
+            return Ok(());
+        };
+
+        let mut ctx = NodeContext::new(&mut simulated.sender).with_scheduler(&simulated.scheduler);
+        handle_event(&mut simulated.node, event, &mut ctx, &self.metrics)?;
+
+        self.collect_sent();
+
+        Ok(())
+    }
+
+    fn collect_sent(&mut self) {
+        let sent = self
+            .nodes
+            .values()
+            .flat_map(|simulated| simulated.sent.lock().unwrap().drain(..).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+
+        for message in sent {
+            self.send(message);
+        }
+    }
+}
+
+impl<N, P> Drop for Simulator<N, P> {
+    fn drop(&mut self) {
+        for simulated in self.nodes.values() {
+            simulated.scheduler.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Latency, Network, Simulator};
+    use crate::{scheduler::Scheduler, Body, Init, Message, Node, NodeContext};
+    use serde::{Deserialize, Serialize};
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Flood,
+        Hello,
+        HelloOk,
+        FloodOk { acks: usize },
+    }
+
+    struct FloodNode {
+        node_id: String,
+        node_ids: Vec<String>,
+        acks: usize,
+        request: Option<Message<Payload>>,
+    }
+
+    impl Node<Payload> for FloodNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<Payload>,
+            ctx: &mut NodeContext<Payload>,
+        ) -> anyhow::Result<()> {
+            match message.body().payload {
+                Payload::Flood => {
+                    let hellos = self
+                        .node_ids
+                        .iter()
+                        .filter(|n| **n != self.node_id)
+                        .map(|n| {
+                            Message::new(
+                                self.node_id.clone(),
+                                n.clone(),
+                                Body::new(None, None, Payload::Hello),
+                            )
+                        })
+                        .collect::<Vec<_>>();
+
+                    self.acks = 0;
+                    self.request = Some(message);
+                    ctx.send_all(hellos)
+                }
+                Payload::Hello => ctx.send(message.reply(Payload::HelloOk)),
+                Payload::HelloOk => {
+                    self.acks += 1;
+                    if self.acks < self.node_ids.len() - 1 {
+                        return Ok(());
+                    }
+
+                    match self.request.take() {
+                        Some(request) => {
+                            ctx.send(request.reply(Payload::FloodOk { acks: self.acks }))
+                        }
+                        None => Ok(()),
+                    }
+                }
+                Payload::FloodOk { .. } => Ok(()),
+            }
+        }
+    }
+
+    fn flood_node(init: Init) -> anyhow::Result<FloodNode> {
+        Ok(FloodNode {
+            node_id: init.node_id,
+            node_ids: init.node_ids,
+            acks: 0,
+            request: None,
+        })
+    }
+
+    fn flood(msg_id: usize) -> Message<Payload> {
+        Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(msg_id), None, Payload::Flood),
+        )
+    }
+
+    #[test]
+    fn test_runs_to_quiescence() {
+        let mut simulator = Simulator::new(3, flood_node).unwrap();
+
+        assert_eq!(simulator.node_ids().collect::<Vec<_>>(), ["n1", "n2", "n3"]);
+
+        simulator.send(flood(1));
+
+        // flood, 2 hellos, 2 hello_oks and the reply to the client
+        assert_eq!(simulator.run().unwrap(), 6);
+        assert_eq!(simulator.node("n1").acks, 2);
+        assert_eq!(simulator.metrics().handled("hello"), 2);
+        assert_eq!(simulator.metrics().sent("hello_ok"), 2);
+
+        let replies = simulator.client_messages();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].dest(), "c1");
+        assert_eq!(replies[0].in_reply_to(), Some(1));
+        assert!(matches!(
+            replies[0].body().payload,
+            Payload::FloodOk { acks: 2 }
+        ));
+    }
+
+    #[test]
+    fn test_seeded_network_is_reproducible() {
+        let run = |seed| {
+            let network = Network::seeded(seed)
+                .with_loss(0.3)
+                .with_latency(Latency::Uniform {
+                    min: Duration::from_millis(1),
+                    max: Duration::from_millis(50),
+                });
+            let mut simulator = Simulator::with_network(8, network, flood_node).unwrap();
+
+            for msg_id in 0..10 {
+                simulator.send(flood(msg_id));
+                simulator.run().unwrap();
+            }
+
+            (
+                simulator.dropped(),
+                simulator.now(),
+                simulator.node("n1").acks,
+            )
+        };
+
+        let first = run(7);
+        assert!(first.0 > 0);
+        assert_eq!(first, run(7));
+        assert_ne!(first, run(8));
+    }
+
+    #[test]
+    fn test_partition() {
+        let mut simulator = Simulator::new(3, flood_node).unwrap();
+
+        simulator.network().partition(&[&["n1"], &["n2", "n3"]]);
+        simulator.send(flood(1));
+        simulator.run().unwrap();
+
+        assert_eq!(simulator.dropped(), 2);
+        assert_eq!(simulator.node("n1").acks, 0);
+        assert!(simulator.client_messages().is_empty());
+
+        simulator.network().heal();
+        simulator.send(flood(2));
+        simulator.run().unwrap();
+
+        assert_eq!(simulator.node("n1").acks, 2);
+        assert_eq!(simulator.client_messages().len(), 1);
+    }
+}
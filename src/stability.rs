@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+/// Highest contiguous sequence number delivered, per origin node.
+pub type Watermarks = HashMap<String, u64>;
+
+/// Tracks the delivered watermarks every cluster member gossips and computes
+/// which items are stable, i.e. known to be received by all nodes. Anything at
+/// or below the stable watermark of its origin can be garbage-collected.
+#[derive(Debug, Default)]
+pub struct StabilityTracker {
+    delivered: HashMap<String, Watermarks>,
+}
+
+impl StabilityTracker {
+    pub fn new<I>(members: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self {
+            delivered: members
+                .into_iter()
+                .map(|member| (member, Watermarks::new()))
+                .collect(),
+        }
+    }
+
+    /// Records the watermarks reported by `node`. Watermarks only move
+    /// forward, so stale or reordered gossip is harmless.
+    pub fn update(&mut self, node: &str, watermarks: &Watermarks) {
+        let Some(delivered) = self.delivered.get_mut(node) else {
+            return;
+        };
+
+        for (origin, watermark) in watermarks {
+            let current = delivered.entry(origin.to_owned()).or_default();
+            *current = (*current).max(*watermark);
+        }
+    }
+
+    pub fn watermarks(&self, node: &str) -> Option<&Watermarks> {
+        self.delivered.get(node)
+    }
+
+    /// Highest sequence number from `origin` that every member has delivered.
+    pub fn stable(&self, origin: &str) -> u64 {
+        self.delivered
+            .values()
+            .map(|watermarks| watermarks.get(origin).copied().unwrap_or_default())
+            .min()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StabilityTracker, Watermarks};
+
+    fn watermarks(entries: &[(&str, u64)]) -> Watermarks {
+        entries
+            .iter()
+            .map(|(origin, watermark)| (origin.to_string(), *watermark))
+            .collect()
+    }
+
+    #[test]
+    fn test_stable() {
+        let mut tracker = StabilityTracker::new(["n1", "n2", "n3"].map(String::from));
+        assert_eq!(tracker.stable("n1"), 0);
+
+        tracker.update("n1", &watermarks(&[("n1", 5), ("n2", 3)]));
+        tracker.update("n2", &watermarks(&[("n1", 4), ("n2", 3)]));
+        // Until every member reported, nothing is stable
+        assert_eq!(tracker.stable("n1"), 0);
+
+        tracker.update("n3", &watermarks(&[("n1", 6), ("n2", 1)]));
+        assert_eq!(tracker.stable("n1"), 4);
+        assert_eq!(tracker.stable("n2"), 1);
+        assert_eq!(tracker.stable("n3"), 0);
+    }
+
+    #[test]
+    fn test_watermarks_only_move_forward() {
+        let mut tracker = StabilityTracker::new(["n1", "n2"].map(String::from));
+        tracker.update("n1", &watermarks(&[("n1", 5)]));
+        tracker.update("n2", &watermarks(&[("n1", 5)]));
+
+        // Stale gossip
+        tracker.update("n2", &watermarks(&[("n1", 2)]));
+        assert_eq!(tracker.stable("n1"), 5);
+        assert_eq!(tracker.watermarks("n2"), Some(&watermarks(&[("n1", 5)])));
+
+        // Nodes outside the membership are ignored
+        tracker.update("n4", &watermarks(&[("n1", 1)]));
+        assert_eq!(tracker.watermarks("n4"), None);
+        assert_eq!(tracker.stable("n1"), 5);
+    }
+}
@@ -0,0 +1,160 @@
+use crate::Message;
+use std::time::{Duration, Instant};
+
+/// How many items a node must be missing from a peer before it asks that
+/// peer for a state transfer rather than waiting on gossip.
+pub const SYNC_THRESHOLD: usize = 100;
+/// Most items sent in one `sync_chunk`.
+pub const SYNC_CHUNK_SIZE: usize = 500;
+/// How long a state transfer may take before another one can start.
+pub const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Keeps track of an outstanding `sync_request` so a node that fell far behind
+/// asks a single peer for a state transfer at a time. If the transfer doesn't
+/// complete within `timeout` (lost chunk, peer partitioned away) a new request
+/// is allowed.
+pub struct StateTransfer {
+    timeout: Duration,
+    started: Option<Instant>,
+}
+
+impl StateTransfer {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            started: None,
+        }
+    }
+
+    /// Marks a transfer as started, returning `false` if one is already running.
+    pub fn begin(&mut self) -> bool {
+        if self.is_running() {
+            return false;
+        }
+
+        self.started = Some(Instant::now());
+        true
+    }
+
+    pub fn finish(&mut self) {
+        self.started = None;
+    }
+
+    /// Whether a transfer started and hasn't finished or timed out yet.
+    pub fn is_running(&self) -> bool {
+        self.started
+            .is_some_and(|started| started.elapsed() < self.timeout)
+    }
+}
+
+/// One chunk of a state transfer, as handed to [`sync_chunks`] callers.
+pub struct Chunk<T> {
+    pub items: Vec<T>,
+    /// Whether it's the first chunk, for state that only needs to travel once.
+    pub first: bool,
+    /// Whether it's the last chunk, which lets the receiver finish the transfer.
+    pub last: bool,
+}
+
+/// Replies to `request` with `items` split into chunks of
+/// [`SYNC_CHUNK_SIZE`], each turned into a payload by `payload`.
+pub fn sync_chunks<P, T, I, F>(request: &Message<P>, items: I, mut payload: F) -> Vec<Message<P>>
+where
+    I: IntoIterator<Item = T>,
+    F: FnMut(Chunk<T>) -> P,
+{
+    let chunks = chunks(items, SYNC_CHUNK_SIZE);
+    let last = chunks.len() - 1;
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, items)| {
+            request.reply(payload(Chunk {
+                items,
+                first: i == 0,
+                last: i == last,
+            }))
+        })
+        .collect()
+}
+
+/// Splits `items` into chunks of at most `size` elements. Always returns at
+/// least one (possibly empty) chunk so the receiver gets a final marker.
+fn chunks<T, I>(items: I, size: usize) -> Vec<Vec<T>>
+where
+    I: IntoIterator<Item = T>,
+{
+    let mut chunks = vec![Vec::new()];
+
+    for item in items {
+        let current = chunks.last_mut().expect("At least one chunk");
+        if current.len() == size {
+            chunks.push(vec![item]);
+        } else {
+            current.push(item);
+        }
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chunks, sync_chunks, StateTransfer, SYNC_CHUNK_SIZE};
+    use crate::{Body, Message};
+    use std::time::Duration;
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks(1..=5, 2), [vec![1, 2], vec![3, 4], vec![5]]);
+        assert_eq!(chunks(1..=4, 2), [vec![1, 2], vec![3, 4]]);
+        // Nothing to send still makes a final marker
+        assert_eq!(chunks(Vec::<usize>::new(), 2), [Vec::<usize>::new()]);
+    }
+
+    #[test]
+    fn test_sync_chunks() {
+        let request = Message::new(
+            "n2".to_owned(),
+            "n1".to_owned(),
+            Body::new(Some(3), None, (0, false, false)),
+        );
+
+        let replies = sync_chunks(&request, 0..SYNC_CHUNK_SIZE + 1, |chunk| {
+            (chunk.items.len(), chunk.first, chunk.last)
+        });
+        let payloads = replies
+            .iter()
+            .map(|reply| {
+                assert_eq!(reply.dest(), "n2");
+                assert_eq!(reply.in_reply_to(), Some(3));
+                reply.body().payload
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(payloads, [(SYNC_CHUNK_SIZE, true, false), (1, false, true)]);
+
+        let replies = sync_chunks(&request, 0..0, |chunk| {
+            (chunk.items.len(), chunk.first, chunk.last)
+        });
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].body().payload, (0, true, true));
+    }
+
+    #[test]
+    fn test_state_transfer() {
+        let mut transfer = StateTransfer::new(Duration::from_millis(20));
+        assert!(transfer.begin());
+        // One at a time
+        assert!(!transfer.begin());
+
+        transfer.finish();
+        assert!(!transfer.is_running());
+        assert!(transfer.begin());
+        assert!(transfer.is_running());
+
+        // A transfer that never finished gives way after the timeout
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(transfer.begin());
+    }
+}
@@ -0,0 +1,678 @@
+//! Runs nodes as networked processes, exchanging the same messages as with
+//! Maelstrom over TCP or Unix socket connections, or UDP datagrams, instead
+//! of stdin and stdout.
+
+use crate::{
+    codec::{Codec, Json},
+    config::Config,
+    dispatch,
+    readers::MessageReader,
+    run,
+    writters::MessageWritter,
+    Init, MalformedInput, Message, Node,
+};
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::{BufReader, Read, Write},
+    marker::PhantomData,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(unix)]
+use std::{
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+};
+
+/// Listeners of connection oriented sockets a [`StreamTransport`] can run
+/// over.
+pub trait StreamListener: Send + 'static {
+    type Stream: Read + Write + Send + 'static;
+
+    fn accept(&self) -> std::io::Result<Self::Stream>;
+
+    fn connect(addr: &str) -> std::io::Result<Self::Stream>;
+
+    fn try_clone(stream: &Self::Stream) -> std::io::Result<Self::Stream>;
+}
+
+impl StreamListener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> std::io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn connect(addr: &str) -> std::io::Result<TcpStream> {
+        TcpStream::connect(addr)
+    }
+
+    fn try_clone(stream: &TcpStream) -> std::io::Result<TcpStream> {
+        stream.try_clone()
+    }
+}
+
+#[cfg(unix)]
+impl StreamListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> std::io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _)| stream)
+    }
+
+    fn connect(addr: &str) -> std::io::Result<UnixStream> {
+        UnixStream::connect(addr)
+    }
+
+    fn try_clone(stream: &UnixStream) -> std::io::Result<UnixStream> {
+        stream.try_clone()
+    }
+}
+
+/// The part of a message the transport needs to route replies.
+#[derive(Deserialize)]
+struct Header {
+    src: String,
+}
+
+/// Streams to write to, by the node id at the other end.
+struct Routes {
+    streams: Mutex<HashMap<String, Box<dyn Write + Send>>>,
+    /// Where to connect to the nodes that didn't connect first.
+    peers: HashMap<String, String>,
+}
+
+/// Listens for connections from clients and other nodes. Whatever arrives
+/// on any of them is handled like stdin would be, the first message being
+/// `init`; messages are sent back on the connection their destination last
+/// sent from, or on a new connection to the address
+/// [`StreamTransport::with_peers`] gave for it. Messages to nodes that can't
+/// be reached are dropped, like a lossy network would.
+pub struct StreamTransport<L, C = Json> {
+    listener: L,
+    peers: HashMap<String, String>,
+    codec: C,
+}
+
+pub type TcpTransport<C = Json> = StreamTransport<TcpListener, C>;
+
+/// Same as [`TcpTransport`] over a Unix socket, to run several nodes on one
+/// machine under a supervisor. Peers are given by socket path.
+#[cfg(unix)]
+pub type UnixTransport<C = Json> = StreamTransport<UnixListener, C>;
+
+impl<L: StreamListener> StreamTransport<L> {
+    pub fn new(listener: L) -> Self {
+        Self {
+            listener,
+            peers: HashMap::new(),
+            codec: Json::default(),
+        }
+    }
+}
+
+impl TcpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr).context("Error binding TCP listener")?;
+
+        Ok(Self::new(listener))
+    }
+}
+
+impl<C> TcpTransport<C> {
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.listener
+            .local_addr()
+            .context("Error reading listener address")
+    }
+}
+
+#[cfg(unix)]
+impl UnixTransport {
+    /// Listens at `path`, replacing the socket a previous run left there.
+    pub fn bind<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Error removing stale socket {}", path.display()))?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Error binding Unix socket {}", path.display()))?;
+
+        Ok(Self::new(listener))
+    }
+}
+
+impl<L, C> StreamTransport<L, C>
+where
+    L: StreamListener,
+    C: Codec + Clone + Send + 'static,
+{
+    pub fn with_codec<D: Codec>(self, codec: D) -> StreamTransport<L, D> {
+        StreamTransport {
+            listener: self.listener,
+            peers: self.peers,
+            codec,
+        }
+    }
+
+    /// Addresses of the other nodes, by node id.
+    pub fn with_peers(mut self, peers: HashMap<String, String>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    /// Same as [`crate::main_loop_with_config`] over the connections of the
+    /// listener. Never returns unless the node fails, since new connections
+    /// may come at any time.
+    pub fn main_loop<N, P, F>(self, config: Config, new_node: F) -> anyhow::Result<()>
+    where
+        N: Node<P>,
+        P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce(Init) -> anyhow::Result<N>,
+    {
+        let routes = Arc::new(Routes {
+            streams: Mutex::new(HashMap::new()),
+            peers: self.peers,
+        });
+        let (tx, rx) = channel();
+
+        let connection = Connection::<L, C> {
+            routes,
+            frames: tx,
+            codec: self.codec.clone(),
+            listener: PhantomData,
+        };
+
+        let accepted = connection.clone();
+        let listener = self.listener;
+        std::thread::spawn(move || loop {
+            match listener.accept() {
+                Ok(stream) => accepted.serve(stream),
+                Err(e) => tracing::warn!(error = %e, "Failed to accept connection"),
+            }
+        });
+
+        let reader = FrameReader {
+            frames: rx,
+            codec: self.codec,
+        };
+        let writter = StreamWritter {
+            connection,
+            buf: Vec::new(),
+        };
+
+        run(
+            reader,
+            writter,
+            MalformedInput::default(),
+            config,
+            new_node,
+            dispatch,
+        )
+    }
+}
+
+/// What every connection shares: the routes, the channel inbound frames go
+/// to and the codec.
+struct Connection<L, C> {
+    routes: Arc<Routes>,
+    frames: Sender<Vec<u8>>,
+    codec: C,
+    listener: PhantomData<fn() -> L>,
+}
+
+// Derived, it would require `L: Clone`
+impl<L, C: Clone> Clone for Connection<L, C> {
+    fn clone(&self) -> Self {
+        Self {
+            routes: self.routes.clone(),
+            frames: self.frames.clone(),
+            codec: self.codec.clone(),
+            listener: PhantomData,
+        }
+    }
+}
+
+impl<L, C> Connection<L, C>
+where
+    L: StreamListener,
+    C: Codec + Clone + Send + 'static,
+{
+    /// Reads the frames of `stream` on a thread of its own, routing replies
+    /// to every node that sends from it back through it.
+    fn serve(&self, stream: L::Stream) {
+        let connection = self.clone();
+
+        std::thread::spawn(move || {
+            let Ok(read_half) = L::try_clone(&stream) else {
+                return;
+            };
+            let mut input = BufReader::new(read_half);
+
+            while let Ok(Some(frame)) = connection.codec.read_frame(&mut input) {
+                connection.route(&frame, &stream);
+
+                if connection.frames.send(frame).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Sends what goes to the source of `frame` through `stream`, unless it
+    /// already has a route.
+    fn route(&self, frame: &[u8], stream: &L::Stream) {
+        let Ok(header) = self.codec.decode::<Header>(frame) else {
+            return;
+        };
+
+        let mut streams = self.routes.streams.lock().unwrap();
+        if !streams.contains_key(&header.src)
+            && let Ok(stream) = L::try_clone(stream)
+        {
+            streams.insert(header.src, Box::new(stream));
+        }
+    }
+
+    fn connect(&self, dest: &str) -> anyhow::Result<()> {
+        let addr = self
+            .routes
+            .peers
+            .get(dest)
+            .with_context(|| format!("No route to {dest}"))?;
+        let stream =
+            L::connect(addr).with_context(|| format!("Error connecting to {dest} at {addr}"))?;
+
+        self.routes
+            .streams
+            .lock()
+            .unwrap()
+            .insert(dest.to_owned(), Box::new(L::try_clone(&stream)?));
+        // The peer may answer on the same connection
+        self.serve(stream);
+
+        Ok(())
+    }
+
+    fn write(&self, dest: &str, frame: &[u8]) -> anyhow::Result<()> {
+        if !self.routes.streams.lock().unwrap().contains_key(dest) {
+            self.connect(dest)?;
+        }
+
+        let mut streams = self.routes.streams.lock().unwrap();
+        let Some(stream) = streams.get_mut(dest) else {
+            anyhow::bail!("No route to {dest}");
+        };
+
+        if let Err(e) = stream.write_all(frame) {
+            // Reconnected or routed again the next time
+            streams.remove(dest);
+            return Err(e).with_context(|| format!("Error writing to {dest}"));
+        }
+
+        Ok(())
+    }
+}
+
+struct FrameReader<C> {
+    frames: Receiver<Vec<u8>>,
+    codec: C,
+}
+
+impl<T, C> MessageReader<T> for FrameReader<C>
+where
+    T: DeserializeOwned,
+    C: Codec,
+{
+    fn read_message(&mut self) -> Option<anyhow::Result<T>> {
+        let frame = self.frames.recv().ok()?;
+
+        Some(self.codec.decode(&frame))
+    }
+}
+
+struct StreamWritter<L, C> {
+    connection: Connection<L, C>,
+    buf: Vec<u8>,
+}
+
+impl<P, L, C> MessageWritter<Message<P>> for StreamWritter<L, C>
+where
+    P: Serialize,
+    L: StreamListener,
+    C: Codec + Clone + Send + 'static,
+{
+    fn send_message(&mut self, message: &Message<P>) -> anyhow::Result<()> {
+        self.buf.clear();
+        self.connection.codec.encode(message, &mut self.buf)?;
+
+        if let Err(error) = self.connection.write(message.dest(), &self.buf) {
+            tracing::warn!(error = format!("{error:#}"), "Dropped message");
+        }
+
+        Ok(())
+    }
+
+    fn send_messages(&mut self, messages: &[Message<P>]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+/// Largest payload of a UDP datagram.
+const MAX_DATAGRAM: usize = 65507;
+
+/// Sends every message as a single JSON datagram, with no retransmission or
+/// ordering: messages are lost, duplicated or reordered as the network
+/// does, unlike with Maelstrom where drops are only simulated. Messages are
+/// sent to the address their destination last sent from, or to the one
+/// [`UdpTransport::with_peers`] gave for it. Messages too big for a
+/// datagram are dropped.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peers: HashMap<String, String>,
+}
+
+impl UdpTransport {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr).context("Error binding UDP socket")?;
+
+        Ok(Self {
+            socket,
+            peers: HashMap::new(),
+        })
+    }
+
+    /// Addresses of the other nodes, by node id.
+    pub fn with_peers(mut self, peers: HashMap<String, String>) -> Self {
+        self.peers = peers;
+        self
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        self.socket
+            .local_addr()
+            .context("Error reading socket address")
+    }
+
+    /// Same as [`crate::main_loop_with_config`] over the datagrams received
+    /// on the socket. Never returns unless the node fails.
+    pub fn main_loop<N, P, F>(self, config: Config, new_node: F) -> anyhow::Result<()>
+    where
+        N: Node<P>,
+        P: std::fmt::Debug + Clone + Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce(Init) -> anyhow::Result<N>,
+    {
+        let addrs = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, rx) = channel();
+
+        let socket = self
+            .socket
+            .try_clone()
+            .context("Error cloning UDP socket")?;
+        let routes = addrs.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM];
+
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Failed to receive datagram");
+                        continue;
+                    }
+                };
+                let frame = buf[..len].to_vec();
+
+                if let Ok(header) = Json::default().decode::<Header>(&frame) {
+                    routes.lock().unwrap().insert(header.src, from);
+                }
+                if tx.send(frame).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let reader = FrameReader {
+            frames: rx,
+            codec: Json::default(),
+        };
+        let writter = UdpWritter {
+            socket: self.socket,
+            addrs,
+            peers: self.peers,
+            buf: Vec::new(),
+        };
+
+        run(
+            reader,
+            writter,
+            MalformedInput::default(),
+            config,
+            new_node,
+            dispatch,
+        )
+    }
+}
+
+struct UdpWritter {
+    socket: UdpSocket,
+    /// Where each node last sent from.
+    addrs: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    peers: HashMap<String, String>,
+    buf: Vec<u8>,
+}
+
+impl UdpWritter {
+    fn send_to(&self, dest: &str) -> anyhow::Result<()> {
+        let addr = self.addrs.lock().unwrap().get(dest).copied();
+        let sent = match (addr, self.peers.get(dest)) {
+            (Some(addr), _) => self.socket.send_to(&self.buf, addr),
+            (None, Some(peer)) => self.socket.send_to(&self.buf, peer.as_str()),
+            (None, None) => anyhow::bail!("No route to {dest}"),
+        };
+
+        sent.with_context(|| format!("Error sending to {dest}"))?;
+
+        Ok(())
+    }
+}
+
+impl<P: Serialize> MessageWritter<Message<P>> for UdpWritter {
+    fn send_message(&mut self, message: &Message<P>) -> anyhow::Result<()> {
+        self.buf.clear();
+        serde_json::to_writer(&mut self.buf, message).context("Error serializing message")?;
+
+        if let Err(error) = self.send_to(message.dest()) {
+            tracing::warn!(error = format!("{error:#}"), "Dropped message");
+        }
+
+        Ok(())
+    }
+
+    fn send_messages(&mut self, messages: &[Message<P>]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TcpTransport, UdpTransport};
+    use crate::{
+        codec::{Codec, Json},
+        config::Config,
+        readers::{CodecReader, MessageReader},
+        scheduler::Scheduler,
+        Body, Init, InitPayload, Message, Node, NodeContext,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{
+        io::{BufReader, Read, Write},
+        net::{TcpStream, UdpSocket},
+        time::Duration,
+    };
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct EchoNode;
+
+    impl Node<Payload> for EchoNode {
+        fn init(&mut self, _scheduler: Scheduler<Message<Payload>>) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn handle_message(
+            &mut self,
+            message: Message<Payload>,
+            ctx: &mut NodeContext<Payload>,
+        ) -> anyhow::Result<()> {
+            let Payload::Echo { echo } = &message.body().payload else {
+                return Ok(());
+            };
+
+            ctx.send(message.reply(Payload::EchoOk { echo: echo.clone() }))
+        }
+    }
+
+    /// Checks a node is initialized and replies to clients through the
+    /// connections `connect` opens, as a write and a read half.
+    fn assert_echoes<S, F>(connect: F)
+    where
+        S: Read + Write,
+        F: Fn() -> (S, S),
+    {
+        let init = Message::new(
+            "c0".to_owned(),
+            "n1".to_owned(),
+            Body::new(
+                Some(0),
+                None,
+                InitPayload::Init(Init {
+                    node_id: "n1".to_owned(),
+                    node_ids: vec!["n1".to_owned()],
+                }),
+            ),
+        );
+        let (mut controller, controller_replies) = connect();
+        let mut frame = Vec::new();
+        Json::default().encode(&init, &mut frame).unwrap();
+        controller.write_all(&frame).unwrap();
+
+        let mut replies = CodecReader::new(BufReader::new(controller_replies), Json::default());
+        let init_ok: Message<InitPayload> = replies.read_message().unwrap().unwrap();
+        assert_eq!(init_ok.in_reply_to(), Some(0));
+
+        // A second client gets its replies on its own connection
+        let (mut client, client_replies) = connect();
+        let echo = Message::new(
+            "c1".to_owned(),
+            "n1".to_owned(),
+            Body::new(
+                Some(1),
+                None,
+                Payload::Echo {
+                    echo: "hi".to_owned(),
+                },
+            ),
+        );
+        frame.clear();
+        Json::default().encode(&echo, &mut frame).unwrap();
+        client.write_all(&frame).unwrap();
+
+        let mut replies = CodecReader::new(BufReader::new(client_replies), Json::default());
+        let reply: Message<Payload> = replies.read_message().unwrap().unwrap();
+        assert_eq!(reply.dest(), "c1");
+        assert_eq!(
+            reply.body().payload,
+            Payload::EchoOk {
+                echo: "hi".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        let transport = TcpTransport::bind("127.0.0.1:0").unwrap();
+        let addr = transport.local_addr().unwrap();
+        std::thread::spawn(move || transport.main_loop(Config::default(), |_| Ok(EchoNode)));
+
+        assert_echoes(|| {
+            let stream = TcpStream::connect(addr).unwrap();
+            (stream.try_clone().unwrap(), stream)
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_transport() {
+        use super::UnixTransport;
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("node-{}.sock", std::process::id()));
+        // A stale socket from a previous run is replaced
+        drop(UnixTransport::bind(&path).unwrap());
+        let transport = UnixTransport::bind(&path).unwrap();
+        std::thread::spawn(move || transport.main_loop(Config::default(), |_| Ok(EchoNode)));
+
+        assert_echoes(|| {
+            let stream = UnixStream::connect(&path).unwrap();
+            (stream.try_clone().unwrap(), stream)
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_udp_transport() {
+        let transport = UdpTransport::bind("127.0.0.1:0").unwrap();
+        let addr = transport.local_addr().unwrap();
+        std::thread::spawn(move || transport.main_loop(Config::default(), |_| Ok(EchoNode)));
+
+        let exchange = |src: &str, body: &str| -> serde_json::Value {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let message = format!(r#"{{"src":"{src}","dest":"n1","body":{body}}}"#);
+            socket.send_to(message.as_bytes(), addr).unwrap();
+
+            let mut buf = [0; 1024];
+            let len = socket.recv(&mut buf).unwrap();
+            Json::default().decode(&buf[..len]).unwrap()
+        };
+
+        let init_ok = exchange(
+            "c0",
+            r#"{"type":"init","msg_id":0,"node_id":"n1","node_ids":["n1"]}"#,
+        );
+        assert_eq!(init_ok["body"]["type"], "init_ok");
+
+        // Replies go to the address the client sent from
+        let echo_ok = exchange("c1", r#"{"type":"echo","msg_id":1,"echo":"hi"}"#);
+        assert_eq!(echo_ok["dest"], "c1");
+        assert_eq!(echo_ok["body"]["echo"], "hi");
+    }
+}
@@ -0,0 +1,293 @@
+//! Write-ahead log of what a node sends. Every message goes to the log
+//! before it's written out, and every ack as it arrives, so a node that
+//! crashed and restarts, its state restored, see [`crate::persistence`],
+//! resumes retransmitting what its peers never acknowledged instead of
+//! silently dropping it.
+
+use crate::Message;
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Env var with the path of the file `main_loop` logs outbound messages to.
+/// `{node_id}` is replaced with the node's id. Nothing is logged when unset.
+pub const WAL_FILE: &str = "WAL_FILE";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry<M> {
+    /// `retry` when sent with [`crate::MessageSender::send_with_retry`].
+    Sent {
+        message: M,
+        retry: bool,
+    },
+    Acked {
+        msg_id: usize,
+    },
+    /// First entry of a rewritten log, keeps the `msg_id`s used by the runs
+    /// before whatever that log no longer holds.
+    NextMsgId {
+        msg_id: usize,
+    },
+}
+
+/// What a previous run of the node left in the log.
+#[derive(Debug)]
+pub struct Recovered<P> {
+    /// Messages sent with retry and never acked, by `msg_id`.
+    pub pending: Vec<Message<P>>,
+    /// Past every `msg_id` the previous run used, so peers deduplicating by
+    /// `msg_id` don't mistake new messages for old ones.
+    pub next_msg_id: usize,
+}
+
+/// The log, one JSON line per entry. Entries are written as they happen,
+/// unbuffered, so they survive the process crashing, not the machine.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// Opens the log at `path`, recovering what the previous run left. The
+    /// log is then rewritten with only the next `msg_id` and the pending
+    /// messages, so it doesn't grow across restarts. A last line cut short
+    /// by the crash is ignored.
+    pub fn open<P, Q>(path: Q) -> anyhow::Result<(Self, Recovered<P>)>
+    where
+        P: Serialize + DeserializeOwned,
+        Q: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let recovered = recover(&path)?;
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut wal = Self {
+            path: path.clone(),
+            file: File::create(&tmp)
+                .with_context(|| format!("Error creating {}", path.display()))?,
+        };
+        wal.append(&Entry::<()>::NextMsgId {
+            msg_id: recovered.next_msg_id,
+        })?;
+        for message in &recovered.pending {
+            wal.append(&Entry::Sent {
+                message,
+                retry: true,
+            })?;
+        }
+        std::fs::rename(&tmp, &path)
+            .with_context(|| format!("Error writing {}", path.display()))?;
+
+        Ok((wal, recovered))
+    }
+
+    /// Log at [`WAL_FILE`], if set.
+    pub fn from_env<P>(node_id: &str) -> anyhow::Result<Option<(Self, Recovered<P>)>>
+    where
+        P: Serialize + DeserializeOwned,
+    {
+        match std::env::var(WAL_FILE) {
+            Ok(path) => Self::open(path.replace("{node_id}", node_id)).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn append<M: Serialize>(&mut self, entry: &Entry<M>) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry).context("Error serializing WAL entry")?;
+        line.push(b'\n');
+
+        self.file
+            .write_all(&line)
+            .with_context(|| format!("Error writing {}", self.path.display()))
+    }
+}
+
+fn recover<P: DeserializeOwned>(path: &Path) -> anyhow::Result<Recovered<P>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Recovered {
+                pending: Vec::new(),
+                next_msg_id: 0,
+            });
+        }
+        Err(e) => return Err(e).with_context(|| format!("Error reading {}", path.display())),
+    };
+
+    let mut pending = BTreeMap::new();
+    let mut next_msg_id = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.with_context(|| format!("Error reading {}", path.display()))?;
+        let Ok(entry) = serde_json::from_str::<Entry<Message<P>>>(&line) else {
+            break;
+        };
+
+        match entry {
+            Entry::Sent { message, retry } => {
+                let Some(msg_id) = message.msg_id() else {
+                    continue;
+                };
+                next_msg_id = next_msg_id.max(msg_id + 1);
+                if retry {
+                    pending.insert(msg_id, message);
+                }
+            }
+            Entry::Acked { msg_id } => {
+                pending.remove(&msg_id);
+            }
+            Entry::NextMsgId { msg_id } => next_msg_id = next_msg_id.max(msg_id),
+        }
+    }
+
+    Ok(Recovered {
+        pending: pending.into_values().collect(),
+        next_msg_id,
+    })
+}
+
+/// A [`Wal`] shared by the senders of a node, with how to write their
+/// messages in it.
+pub(crate) struct WalHandle<P> {
+    wal: Arc<Mutex<Wal>>,
+    encode: fn(&Message<P>) -> serde_json::Result<serde_json::Value>,
+}
+
+impl<P> Clone for WalHandle<P> {
+    fn clone(&self) -> Self {
+        Self {
+            wal: self.wal.clone(),
+            encode: self.encode,
+        }
+    }
+}
+
+impl<P: Serialize> WalHandle<P> {
+    pub(crate) fn new(wal: Wal) -> Self {
+        Self {
+            wal: Arc::new(Mutex::new(wal)),
+            encode: |message| serde_json::to_value(message),
+        }
+    }
+}
+
+impl<P> WalHandle<P> {
+    pub(crate) fn sent(&self, message: &Message<P>, retry: bool) -> anyhow::Result<()> {
+        let message = (self.encode)(message).context("Error serializing message")?;
+
+        self.wal
+            .lock()
+            .unwrap()
+            .append(&Entry::Sent { message, retry })
+    }
+
+    pub(crate) fn acked(&self, msg_id: usize) -> anyhow::Result<()> {
+        self.wal
+            .lock()
+            .unwrap()
+            .append(&Entry::<()>::Acked { msg_id })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Wal;
+    use crate::{writters::MemoryWritter, Body, Message, MessageSender};
+    use serde_json::{json, Value};
+    use std::io::Write;
+
+    #[test]
+    fn test_wal() {
+        let path = std::env::temp_dir().join(format!("wal-{}.jsonl", std::process::id()));
+        let message = |dest: &str, kind: &str| {
+            Message::new(
+                "n1".to_owned(),
+                dest.to_owned(),
+                Body::new(None, None, json!({ "type": kind })),
+            )
+        };
+
+        let (wal, recovered) = Wal::open::<Value, _>(&path).unwrap();
+        assert!(recovered.pending.is_empty());
+        let mut sender = MessageSender::new(MemoryWritter::new());
+        sender.set_wal(wal, recovered);
+        sender.send(message("c1", "reply")).unwrap();
+        let acked = sender.send_with_retry(message("n2", "acked")).unwrap();
+        let pending = sender.send_with_retry(message("n3", "pending")).unwrap();
+        let ack = Message::new(
+            "n2".to_owned(),
+            "n1".to_owned(),
+            Body::new(None, Some(acked), json!({ "type": "ok" })),
+        );
+        assert!(sender.ack(&ack));
+        drop(sender);
+
+        // Crashed halfway through a line
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(br#"{"sent":{"mess"#).unwrap();
+
+        let (wal, recovered) = Wal::open::<Value, _>(&path).unwrap();
+        let pending_ids = recovered
+            .pending
+            .iter()
+            .map(|message| message.msg_id())
+            .collect::<Vec<_>>();
+        assert_eq!(pending_ids, [Some(pending)]);
+        assert_eq!(recovered.next_msg_id, 3);
+
+        let writter = MemoryWritter::new();
+        let sent = writter.messages();
+        let mut sender = MessageSender::new(writter);
+        sender.set_wal(wal, recovered);
+        assert_eq!(sender.pending_retries(), 1);
+        assert_eq!(sender.next_msg_id(), 3);
+        sender.retry_due().unwrap();
+        assert_eq!(sent.lock().unwrap()[0].body().payload["type"], "pending");
+        drop(sender);
+
+        // Compacted to the next msg_id and what's pending
+        let lines = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_restarts_keep_msg_ids() {
+        let path = std::env::temp_dir().join(format!("wal-ids-{}.jsonl", std::process::id()));
+
+        let (wal, recovered) = Wal::open::<Value, _>(&path).unwrap();
+        let mut sender = MessageSender::new(MemoryWritter::new());
+        sender.set_wal(wal, recovered);
+        for _ in 0..2 {
+            sender
+                .send(Message::new(
+                    "n1".to_owned(),
+                    "c1".to_owned(),
+                    Body::new(None, None, json!({ "type": "reply" })),
+                ))
+                .unwrap();
+        }
+        drop(sender);
+
+        // Nothing pending, restarted twice without sending anything
+        let (wal, recovered) = Wal::open::<Value, _>(&path).unwrap();
+        assert!(recovered.pending.is_empty());
+        assert_eq!(recovered.next_msg_id, 2);
+        drop(wal);
+
+        let (_, recovered) = Wal::open::<Value, _>(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recovered.next_msg_id, 2);
+    }
+}
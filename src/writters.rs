@@ -1,11 +1,34 @@
+use crate::codec::{Codec, Json};
 use anyhow::Context;
 use serde::Serialize;
-use std::io::{StdoutLock, Write};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{StdoutLock, Write},
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Env var with the time, in milliseconds, `main_loop` may hold outbound
+/// messages back to write them together. Every send is written right away
+/// when unset. See [`crate::config::Config`].
+pub const BATCH_INTERVAL: &str = "BATCH_INTERVAL";
+
+/// Env var with the path of a file `main_loop` appends every message the
+/// node sends to. Nothing is recorded when unset.
+pub const TEE_FILE: &str = "TEE_FILE";
 
 pub trait MessageWritter<T> {
     fn send_message(&mut self, message: &T) -> anyhow::Result<()>;
 
     fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()>;
+
+    /// Writes out the messages held back, if any. `main_loop` calls it on
+    /// every tick and before returning.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 pub struct StdoutJsonWritter<'a> {
@@ -40,3 +63,374 @@ where
         Ok(())
     }
 }
+
+/// Writes messages in a [`Codec`], holding them back until `max_messages`
+/// are queued or the oldest one has waited `max_delay`, so bursts of sends
+/// (e.g. a round of gossip) reach the output in a single write.
+///
+/// The delay is only checked on sends and [`MessageWritter::flush`], a
+/// message may wait until the next one of those.
+pub struct BatchingWritter<W: Write, C: Codec = Json> {
+    out: W,
+    codec: C,
+    buf: Vec<u8>,
+    queued: usize,
+    oldest: Option<Instant>,
+    max_messages: usize,
+    max_delay: Duration,
+}
+
+/// [`BatchingWritter`] of JSON lines, as Maelstrom reads them.
+pub type BatchingJsonWritter<W> = BatchingWritter<W, Json>;
+
+impl<W: Write> BatchingWritter<W, Json> {
+    pub fn new(out: W, max_messages: usize, max_delay: Duration) -> Self {
+        Self::with_codec(out, Json::default(), max_messages, max_delay)
+    }
+}
+
+impl<W: Write, C: Codec> BatchingWritter<W, C> {
+    pub fn with_codec(out: W, codec: C, max_messages: usize, max_delay: Duration) -> Self {
+        Self {
+            out,
+            codec,
+            buf: Vec::new(),
+            queued: 0,
+            oldest: None,
+            max_messages: max_messages.max(1),
+            max_delay,
+        }
+    }
+
+    fn queue<T: Serialize>(&mut self, message: &T) -> anyhow::Result<()> {
+        self.codec.encode(message, &mut self.buf)?;
+
+        self.queued += 1;
+        self.oldest.get_or_insert_with(Instant::now);
+
+        Ok(())
+    }
+
+    fn flush_if_due(&mut self) -> anyhow::Result<()> {
+        let expired = self
+            .oldest
+            .is_some_and(|oldest| oldest.elapsed() >= self.max_delay);
+
+        if self.queued >= self.max_messages || expired {
+            self.write_out()?;
+        }
+
+        Ok(())
+    }
+
+    fn write_out(&mut self) -> anyhow::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        self.out
+            .write_all(&self.buf)
+            .and_then(|_| self.out.flush())
+            .context("Error writing batch")?;
+
+        self.buf.clear();
+        self.queued = 0;
+        self.oldest = None;
+
+        Ok(())
+    }
+}
+
+impl<T, W, C> MessageWritter<T> for BatchingWritter<W, C>
+where
+    T: Serialize,
+    W: Write,
+    C: Codec,
+{
+    fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+        self.queue(message)?;
+        self.flush_if_due()
+    }
+
+    fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+        for message in messages {
+            self.queue(message)?;
+        }
+
+        self.flush_if_due()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_out()
+    }
+}
+
+impl<W: Write, C: Codec> Drop for BatchingWritter<W, C> {
+    fn drop(&mut self) {
+        let _ = self.write_out();
+    }
+}
+
+/// Writes one JSON document per line to a file, the counterpart of
+/// [`crate::readers::FileJsonReader`]. Every message is written at once, so
+/// several processes may append to the same file.
+pub struct FileJsonWritter {
+    file: File,
+}
+
+impl FileJsonWritter {
+    /// Truncates `path` if it exists.
+    pub fn create<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::open(path.as_ref(), OpenOptions::new().write(true).truncate(true))
+    }
+
+    /// Writes after whatever `path` already holds.
+    pub fn append<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::open(path.as_ref(), OpenOptions::new().append(true))
+    }
+
+    fn open(path: &Path, options: &mut OpenOptions) -> anyhow::Result<Self> {
+        let file = options
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Error opening messages file {}", path.display()))?;
+
+        Ok(Self { file })
+    }
+}
+
+impl<T> MessageWritter<T> for FileJsonWritter
+where
+    T: Serialize,
+{
+    fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(message).context("Error serializing message")?;
+        line.push(b'\n');
+
+        self.file
+            .write_all(&line)
+            .context("Error writing message to file")
+    }
+
+    fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+        for message in messages {
+            self.send_message(message)?
+        }
+
+        Ok(())
+    }
+}
+
+/// Sends every message through `inner` and also appends it to a file, as a
+/// JSON line with the time it was sent, e.g.
+/// `{"ts_us":1700000000000000,"message":{...}}`. All the nodes of a test may
+/// share the file.
+pub struct TeeWritter<W> {
+    inner: W,
+    file: Option<FileJsonWritter>,
+}
+
+impl<W> TeeWritter<W> {
+    pub fn new(inner: W, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner,
+            file: Some(FileJsonWritter::append(path)?),
+        })
+    }
+
+    /// Tees to [`TEE_FILE`] if set, otherwise only writes to `inner`.
+    pub fn from_env(inner: W) -> anyhow::Result<Self> {
+        match std::env::var(TEE_FILE) {
+            Ok(path) => Self::new(inner, path),
+            Err(_) => Ok(Self { inner, file: None }),
+        }
+    }
+
+    fn record<T: Serialize>(&mut self, message: &T) -> anyhow::Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+
+        let ts_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        file.send_message(&serde_json::json!({
+            "ts_us": ts_us,
+            "message": message,
+        }))
+    }
+}
+
+impl<T, W> MessageWritter<T> for TeeWritter<W>
+where
+    T: Serialize,
+    W: MessageWritter<T>,
+{
+    fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+        self.inner.send_message(message)?;
+        self.record(message)
+    }
+
+    fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+        self.inner.send_messages(messages)?;
+
+        for message in messages {
+            self.record(message)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Replays and the simulator collect what nodes send with it, other crates
+// only get it for their tests
+#[cfg(feature = "testing")]
+pub use memory::MemoryWritter;
+#[cfg(not(feature = "testing"))]
+pub(crate) use memory::MemoryWritter;
+
+mod memory {
+    use super::MessageWritter;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps every message in memory, mostly useful to inspect what a node
+    /// sent from tests.
+    pub struct MemoryWritter<T> {
+        messages: Arc<Mutex<Vec<T>>>,
+    }
+
+    impl<T> MemoryWritter<T> {
+        pub fn new() -> Self {
+            Self {
+                messages: Arc::new(Mutex::new(Vec::new())),
+            }
+        }
+
+        /// Shared handle to the messages written so far.
+        pub fn messages(&self) -> Arc<Mutex<Vec<T>>> {
+            self.messages.clone()
+        }
+    }
+
+    impl<T> Default for MemoryWritter<T> {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl<T> MessageWritter<T> for MemoryWritter<T>
+    where
+        T: Clone,
+    {
+        fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+            self.messages.lock().unwrap().push(message.clone());
+
+            Ok(())
+        }
+
+        fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+            self.messages.lock().unwrap().extend_from_slice(messages);
+
+            Ok(())
+        }
+    }
+}
+
+/// Writter shared by its clones, so messages of different types can go to
+/// the same output, in the order they were sent.
+pub(crate) struct SharedWritter<W> {
+    inner: Rc<RefCell<W>>,
+}
+
+impl<W> SharedWritter<W> {
+    pub(crate) fn new(writter: W) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(writter)),
+        }
+    }
+}
+
+impl<W> Clone for SharedWritter<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, W> MessageWritter<T> for SharedWritter<W>
+where
+    W: MessageWritter<T>,
+{
+    fn send_message(&mut self, message: &T) -> anyhow::Result<()> {
+        self.inner.borrow_mut().send_message(message)
+    }
+
+    fn send_messages(&mut self, messages: &[T]) -> anyhow::Result<()> {
+        self.inner.borrow_mut().send_messages(messages)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BatchingJsonWritter, MemoryWritter, MessageWritter, TeeWritter};
+    use std::time::Duration;
+
+    #[test]
+    fn test_batching_writter() {
+        let mut out = Vec::new();
+        let mut writter = BatchingJsonWritter::new(&mut out, 3, Duration::from_secs(60));
+
+        writter.send_message(&1).unwrap();
+        writter.send_message(&2).unwrap();
+        assert!(writter.out.is_empty());
+
+        writter.send_messages(&[3, 4]).unwrap();
+        assert_eq!(writter.out.as_slice(), b"1\n2\n3\n4\n");
+
+        writter.send_message(&5).unwrap();
+        MessageWritter::<u8>::flush(&mut writter).unwrap();
+        drop(writter);
+        assert_eq!(out, b"1\n2\n3\n4\n5\n");
+
+        let mut out = Vec::new();
+        let mut writter = BatchingJsonWritter::new(&mut out, 3, Duration::ZERO);
+        writter.send_message(&1).unwrap();
+        assert_eq!(writter.out.as_slice(), b"1\n");
+    }
+
+    #[test]
+    fn test_tee_writter() {
+        let path = std::env::temp_dir().join(format!("tee-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let inner = MemoryWritter::new();
+        let sent = inner.messages();
+        let mut writter = TeeWritter::new(inner, &path).unwrap();
+
+        writter.send_message(&"ping").unwrap();
+        writter.send_messages(&["a", "b"]).unwrap();
+
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(*sent.lock().unwrap(), ["ping", "a", "b"]);
+        let messages = recorded
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .inspect(|line| assert!(line["ts_us"].as_u64().unwrap() > 0))
+            .map(|line| line["message"].as_str().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(messages, ["ping", "a", "b"]);
+    }
+}